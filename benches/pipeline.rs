@@ -0,0 +1,124 @@
+use std::{hint::black_box, io::Cursor};
+
+use akp05::{
+    images,
+    inputs::{InputState, ReportDecoder},
+    mappings::{KEY_COUNT, Kind},
+    transport::REPORT_LENGTH,
+};
+use criterion::{Criterion, criterion_group, criterion_main};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+/// Same kind of icon OpenDeck renders: 144x144 RGBA with some detail in it
+fn icon(seed: u8) -> DynamicImage {
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(144, 144, |x, y| {
+        Rgba([
+            (x as u8).wrapping_add(seed),
+            (y as u8).wrapping_mul(3),
+            ((x ^ y) as u8).wrapping_add(seed),
+            255,
+        ])
+    }))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - i * 6) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Data url the way OpenDeck sends key images
+fn data_url(seed: u8) -> String {
+    let mut body = Cursor::new(vec![]);
+    icon(seed)
+        .into_rgb8()
+        .write_to(&mut body, ImageFormat::Jpeg)
+        .unwrap();
+
+    format!("data:image/jpeg;base64,{}", base64(&body.into_inner()))
+}
+
+fn report(input: u8, state: u8) -> Vec<u8> {
+    let mut report = vec![0u8; REPORT_LENGTH];
+    report[..3].copy_from_slice(&[65, 67, 75]);
+    report[9] = input;
+    report[10] = state;
+
+    report
+}
+
+fn encode(c: &mut Criterion) {
+    let format = Kind::Akp05E.image_format();
+    let image = icon(0);
+
+    c.bench_function("encode rgba to 120x120 jpeg", |b| {
+        b.iter(|| images::encode_image(format, 90, black_box(image.clone())).unwrap())
+    });
+}
+
+fn page_refresh(c: &mut Criterion) {
+    let format = Kind::Akp05E.image_format();
+    let urls: Vec<String> = (0..KEY_COUNT as u8).map(|i| data_url(i * 20)).collect();
+
+    c.bench_function("prepare full 10 key page", |b| {
+        b.iter(|| {
+            for url in &urls {
+                let image = images::decode_data_url(black_box(url)).unwrap();
+
+                images::encode_image(format, 90, image).unwrap();
+            }
+        })
+    });
+}
+
+fn decode(c: &mut Criterion) {
+    let kind = Kind::Akp05E;
+
+    // Press and release of every key, then every knob twisted both ways and clicked
+    let mut reports = vec![];
+    for key in 0x01..=0x0A {
+        reports.push(report(key, 1));
+        reports.push(report(key, 0));
+    }
+    for code in [0xA0, 0xA1, 0x50, 0x51, 0x90, 0x91, 0x70, 0x71] {
+        reports.push(report(code, 0));
+    }
+    for code in [0x37, 0x35, 0x33, 0x36] {
+        reports.push(report(code, 1));
+        reports.push(report(code, 0));
+    }
+
+    c.bench_function("decode input reports", |b| {
+        let mut state = InputState::new(&kind);
+        let decoder = ReportDecoder::for_kind(&kind);
+
+        b.iter(|| {
+            for report in &reports {
+                let input = decoder.decode(black_box(report)).unwrap();
+                black_box(state.apply(input));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, encode, page_refresh, decode);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+/*
+ * C API of the akp05 library, build it with
+ *
+ *   cargo build --release --lib --features ffi
+ *
+ * Functions returning int return 0 on success and -1 on failure, akp05_last_error()
+ * tells what went wrong. Every call blocks until the device is done.
+ */
+
+#ifndef AKP05_H
+#define AKP05_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Akp05Device Akp05Device;
+
+typedef enum Akp05EventKind {
+    AKP05_KEY_DOWN = 0,
+    AKP05_KEY_UP = 1,
+    AKP05_ENCODER_DOWN = 2,
+    AKP05_ENCODER_UP = 3,
+    AKP05_ENCODER_TWIST = 4,
+} Akp05EventKind;
+
+/* index is the key or encoder, ticks is only set for twists */
+typedef struct Akp05Event {
+    Akp05EventKind kind;
+    uint8_t index;
+    int8_t ticks;
+} Akp05Event;
+
+/* Connects to the first device found and blanks it, brightness is 0 - 100, NULL on failure */
+Akp05Device *akp05_open(uint8_t brightness);
+
+/* Blanks the device and disconnects from it, NULL is ignored */
+void akp05_close(Akp05Device *device);
+
+/* Device ID, e.g. "a5-<serial>", free it with akp05_free_string() */
+char *akp05_device_id(const Akp05Device *device);
+
+void akp05_free_string(char *string);
+
+int akp05_set_brightness(Akp05Device *device, uint8_t brightness);
+
+/*
+ * Draws an image on a position, numbered like in OpenDeck: strip zones 0 - 4, then keys 5 - 14.
+ * rgb holds width * height pixels, 3 bytes each, row by row. NULL rgb clears the position.
+ */
+int akp05_set_image(Akp05Device *device, uint8_t position, const uint8_t *rgb, uint32_t width,
+                    uint32_t height);
+
+int akp05_clear_all(Akp05Device *device);
+
+/*
+ * Waits up to timeout_ms for an input event, negative timeout waits forever.
+ * Returns 1 if event was filled in, 0 on timeout and -1 on failure.
+ */
+int akp05_poll_input(Akp05Device *device, int timeout_ms, Akp05Event *event);
+
+/* Message of the last failure on this thread, valid until the next failing call */
+const char *akp05_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,225 @@
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage, Rgba, imageops};
+
+use akp05::{
+    images::{KeyImage, decode_data_url},
+    text::{draw_text, text_size},
+};
+
+/// Share of the key's shorter side a counter or icon badge covers
+pub const BADGE_SCALE: f32 = 0.4;
+
+/// Share of the key's shorter side a dot covers
+pub const DOT_SCALE: f32 = 0.2;
+
+/// Highest count shown as is, higher ones show as "99+"
+pub const MAX_COUNT: u32 = 99;
+
+const COUNTER_COLOR: Rgb<u8> = Rgb([220, 40, 40]);
+const COUNTER_TEXT: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Corner of the key a badge sits in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Corner {
+    TopLeft,
+    #[default]
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "top_left" => Some(Self::TopLeft),
+            "top_right" => Some(Self::TopRight),
+            "bottom_left" => Some(Self::BottomLeft),
+            "bottom_right" => Some(Self::BottomRight),
+            _ => None,
+        }
+    }
+
+    /// Top left of a badge of `size` in an image of `width` and `height`
+    fn origin(self, width: u32, height: u32, size: u32) -> (i64, i64) {
+        let right = width.saturating_sub(size) as i64;
+        let bottom = height.saturating_sub(size) as i64;
+
+        match self {
+            Self::TopLeft => (0, 0),
+            Self::TopRight => (right, 0),
+            Self::BottomLeft => (0, bottom),
+            Self::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// What a badge shows
+#[derive(Debug, Clone, PartialEq)]
+pub enum BadgeKind {
+    /// Number in a red circle, e.g. unread messages
+    Count(u32),
+    /// Plain colored dot, e.g. a status light
+    Dot(Rgb<u8>),
+    /// Small image, transparent parts show the key image
+    Icon(KeyImage),
+}
+
+/// Small overlay drawn over a key image, kept by the writer until it's cleared
+///
+/// The key image itself stays as it was sent, so changing the badge doesn't need it again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Badge {
+    pub kind: BadgeKind,
+    pub corner: Corner,
+}
+
+impl Badge {
+    /// Key image with the badge drawn over it
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let (width, height) = image.dimensions();
+        let mut image = image.to_rgba8();
+        let short = width.min(height) as f32;
+
+        match &self.kind {
+            BadgeKind::Count(count) => {
+                let text = match count {
+                    count if *count > MAX_COUNT => format!("{}+", MAX_COUNT),
+                    count => count.to_string(),
+                };
+
+                let size = ((short * BADGE_SCALE).round() as u32).max(1);
+                let (text_width, text_height) = text_size(&text, 1);
+
+                // Wider numbers get a pill instead of a circle
+                let badge_width = size.max(text_width + size / 2);
+                let mut badge = RgbImage::new(badge_width, size);
+                let mut mask = pill(badge_width, size);
+
+                for pixel in badge.pixels_mut() {
+                    *pixel = COUNTER_COLOR;
+                }
+
+                let x = (badge_width as i64 - text_width as i64) / 2;
+                let y = (size as i64 - text_height as i64) / 2;
+                draw_text(&mut badge, x, y, &text, 1, COUNTER_TEXT);
+
+                for (bx, by, pixel) in mask.enumerate_pixels_mut() {
+                    let [r, g, b] = badge.get_pixel(bx, by).0;
+                    pixel.0 = [r, g, b, pixel.0[3]];
+                }
+
+                let (x, y) = self.corner.origin(width, height, size);
+                // Pills stick out to the side away from the edge
+                let x = match self.corner {
+                    Corner::TopRight | Corner::BottomRight => {
+                        width.saturating_sub(badge_width) as i64
+                    }
+                    _ => x,
+                };
+
+                imageops::overlay(&mut image, &mask, x, y);
+            }
+            BadgeKind::Dot(color) => {
+                let size = ((short * DOT_SCALE).round() as u32).max(1);
+                let mut dot = pill(size, size);
+
+                for pixel in dot.pixels_mut() {
+                    let [r, g, b] = color.0;
+                    pixel.0 = [r, g, b, pixel.0[3]];
+                }
+
+                let (x, y) = self.corner.origin(width, height, size);
+                imageops::overlay(&mut image, &dot, x, y);
+            }
+            BadgeKind::Icon(icon) => {
+                let icon = match icon {
+                    KeyImage::DataUrl(url) => match decode_data_url(url) {
+                        Ok(icon) => icon,
+                        Err(err) => {
+                            log::warn!("Failed to decode badge icon: {}", err);
+                            return DynamicImage::ImageRgba8(image);
+                        }
+                    },
+                    KeyImage::Rendered(icon) => (**icon).clone(),
+                };
+
+                let size = ((short * BADGE_SCALE).round() as u32).max(1);
+                let icon = icon.resize_exact(size, size, imageops::FilterType::Triangle);
+
+                let (x, y) = self.corner.origin(width, height, size);
+                imageops::overlay(&mut image, &icon.to_rgba8(), x, y);
+            }
+        }
+
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+/// White image of `width` by `height` with rounded ends, everything outside is transparent
+fn pill(width: u32, height: u32) -> image::RgbaImage {
+    let radius = height as f32 / 2.0;
+
+    image::RgbaImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        // Distance to the line between the centers of both ends
+        let cx = x.clamp(radius, (width as f32 - radius).max(radius));
+        let inside = (x - cx).powi(2) + (y - radius).powi(2) <= radius * radius;
+
+        Rgba([255, 255, 255, if inside { 255 } else { 0 }])
+    })
+}
+
+/// Parses a color like `#ff8800`
+pub fn parse_color(value: &str) -> Option<Rgb<u8>> {
+    let hex = value.strip_prefix('#')?;
+
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let channel = |index: usize| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok();
+
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn black(size: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::new(size, size))
+    }
+
+    #[test]
+    fn badges_are_drawn_in_their_corner() {
+        let dot = Badge {
+            kind: BadgeKind::Dot(Rgb([0, 255, 0])),
+            corner: Corner::BottomLeft,
+        };
+        let image = dot.apply(&black(100)).to_rgb8();
+
+        // 20 pixel dot in the bottom left, the rest of the key is untouched
+        assert_eq!(image.get_pixel(10, 90), &Rgb([0, 255, 0]));
+        assert_eq!(image.get_pixel(90, 10), &Rgb([0, 0, 0]));
+        assert_eq!(image.get_pixel(50, 50), &Rgb([0, 0, 0]));
+        // Corners of the dot are cut off
+        assert_eq!(image.get_pixel(0, 99), &Rgb([0, 0, 0]));
+
+        let count = Badge {
+            kind: BadgeKind::Count(3),
+            corner: Corner::TopRight,
+        };
+        let image = count.apply(&black(100)).to_rgb8();
+
+        assert_eq!(image.get_pixel(65, 20), &COUNTER_COLOR);
+        assert_eq!(image.get_pixel(20, 20), &Rgb([0, 0, 0]));
+        assert!(image.pixels().any(|pixel| *pixel == COUNTER_TEXT));
+    }
+
+    #[test]
+    fn colors_are_parsed() {
+        assert_eq!(parse_color("#ff8800"), Some(Rgb([255, 136, 0])));
+        assert_eq!(parse_color("ff8800"), None);
+        assert_eq!(parse_color("#ff88"), None);
+        assert_eq!(parse_color("#gg8800"), None);
+    }
+}
@@ -0,0 +1,319 @@
+/// Direction of a character as far as reordering cares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    /// Latin, Cyrillic, CJK and everything else written left to right
+    Left,
+    /// Hebrew and Arabic
+    Right,
+    /// Digits, they keep their order inside right-to-left text
+    Number,
+    /// Spaces and punctuation, they take the direction of what's around them
+    Neutral,
+}
+
+fn class(ch: char) -> Class {
+    match ch {
+        '0'..='9' | '\u{0660}'..='\u{0669}' | '\u{06F0}'..='\u{06F9}' => Class::Number,
+        '\u{0590}'..='\u{08FF}' | '\u{FB1D}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFF}' => Class::Right,
+        _ if ch.is_alphabetic() => Class::Left,
+        _ => Class::Neutral,
+    }
+}
+
+/// Checks if text has any right-to-left characters, only those need reordering
+pub fn has_right_to_left(text: &str) -> bool {
+    text.chars().any(|ch| class(ch) == Class::Right)
+}
+
+// Arabic letters with their presentation forms, which start at U+FE80 in this order. Letters
+// with 4 forms join on both sides, ones with 2 only to the letter before them.
+const ARABIC_FORMS: [(char, u32); 36] = [
+    ('\u{0621}', 1),
+    ('\u{0622}', 2),
+    ('\u{0623}', 2),
+    ('\u{0624}', 2),
+    ('\u{0625}', 2),
+    ('\u{0626}', 4),
+    ('\u{0627}', 2),
+    ('\u{0628}', 4),
+    ('\u{0629}', 2),
+    ('\u{062A}', 4),
+    ('\u{062B}', 4),
+    ('\u{062C}', 4),
+    ('\u{062D}', 4),
+    ('\u{062E}', 4),
+    ('\u{062F}', 2),
+    ('\u{0630}', 2),
+    ('\u{0631}', 2),
+    ('\u{0632}', 2),
+    ('\u{0633}', 4),
+    ('\u{0634}', 4),
+    ('\u{0635}', 4),
+    ('\u{0636}', 4),
+    ('\u{0637}', 4),
+    ('\u{0638}', 4),
+    ('\u{0639}', 4),
+    ('\u{063A}', 4),
+    ('\u{0641}', 4),
+    ('\u{0642}', 4),
+    ('\u{0643}', 4),
+    ('\u{0644}', 4),
+    ('\u{0645}', 4),
+    ('\u{0646}', 4),
+    ('\u{0647}', 4),
+    ('\u{0648}', 2),
+    ('\u{0649}', 2),
+    ('\u{064A}', 4),
+];
+
+// Lam followed by these alefs is always written as one ligature, isolated form first
+const LAM_ALEF: [(char, char); 4] = [
+    ('\u{0622}', '\u{FEF5}'),
+    ('\u{0623}', '\u{FEF7}'),
+    ('\u{0625}', '\u{FEF9}'),
+    ('\u{0627}', '\u{FEFB}'),
+];
+
+const LAM: char = '\u{0644}';
+const TATWEEL: char = '\u{0640}';
+
+/// First presentation form and number of forms of an Arabic letter
+fn arabic_forms(ch: char) -> Option<(u32, u32)> {
+    let mut first = 0xFE80;
+
+    for (letter, forms) in ARABIC_FORMS {
+        if letter == ch {
+            return Some((first, forms));
+        }
+
+        first += forms;
+    }
+
+    None
+}
+
+/// Vowel marks sit on letters without breaking the joins between them
+fn is_transparent(ch: char) -> bool {
+    matches!(ch, '\u{064B}'..='\u{065F}' | '\u{0670}')
+}
+
+fn joins_next(ch: char) -> bool {
+    ch == TATWEEL || arabic_forms(ch).is_some_and(|(_, forms)| forms == 4)
+}
+
+fn joins_previous(ch: char) -> bool {
+    ch == TATWEEL || arabic_forms(ch).is_some_and(|(_, forms)| forms > 1)
+}
+
+/// Replaces Arabic letters with the forms they take next to their neighbours
+///
+/// Fonts draw the presentation forms, so joined text looks joined without a shaping engine.
+pub fn shape_arabic(chars: &[char]) -> Vec<char> {
+    // Neighbours that take part in joining, skipping vowel marks
+    let neighbour = |from: usize, step: isize| {
+        let mut index = from as isize + step;
+
+        while index >= 0 && (index as usize) < chars.len() {
+            if !is_transparent(chars[index as usize]) {
+                return Some(chars[index as usize]);
+            }
+
+            index += step;
+        }
+
+        None
+    };
+
+    let mut shaped = Vec::with_capacity(chars.len());
+    let mut index = 0;
+
+    while index < chars.len() {
+        let ch = chars[index];
+        let after_joining = neighbour(index, -1).is_some_and(joins_next);
+
+        if ch == LAM
+            && let Some(next) = chars.get(index + 1)
+            && let Some((_, ligature)) = LAM_ALEF.iter().find(|(alef, _)| alef == next)
+        {
+            shaped.push(char::from_u32(*ligature as u32 + after_joining as u32).unwrap_or(ch));
+            index += 2;
+            continue;
+        }
+
+        let form = match arabic_forms(ch) {
+            Some((first, forms)) => {
+                let before = after_joining && forms > 1;
+                let after = forms == 4 && neighbour(index, 1).is_some_and(joins_previous);
+
+                // Isolated, final, initial and medial
+                let offset = match (before, after) {
+                    (false, false) => 0,
+                    (true, false) => 1,
+                    (false, true) => 2,
+                    (true, true) => 3,
+                };
+
+                char::from_u32(first + offset).unwrap_or(ch)
+            }
+            None => ch,
+        };
+
+        shaped.push(form);
+        index += 1;
+    }
+
+    shaped
+}
+
+fn mirrored(ch: char) -> char {
+    match ch {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        _ => ch,
+    }
+}
+
+/// Puts a line of text in the order it's drawn from left to right
+///
+/// A simplified version of the Unicode bidirectional algorithm, without explicit embeddings:
+/// the first strong character sets the direction of the line, runs of the other direction are
+/// reversed and numbers keep their order. Arabic letters are shaped before reordering.
+pub fn visual_order(text: &str) -> Vec<char> {
+    let chars: Vec<char> = text.chars().collect();
+
+    if !has_right_to_left(text) {
+        return chars;
+    }
+
+    let chars = shape_arabic(&chars);
+    let classes: Vec<Class> = chars.iter().map(|ch| class(*ch)).collect();
+
+    let right_to_left = classes
+        .iter()
+        .find(|class| matches!(class, Class::Left | Class::Right))
+        == Some(&Class::Right);
+    let base = right_to_left as u8;
+
+    // Numbers after left-to-right text are part of it, otherwise they're a run of their own
+    let mut strong = if right_to_left {
+        Class::Right
+    } else {
+        Class::Left
+    };
+    let mut resolved = classes.clone();
+
+    for (index, class) in classes.iter().enumerate() {
+        match class {
+            Class::Left | Class::Right => strong = *class,
+            Class::Number if strong == Class::Left => resolved[index] = Class::Left,
+            _ => {}
+        }
+    }
+
+    // Neutrals between two runs of the same direction take it, others the line's direction
+    let direction = |class: Class| match class {
+        Class::Left => Some(Class::Left),
+        Class::Right | Class::Number => Some(Class::Right),
+        Class::Neutral => None,
+    };
+
+    let levels: Vec<u8> = (0..chars.len())
+        .map(|index| {
+            let class = match resolved[index] {
+                Class::Neutral => {
+                    let before = resolved[..index].iter().rev().find_map(|c| direction(*c));
+                    let after = resolved[index + 1..].iter().find_map(|c| direction(*c));
+
+                    match (before, after) {
+                        (Some(before), Some(after)) if before == after => before,
+                        _ if right_to_left => Class::Right,
+                        _ => Class::Left,
+                    }
+                }
+                class => class,
+            };
+
+            match class {
+                Class::Right => 1,
+                // Numbers and left-to-right text inside right-to-left text go one level up
+                Class::Number => 2,
+                _ => base * 2,
+            }
+        })
+        .collect();
+
+    // Reverse every run at each level, from the highest one down
+    let mut order: Vec<usize> = (0..chars.len()).collect();
+    let highest = levels.iter().copied().max().unwrap_or(0);
+
+    for level in (1..=highest).rev() {
+        let mut start = 0;
+
+        while start < order.len() {
+            if levels[order[start]] < level {
+                start += 1;
+                continue;
+            }
+
+            let end = (start..order.len())
+                .find(|i| levels[order[*i]] < level)
+                .unwrap_or(order.len());
+
+            order[start..end].reverse();
+            start = end;
+        }
+    }
+
+    order
+        .into_iter()
+        .map(|index| match levels[index] % 2 {
+            1 => mirrored(chars[index]),
+            _ => chars[index],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visual(text: &str) -> String {
+        visual_order(text).into_iter().collect()
+    }
+
+    #[test]
+    fn right_to_left_runs_are_reversed() {
+        assert_eq!(visual("Mute"), "Mute");
+        // Hebrew "shalom" in an English line, and English in a Hebrew one
+        assert_eq!(visual("Say שלום now"), "Say םולש now");
+        assert_eq!(visual("שלום OBS"), "OBS םולש");
+        // Numbers keep their order, brackets are mirrored
+        assert_eq!(visual("ערוץ 12 (א)"), "(א) 12 ץורע");
+    }
+
+    #[test]
+    fn arabic_letters_are_joined() {
+        // Beh, teh, alef: initial, medial and final forms, then reversed
+        assert_eq!(
+            shape_arabic(&['\u{0628}', '\u{062A}', '\u{0627}']),
+            ['\u{FE91}', '\u{FE98}', '\u{FE8E}']
+        );
+        // Alef doesn't join the next letter, so beh after it stands alone
+        assert_eq!(
+            shape_arabic(&['\u{0627}', '\u{0628}']),
+            ['\u{FE8D}', '\u{FE8F}']
+        );
+        // Lam and alef become one ligature
+        assert_eq!(shape_arabic(&['\u{0644}', '\u{0627}']), ['\u{FEFB}']);
+        assert_eq!(
+            visual("\u{0628}\u{062A}\u{0627}"),
+            "\u{FE8E}\u{FE98}\u{FE91}"
+        );
+    }
+}
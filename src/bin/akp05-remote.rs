@@ -0,0 +1,680 @@
+//! Standalone WebSocket server for driving AKP05 decks without OpenDeck, e.g. from Bitfocus Companion
+//!
+//! Usage: `akp05-remote [port] [--simulate] [--vendor-interface]`, listens on `127.0.0.1:9870` by default. Every
+//! message is a JSON object with an `event` field, see the README for the full list.
+//!
+//! `--simulate` adds a fake deck shown by `tools/simulator.html`, for development without the
+//! hardware. `--vendor-interface` also opens the secondary interface of devices that have one.
+
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use akp05::{
+    capabilities::Capabilities,
+    deck::{connect, handle_set_image, initialize_device, read_updates},
+    discovery::{device_info_to_candidate, get_candidates, serial_to_id},
+    error::{Akp05Error, ErrorContext, Operation},
+    images::{DEFAULT_JPEG_QUALITY, ImageFormat, KeyImage},
+    inputs::InputState,
+    mappings::{CandidateDevice, Kind, QUERIES},
+    simulator::{DEFAULT_SIM_PORT, SIMULATED_ID, SimTransport},
+    transport::{DeviceTransport, HidTransport},
+};
+use futures_lite::StreamExt;
+use futures_util::SinkExt;
+use image::DynamicImage;
+use mirajazz::{
+    device::DeviceWatcher, error::MirajazzError, state::DeviceStateUpdate,
+    types::DeviceLifecycleEvent,
+};
+use serde_json::{Value, json};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, RwLock, broadcast},
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+/// Port used when none is given
+const DEFAULT_PORT: u16 = 9870;
+
+/// Brightness devices start with, clients can change it
+const DEFAULT_BRIGHTNESS: u8 = 50;
+
+/// How many events a slow client can fall behind before it starts missing them
+const EVENT_BUFFER: usize = 256;
+
+/// Real deck, or the simulated one
+enum Deck {
+    // Boxed, it's much bigger than the simulated one
+    Hid(Box<HidTransport>),
+    Sim(SimTransport),
+}
+
+impl DeviceTransport for Deck {
+    fn vid(&self) -> u16 {
+        match self {
+            Self::Hid(device) => device.vid(),
+            Self::Sim(device) => device.vid(),
+        }
+    }
+
+    fn pid(&self) -> u16 {
+        match self {
+            Self::Hid(device) => device.pid(),
+            Self::Sim(device) => device.pid(),
+        }
+    }
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.set_brightness(percent).await,
+            Self::Sim(device) => device.set_brightness(percent).await,
+        }
+    }
+
+    async fn set_button_image(
+        &self,
+        key: u8,
+        format: ImageFormat,
+        quality: u8,
+        image: DynamicImage,
+    ) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.set_button_image(key, format, quality, image).await,
+            Self::Sim(device) => device.set_button_image(key, format, quality, image).await,
+        }
+    }
+
+    async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.clear_button_image(key).await,
+            Self::Sim(device) => device.clear_button_image(key).await,
+        }
+    }
+
+    async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.clear_all_button_images().await,
+            Self::Sim(device) => device.clear_all_button_images().await,
+        }
+    }
+
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.flush().await,
+            Self::Sim(device) => device.flush().await,
+        }
+    }
+
+    async fn reset(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.reset().await,
+            Self::Sim(device) => device.reset().await,
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.shutdown().await,
+            Self::Sim(device) => device.shutdown().await,
+        }
+    }
+
+    async fn send_command(&self, command: &[u8]) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.send_command(command).await,
+            Self::Sim(device) => device.send_command(command).await,
+        }
+    }
+
+    async fn read_report(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, MirajazzError> {
+        match self {
+            Self::Hid(device) => device.read_report(timeout).await,
+            Self::Sim(device) => device.read_report(timeout).await,
+        }
+    }
+
+    fn uploaded_bytes(&self) -> u64 {
+        match self {
+            Self::Hid(device) => device.uploaded_bytes(),
+            Self::Sim(device) => device.uploaded_bytes(),
+        }
+    }
+
+    fn has_vendor_interface(&self) -> bool {
+        match self {
+            Self::Hid(device) => device.has_vendor_interface(),
+            Self::Sim(device) => device.has_vendor_interface(),
+        }
+    }
+
+    async fn send_vendor_command(&self, command: &[u8]) -> Result<(), MirajazzError> {
+        match self {
+            Self::Hid(device) => device.send_vendor_command(command).await,
+            Self::Sim(device) => device.send_vendor_command(command).await,
+        }
+    }
+}
+
+/// Device the server is connected to
+struct Connected {
+    capabilities: Capabilities,
+    device: Arc<Deck>,
+    // Keeps image uploads of different clients from interleaving
+    writes: Arc<Mutex<()>>,
+    token: CancellationToken,
+}
+
+struct Server {
+    devices: RwLock<HashMap<String, Connected>>,
+    // Events as JSON text, sent to every client
+    events: broadcast::Sender<String>,
+    vendor_interface: bool,
+}
+
+/// Message sent by a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    ListDevices,
+    /// Sets image of a position, clears it if `image` is missing and clears everything without
+    /// `position` either
+    SetImage {
+        device: Option<String>,
+        position: Option<u8>,
+        image: Option<String>,
+    },
+    SetBrightness {
+        device: Option<String>,
+        brightness: u8,
+    },
+}
+
+fn parse_request(text: &str) -> Result<Request, String> {
+    let value: Value = serde_json::from_str(text).map_err(|err| format!("Bad JSON: {}", err))?;
+
+    let device = value
+        .get("device")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let number = |field: &str, max: u64| match value.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(number) => number
+            .as_u64()
+            .filter(|number| *number <= max)
+            .map(|number| Some(number as u8))
+            .ok_or_else(|| format!("\"{}\" must be a number between 0 and {}", field, max)),
+    };
+
+    match value.get("event").and_then(Value::as_str) {
+        Some("listDevices") => Ok(Request::ListDevices),
+        Some("setImage") => Ok(Request::SetImage {
+            device,
+            position: number("position", u8::MAX as u64)?,
+            image: value
+                .get("image")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        }),
+        Some("setBrightness") => Ok(Request::SetBrightness {
+            device,
+            brightness: number("brightness", 100)?.ok_or("\"brightness\" is missing")?,
+        }),
+        Some(event) => Err(format!("Unknown event \"{}\"", event)),
+        None => Err("\"event\" is missing".to_string()),
+    }
+}
+
+/// Input event as sent to clients, named like the OpenDeck ones
+fn update_event(id: &str, update: &DeviceStateUpdate) -> Value {
+    match *update {
+        DeviceStateUpdate::ButtonDown(key) => {
+            json!({ "event": "keyDown", "device": id, "key": key })
+        }
+        DeviceStateUpdate::ButtonUp(key) => json!({ "event": "keyUp", "device": id, "key": key }),
+        DeviceStateUpdate::EncoderDown(encoder) => {
+            json!({ "event": "dialDown", "device": id, "dial": encoder })
+        }
+        DeviceStateUpdate::EncoderUp(encoder) => {
+            json!({ "event": "dialUp", "device": id, "dial": encoder })
+        }
+        DeviceStateUpdate::EncoderTwist(encoder, ticks) => {
+            json!({ "event": "dialRotate", "device": id, "dial": encoder, "ticks": ticks })
+        }
+    }
+}
+
+fn device_info(id: &str, capabilities: &Capabilities) -> Value {
+    let mut info = capabilities.to_json();
+    info["id"] = json!(id);
+
+    info
+}
+
+fn error_event(message: impl ToString) -> Value {
+    json!({ "event": "error", "message": message.to_string() })
+}
+
+impl Server {
+    fn publish(&self, event: Value) {
+        // Nobody listening is fine
+        let _ = self.events.send(event.to_string());
+    }
+
+    async fn devices_event(&self) -> Value {
+        let devices: Vec<Value> = self
+            .devices
+            .read()
+            .await
+            .iter()
+            .map(|(id, connected)| device_info(id, &connected.capabilities))
+            .collect();
+
+        json!({ "event": "devices", "devices": devices })
+    }
+
+    /// Devices a request is for, every device if it doesn't name one
+    async fn targets(
+        &self,
+        device: &Option<String>,
+    ) -> Result<Vec<(String, Arc<Deck>, Arc<Mutex<()>>)>, String> {
+        let devices = self.devices.read().await;
+
+        let targets: Vec<_> = devices
+            .iter()
+            .filter(|(id, _)| device.as_ref().is_none_or(|device| device == *id))
+            .map(|(id, connected)| {
+                (
+                    id.clone(),
+                    connected.device.clone(),
+                    connected.writes.clone(),
+                )
+            })
+            .collect();
+
+        match device {
+            Some(device) if targets.is_empty() => Err(format!("Unknown device \"{}\"", device)),
+            _ => Ok(targets),
+        }
+    }
+
+    /// Applies a request, returning reply for the client that sent it
+    async fn handle(&self, request: Request) -> Option<Value> {
+        let device = match &request {
+            Request::ListDevices => return Some(self.devices_event().await),
+            Request::SetImage { device, .. } | Request::SetBrightness { device, .. } => device,
+        };
+
+        let targets = match self.targets(device).await {
+            Ok(targets) => targets,
+            Err(err) => return Some(error_event(err)),
+        };
+
+        for (id, device, writes) in targets {
+            let _writes = writes.lock().await;
+
+            let result = match &request {
+                Request::SetImage {
+                    position, image, ..
+                } => {
+                    let image = image.clone().map(KeyImage::DataUrl);
+
+                    handle_set_image(&id, device.as_ref(), *position, image, DEFAULT_JPEG_QUALITY)
+                        .await
+                }
+                Request::SetBrightness { brightness, .. } => device
+                    .set_brightness(*brightness)
+                    .await
+                    .context(&id, Operation::SetBrightness),
+                Request::ListDevices => Ok(()),
+            };
+
+            if let Err(err) = result {
+                log::error!("{}", err);
+
+                if err.is_fatal() {
+                    self.remove_device(&id).await;
+                }
+
+                return Some(error_event(err));
+            }
+        }
+
+        None
+    }
+
+    async fn add_device(self: &Arc<Self>, candidate: CandidateDevice) {
+        if self.devices.read().await.contains_key(&candidate.id) {
+            return;
+        }
+
+        let device = async {
+            let mut device = connect(&candidate).await?;
+            if self.vendor_interface {
+                device = device.with_vendor_interface(&candidate).await;
+            }
+
+            initialize_device(&candidate.id, &device, DEFAULT_BRIGHTNESS).await?;
+
+            Ok::<_, Akp05Error>(device)
+        }
+        .await;
+
+        match device {
+            Ok(device) => {
+                self.attach(candidate.id, candidate.kind, Deck::Hid(Box::new(device)))
+                    .await
+            }
+            Err(err) => log::error!("{}", err),
+        }
+    }
+
+    /// Makes a connected device available to clients and starts reading its inputs
+    async fn attach(self: &Arc<Self>, id: String, kind: Kind, device: Deck) {
+        let device = Arc::new(device);
+        let token = CancellationToken::new();
+        let capabilities = Capabilities::of_device(kind, device.as_ref());
+
+        self.devices.write().await.insert(
+            id.clone(),
+            Connected {
+                capabilities: capabilities.clone(),
+                device: device.clone(),
+                writes: Arc::new(Mutex::new(())),
+                token: token.clone(),
+            },
+        );
+
+        log::info!("Connected to {}", id);
+        self.publish(json!({
+            "event": "deviceConnected",
+            "device": device_info(&id, &capabilities),
+        }));
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = server.read_inputs(&id, &kind, device.as_ref()) => {}
+                _ = token.cancelled() => {}
+            }
+        });
+    }
+
+    async fn remove_device(&self, id: &str) {
+        let Some(connected) = self.devices.write().await.remove(id) else {
+            return;
+        };
+
+        connected.token.cancel();
+
+        log::info!("Disconnected from {}", id);
+        self.publish(json!({ "event": "deviceDisconnected", "device": id }));
+    }
+
+    /// Sends inputs of a device to every client until the device fails
+    async fn read_inputs(&self, id: &str, kind: &Kind, device: &Deck) {
+        let mut state = InputState::new(kind);
+
+        loop {
+            match read_updates(id, device, &mut state, None, None).await {
+                Ok(updates) => {
+                    for update in updates {
+                        self.publish(update_event(id, &update));
+                    }
+                }
+                Err(err) => {
+                    log::error!("{}", err);
+
+                    if err.is_fatal() {
+                        self.remove_device(id).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the simulated deck whenever a panel connects, a new panel replaces the old one
+    async fn serve_simulator(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, DEFAULT_SIM_PORT)).await?;
+        log::info!(
+            "Waiting for simulator panel on ws://{}",
+            listener.local_addr()?
+        );
+
+        loop {
+            let (stream, address) = listener.accept().await?;
+
+            let device = async {
+                let device = SimTransport::accept(stream)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                let device = Deck::Sim(device);
+
+                initialize_device(SIMULATED_ID, &device, DEFAULT_BRIGHTNESS)
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+                Ok::<_, String>(device)
+            }
+            .await;
+
+            match device {
+                Ok(device) => {
+                    log::info!("Simulator panel {} connected", address);
+
+                    self.remove_device(SIMULATED_ID).await;
+                    self.attach(SIMULATED_ID.to_string(), Kind::Akp05E, device)
+                        .await;
+                }
+                Err(err) => log::warn!("Simulator panel {} failed: {}", address, err),
+            }
+        }
+    }
+
+    /// Connects to devices that are plugged in now and later on
+    async fn watch_devices(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        for candidate in get_candidates().await? {
+            self.add_device(candidate).await;
+        }
+
+        let mut watcher = DeviceWatcher::new();
+        let mut stream = watcher.watch(&QUERIES).await?;
+
+        while let Some(event) = stream.next().await {
+            match event {
+                DeviceLifecycleEvent::Connected(info) => {
+                    if let Some(candidate) = device_info_to_candidate(info) {
+                        self.add_device(candidate).await;
+                    }
+                }
+                DeviceLifecycleEvent::Disconnected(info) => {
+                    if let Some(serial) = info.serial_number {
+                        self.remove_device(&serial_to_id(&serial)).await;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn serve_client(&self, stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+        let mut socket = tokio_tungstenite::accept_async(stream).await?;
+        let mut events = self.events.subscribe();
+
+        // Clients learn about devices right away, without asking
+        socket
+            .send(Message::text(self.devices_event().await.to_string()))
+            .await?;
+
+        loop {
+            tokio::select! {
+                message = socket.next() => {
+                    let text = match message {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(err)) => return Err(err.into()),
+                    };
+
+                    let reply = match parse_request(&text) {
+                        Ok(request) => self.handle(request).await,
+                        Err(err) => Some(error_event(err)),
+                    };
+
+                    if let Some(reply) = reply {
+                        socket.send(Message::text(reply.to_string())).await?;
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(event) => socket.send(Message::text(event)).await?,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("Client is too slow, it missed {} events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    simplelog::TermLogger::init(
+        simplelog::LevelFilter::Info,
+        simplelog::Config::default(),
+        simplelog::TerminalMode::Stdout,
+        simplelog::ColorChoice::Never,
+    )
+    .unwrap();
+
+    let mut port = DEFAULT_PORT;
+    let mut simulate = false;
+    let mut vendor_interface = false;
+
+    for arg in std::env::args().skip(1) {
+        if arg == "--simulate" {
+            simulate = true;
+            continue;
+        }
+
+        if arg == "--vendor-interface" {
+            vendor_interface = true;
+            continue;
+        }
+
+        port = arg.parse::<u16>().map_err(|_| {
+            format!(
+                "Bad port \"{}\", usage: akp05-remote [port] [--simulate] [--vendor-interface]",
+                arg
+            )
+        })?;
+    }
+
+    // Local only, anyone who can connect can also draw on the deck and read its inputs
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+    log::info!("Listening on ws://{}", listener.local_addr()?);
+
+    let server = Arc::new(Server {
+        devices: RwLock::new(HashMap::new()),
+        events: broadcast::channel(EVENT_BUFFER).0,
+        vendor_interface,
+    });
+
+    let watcher = server.clone();
+    tokio::spawn(async move {
+        if let Err(err) = watcher.watch_devices().await {
+            log::error!("Device watcher failed: {}", err);
+        }
+    });
+
+    if simulate {
+        let simulator = server.clone();
+        tokio::spawn(async move {
+            if let Err(err) = simulator.serve_simulator().await {
+                log::error!("Simulator failed: {}", err);
+            }
+        });
+    }
+
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            log::info!("Client {} connected", address);
+
+            if let Err(err) = server.serve_client(stream).await {
+                log::warn!("Client {} failed: {}", address, err);
+            }
+
+            log::info!("Client {} disconnected", address);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_parsed() {
+        assert_eq!(
+            parse_request(
+                r#"{ "event": "setImage", "position": 5, "image": "data:image/jpeg;base64," }"#
+            ),
+            Ok(Request::SetImage {
+                device: None,
+                position: Some(5),
+                image: Some("data:image/jpeg;base64,".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_request(r#"{ "event": "setImage", "device": "a5-1" }"#),
+            Ok(Request::SetImage {
+                device: Some("a5-1".to_string()),
+                position: None,
+                image: None,
+            })
+        );
+        assert_eq!(
+            parse_request(r#"{ "event": "setBrightness", "brightness": 20 }"#),
+            Ok(Request::SetBrightness {
+                device: None,
+                brightness: 20
+            })
+        );
+
+        for (text, error) in [
+            (
+                r#"{ "event": "setBrightness", "brightness": 120 }"#,
+                "\"brightness\" must be a number between 0 and 100",
+            ),
+            (
+                r#"{ "event": "setBrightness" }"#,
+                "\"brightness\" is missing",
+            ),
+            (r#"{ "event": "reboot" }"#, "Unknown event \"reboot\""),
+            (r#"{}"#, "\"event\" is missing"),
+        ] {
+            assert_eq!(parse_request(text), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn updates_are_sent_as_json() {
+        assert_eq!(
+            update_event("a5-1", &DeviceStateUpdate::EncoderTwist(2, -1)),
+            json!({ "event": "dialRotate", "device": "a5-1", "dial": 2, "ticks": -1 })
+        );
+        assert_eq!(
+            update_event("a5-1", &DeviceStateUpdate::ButtonDown(7)),
+            json!({ "event": "keyDown", "device": "a5-1", "key": 7 })
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::images::KeyImage;
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{WRITERS, safemode, writer::WriterCommand};
+
+/// How long each image shows when no rate is given
+pub const DEFAULT_BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Fastest rate keys can blink at, quicker ones would only queue up images
+pub const MIN_BLINK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest a key can be asked to blink for
+pub const MAX_BLINK_SECONDS: u64 = 3600;
+
+/// What a blinking key alternates its own image with
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlinkImage {
+    /// Darker variant of the key image
+    Dimmed,
+    Image(KeyImage),
+}
+
+/// Key alternating between two images, for "attention" notifications like an incoming call
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blink {
+    pub image: BlinkImage,
+    /// How long each of the images shows
+    pub interval: Duration,
+    /// How long the key blinks, zero stops blinking right away
+    pub duration: Duration,
+}
+
+impl Blink {
+    /// Whether the other image shows `elapsed` after blinking started, [None] once it's over
+    fn phase(&self, elapsed: Duration) -> Option<bool> {
+        if elapsed >= self.duration {
+            return None;
+        }
+
+        Some((elapsed.as_millis() / self.interval.as_millis()).is_multiple_of(2))
+    }
+
+    /// Time since blinking started when the image changes next
+    fn next_change(&self, elapsed: Duration) -> Duration {
+        let changes = (elapsed.as_millis() / self.interval.as_millis()) as u32 + 1;
+
+        (self.interval * changes).min(self.duration)
+    }
+}
+
+struct Running {
+    blink: Blink,
+    started: Instant,
+    // Other image that's on the device, if any
+    sent: Option<BlinkImage>,
+}
+
+// Device id and position to the blink running there
+static BLINKS: LazyLock<Mutex<HashMap<(String, u8), Running>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Starts blinking a key, replacing whatever blink it had
+pub fn start(device: &str, position: u8, mut blink: Blink) {
+    blink.interval = blink.interval.max(MIN_BLINK_INTERVAL);
+
+    let mut blinks = BLINKS.lock().unwrap();
+    let key = (device.to_string(), position);
+    // Image of the blink this one replaces is still on the device
+    let sent = blinks.remove(&key).and_then(|running| running.sent);
+
+    log::info!(
+        "Blinking key {} of {} for {:?}",
+        position,
+        device,
+        blink.duration
+    );
+
+    blinks.insert(
+        key,
+        Running {
+            blink,
+            started: Instant::now(),
+            sent,
+        },
+    );
+    CHANGED.notify_one();
+}
+
+/// Switches images of blinking keys on time, and puts the key image back when blinking is over
+pub async fn blink_task(token: CancellationToken) {
+    loop {
+        let now = Instant::now();
+        let mut changes = vec![];
+        let mut wake: Option<Instant> = None;
+
+        BLINKS
+            .lock()
+            .unwrap()
+            .retain(|(device, position), running| {
+                let elapsed = now - running.started;
+                // Safe mode stops blinking, the key shows its own image again
+                let phase = match safemode::is_active(device) {
+                    true => None,
+                    false => running.blink.phase(elapsed),
+                };
+
+                let image = phase.unwrap_or(false).then(|| running.blink.image.clone());
+
+                if image != running.sent {
+                    running.sent = image.clone();
+                    changes.push((device.clone(), *position, image));
+                }
+
+                if phase.is_some() {
+                    let at = running.started + running.blink.next_change(elapsed);
+                    wake = Some(wake.map_or(at, |wake| wake.min(at)));
+                }
+
+                phase.is_some()
+            });
+
+        for (device, position, image) in changes {
+            if let Some(writer) = WRITERS.read().await.get(&device) {
+                writer.send(WriterCommand::Blink { position, image });
+            }
+        }
+
+        tokio::select! {
+            _ = async {
+                match wake {
+                    Some(wake) => tokio::time::sleep_until(wake).await,
+                    None => std::future::pending().await,
+                }
+            } => {}
+            _ = CHANGED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blinks_alternate_until_they_are_over() {
+        let blink = Blink {
+            image: BlinkImage::Dimmed,
+            interval: Duration::from_millis(500),
+            duration: Duration::from_millis(1200),
+        };
+
+        assert_eq!(blink.phase(Duration::ZERO), Some(true));
+        assert_eq!(blink.phase(Duration::from_millis(499)), Some(true));
+        assert_eq!(blink.phase(Duration::from_millis(500)), Some(false));
+        assert_eq!(blink.phase(Duration::from_millis(1000)), Some(true));
+        assert_eq!(blink.phase(Duration::from_millis(1200)), None);
+
+        assert_eq!(
+            blink.next_change(Duration::from_millis(100)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            blink.next_change(Duration::from_millis(1100)),
+            Duration::from_millis(1200)
+        );
+    }
+}
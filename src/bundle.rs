@@ -0,0 +1,267 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, LazyLock, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use akp05::{capabilities::Capabilities, capture::ReportHistory, mappings::CandidateDevice};
+use serde_json::{Value, json};
+
+use crate::{CONFIG, DEVICES};
+
+/// Environment variable pointing to the directory bug reports are saved to, home by default
+pub const REPORT_DIR_ENV: &str = "OPENDECK_AKP05_REPORT_DIR";
+
+/// Log lines kept for bug reports
+pub const LOG_LINES: usize = 2000;
+
+/// Raw reports kept for bug reports, per device
+pub const REPORT_COUNT: usize = 200;
+
+// Finished lines, and the one being written
+static LOG: LazyLock<Mutex<(VecDeque<String>, String)>> =
+    LazyLock::new(|| Mutex::new((VecDeque::with_capacity(LOG_LINES), String::new())));
+
+static HISTORIES: LazyLock<Mutex<HashMap<String, Arc<ReportHistory>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// What was found out about every device connected since the start
+static DEVICE_INFO: LazyLock<Mutex<HashMap<String, Value>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Log output kept in memory, the last [LOG_LINES] lines go into bug reports
+pub struct LogBuffer;
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut log = LOG.lock().unwrap();
+        let (lines, partial) = &mut *log;
+
+        partial.push_str(&String::from_utf8_lossy(buf));
+
+        while let Some(end) = partial.find('\n') {
+            if lines.len() == LOG_LINES {
+                lines.pop_front();
+            }
+
+            lines.push_back(partial[..end].to_string());
+            partial.drain(..=end);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Raw reports of a device kept for bug reports, the reader records into it
+pub fn history(device: &str) -> Arc<ReportHistory> {
+    HISTORIES
+        .lock()
+        .unwrap()
+        .entry(device.to_string())
+        .or_insert_with(|| Arc::new(ReportHistory::new(REPORT_COUNT)))
+        .clone()
+}
+
+/// Keeps what's known about a device that just connected
+pub fn record_device(candidate: &CandidateDevice, capabilities: &Capabilities) {
+    let info = json!({
+        "id": candidate.id,
+        "serial": candidate.serial,
+        "port": candidate.port,
+        "vid": format!("{:04x}", candidate.dev.vendor_id),
+        "pid": format!("{:04x}", candidate.dev.product_id),
+        "protocolVersion": candidate.kind.protocol_version(),
+        "capabilities": capabilities.to_json(),
+    });
+
+    DEVICE_INFO
+        .lock()
+        .unwrap()
+        .insert(candidate.id.clone(), info);
+}
+
+/// Zip archive with every file stored as is, so no compression library is needed
+///
+/// Timestamps are left at the earliest date zip has, nothing reads them.
+pub fn zip(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    // Version 2.0, no flags, stored, 00:00 on 1980-01-01
+    let header = |crc: u32, size: u32, name: &str| {
+        let mut header = vec![];
+        header.extend(20u16.to_le_bytes());
+        header.extend([0, 0, 0, 0]);
+        header.extend([0, 0, 0x21, 0]);
+        header.extend(crc.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header
+    };
+
+    let mut zip = vec![];
+    let mut directory = vec![];
+
+    for (name, data) in files {
+        let offset = zip.len() as u32;
+        let header = header(crc32fast::hash(data), data.len() as u32, name);
+
+        zip.extend(0x04034b50u32.to_le_bytes());
+        zip.extend(&header);
+        zip.extend(name.as_bytes());
+        zip.extend(data);
+
+        // Made by version 2.0, no comment, disk 0, no attributes
+        directory.extend(0x02014b50u32.to_le_bytes());
+        directory.extend(20u16.to_le_bytes());
+        directory.extend(&header);
+        directory.extend([0; 10]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+
+    let (start, size) = (zip.len() as u32, directory.len() as u32);
+    zip.extend(directory);
+
+    zip.extend(0x06054b50u32.to_le_bytes());
+    zip.extend([0; 4]);
+    zip.extend((files.len() as u16).to_le_bytes());
+    zip.extend((files.len() as u16).to_le_bytes());
+    zip.extend(size.to_le_bytes());
+    zip.extend(start.to_le_bytes());
+    zip.extend(0u16.to_le_bytes());
+
+    zip
+}
+
+/// Directory bug reports are saved to, [REPORT_DIR_ENV] or the home directory
+fn report_dir() -> PathBuf {
+    std::env::var_os(REPORT_DIR_ENV)
+        .or_else(|| std::env::var_os("HOME"))
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Files of a bug report, nothing in them leaves the machine unless the user sends it
+async fn report_files() -> Vec<(String, Vec<u8>)> {
+    let connected: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+    let devices: Vec<Value> = DEVICE_INFO
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, info)| {
+            let mut info = info.clone();
+            info["connected"] = json!(connected.contains(id));
+            info
+        })
+        .collect();
+
+    let system = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+    });
+
+    // Passwords stay out, the rest helps reproducing
+    let mut config = CONFIG.borrow().clone();
+    for password in [&mut config.mqtt_password, &mut config.obs_password] {
+        if password.is_some() {
+            *password = Some("<redacted>".to_string());
+        }
+    }
+
+    let log = LOG
+        .lock()
+        .unwrap()
+        .0
+        .iter()
+        .fold(String::new(), |log, line| log + line.as_str() + "\n");
+
+    let mut files = vec![
+        ("system.json".to_string(), system.to_string().into_bytes()),
+        (
+            "devices.json".to_string(),
+            Value::from(devices).to_string().into_bytes(),
+        ),
+        (
+            "config.txt".to_string(),
+            format!("{:#?}", config).into_bytes(),
+        ),
+        ("plugin.log".to_string(), log.into_bytes()),
+    ];
+
+    for (id, history) in HISTORIES.lock().unwrap().iter() {
+        files.push((
+            format!("reports/{}.cap", id),
+            history.to_capture().into_bytes(),
+        ));
+    }
+
+    files
+}
+
+/// Saves a bug report zip with recent logs, devices, their capabilities and last raw reports
+pub async fn save_report() -> Result<PathBuf, String> {
+    let files = report_files().await;
+
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let path = report_dir().join(format!("opendeck-akp05-report-{}.zip", created));
+
+    std::fs::write(&path, zip(&files))
+        .map_err(|err| format!("Failed to save {}: {}", path.display(), err))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zip_entries_can_be_found() {
+        let files = vec![
+            ("a.txt".to_string(), b"hello".to_vec()),
+            ("reports/b.cap".to_string(), vec![]),
+        ];
+        let zip = zip(&files);
+
+        // First entry starts right away, with the CRC of its data
+        assert_eq!(&zip[..4], b"PK\x03\x04");
+        assert_eq!(&zip[14..18], &crc32fast::hash(b"hello").to_le_bytes());
+        assert_eq!(&zip[30..35], b"a.txt");
+        assert_eq!(&zip[35..40], b"hello");
+
+        // End record points to the central directory holding both entries
+        let end = &zip[zip.len() - 22..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+
+        let start = u32::from_le_bytes(end[16..20].try_into().unwrap()) as usize;
+        assert_eq!(&zip[start..start + 4], b"PK\x01\x02");
+        assert_eq!(&zip[start + 46..start + 51], b"a.txt");
+    }
+
+    #[test]
+    fn only_the_last_log_lines_are_kept() {
+        let mut buffer = LogBuffer;
+
+        for number in 0..LOG_LINES + 1 {
+            write!(buffer, "line {}", number).unwrap();
+            writeln!(buffer, " done").unwrap();
+        }
+
+        let log = LOG.lock().unwrap();
+        assert_eq!(log.0.len(), LOG_LINES);
+        assert_eq!(log.0[0], "line 1 done");
+        assert!(log.1.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+
+use serde_json::{Value, json};
+
+use crate::{mappings::Kind, transport::DeviceTransport};
+
+/// Optional feature, not every model has it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    /// Images on the touch strip above the encoders
+    StripImage,
+    /// Turning the displays off and on again
+    Sleep,
+    /// Configuration commands through the secondary vendor interface
+    VendorCommands,
+}
+
+impl Feature {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::StripImage => "stripImage",
+            Self::Sleep => "sleep",
+            Self::VendorCommands => "vendorCommands",
+        }
+    }
+}
+
+/// What a connected device has, the layout and features of its model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub name: String,
+    pub rows: usize,
+    pub columns: usize,
+    pub encoders: usize,
+    pub features: BTreeSet<Feature>,
+}
+
+impl Capabilities {
+    /// Layout and features every device of the model has
+    pub fn of_kind(kind: Kind) -> Self {
+        let features = match kind {
+            Kind::Akp05E => BTreeSet::from([Feature::StripImage, Feature::Sleep]),
+        };
+
+        Self {
+            name: kind.human_name(),
+            rows: kind.row_count(),
+            columns: kind.col_count(),
+            encoders: kind.encoder_count(),
+            features,
+        }
+    }
+
+    /// Features of the model, with vendor commands if the device's vendor interface was opened
+    ///
+    /// Nothing is sent to the device, [Capabilities::probe] also tries the strip.
+    pub fn of_device(kind: Kind, device: &impl DeviceTransport) -> Self {
+        let mut capabilities = Self::of_kind(kind);
+
+        if device.has_vendor_interface() {
+            capabilities.features.insert(Feature::VendorCommands);
+        }
+
+        capabilities
+    }
+
+    /// Same as [Capabilities::of_device], but clears the strip to check the device takes strip
+    /// images, leaving [Feature::StripImage] out if it refuses
+    ///
+    /// Only commands the plugin sends anyway are tried, the others would have to be guessed.
+    /// Sleep is left as the model has it, the only way to try it is turning the displays off. A
+    /// firmware that quietly ignores a command still passes, the device doesn't answer them.
+    pub async fn probe(kind: Kind, device: &impl DeviceTransport) -> Self {
+        let mut capabilities = Self::of_device(kind, device);
+
+        if capabilities.has(Feature::StripImage) && !clears_strip(kind, device).await {
+            capabilities.features.remove(&Feature::StripImage);
+        }
+
+        capabilities
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "rows": self.rows,
+            "columns": self.columns,
+            "encoders": self.encoders,
+            "features": self.features.iter().map(|feature| feature.name()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Blanks every strip zone, returns whether the device took it
+async fn clears_strip(kind: Kind, device: &impl DeviceTransport) -> bool {
+    // Strip zones come first in software order, the keys after them
+    for zone in 0..kind.mapped_index_count() - kind.key_count() {
+        let key = kind.map_button_index(zone) as u8;
+
+        if let Err(err) = device.clear_button_image(key).await {
+            log::debug!("Clearing strip zone {} failed: {}", zone, err);
+            return false;
+        }
+    }
+
+    match device.flush().await {
+        Ok(()) => true,
+        Err(err) => {
+            log::debug!("Clearing the strip failed: {}", err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{MockTransport, MockWrite};
+
+    #[test]
+    fn features_come_from_the_model() {
+        let capabilities = Capabilities::of_device(Kind::Akp05E, &MockTransport::new());
+
+        assert!(capabilities.has(Feature::StripImage));
+        assert!(!capabilities.has(Feature::VendorCommands));
+        assert_eq!(
+            capabilities.to_json()["features"],
+            json!(["stripImage", "sleep"])
+        );
+    }
+
+    #[tokio::test]
+    async fn probing_leaves_out_what_the_device_refuses() {
+        let device = MockTransport::new();
+        let capabilities = Capabilities::probe(Kind::Akp05E, &device).await;
+
+        assert_eq!(capabilities, Capabilities::of_kind(Kind::Akp05E));
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Clear(10),
+                MockWrite::Clear(11),
+                MockWrite::Clear(12),
+                MockWrite::Clear(13),
+                MockWrite::Clear(14),
+                MockWrite::Flush,
+            ]
+        );
+
+        let device = MockTransport::new().without_screen(12);
+        let capabilities = Capabilities::probe(Kind::Akp05E, &device).await;
+
+        assert!(!capabilities.has(Feature::StripImage));
+        assert!(capabilities.has(Feature::Sleep));
+        assert_eq!(
+            device.take_writes(),
+            vec![MockWrite::Clear(10), MockWrite::Clear(11)]
+        );
+    }
+
+    #[tokio::test]
+    async fn vendor_commands_need_the_interface() {
+        let device = MockTransport::new();
+        let capabilities = Capabilities::of_device(Kind::Akp05E, &device);
+
+        assert!(!capabilities.has(Feature::VendorCommands));
+        assert!(device.send_vendor_command(b"TEST").await.is_err());
+
+        let device = MockTransport::new().with_vendor_interface();
+        let capabilities = Capabilities::of_device(Kind::Akp05E, &device);
+
+        assert!(capabilities.has(Feature::VendorCommands));
+        device.send_vendor_command(b"TEST").await.unwrap();
+        assert_eq!(
+            device.take_writes(),
+            vec![MockWrite::VendorCommand(b"TEST".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::transport::REPORT_LENGTH;
+
+/// Environment variable pointing to a directory where raw input reports are recorded
+pub const CAPTURE_DIR_ENV: &str = "OPENDECK_AKP05_CAPTURE_DIR";
+
+/// Input report captured from the device
+///
+/// Captures are stored as text, one report per line: milliseconds since the capture
+/// was started, followed by report bytes in hex. Trailing zero bytes are trimmed,
+/// lines starting with `#` are comments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureEntry {
+    pub at: Duration,
+    pub report: Vec<u8>,
+}
+
+impl CaptureEntry {
+    /// Formats entry as a single capture line
+    pub fn to_line(&self) -> String {
+        capture_line(self.at, &self.report)
+    }
+}
+
+fn capture_line(at: Duration, report: &[u8]) -> String {
+    let len = report
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |i| i + 1);
+
+    let bytes: Vec<String> = report[..len]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    format!("{} {}", at.as_millis(), bytes.join(" "))
+}
+
+/// Parses capture text, padding every report back to `report_length` bytes
+pub fn parse_capture(text: &str, report_length: usize) -> Result<Vec<CaptureEntry>, String> {
+    let mut entries = vec![];
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+
+        let at = parts
+            .next()
+            .and_then(|millis| millis.parse::<u64>().ok())
+            .ok_or(format!("Line {}: missing timestamp", number + 1))?;
+
+        let mut report = parts
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|err| format!("Line {}: {}", number + 1, err))?;
+
+        if report.len() > report_length {
+            return Err(format!("Line {}: report is too long", number + 1));
+        }
+
+        report.resize(report_length, 0);
+
+        entries.push(CaptureEntry {
+            at: Duration::from_millis(at),
+            report,
+        });
+    }
+
+    Ok(entries)
+}
+
+// Slots of the history ring, `next` is written next and is the oldest once every slot is used
+#[derive(Debug)]
+struct Ring {
+    slots: Box<[(Duration, [u8; REPORT_LENGTH])]>,
+    next: usize,
+    len: usize,
+}
+
+/// Last reports of a device kept in memory, older ones are overwritten once there are `limit`
+///
+/// Slots are allocated up front and reports are copied into them, so keeping history doesn't
+/// allocate for every report.
+#[derive(Debug)]
+pub struct ReportHistory {
+    ring: Mutex<Ring>,
+}
+
+impl ReportHistory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                slots: vec![(Duration::ZERO, [0; REPORT_LENGTH]); limit].into_boxed_slice(),
+                next: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    /// Copies a report in, longer ones are cut to [REPORT_LENGTH]
+    pub fn push(&self, at: Duration, report: &[u8]) {
+        let mut ring = self.ring.lock().unwrap();
+        let limit = ring.slots.len();
+
+        if limit == 0 {
+            return;
+        }
+
+        let next = ring.next;
+        let (slot_at, slot) = &mut ring.slots[next];
+        let len = report.len().min(REPORT_LENGTH);
+
+        *slot_at = at;
+        slot[..len].copy_from_slice(&report[..len]);
+        slot[len..].fill(0);
+
+        ring.next = (next + 1) % limit;
+        ring.len = (ring.len + 1).min(limit);
+    }
+
+    /// Kept reports as capture text, it can be replayed like a capture file
+    pub fn to_capture(&self) -> String {
+        let ring = self.ring.lock().unwrap();
+        let limit = ring.slots.len();
+        let oldest = (ring.next + limit - ring.len) % limit.max(1);
+
+        (0..ring.len)
+            .map(|i| {
+                let (at, report) = &ring.slots[(oldest + i) % limit];
+                capture_line(*at, report) + "\n"
+            })
+            .collect()
+    }
+}
+
+/// Appends raw input reports of a device to a capture file, a history in memory or both
+pub struct CaptureRecorder {
+    file: Option<File>,
+    history: Option<Arc<ReportHistory>>,
+    started: Instant,
+}
+
+// Keeps nothing until a history is added
+impl Default for CaptureRecorder {
+    fn default() -> Self {
+        Self {
+            file: None,
+            history: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl CaptureRecorder {
+    /// Starts recording if [CAPTURE_DIR_ENV] is set, file is named after the device id
+    pub fn from_env(id: &str) -> Option<Self> {
+        let dir = PathBuf::from(std::env::var_os(CAPTURE_DIR_ENV)?);
+        let path = dir.join(format!("{}.cap", id));
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| log::error!("Failed to open capture file {:?}: {}", path, err))
+            .ok()?;
+
+        log::info!("Recording input reports of {} to {:?}", id, path);
+
+        Some(Self {
+            file: Some(file),
+            ..Self::default()
+        })
+    }
+
+    /// Also keeps recorded reports in `history`
+    pub fn with_history(self, history: Arc<ReportHistory>) -> Self {
+        Self {
+            history: Some(history),
+            ..self
+        }
+    }
+
+    /// Appends a report to the capture file and history, failures are only logged
+    pub fn record(&mut self, report: &[u8]) {
+        let at = self.started.elapsed();
+
+        if let Some(file) = &mut self.file
+            && let Err(err) = writeln!(file, "{}", capture_line(at, report))
+        {
+            log::error!("Failed to write capture: {}", err);
+        }
+
+        if let Some(history) = &self.history {
+            history.push(at, report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        inputs::{InputState, ReportDecoder},
+        mappings::Kind,
+    };
+
+    // Table tests written from the code table in inputs.rs, they only catch changes to decoding,
+    // not codes that are wrong to begin with. That takes captures recorded on hardware.
+    const TABLES: [(&str, &str, &str); 3] = [
+        (
+            "buttons",
+            include_str!("../tests/tables/akp05e/buttons.cap"),
+            include_str!("../tests/tables/akp05e/buttons.expected"),
+        ),
+        (
+            "encoders",
+            include_str!("../tests/tables/akp05e/encoders.cap"),
+            include_str!("../tests/tables/akp05e/encoders.expected"),
+        ),
+        (
+            "touchscreen",
+            include_str!("../tests/tables/akp05e/touchscreen.cap"),
+            include_str!("../tests/tables/akp05e/touchscreen.expected"),
+        ),
+    ];
+
+    /// Replays capture through the decoder, returning one line per update or error
+    fn replay(kind: &Kind, capture: &str) -> Vec<String> {
+        let mut state = InputState::new(kind);
+        let decoder = ReportDecoder::for_kind(kind);
+        let mut events = vec![];
+
+        for entry in parse_capture(capture, REPORT_LENGTH).unwrap() {
+            match decoder.decode(&entry.report) {
+                Ok(input) => {
+                    for update in state.apply(input) {
+                        events.push(format!("{:?}", update));
+                    }
+                }
+                Err(err) => events.push(format!("Error({})", err)),
+            }
+        }
+
+        events
+    }
+
+    #[test]
+    fn code_tables_replay() {
+        for (name, capture, expected) in TABLES {
+            let expected: Vec<&str> = expected
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect();
+
+            assert_eq!(replay(&Kind::Akp05E, capture), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn capture_line_round_trips() {
+        let mut report = vec![0u8; REPORT_LENGTH];
+        report[..3].copy_from_slice(&[65, 67, 75]);
+        report[9] = 0x51;
+
+        let entry = CaptureEntry {
+            at: Duration::from_millis(1500),
+            report,
+        };
+
+        assert_eq!(entry.to_line(), "1500 41 43 4b 00 00 00 00 00 00 51");
+        assert_eq!(
+            parse_capture(&entry.to_line(), REPORT_LENGTH).unwrap(),
+            vec![entry]
+        );
+    }
+
+    #[test]
+    fn history_keeps_the_last_reports() {
+        let history = Arc::new(ReportHistory::new(2));
+        let mut recorder = CaptureRecorder::default().with_history(history.clone());
+
+        for code in [0x01, 0x02, 0x03] {
+            recorder.record(&crate::transport::build_report(code, 0x01));
+        }
+
+        let entries = parse_capture(&history.to_capture(), REPORT_LENGTH).unwrap();
+        let codes: Vec<u8> = entries.iter().map(|entry| entry.report[9]).collect();
+        assert_eq!(codes, [0x02, 0x03]);
+    }
+
+    #[test]
+    fn malformed_capture_is_rejected() {
+        assert!(parse_capture("abc 41 43", REPORT_LENGTH).is_err());
+        assert!(parse_capture("10 zz", REPORT_LENGTH).is_err());
+    }
+}
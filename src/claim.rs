@@ -0,0 +1,77 @@
+use std::{
+    fs::{File, TryLockError},
+    io::Write,
+    path::PathBuf,
+};
+
+/// Lock file of a device, other plugins for Ajazz decks can take the same one to share devices
+///
+/// Files stay around after the lock is released, only the lock itself matters.
+pub fn lock_path(serial: &str) -> PathBuf {
+    let serial: String = serial
+        .chars()
+        .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+        .collect();
+
+    std::env::temp_dir().join(format!("opendeck-device-{}.lock", serial))
+}
+
+/// Device taken by this plugin, the lock goes away when this is dropped or the plugin exits
+#[derive(Debug)]
+pub struct DeviceClaim {
+    _file: File,
+}
+
+/// Takes the lock file of a device, failing if another plugin or instance already has it
+pub fn claim(serial: &str) -> Result<DeviceClaim, String> {
+    let path = lock_path(serial);
+
+    let mut file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(format!(
+                "Device is claimed by another plugin, see {}",
+                path.display()
+            ));
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(format!("Failed to lock {}: {}", path.display(), err));
+        }
+    }
+
+    // Only for people looking at the file, the lock is what other plugins check
+    let _ = file.set_len(0);
+    let _ = writeln!(file, "opendeck-akp05 {}", std::process::id());
+
+    Ok(DeviceClaim { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_is_claimed_once() {
+        let serial = format!("test-{}", std::process::id());
+
+        let first = claim(&serial).unwrap();
+        assert!(claim(&serial).is_err());
+
+        drop(first);
+        assert!(claim(&serial).is_ok());
+
+        let _ = std::fs::remove_file(lock_path(&serial));
+    }
+
+    #[test]
+    fn serials_make_safe_file_names() {
+        assert!(lock_path("AB/12:3").ends_with("opendeck-device-AB_12_3.lock"));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{process::Command, time::Duration};
+
+use akp05::text::{TextStyle, render_lines};
+use image::DynamicImage;
+use time::{OffsetDateTime, UtcOffset};
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, writer::KeyPainter};
+
+/// How often the clock is checked for changes
+pub const CLOCK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size the clock is rendered at, images are resized to the device format anyway
+pub const CLOCK_SIZE: (u32, u32) = (120, 120);
+
+/// Local offset is looked up again this often (in ticks), so DST changes are picked up
+pub const OFFSET_REFRESH_TICKS: u64 = 60;
+
+/// Lines of the clock, time and date, e.g. `14:05` and `Mon 14 Oct`
+pub fn clock_lines(now: OffsetDateTime, h24: bool) -> [String; 2] {
+    let time = if h24 {
+        format!("{:02}:{:02}", now.hour(), now.minute())
+    } else {
+        let hour = match now.hour() % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        let suffix = if now.hour() < 12 { "AM" } else { "PM" };
+
+        format!("{}:{:02} {}", hour, now.minute(), suffix)
+    };
+
+    let date = format!(
+        "{} {} {}",
+        &now.weekday().to_string()[..3],
+        now.day(),
+        &now.month().to_string()[..3]
+    );
+
+    [time, date]
+}
+
+/// Renders the clock, usable for a key, a strip zone or as screensaver content
+pub fn render_clock(now: OffsetDateTime, size: (u32, u32), h24: bool) -> DynamicImage {
+    let [time, date] = clock_lines(now, h24);
+
+    render_lines(size, &[&time, &date], &[2, 1], TextStyle::default())
+}
+
+/// Parses output of `date +%z`, e.g. `+0200` or `-0930`
+fn parse_offset(output: &str) -> Option<UtcOffset> {
+    let output = output.trim();
+    let sign = match output.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+
+    let hours: i8 = output.get(1..3)?.parse().ok()?;
+    let minutes: i8 = output.get(3..5)?.parse().ok()?;
+
+    UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+/// Offset of the local time zone, UTC if it can't be determined
+pub fn local_offset() -> UtcOffset {
+    if let Ok(offset) = UtcOffset::current_local_offset() {
+        return offset;
+    }
+
+    // time refuses to read the offset in multithreaded programs on unix, so ask `date` instead
+    Command::new("date")
+        .arg("+%z")
+        .output()
+        .ok()
+        .and_then(|output| parse_offset(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or(UtcOffset::UTC)
+}
+
+/// Draws current time on `clock_key` of every connected device
+///
+/// Images are only sent when the text changes or a device doesn't have it yet, the writer
+/// restores them after resets.
+pub async fn clock_task(token: CancellationToken) {
+    let mut offset = UtcOffset::UTC;
+    let mut ticks = 0u64;
+    let mut painter = KeyPainter::default();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(CLOCK_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let (clock_key, h24) = {
+            let config = CONFIG.borrow();
+
+            (config.clock_key, config.clock_24h)
+        };
+
+        painter.retain(|_, position| Some(position) == clock_key);
+
+        let Some(position) = clock_key else {
+            continue;
+        };
+
+        if ticks.is_multiple_of(OFFSET_REFRESH_TICKS) {
+            offset = tokio::task::spawn_blocking(local_offset)
+                .await
+                .unwrap_or(UtcOffset::UTC);
+        }
+        ticks += 1;
+
+        let now = OffsetDateTime::now_utc().to_offset(offset);
+
+        painter
+            .paint(position, clock_lines(now, h24).to_vec(), || {
+                render_clock(now, CLOCK_SIZE, h24)
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Date, Month, Time};
+
+    use super::*;
+
+    fn at(hour: u8, minute: u8) -> OffsetDateTime {
+        let date = Date::from_calendar_date(2024, Month::October, 14).unwrap();
+
+        date.with_time(Time::from_hms(hour, minute, 0).unwrap())
+            .assume_utc()
+    }
+
+    #[test]
+    fn clock_lines_follow_format() {
+        assert_eq!(clock_lines(at(14, 5), true), ["14:05", "Mon 14 Oct"]);
+        assert_eq!(clock_lines(at(14, 5), false), ["2:05 PM", "Mon 14 Oct"]);
+        assert_eq!(clock_lines(at(0, 30), false), ["12:30 AM", "Mon 14 Oct"]);
+    }
+
+    #[test]
+    fn date_offset_is_parsed() {
+        assert_eq!(
+            parse_offset("+0200\n"),
+            Some(UtcOffset::from_hms(2, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_offset("-0930"),
+            Some(UtcOffset::from_hms(-9, -30, 0).unwrap())
+        );
+        assert_eq!(parse_offset("UTC"), None);
+    }
+}
@@ -0,0 +1,2188 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use akp05::{
+    deck,
+    discovery::{DeviceFilter, serial_to_id},
+    images::{ColorCorrection, DEFAULT_JPEG_QUALITY, KeyTransform},
+    inputs::{
+        EncoderCode, EncoderPress, GHOST_WINDOW, InputOptions, ProtocolErrors, SystemEvent,
+        UnknownInputs,
+    },
+    mappings::{COL_COUNT, ENCODER_COUNT, KEY_COUNT},
+    progress::ProgressBar,
+    text::{self, BitmapFont, EmojiAtlas},
+    tiles::DEFAULT_CAPACITY,
+    transport::UploadPacing,
+};
+use image::Rgb;
+use serde_json::{Map, Value};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, WRITERS,
+    badge::parse_color,
+    dnd,
+    focus::AppProfiles,
+    icons::{self, Icons},
+    labels::{Labels, Variables},
+    lock::UnlockGesture,
+    macros::{Macros, parse_macros},
+    media::MediaRotate,
+    mixer::MixerDials,
+    mqtt::MqttSettings,
+    obs::{DEFAULT_URL, ObsScenes, ObsSettings},
+    orientation::Orientation,
+    pacing::{Pacing, ReadPacer},
+    pages::{Folders, MAX_PAGES, PAGE_SIZE},
+    press::PressEffect,
+    sinks::EventSink,
+    stats::{Widget, Widgets},
+    transition::Transition,
+    writer::WriterCommand,
+};
+
+/// Environment variable pointing to the config file, overrides the default location
+pub const CONFIG_PATH_ENV: &str = "OPENDECK_AKP05_CONFIG";
+
+/// Prefix of environment variables overriding single settings, e.g. `OPENDECK_AKP05_BRIGHTNESS`
+pub const ENV_PREFIX: &str = "OPENDECK_AKP05_";
+
+/// Name of the config file looked up next to the plugin executable
+pub const CONFIG_FILE_NAME: &str = "config.json";
+
+const MEGABYTE: usize = 1024 * 1024;
+
+/// How often config file is checked for changes
+pub const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// Last global settings received from OpenDeck, so reloading the file doesn't drop them
+static OPENDECK_SETTINGS: LazyLock<Mutex<Option<Value>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Every setting that can be configured, in the order they are documented
+pub const KEYS: [&str; 72] = [
+    "brightness",
+    "splash",
+    "encoder_press",
+    "debounce_ms",
+    "encoder_acceleration",
+    "encoder_noise_ms",
+    "encoder_codes",
+    "invert_encoders",
+    "event_rate_limit",
+    "jpeg_quality",
+    "upload_limit_kb",
+    "upload_chunk_bytes",
+    "upload_chunk_delay_us",
+    "vendor_interface",
+    "probe_features",
+    "press_effect",
+    "color_correction",
+    "orientation",
+    "key_transforms",
+    "dead_keys",
+    "setup_wizard",
+    "poll_interval_ms",
+    "read_pacing",
+    "app_profiles",
+    "focus_command",
+    "clock_key",
+    "clock_24h",
+    "widgets",
+    "widget_refresh_ms",
+    "labels",
+    "variables",
+    "fonts",
+    "emoji_dir",
+    "progress_color",
+    "progress_track",
+    "icon_pack",
+    "icons",
+    "tile_cache_mb",
+    "pages",
+    "page_keys",
+    "page_dial",
+    "folders",
+    "page_transition",
+    "sliders",
+    "dnd_brightness",
+    "unlock_chord",
+    "unlock_hold_ms",
+    "menu_dial",
+    "macros",
+    "mqtt_broker",
+    "mqtt_topic",
+    "mqtt_username",
+    "mqtt_password",
+    "media_dial",
+    "media_rotate",
+    "mixer_dials",
+    "system_volume_devices",
+    "obs_url",
+    "obs_password",
+    "obs_scenes",
+    "midi_output",
+    "midi_channel",
+    "hook_command",
+    "hook_script",
+    "event_sinks",
+    "excluded_serials",
+    "included_serials",
+    "usb_port_ids",
+    "diagnostics",
+    "unknown_inputs",
+    "system_codes",
+    "protocol_errors",
+];
+
+/// Plugin settings
+///
+/// Loaded in layers, each one overriding the previous: built-in defaults, config file,
+/// environment variables and finally global settings stored by OpenDeck.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Brightness set when device is connected, until OpenDeck sends its own value
+    pub brightness: u8,
+
+    /// Shows a splash with connection progress until OpenDeck sends the first image
+    pub splash: bool,
+
+    /// How encoder presses are reported to OpenDeck
+    pub encoder_press: EncoderPress,
+
+    /// Key changes that come sooner than this after the previous one wait for it to be over, 0
+    /// disables it
+    pub debounce_ms: u64,
+
+    /// Multiplier for encoder ticks when the knob is spun fast, 1 disables it
+    pub encoder_acceleration: u8,
+
+    /// Time a lone tick of each encoder waits for a second one before it's dropped, 0 is off
+    pub encoder_noise_ms: [u64; ENCODER_COUNT],
+
+    /// Extra input codes encoders turn with, for units sending codes the plugin doesn't know
+    pub encoder_codes: Vec<EncoderCode>,
+
+    /// Reverses direction of every encoder
+    pub invert_encoders: bool,
+
+    /// Input events sent per second before twists are held back and merged, 0 sends every one
+    pub event_rate_limit: u32,
+
+    /// Quality of JPEG images sent to the device
+    pub jpeg_quality: u8,
+
+    /// Kilobytes of images a second all devices together get at most, 0 is no limit
+    pub upload_limit_kb: u64,
+
+    /// Bytes of image data sent back to back before pausing for `upload_chunk_delay_us`, 0
+    /// never pauses
+    pub upload_chunk_bytes: u64,
+
+    /// Pause between chunks of image data, in microseconds
+    pub upload_chunk_delay_us: u64,
+
+    /// Opens the secondary interface for configuration commands on devices that have one, off by
+    /// default since nothing is sent through it yet and its HID usage isn't verified
+    pub vendor_interface: bool,
+
+    /// Clears the strip on connect to check the device takes strip images, instead of going by
+    /// the model alone
+    pub probe_features: bool,
+
+    /// How key images change while the key is held
+    pub press_effect: PressEffect,
+
+    /// Color correction of devices by serial number or ID
+    pub color_correction: BTreeMap<String, ColorCorrection>,
+
+    /// Which way up devices are mounted by serial number or ID, set by the setup wizard
+    pub orientation: BTreeMap<String, Orientation>,
+
+    /// Rotation and mirroring of single key panels by serial number or ID, for units with some
+    /// panels mounted differently
+    pub key_transforms: BTreeMap<String, BTreeMap<u8, KeyTransform>>,
+
+    /// Keys that don't work by serial number or ID, they get no images and send no inputs
+    pub dead_keys: BTreeMap<String, Vec<u8>>,
+
+    /// Runs the setup wizard on devices without an orientation when they connect
+    pub setup_wizard: bool,
+
+    /// How often the read loop wakes up when there's no input, 0 waits for input indefinitely
+    pub poll_interval_ms: u64,
+
+    /// How much the read loop slows down while there's no input
+    pub read_pacing: Pacing,
+
+    /// Profiles to switch to when an application gets focus, see [AppProfiles]
+    pub app_profiles: AppProfiles,
+
+    /// Shell command printing name of the focused application, overrides built-in detection
+    pub focus_command: Option<String>,
+
+    /// Position of the key or strip zone showing the clock, [None] disables it
+    pub clock_key: Option<u8>,
+
+    /// Shows the clock in 24 hour format instead of 12 hour one
+    pub clock_24h: bool,
+
+    /// System stats drawn by the plugin, position to widget
+    pub widgets: Widgets,
+
+    /// How often widgets are refreshed
+    pub widget_refresh_ms: u64,
+
+    /// Text drawn by the plugin with placeholders filled in, position to template
+    pub labels: Labels,
+
+    /// Initial values of `{var:name}` placeholders in labels
+    pub variables: Variables,
+
+    /// BDF fonts tried in order for characters the built-in font lacks
+    pub fonts: Vec<PathBuf>,
+
+    /// Directory of color emoji images named by their codepoints
+    pub emoji_dir: Option<PathBuf>,
+
+    /// Colors of level bars drawn by the plugin, e.g. for volumes and timers
+    pub progress_bar: ProgressBar,
+
+    /// Directory of JPEG or BMP icons, encoded ahead of time for connected devices
+    pub icon_pack: Option<PathBuf>,
+
+    /// Icons of the pack drawn by the plugin, position to file name without extension
+    pub icons: Icons,
+
+    /// Megabytes of encoded images kept in memory, the least recently used go first
+    pub tile_cache_mb: u64,
+
+    /// Pages of keys managed by the plugin, 1 leaves paging to OpenDeck
+    pub pages: u8,
+
+    /// Keys going to the previous and the next page, empty if there are none
+    pub page_keys: Vec<u8>,
+
+    /// Encoder turning pages, pressing it goes back to the first one
+    pub page_dial: Option<u8>,
+
+    /// Keys opening pages as folders, position as OpenDeck knows it to the page
+    pub folders: Folders,
+
+    /// Animation played when the plugin switches pages
+    pub page_transition: Transition,
+
+    /// Strip zones acting as sliders, touching them sets a value drawn as a fill bar
+    pub sliders: Vec<u8>,
+
+    /// Brightness while do-not-disturb is on, 0 blanks every display
+    pub dnd_brightness: u8,
+
+    /// Keys to hold together to unlock a locked device, empty disables the chord
+    pub unlock_chord: Vec<u8>,
+
+    /// How long to hold an encoder before releasing it unlocks a locked device
+    pub unlock_hold_ms: u64,
+
+    /// Encoder opening the on-device menu on the strip, it's the menu's own while set
+    pub menu_dial: Option<u8>,
+
+    /// Sequences of key events sent to OpenDeck instead of presses of these keys
+    pub macros: Macros,
+
+    /// MQTT broker to mirror inputs to and take commands from, [None] disables the bridge
+    pub mqtt_broker: Option<String>,
+
+    /// Prefix of every MQTT topic
+    pub mqtt_topic: String,
+
+    pub mqtt_username: Option<String>,
+
+    pub mqtt_password: Option<String>,
+
+    /// Encoder controlling media players, its strip zone shows the playing track
+    pub media_dial: Option<u8>,
+
+    /// What turning the media dial does
+    pub media_rotate: MediaRotate,
+
+    /// Applications whose volume encoders control, names or executables as `pactl` shows them
+    pub mixer_dials: MixerDials,
+
+    /// Devices whose leftmost encoder controls the system volume, `*` for every device
+    pub system_volume_devices: Vec<String>,
+
+    /// obs-websocket address, OBS integration is off without it
+    pub obs_url: Option<String>,
+
+    pub obs_password: Option<String>,
+
+    /// Scenes shown on positions, pressing one switches OBS to it
+    pub obs_scenes: ObsScenes,
+
+    /// Raw MIDI device on Linux or output name on Windows, MIDI mode sends to it
+    pub midi_output: Option<String>,
+
+    /// MIDI channel, 1 - 16
+    pub midi_channel: u8,
+
+    /// Shell command of a script that gets every input and can send commands back
+    pub hook_command: Option<String>,
+
+    /// Rhai script run inside the plugin, its `on_key`, `on_dial` and `on_touch` get every input
+    pub hook_script: Option<PathBuf>,
+
+    /// Where inputs are mirrored to as JSON lines, next to OpenDeck
+    pub event_sinks: Vec<EventSink>,
+
+    /// Serial numbers, IDs or device paths of devices left to other plugins
+    pub excluded_serials: Vec<String>,
+
+    /// Serial numbers, IDs or device paths of the only devices used, every one if empty
+    pub included_serials: Vec<String>,
+
+    /// Build device IDs from the USB port too, so identical decks keep theirs across boots
+    pub usb_port_ids: bool,
+
+    /// Draw raw input codes on the controls they come from, instead of OpenDeck images
+    pub diagnostics: bool,
+
+    /// What happens with input codes no control is known to send
+    pub unknown_inputs: UnknownInputs,
+
+    /// Input codes the device sends status notifications with
+    pub system_codes: Vec<(u8, SystemEvent)>,
+
+    /// Whether protocol anomalies are errors or only logged
+    pub protocol_errors: ProtocolErrors,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            brightness: 50,
+            splash: true,
+            encoder_press: EncoderPress::default(),
+            debounce_ms: 0,
+            encoder_acceleration: 1,
+            encoder_noise_ms: [0; ENCODER_COUNT],
+            encoder_codes: vec![],
+            invert_encoders: false,
+            event_rate_limit: 200,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+            upload_limit_kb: 0,
+            upload_chunk_bytes: UploadPacing::default().chunk as u64,
+            upload_chunk_delay_us: UploadPacing::default().delay.as_micros() as u64,
+            vendor_interface: false,
+            probe_features: false,
+            press_effect: PressEffect::default(),
+            color_correction: BTreeMap::new(),
+            orientation: BTreeMap::new(),
+            key_transforms: BTreeMap::new(),
+            dead_keys: BTreeMap::new(),
+            setup_wizard: true,
+            poll_interval_ms: 0,
+            read_pacing: Pacing::default(),
+            app_profiles: AppProfiles::new(),
+            focus_command: None,
+            clock_key: None,
+            clock_24h: true,
+            widgets: Widgets::new(),
+            widget_refresh_ms: 2000,
+            labels: Labels::new(),
+            variables: Variables::new(),
+            fonts: vec![],
+            emoji_dir: None,
+            progress_bar: ProgressBar::default(),
+            icon_pack: None,
+            icons: Icons::new(),
+            tile_cache_mb: (DEFAULT_CAPACITY / MEGABYTE) as u64,
+            pages: 1,
+            page_keys: vec![],
+            page_dial: None,
+            folders: Folders::new(),
+            page_transition: Transition::default(),
+            sliders: vec![],
+            dnd_brightness: 0,
+            unlock_chord: vec![],
+            unlock_hold_ms: 2000,
+            menu_dial: None,
+            macros: Macros::new(),
+            mqtt_broker: None,
+            mqtt_topic: "akp05".to_string(),
+            mqtt_username: None,
+            mqtt_password: None,
+            media_dial: None,
+            media_rotate: MediaRotate::default(),
+            mixer_dials: MixerDials::new(),
+            system_volume_devices: vec![],
+            obs_url: None,
+            obs_password: None,
+            obs_scenes: ObsScenes::new(),
+            midi_output: None,
+            midi_channel: 1,
+            hook_command: None,
+            hook_script: None,
+            event_sinks: vec![],
+            excluded_serials: vec![],
+            included_serials: vec![],
+            usb_port_ids: false,
+            diagnostics: false,
+            unknown_inputs: UnknownInputs::default(),
+            system_codes: vec![],
+            protocol_errors: ProtocolErrors::default(),
+        }
+    }
+}
+
+fn int_in_range(key: &str, value: &Value, min: u64, max: u64) -> Result<u64, String> {
+    // Env variables come as strings, so accept numbers in strings too
+    let number = match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    };
+
+    match number {
+        Some(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(format!(
+            "\"{}\" must be a number between {} and {}, got {}",
+            key, min, max, value
+        )),
+    }
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(value) => Ok(*value),
+        Value::String(string) if string.trim() == "true" => Ok(true),
+        Value::String(string) if string.trim() == "false" => Ok(false),
+        _ => Err(format!(
+            "\"{}\" must be either true or false, got {}",
+            key, value
+        )),
+    }
+}
+
+fn json_value(key: &str, value: &Value) -> Result<Value, String> {
+    // Env variables hold the same JSON as a string
+    match value {
+        Value::String(string) => serde_json::from_str(string)
+            .map_err(|err| format!("\"{}\" is not valid JSON: {}", key, err)),
+        value => Ok(value.clone()),
+    }
+}
+
+fn optional_string(key: &str, value: &Value) -> Result<Option<String>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::String(string) if string.trim().is_empty() => Ok(None),
+        Value::String(string) => Ok(Some(string.clone())),
+        _ => Err(format!("\"{}\" must be a string, got {}", key, value)),
+    }
+}
+
+fn color(key: &str, value: &Value) -> Result<Rgb<u8>, String> {
+    value.as_str().and_then(parse_color).ok_or(format!(
+        "\"{}\" must be a color like \"#00c850\", got {}",
+        key, value
+    ))
+}
+
+fn color_correction(key: &str, value: &Value) -> Result<BTreeMap<String, ColorCorrection>, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map serial numbers to {{ \"gamma\": 0.2-5, \"gain\": [red, green, blue] }} with gains of 0-2, got {}",
+            key, value
+        )
+    };
+
+    let mut corrections = BTreeMap::new();
+
+    for (device, correction) in value.as_object().ok_or_else(invalid)? {
+        let correction = correction.as_object().ok_or_else(invalid)?;
+
+        if correction
+            .keys()
+            .any(|name| name != "gamma" && name != "gain")
+        {
+            return Err(invalid());
+        }
+
+        let gamma = match correction.get("gamma") {
+            Some(gamma) => gamma
+                .as_f64()
+                .filter(|gamma| (0.2..=5.0).contains(gamma))
+                .ok_or_else(invalid)? as f32,
+            None => 1.0,
+        };
+
+        let gain = match correction.get("gain") {
+            Some(gain) => {
+                let gain = gain
+                    .as_array()
+                    .filter(|gain| gain.len() == 3)
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(|gain| gain.as_f64().filter(|gain| (0.0..=2.0).contains(gain)))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(invalid)?;
+
+                [gain[0] as f32, gain[1] as f32, gain[2] as f32]
+            }
+            None => [1.0; 3],
+        };
+
+        corrections.insert(device.clone(), ColorCorrection { gamma, gain });
+    }
+
+    Ok(corrections)
+}
+
+fn orientations(key: &str, value: &Value) -> Result<BTreeMap<String, Orientation>, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map serial numbers to \"normal\" or \"flipped\", got {}",
+            key, value
+        )
+    };
+
+    value
+        .as_object()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|(device, orientation)| {
+            let orientation = orientation
+                .as_str()
+                .and_then(Orientation::parse)
+                .ok_or_else(invalid)?;
+
+            Ok((device.clone(), orientation))
+        })
+        .collect()
+}
+
+fn key_transforms(
+    key: &str,
+    value: &Value,
+) -> Result<BTreeMap<String, BTreeMap<u8, KeyTransform>>, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map serial numbers to positions (0-{}) to {{ \"rotation\": 0, 90, 180 or 270, \"mirror\": \"none\", \"x\", \"y\" or \"both\" }}, got {}",
+            key, LAST_POSITION, value
+        )
+    };
+
+    let mut devices = BTreeMap::new();
+
+    for (device, keys) in value.as_object().ok_or_else(invalid)? {
+        let mut transforms = BTreeMap::new();
+
+        for (position, transform) in keys.as_object().ok_or_else(invalid)? {
+            let position = position
+                .parse::<u8>()
+                .ok()
+                .filter(|position| *position as u64 <= LAST_POSITION)
+                .ok_or_else(invalid)?;
+            let transform = transform.as_object().ok_or_else(invalid)?;
+
+            if transform
+                .keys()
+                .any(|name| name != "rotation" && name != "mirror")
+            {
+                return Err(invalid());
+            }
+
+            let rotation = match transform.get("rotation") {
+                Some(rotation) => rotation
+                    .as_u64()
+                    .and_then(|rotation| u16::try_from(rotation).ok())
+                    .ok_or_else(invalid)?,
+                None => 0,
+            };
+            let mirror = match transform.get("mirror") {
+                Some(mirror) => mirror.as_str().ok_or_else(invalid)?,
+                None => "none",
+            };
+
+            transforms.insert(
+                position,
+                KeyTransform::new(rotation, mirror).ok_or_else(invalid)?,
+            );
+        }
+
+        devices.insert(device.clone(), transforms);
+    }
+
+    Ok(devices)
+}
+
+fn dead_keys(key: &str, value: &Value) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map serial numbers to lists of keys (0-{}), got {}",
+            key,
+            KEY_COUNT - 1,
+            value
+        )
+    };
+
+    value
+        .as_object()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|(device, keys)| {
+            let keys = keys
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|position| {
+                    int_in_range(key, position, 0, KEY_COUNT as u64 - 1)
+                        .map(|position| position as u8)
+                        .map_err(|_| invalid())
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+
+            Ok((device.clone(), keys))
+        })
+        .collect()
+}
+
+fn app_profiles(key: &str, value: &Value) -> Result<AppProfiles, String> {
+    let value = json_value(key, value)?;
+
+    serde_json::from_value(value).map_err(|err| {
+        format!(
+            "\"{}\" must map device ids to application names to profiles: {}",
+            key, err
+        )
+    })
+}
+
+// Last position that can show an image, strip zones included
+const LAST_POSITION: u64 = (KEY_COUNT + COL_COUNT - 1) as u64;
+
+fn widgets(key: &str, value: &Value) -> Result<Widgets, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map positions (0-{}) to \"cpu\", \"ram\" or \"net\", got {}",
+            key, LAST_POSITION, value
+        )
+    };
+
+    let mut widgets = Widgets::new();
+
+    for (position, widget) in value.as_object().ok_or_else(invalid)? {
+        let position = position
+            .parse::<u8>()
+            .ok()
+            .filter(|position| *position as u64 <= LAST_POSITION)
+            .ok_or_else(invalid)?;
+        let widget = widget
+            .as_str()
+            .and_then(Widget::parse)
+            .ok_or_else(invalid)?;
+
+        widgets.insert(position, widget);
+    }
+
+    Ok(widgets)
+}
+
+fn icons(key: &str, value: &Value) -> Result<Icons, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map positions (0-{}) to icon names, got {}",
+            key, LAST_POSITION, value
+        )
+    };
+
+    let mut icons = Icons::new();
+
+    for (position, name) in value.as_object().ok_or_else(invalid)? {
+        let position = position
+            .parse::<u8>()
+            .ok()
+            .filter(|position| *position as u64 <= LAST_POSITION)
+            .ok_or_else(invalid)?;
+        let name = name
+            .as_str()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(invalid)?;
+
+        icons.insert(position, name.to_string());
+    }
+
+    Ok(icons)
+}
+
+fn labels(key: &str, value: &Value) -> Result<Labels, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map positions (0-{}) to text, got {}",
+            key, LAST_POSITION, value
+        )
+    };
+
+    let mut labels = Labels::new();
+
+    for (position, template) in value.as_object().ok_or_else(invalid)? {
+        let position = position
+            .parse::<u8>()
+            .ok()
+            .filter(|position| *position as u64 <= LAST_POSITION)
+            .ok_or_else(invalid)?;
+        let template = template.as_str().ok_or_else(invalid)?;
+
+        labels.insert(position, template.to_string());
+    }
+
+    Ok(labels)
+}
+
+fn variables(key: &str, value: &Value) -> Result<Variables, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || format!("\"{}\" must map names to values, got {}", key, value);
+
+    let mut variables = Variables::new();
+
+    for (name, variable) in value.as_object().ok_or_else(invalid)? {
+        // Numbers and booleans are shown as they are written
+        let variable = match variable {
+            Value::String(string) => string.clone(),
+            Value::Number(_) | Value::Bool(_) => variable.to_string(),
+            _ => return Err(invalid()),
+        };
+
+        variables.insert(name.clone(), variable);
+    }
+
+    Ok(variables)
+}
+
+fn folders(key: &str, value: &Value) -> Result<Folders, String> {
+    let value = json_value(key, value)?;
+    let last_position = MAX_PAGES as u64 * PAGE_SIZE as u64 - 1;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map positions (0-{}) to pages (2-{}), got {}",
+            key, last_position, MAX_PAGES, value
+        )
+    };
+
+    let mut folders = Folders::new();
+
+    for (position, page) in value.as_object().ok_or_else(invalid)? {
+        let position = position
+            .parse::<u8>()
+            .ok()
+            .filter(|position| *position as u64 <= last_position)
+            .ok_or_else(invalid)?;
+        let page = page
+            .as_u64()
+            .filter(|page| (2..=MAX_PAGES as u64).contains(page))
+            .ok_or_else(invalid)?;
+
+        folders.insert(position, page as u8);
+    }
+
+    Ok(folders)
+}
+
+fn encoder_noise(key: &str, value: &Value) -> Result<[u64; ENCODER_COUNT], String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map encoders (0-{}) to milliseconds (0-1000), got {}",
+            key,
+            ENCODER_COUNT - 1,
+            value
+        )
+    };
+
+    let mut windows = [0; ENCODER_COUNT];
+
+    for (encoder, millis) in value.as_object().ok_or_else(invalid)? {
+        let encoder = encoder
+            .parse::<usize>()
+            .ok()
+            .filter(|encoder| *encoder < ENCODER_COUNT)
+            .ok_or_else(invalid)?;
+
+        windows[encoder] = millis
+            .as_u64()
+            .filter(|millis| *millis <= 1000)
+            .ok_or_else(invalid)?;
+    }
+
+    Ok(windows)
+}
+
+/// Parses an input code map key, either hex like `0x60` or decimal
+fn input_code(code: &str) -> Option<u8> {
+    match code.strip_prefix("0x").or(code.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => code.parse().ok(),
+    }
+}
+
+fn system_codes(key: &str, value: &Value) -> Result<Vec<(u8, SystemEvent)>, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map input codes like \"0xE0\" to \"wake\" or \"over_temperature\", got {}",
+            key, value
+        )
+    };
+
+    value
+        .as_object()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|(code, event)| {
+            let code = input_code(code).ok_or_else(invalid)?;
+            let event = event
+                .as_str()
+                .and_then(SystemEvent::parse)
+                .ok_or_else(invalid)?;
+
+            Ok((code, event))
+        })
+        .collect()
+}
+
+fn encoder_codes(key: &str, value: &Value) -> Result<Vec<EncoderCode>, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map input codes like \"0x60\" to an encoder (0-{}) and a direction (-1 or 1), got {}",
+            key,
+            ENCODER_COUNT - 1,
+            value
+        )
+    };
+
+    value
+        .as_object()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|(code, turn)| {
+            let code = input_code(code).ok_or_else(invalid)?;
+
+            match turn.as_array().map(Vec::as_slice) {
+                Some([encoder, ticks]) => {
+                    let encoder = encoder
+                        .as_u64()
+                        .filter(|encoder| *encoder < ENCODER_COUNT as u64)
+                        .ok_or_else(invalid)?;
+                    let ticks = ticks
+                        .as_i64()
+                        .filter(|ticks| ticks.abs() == 1)
+                        .ok_or_else(invalid)?;
+
+                    Ok(EncoderCode::new(code, encoder as u8, ticks as i8))
+                }
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+fn mixer_dials(key: &str, value: &Value) -> Result<MixerDials, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map encoders (0-{}) to application names, got {}",
+            key,
+            ENCODER_COUNT - 1,
+            value
+        )
+    };
+
+    let mut dials = MixerDials::new();
+
+    for (encoder, app) in value.as_object().ok_or_else(invalid)? {
+        let encoder = encoder
+            .parse::<u8>()
+            .ok()
+            .filter(|encoder| (*encoder as usize) < ENCODER_COUNT)
+            .ok_or_else(invalid)?;
+        let app = app
+            .as_str()
+            .map(str::trim)
+            .filter(|app| !app.is_empty())
+            .ok_or_else(invalid)?;
+
+        dials.insert(encoder, app.to_string());
+    }
+
+    Ok(dials)
+}
+
+fn obs_scenes(key: &str, value: &Value) -> Result<ObsScenes, String> {
+    let value = json_value(key, value)?;
+
+    let invalid = || {
+        format!(
+            "\"{}\" must map positions (0-{}) to scene names, got {}",
+            key, LAST_POSITION, value
+        )
+    };
+
+    let mut scenes = ObsScenes::new();
+
+    for (position, scene) in value.as_object().ok_or_else(invalid)? {
+        let position = position
+            .parse::<u8>()
+            .ok()
+            .filter(|position| *position as u64 <= LAST_POSITION)
+            .ok_or_else(invalid)?;
+        let scene = scene
+            .as_str()
+            .filter(|scene| !scene.is_empty())
+            .ok_or_else(invalid)?;
+
+        scenes.insert(position, scene.to_string());
+    }
+
+    Ok(scenes)
+}
+
+fn strings(key: &str, value: &Value, what: &str) -> Result<Vec<String>, String> {
+    let invalid = || format!("\"{}\" must be a list of {}, got {}", key, what, value);
+
+    // Env variables hold a comma separated list
+    let ids: Vec<String> = match value {
+        Value::Array(values) => values
+            .iter()
+            .map(|id| id.as_str().map(str::to_string).ok_or_else(invalid))
+            .collect::<Result<_, _>>()?,
+        Value::String(string) => string.split(',').map(str::to_string).collect(),
+        _ => return Err(invalid()),
+    };
+
+    Ok(ids
+        .iter()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect())
+}
+
+fn positions(key: &str, value: &Value) -> Result<Vec<u8>, String> {
+    let invalid = || {
+        format!(
+            "\"{}\" must be a list of positions (0-{}), got {}",
+            key, LAST_POSITION, value
+        )
+    };
+
+    // Env variables hold a comma separated list
+    let values: Vec<Value> = match value {
+        Value::Array(values) => values.clone(),
+        Value::String(string) if string.trim().is_empty() => vec![],
+        Value::String(string) => string
+            .split(',')
+            .map(|position| Value::String(position.to_string()))
+            .collect(),
+        _ => return Err(invalid()),
+    };
+
+    values
+        .iter()
+        .map(|position| {
+            int_in_range(key, position, 0, LAST_POSITION)
+                .map(|position| position as u8)
+                .map_err(|_| invalid())
+        })
+        .collect()
+}
+
+impl Config {
+    /// Sets a single setting, rejecting unknown keys and invalid values
+    pub fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "brightness" => self.brightness = int_in_range(key, value, 0, 100)? as u8,
+            "splash" => self.splash = boolean(key, value)?,
+            "encoder_press" => {
+                self.encoder_press = value.as_str().and_then(EncoderPress::parse).ok_or(format!(
+                    "\"{}\" must be either \"dial\" or \"keys\", got {}",
+                    key, value
+                ))?
+            }
+            "debounce_ms" => self.debounce_ms = int_in_range(key, value, 0, 1000)?,
+            "encoder_acceleration" => {
+                self.encoder_acceleration = int_in_range(key, value, 1, 10)? as u8
+            }
+            "encoder_noise_ms" => self.encoder_noise_ms = encoder_noise(key, value)?,
+            "encoder_codes" => self.encoder_codes = encoder_codes(key, value)?,
+            "invert_encoders" => self.invert_encoders = boolean(key, value)?,
+            "event_rate_limit" => {
+                self.event_rate_limit = int_in_range(key, value, 0, 10000)? as u32
+            }
+            "jpeg_quality" => self.jpeg_quality = int_in_range(key, value, 1, 100)? as u8,
+            "upload_limit_kb" => self.upload_limit_kb = int_in_range(key, value, 0, 100000)?,
+            "upload_chunk_bytes" => self.upload_chunk_bytes = int_in_range(key, value, 0, 1048576)?,
+            "upload_chunk_delay_us" => {
+                self.upload_chunk_delay_us = int_in_range(key, value, 0, 100000)?
+            }
+            "vendor_interface" => self.vendor_interface = boolean(key, value)?,
+            "probe_features" => self.probe_features = boolean(key, value)?,
+            "press_effect" => {
+                self.press_effect = value.as_str().and_then(PressEffect::parse).ok_or(format!(
+                    "\"{}\" must be \"none\", \"darken\", \"invert\" or \"shrink\", got {}",
+                    key, value
+                ))?
+            }
+            "color_correction" => self.color_correction = color_correction(key, value)?,
+            "orientation" => self.orientation = orientations(key, value)?,
+            "key_transforms" => self.key_transforms = key_transforms(key, value)?,
+            "dead_keys" => self.dead_keys = dead_keys(key, value)?,
+            "setup_wizard" => self.setup_wizard = boolean(key, value)?,
+            "poll_interval_ms" => self.poll_interval_ms = int_in_range(key, value, 0, 10000)?,
+            "read_pacing" => {
+                self.read_pacing = value.as_str().and_then(Pacing::parse).ok_or(format!(
+                    "\"{}\" must be \"latency\", \"balanced\" or \"power\", got {}",
+                    key, value
+                ))?
+            }
+            "app_profiles" => self.app_profiles = app_profiles(key, value)?,
+            "focus_command" => self.focus_command = optional_string(key, value)?,
+            "clock_key" => {
+                self.clock_key = match value {
+                    Value::Null => None,
+                    Value::String(string) if string.trim().is_empty() => None,
+                    value => Some(int_in_range(key, value, 0, LAST_POSITION)? as u8),
+                }
+            }
+            "clock_24h" => self.clock_24h = boolean(key, value)?,
+            "widgets" => self.widgets = widgets(key, value)?,
+            "widget_refresh_ms" => self.widget_refresh_ms = int_in_range(key, value, 250, 60000)?,
+            "labels" => self.labels = labels(key, value)?,
+            "variables" => self.variables = variables(key, value)?,
+            "fonts" => {
+                self.fonts = strings(key, value, "font paths")?
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect()
+            }
+            "emoji_dir" => self.emoji_dir = optional_string(key, value)?.map(PathBuf::from),
+            "progress_color" => self.progress_bar.fill = color(key, value)?,
+            "progress_track" => {
+                self.progress_bar.track = match value {
+                    Value::Null => None,
+                    Value::String(string) if string.trim().is_empty() => None,
+                    value => Some(color(key, value)?),
+                }
+            }
+            "icon_pack" => self.icon_pack = optional_string(key, value)?.map(PathBuf::from),
+            "icons" => self.icons = icons(key, value)?,
+            "tile_cache_mb" => self.tile_cache_mb = int_in_range(key, value, 1, 1024)?,
+            "pages" => self.pages = int_in_range(key, value, 1, MAX_PAGES as u64)? as u8,
+            "page_keys" => {
+                self.page_keys = positions(key, value)?;
+
+                if !matches!(self.page_keys[..], [] | [_, _]) {
+                    let count = self.page_keys.len();
+                    self.page_keys.clear();
+
+                    return Err(format!(
+                        "\"{}\" must be the previous and the next key, got {} keys",
+                        key, count
+                    ));
+                }
+            }
+            "folders" => self.folders = folders(key, value)?,
+            "sliders" => {
+                self.sliders = positions(key, value)?;
+
+                if let Some(zone) = self
+                    .sliders
+                    .iter()
+                    .find(|zone| **zone as usize >= ENCODER_COUNT)
+                {
+                    let zone = *zone;
+                    self.sliders.clear();
+
+                    return Err(format!(
+                        "\"{}\" must only hold strip zones (0-{}), got {}",
+                        key,
+                        ENCODER_COUNT - 1,
+                        zone
+                    ));
+                }
+            }
+            "page_transition" => {
+                self.page_transition = value.as_str().and_then(Transition::parse).ok_or(format!(
+                    "\"{}\" must be \"none\", \"fade\" or \"slide\", got {}",
+                    key, value
+                ))?
+            }
+            "page_dial" => {
+                self.page_dial = match value {
+                    Value::Null => None,
+                    Value::String(string) if string.trim().is_empty() => None,
+                    value => Some(int_in_range(key, value, 0, ENCODER_COUNT as u64 - 1)? as u8),
+                }
+            }
+            "dnd_brightness" => self.dnd_brightness = int_in_range(key, value, 0, 100)? as u8,
+            "unlock_chord" => self.unlock_chord = positions(key, value)?,
+            "unlock_hold_ms" => self.unlock_hold_ms = int_in_range(key, value, 500, 10000)?,
+            "menu_dial" => {
+                self.menu_dial = match value {
+                    Value::Null => None,
+                    Value::String(string) if string.trim().is_empty() => None,
+                    value => Some(int_in_range(key, value, 0, ENCODER_COUNT as u64 - 1)? as u8),
+                }
+            }
+            "macros" => {
+                self.macros = parse_macros(&json_value(key, value)?, LAST_POSITION as u8)
+                    .map_err(|err| format!("\"{}\": {}", key, err))?
+            }
+            "mqtt_broker" => self.mqtt_broker = optional_string(key, value)?,
+            "mqtt_topic" => {
+                self.mqtt_topic = optional_string(key, value)?
+                    .map(|topic| topic.trim().trim_end_matches('/').to_string())
+                    .filter(|topic| !topic.is_empty() && !topic.contains(['+', '#']))
+                    .ok_or(format!(
+                        "\"{}\" must be a topic without wildcards, got {}",
+                        key, value
+                    ))?
+            }
+            "mqtt_username" => self.mqtt_username = optional_string(key, value)?,
+            "mqtt_password" => self.mqtt_password = optional_string(key, value)?,
+            "media_dial" => {
+                self.media_dial = match value {
+                    Value::Null => None,
+                    Value::String(string) if string.trim().is_empty() => None,
+                    value => Some(int_in_range(key, value, 0, ENCODER_COUNT as u64 - 1)? as u8),
+                }
+            }
+            "mixer_dials" => self.mixer_dials = mixer_dials(key, value)?,
+            "system_volume_devices" => {
+                self.system_volume_devices = strings(key, value, "device IDs")?
+            }
+            "obs_url" => {
+                self.obs_url = optional_string(key, value)?
+                    .map(|url| {
+                        let url = url.trim();
+
+                        if url.starts_with("ws://") || url.starts_with("wss://") {
+                            Ok(url.to_string())
+                        } else {
+                            Err(format!(
+                                "\"{}\" must start with ws:// or wss://, got {}",
+                                key, url
+                            ))
+                        }
+                    })
+                    .transpose()?
+            }
+            "obs_password" => self.obs_password = optional_string(key, value)?,
+            "obs_scenes" => self.obs_scenes = obs_scenes(key, value)?,
+            "midi_output" => self.midi_output = optional_string(key, value)?,
+            "midi_channel" => self.midi_channel = int_in_range(key, value, 1, 16)? as u8,
+            "hook_command" => self.hook_command = optional_string(key, value)?,
+            "hook_script" => self.hook_script = optional_string(key, value)?.map(PathBuf::from),
+            "event_sinks" => {
+                self.event_sinks = strings(key, value, "event sinks")?
+                    .iter()
+                    .map(|sink| {
+                        EventSink::parse(sink).ok_or(format!(
+                            "\"{}\" must be stdout, file:<path> or socket:<path>, got {}",
+                            key, sink
+                        ))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "excluded_serials" => self.excluded_serials = strings(key, value, "serial numbers")?,
+            "included_serials" => self.included_serials = strings(key, value, "serial numbers")?,
+            "usb_port_ids" => self.usb_port_ids = boolean(key, value)?,
+            "diagnostics" => self.diagnostics = boolean(key, value)?,
+            "system_codes" => self.system_codes = system_codes(key, value)?,
+            "protocol_errors" => {
+                self.protocol_errors =
+                    value
+                        .as_str()
+                        .and_then(ProtocolErrors::parse)
+                        .ok_or(format!(
+                            "\"{}\" must be either \"lenient\" or \"strict\", got {}",
+                            key, value
+                        ))?
+            }
+            "unknown_inputs" => {
+                self.unknown_inputs =
+                    value
+                        .as_str()
+                        .and_then(UnknownInputs::parse)
+                        .ok_or(format!(
+                            "\"{}\" must be \"ignore\", \"log\" or \"forward\", got {}",
+                            key, value
+                        ))?
+            }
+            "media_rotate" => {
+                self.media_rotate = value.as_str().and_then(MediaRotate::parse).ok_or(format!(
+                    "\"{}\" must be either \"volume\" or \"seek\", got {}",
+                    key, value
+                ))?
+            }
+            _ => return Err(format!("unknown setting \"{}\"", key)),
+        }
+
+        Ok(())
+    }
+
+    /// Applies every setting from a JSON object, collecting errors prefixed with `source`
+    pub fn apply_json(&mut self, source: &str, value: &Value, errors: &mut Vec<String>) {
+        let Some(object) = value.as_object() else {
+            errors.push(format!("{}: expected an object, got {}", source, value));
+            return;
+        };
+
+        for (key, value) in object {
+            if let Err(err) = self.set(key, value) {
+                errors.push(format!("{}: {}", source, err));
+            }
+        }
+    }
+
+    /// Applies settings from environment variables, `var` looks up a variable by name
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>, errors: &mut Vec<String>) {
+        for key in KEYS {
+            let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+
+            if let Some(value) = var(&name)
+                && let Err(err) = self.set(key, &Value::String(value))
+            {
+                errors.push(format!("{}: {}", name, err));
+            }
+        }
+    }
+
+    /// Builds config from every layer, returning it along with problems found on the way
+    ///
+    /// Invalid values are reported and skipped, so the previous layer's value is kept.
+    pub fn load_from(
+        file: Option<(&str, &str)>,
+        var: impl Fn(&str) -> Option<String>,
+        settings: Option<&Value>,
+    ) -> (Self, Vec<String>) {
+        let mut config = Self::default();
+        let mut errors = vec![];
+
+        if let Some((path, text)) = file {
+            match serde_json::from_str::<Value>(text) {
+                Ok(value) => config.apply_json(path, &value, &mut errors),
+                Err(err) => errors.push(format!("{}: {}", path, err)),
+            }
+        }
+
+        config.apply_env(var, &mut errors);
+
+        // OpenDeck may send null until settings are saved for the first time
+        if let Some(settings) = settings.filter(|settings| !settings.is_null()) {
+            config.apply_json("OpenDeck settings", settings, &mut errors);
+        }
+
+        (config, errors)
+    }
+
+    /// Loads config from the real file and environment, on top of OpenDeck settings if known
+    pub fn load(settings: Option<&Value>) -> (Self, Vec<String>) {
+        let path = config_path();
+        let text = path.as_ref().map(std::fs::read_to_string);
+        let path = path.map(|path| path.display().to_string());
+
+        let file = match (&path, &text) {
+            (Some(path), Some(Ok(text))) => Some((path.as_str(), text.as_str())),
+            _ => None,
+        };
+
+        let (config, mut errors) = Self::load_from(file, |name| std::env::var(name).ok(), settings);
+
+        // Missing file is fine, unless user explicitly pointed to it
+        if let (Some(path), Some(Err(err))) = (&path, &text)
+            && std::env::var_os(CONFIG_PATH_ENV).is_some()
+        {
+            errors.insert(0, format!("{}: {}", path, err));
+        }
+
+        (config, errors)
+    }
+
+    /// Devices the plugin may use
+    pub fn device_filter(&self) -> DeviceFilter {
+        DeviceFilter {
+            allow: self.included_serials.clone(),
+            deny: self.excluded_serials.clone(),
+        }
+    }
+
+    /// Color correction of a device, set either for its ID or its serial number
+    pub fn color_correction(&self, id: &str) -> ColorCorrection {
+        by_device(&self.color_correction, id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Transforms of single keys of a device, set either for its ID or its serial number
+    pub fn key_transforms(&self, id: &str) -> HashMap<u8, KeyTransform> {
+        by_device(&self.key_transforms, id)
+            .map(|transforms| transforms.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Keys of a device that don't work, set either for its ID or its serial number
+    pub fn dead_keys(&self, id: &str) -> HashSet<u8> {
+        by_device(&self.dead_keys, id)
+            .map(|keys| keys.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Which way up the device is, [None] if it was never set up
+    pub fn orientation(&self, id: &str) -> Option<Orientation> {
+        by_device(&self.orientation, id).copied()
+    }
+
+    pub fn input_options(&self) -> InputOptions {
+        InputOptions {
+            encoder_press: self.encoder_press,
+            debounce: Duration::from_millis(self.debounce_ms),
+            acceleration: self.encoder_acceleration,
+            invert_encoders: self.invert_encoders,
+            noise_filter: self.encoder_noise_ms.map(Duration::from_millis),
+            encoder_codes: self.encoder_codes.clone(),
+            system_codes: self.system_codes.clone(),
+            ghost_window: GHOST_WINDOW,
+            protocol_errors: self.protocol_errors,
+        }
+    }
+
+    /// Checks if switching to `other` changes how images look, so they have to be uploaded again
+    ///
+    /// Moving the clock or widgets also needs OpenDeck to draw the buttons they were on before.
+    pub fn affects_images(&self, other: &Config) -> bool {
+        self.jpeg_quality != other.jpeg_quality
+            || self.color_correction != other.color_correction
+            || self.orientation != other.orientation
+            || self.key_transforms != other.key_transforms
+            || self.dead_keys != other.dead_keys
+            || self.clock_key != other.clock_key
+            || self.widgets.keys().ne(other.widgets.keys())
+            || self.labels.keys().ne(other.labels.keys())
+            || self.icons.keys().ne(other.icons.keys())
+            || self.page_keys != other.page_keys
+            || self.page_dial != other.page_dial
+            || self.media_dial != other.media_dial
+            || self.mixer_dials.keys().ne(other.mixer_dials.keys())
+            || self.system_volume_devices != other.system_volume_devices
+            || self.obs_scenes.keys().ne(other.obs_scenes.keys())
+            || self.sliders != other.sliders
+            || self.diagnostics != other.diagnostics
+    }
+
+    /// Checks if the plugin draws this position itself, so OpenDeck images for it are ignored
+    pub fn draws_position(&self, position: u8) -> bool {
+        self.clock_key == Some(position)
+            || self.widgets.contains_key(&position)
+            || self.labels.contains_key(&position)
+            || self.icons.contains_key(&position)
+            || self.media_dial == Some(position)
+            || self.mixer_dials.contains_key(&position)
+            || self.obs_scenes.contains_key(&position)
+            || self.sliders.contains(&position)
+    }
+
+    /// Checks if the leftmost encoder of the device controls the system volume
+    pub fn system_volume_on(&self, device: &str) -> bool {
+        self.system_volume_devices
+            .iter()
+            .any(|id| id == "*" || id == device)
+    }
+
+    pub fn unlock_gesture(&self) -> UnlockGesture {
+        UnlockGesture {
+            chord: self.unlock_chord.clone(),
+            hold: Duration::from_millis(self.unlock_hold_ms),
+        }
+    }
+
+    /// Settings of the OBS connection, [None] if it's disabled
+    ///
+    /// Scene keys alone connect to OBS at its default address.
+    pub fn obs_settings(&self) -> Option<ObsSettings> {
+        let url = match &self.obs_url {
+            Some(url) => url.clone(),
+            None if !self.obs_scenes.is_empty() => DEFAULT_URL.to_string(),
+            None => return None,
+        };
+
+        Some(ObsSettings {
+            url,
+            password: self.obs_password.clone(),
+        })
+    }
+
+    /// Settings of the MQTT bridge, [None] if it's disabled
+    pub fn mqtt_settings(&self) -> Option<MqttSettings> {
+        Some(MqttSettings {
+            broker: self.mqtt_broker.clone()?,
+            topic: self.mqtt_topic.clone(),
+            username: self.mqtt_username.clone(),
+            password: self.mqtt_password.clone(),
+        })
+    }
+
+    pub fn widget_refresh(&self) -> Duration {
+        Duration::from_millis(self.widget_refresh_ms)
+    }
+
+    /// Read timeout for the input loop, [None] if it should wait for input indefinitely
+    pub fn poll_interval(&self) -> Option<Duration> {
+        (self.poll_interval_ms > 0).then(|| Duration::from_millis(self.poll_interval_ms))
+    }
+
+    /// Pacing of image uploads, taken by devices when they connect
+    pub fn upload_pacing(&self) -> UploadPacing {
+        UploadPacing {
+            chunk: self.upload_chunk_bytes as usize,
+            delay: Duration::from_micros(self.upload_chunk_delay_us),
+        }
+    }
+
+    /// Read timeouts for the input loop, starting from `poll_interval_ms`
+    pub fn read_pacer(&self) -> ReadPacer {
+        ReadPacer::new(self.poll_interval(), self.read_pacing)
+    }
+}
+
+/// Last global settings received from OpenDeck, [None] until they arrive
+pub fn opendeck_settings() -> Option<Value> {
+    OPENDECK_SETTINGS.lock().unwrap().clone()
+}
+
+/// Setting of a device keyed by either its ID or its serial number
+fn by_device<'a, T>(settings: &'a BTreeMap<String, T>, id: &str) -> Option<&'a T> {
+    if let Some(setting) = settings.get(id) {
+        return Some(setting);
+    }
+
+    // IDs built from the USB port too still belong to the serial number
+    settings
+        .iter()
+        .find(|(serial, _)| {
+            let serial_id = serial_to_id(serial);
+            id == serial_id || id.starts_with(&format!("{}@", serial_id))
+        })
+        .map(|(_, setting)| setting)
+}
+
+/// Changes settings in the config file, creating it if it's missing, and returns its path
+///
+/// Settings `edit` leaves alone are kept as they are. The file watcher picks the change up, call
+/// [reload] to apply it right away.
+pub async fn edit_file(edit: impl FnOnce(&mut Map<String, Value>)) -> Result<PathBuf, String> {
+    let path = config_path().ok_or("config file location isn't known")?;
+
+    let mut settings = match tokio::fs::read_to_string(&path).await {
+        Ok(text) => match serde_json::from_str(&text) {
+            Ok(Value::Object(settings)) => settings,
+            Ok(_) => return Err(format!("{}: expected an object", path.display())),
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Map::new(),
+        Err(err) => return Err(format!("{}: {}", path.display(), err)),
+    };
+
+    edit(&mut settings);
+
+    let text = serde_json::to_string_pretty(&settings).map_err(|err| err.to_string())?;
+    tokio::fs::write(&path, text)
+        .await
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    Ok(path)
+}
+
+/// Loads config again and makes it current, logging every problem found
+///
+/// New OpenDeck settings replace the remembered ones, [None] keeps using them.
+pub async fn reload(settings: Option<Value>) {
+    let settings = {
+        let mut stored = OPENDECK_SETTINGS.lock().unwrap();
+
+        if let Some(settings) = settings {
+            *stored = Some(settings);
+        }
+
+        stored.clone()
+    };
+
+    let (config, errors) = Config::load(settings.as_ref());
+
+    for error in errors {
+        log::error!("Config error, {}", error);
+    }
+
+    let old = CONFIG.send_replace(config.clone());
+
+    if old == config {
+        return;
+    }
+
+    log::info!("Using config {:?}", config);
+
+    apply_changes(&old, &config).await;
+}
+
+/// Pushes changed settings to connected devices, input settings are picked up by device tasks
+/// Loads fallback fonts for text the plugin draws, fonts that can't be read are left out
+async fn load_fonts(paths: Vec<PathBuf>) {
+    let fonts = tokio::task::spawn_blocking(move || {
+        paths
+            .iter()
+            .filter_map(|path| match BitmapFont::load(path) {
+                Ok(font) => {
+                    log::info!("Loaded {} characters from {}", font.len(), path.display());
+                    Some(font)
+                }
+                Err(err) => {
+                    log::error!("{}", err);
+                    None
+                }
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default();
+
+    text::set_fallback_fonts(fonts);
+}
+
+/// Loads color emoji for text the plugin draws, [None] drops them
+async fn load_emoji(dir: Option<PathBuf>) {
+    let emoji = tokio::task::spawn_blocking(move || {
+        let dir = dir?;
+
+        match EmojiAtlas::load(&dir) {
+            Ok(emoji) => {
+                log::info!("Loaded {} emoji from {}", emoji.len(), dir.display());
+                Some(emoji)
+            }
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+    .unwrap_or_default();
+
+    text::set_emoji(emoji);
+}
+
+async fn apply_changes(old: &Config, new: &Config) {
+    if old.fonts != new.fonts {
+        load_fonts(new.fonts.clone()).await;
+    }
+
+    if old.emoji_dir != new.emoji_dir {
+        load_emoji(new.emoji_dir.clone()).await;
+    }
+
+    if old.tile_cache_mb != new.tile_cache_mb {
+        icons::tiles().set_capacity(new.tile_cache_mb as usize * MEGABYTE);
+    }
+
+    for (id, writer) in WRITERS.read().await.iter() {
+        if old.brightness != new.brightness {
+            log::info!("Applying new brightness to {}", id);
+            writer.send(WriterCommand::SetBrightness(new.brightness));
+        }
+
+        if old.dnd_brightness != new.dnd_brightness && dnd::is_enabled() {
+            writer.send(WriterCommand::Dim(dnd::dim_level()));
+        }
+
+        if old.key_transforms != new.key_transforms {
+            deck::set_key_transforms(id, new.key_transforms(id));
+        }
+
+        if old.dead_keys != new.dead_keys {
+            // OpenDeck keeps the layout the device registered with until it reconnects
+            log::info!("Applying new dead keys to {}", id);
+            deck::set_dead_keys(id, new.dead_keys(id));
+        }
+
+        if old.affects_images(new) {
+            log::info!("Redrawing {} with new image settings", id);
+            writer.send(WriterCommand::Redraw);
+        }
+    }
+}
+
+fn modified_at(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Reloads config whenever the file changes
+pub async fn file_watcher_task(token: CancellationToken) {
+    let Some(path) = config_path() else {
+        return;
+    };
+
+    log::info!("Watching config file {:?}", path);
+
+    let mut last_modified = modified_at(&path);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(FILE_CHECK_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let modified = modified_at(&path);
+
+        if modified != last_modified {
+            last_modified = modified;
+
+            log::info!("Config file changed, reloading");
+            reload(None).await;
+        }
+    }
+}
+
+/// Path of the config file, either from [CONFIG_PATH_ENV] or next to the executable
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(CONFIG_PATH_ENV) {
+        return Some(PathBuf::from(path));
+    }
+
+    let exe = std::env::current_exe().ok()?;
+
+    Some(exe.parent()?.join(CONFIG_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let file = r#"{ "brightness": 20, "jpeg_quality": 70, "debounce_ms": 15 }"#;
+        let env = |name: &str| match name {
+            "OPENDECK_AKP05_BRIGHTNESS" => Some("30".to_string()),
+            "OPENDECK_AKP05_ENCODER_PRESS" => Some("keys".to_string()),
+            _ => None,
+        };
+        let settings = json!({ "brightness": 40 });
+
+        let (config, errors) = Config::load_from(Some(("config.json", file)), env, Some(&settings));
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config,
+            Config {
+                brightness: 40,
+                encoder_press: EncoderPress::Keys,
+                debounce_ms: 15,
+                jpeg_quality: 70,
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported_and_skipped() {
+        let file = r#"{ "jpeg_quality": 150, "brightness": 10, "colour": "red" }"#;
+        let env =
+            |name: &str| (name == "OPENDECK_AKP05_ENCODER_PRESS").then(|| "buttons".to_string());
+
+        let (config, errors) = Config::load_from(Some(("config.json", file)), env, None);
+
+        assert_eq!(config.brightness, 10);
+        assert_eq!(config.jpeg_quality, Config::default().jpeg_quality);
+        assert_eq!(config.encoder_press, EncoderPress::Dial);
+        assert_eq!(
+            errors,
+            vec![
+                "config.json: unknown setting \"colour\"",
+                "config.json: \"jpeg_quality\" must be a number between 1 and 100, got 150",
+                "OPENDECK_AKP05_ENCODER_PRESS: \"encoder_press\" must be either \"dial\" or \"keys\", got \"buttons\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_file_is_reported() {
+        let (config, errors) = Config::load_from(Some(("config.json", "{ nope")), |_| None, None);
+
+        assert_eq!(config, Config::default());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("config.json: "));
+    }
+
+    #[test]
+    fn only_image_settings_require_redraw() {
+        let config = Config::default();
+
+        assert!(!config.affects_images(&Config {
+            brightness: 10,
+            invert_encoders: true,
+            ..Config::default()
+        }));
+        assert!(config.affects_images(&Config {
+            jpeg_quality: 60,
+            ..Config::default()
+        }));
+        assert!(config.affects_images(&Config {
+            clock_key: Some(14),
+            ..Config::default()
+        }));
+    }
+
+    #[test]
+    fn app_profiles_are_read_from_json_and_env() {
+        let file = r#"{ "app_profiles": { "*": { "firefox": "Browser" } } }"#;
+        let env = |name: &str| {
+            (name == "OPENDECK_AKP05_APP_PROFILES")
+                .then(|| r#"{ "a5-1": { "code": "Coding" } }"#.to_string())
+        };
+
+        let (config, errors) = Config::load_from(Some(("config.json", file)), |_| None, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.app_profiles["*"]["firefox"], "Browser");
+
+        let (config, errors) = Config::load_from(Some(("config.json", file)), env, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.app_profiles.keys().collect::<Vec<_>>(), vec!["a5-1"]);
+
+        let (_, errors) = Config::load_from(
+            Some(("config.json", r#"{ "app_profiles": [1] }"#)),
+            |_| None,
+            None,
+        );
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn clock_key_can_be_disabled() {
+        let env = |name: &str| (name == "OPENDECK_AKP05_CLOCK_KEY").then(|| "14".to_string());
+
+        let (config, errors) = Config::load_from(None, env, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.clock_key, Some(14));
+
+        let settings = json!({ "clock_key": null });
+        let (config, _) = Config::load_from(None, env, Some(&settings));
+        assert_eq!(config.clock_key, None);
+
+        let settings = json!({ "clock_key": 15 });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(config.clock_key, None);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn widgets_are_validated() {
+        let settings = json!({ "widgets": { "5": "cpu", "14": "net" } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.widgets,
+            Widgets::from([(5, Widget::Cpu), (14, Widget::Net)])
+        );
+        assert!(config.draws_position(14));
+        assert!(!config.draws_position(6));
+
+        for widgets in [
+            json!({ "15": "cpu" }),
+            json!({ "5": "gpu" }),
+            json!(["cpu"]),
+        ] {
+            let settings = json!({ "widgets": widgets });
+            let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+            assert!(config.widgets.is_empty());
+            assert_eq!(errors.len(), 1, "{}", widgets);
+        }
+    }
+
+    #[test]
+    fn labels_and_variables_are_validated() {
+        let settings = json!({
+            "labels": { "0": "{time}", "7": "Scene\n{var:scene}" },
+            "variables": { "scene": "Live", "count": 3 }
+        });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.labels,
+            Labels::from([
+                (0, "{time}".to_string()),
+                (7, "Scene\n{var:scene}".to_string())
+            ])
+        );
+        assert_eq!(config.variables["count"], "3");
+        assert!(config.draws_position(7));
+
+        let settings = json!({ "labels": { "15": "x" }, "variables": { "scene": ["a"] } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(config.labels.is_empty() && config.variables.is_empty());
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+    }
+
+    #[test]
+    fn encoder_noise_is_validated() {
+        let settings = json!({ "encoder_noise_ms": { "2": 150 } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.input_options().noise_filter[2],
+            Duration::from_millis(150)
+        );
+
+        let settings = json!({ "encoder_noise_ms": { "4": 150 } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(config.encoder_noise_ms, [0; ENCODER_COUNT]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn encoder_codes_are_parsed() {
+        let settings = json!({ "encoder_codes": { "0x62": [0, -1], "99": [3, 1] } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.input_options().encoder_codes,
+            vec![EncoderCode::new(0x62, 0, -1), EncoderCode::new(99, 3, 1)]
+        );
+
+        for codes in [
+            json!({ "0x62": [4, 1] }),
+            json!({ "0x62": [0, 2] }),
+            json!({ "0x162": [0, 1] }),
+            json!({ "0x62": 0 }),
+        ] {
+            let settings = json!({ "encoder_codes": codes });
+            let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+            assert!(config.encoder_codes.is_empty());
+            assert_eq!(errors.len(), 1, "{}", codes);
+        }
+    }
+
+    #[test]
+    fn system_codes_are_parsed() {
+        let settings = json!({ "system_codes": { "0xE0": "wake", "225": "over_temperature" } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.input_options().system_codes,
+            vec![
+                (0xE0, SystemEvent::Wake),
+                (225, SystemEvent::OverTemperature)
+            ]
+        );
+
+        let settings = json!({ "system_codes": { "0xE0": "sleep" } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(config.system_codes.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn protocol_errors_are_parsed() {
+        let settings = json!({ "protocol_errors": "strict" });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.input_options().protocol_errors,
+            ProtocolErrors::Strict
+        );
+
+        let settings = json!({ "protocol_errors": "loud" });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(config.protocol_errors, ProtocolErrors::Lenient);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn page_keys_come_in_pairs() {
+        let settings = json!({
+            "pages": 3,
+            "page_keys": [5, 9],
+            "page_dial": 3,
+            "page_transition": "slide"
+        });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            (config.pages, config.page_keys, config.page_dial),
+            (3, vec![5, 9], Some(3))
+        );
+        assert_eq!(config.page_transition, Transition::Slide);
+
+        let settings = json!({
+            "pages": 11,
+            "page_keys": [5],
+            "page_dial": 4,
+            "page_transition": "spin"
+        });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(
+            (config.pages, config.page_keys, config.page_dial),
+            (1, vec![], None)
+        );
+        assert_eq!(config.page_transition, Transition::None);
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+    }
+
+    #[test]
+    fn folders_are_validated() {
+        let settings = json!({ "folders": { "7": 3, "17": 4 } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.folders, Folders::from([(7, 3), (17, 4)]));
+
+        for folders in [json!({ "7": 1 }), json!({ "100": 2 }), json!({ "7": "3" })] {
+            let settings = json!({ "folders": folders });
+            let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+            assert!(config.folders.is_empty());
+            assert_eq!(errors.len(), 1, "{}", folders);
+        }
+    }
+
+    #[test]
+    fn mixer_dials_are_validated() {
+        let settings = json!({ "mixer_dials": { "0": "firefox", "3": " Spotify " } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.mixer_dials,
+            MixerDials::from([(0, "firefox".to_string()), (3, "Spotify".to_string())])
+        );
+        assert!(config.draws_position(3));
+
+        for dials in [
+            json!({ "4": "firefox" }),
+            json!({ "0": "" }),
+            json!({ "0": 1 }),
+        ] {
+            let settings = json!({ "mixer_dials": dials });
+            let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+            assert!(config.mixer_dials.is_empty());
+            assert_eq!(errors.len(), 1, "{}", dials);
+        }
+    }
+
+    #[test]
+    fn system_volume_is_per_device() {
+        let env = |name: &str| {
+            (name == "OPENDECK_AKP05_SYSTEM_VOLUME_DEVICES").then(|| "a5-1, a5-2".to_string())
+        };
+        let (config, errors) = Config::load_from(None, env, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(config.system_volume_on("a5-2"));
+        assert!(!config.system_volume_on("a5-3"));
+
+        let settings = json!({ "system_volume_devices": ["*"] });
+        let (config, _) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(config.system_volume_on("a5-3"));
+
+        let settings = json!({ "system_volume_devices": [1] });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(config.system_volume_devices.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn color_correction_is_per_device() {
+        let settings = json!({ "color_correction": {
+            "ABC": { "gain": [1.0, 0.95, 0.9] },
+            "a5-DEF": { "gamma": 1.2 },
+        } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.color_correction("a5-ABC").gain, [1.0, 0.95, 0.9]);
+        assert_eq!(
+            config.color_correction("a5-ABC@1-2.3").gain,
+            [1.0, 0.95, 0.9]
+        );
+        assert_eq!(config.color_correction("a5-DEF").gamma, 1.2);
+        assert!(config.color_correction("a5-GHI").is_identity());
+
+        for correction in [
+            json!({ "gamma": 0 }),
+            json!({ "gain": [1.0, 1.0] }),
+            json!({ "gain": [1.0, 1.0, 3.0] }),
+            json!({ "tint": 1 }),
+        ] {
+            let settings = json!({ "color_correction": { "ABC": correction } });
+            let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+            assert!(config.color_correction.is_empty());
+            assert_eq!(errors.len(), 1, "{}", correction);
+        }
+    }
+
+    #[test]
+    fn key_transforms_are_per_device() {
+        let settings = json!({ "key_transforms": {
+            "ABC": { "2": { "rotation": 0 }, "7": { "rotation": 0, "mirror": "x" } },
+        } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let transforms = config.key_transforms("a5-ABC");
+        assert_eq!(transforms[&2], KeyTransform::new(0, "none").unwrap());
+        assert_eq!(transforms[&7], KeyTransform::new(0, "x").unwrap());
+        assert!(config.key_transforms("a5-DEF").is_empty());
+
+        for transform in [
+            json!({ "rotation": 45 }),
+            json!({ "mirror": "diagonal" }),
+            json!({ "flip": true }),
+        ] {
+            let settings = json!({ "key_transforms": { "ABC": { "2": transform } } });
+            let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+            assert!(config.key_transforms.is_empty());
+            assert_eq!(errors.len(), 1, "{}", transform);
+        }
+
+        let settings = json!({ "key_transforms": { "ABC": { "20": { "rotation": 0 } } } });
+        let (_, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn dead_keys_are_per_device() {
+        let settings = json!({ "dead_keys": { "ABC": [3, 8] } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(config.dead_keys("a5-ABC"), HashSet::from([3, 8]));
+        assert!(config.dead_keys("a5-DEF").is_empty());
+
+        // Strip zones and encoders aren't keys
+        let settings = json!({ "dead_keys": { "ABC": [10] } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(config.dead_keys.is_empty());
+        assert_eq!(
+            errors,
+            [
+                "OpenDeck settings: \"dead_keys\" must map serial numbers to lists of keys (0-9), got {\"ABC\":[10]}"
+            ]
+        );
+    }
+
+    #[test]
+    fn obs_connects_with_scene_keys() {
+        let (config, _) = Config::load_from(None, |_| None, None);
+        assert_eq!(config.obs_settings(), None);
+
+        let settings = json!({ "obs_scenes": { "5": "Camera" }, "obs_password": "secret" });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.obs_settings(),
+            Some(ObsSettings {
+                url: DEFAULT_URL.to_string(),
+                password: Some("secret".to_string()),
+            })
+        );
+        assert!(config.draws_position(5));
+
+        let settings =
+            json!({ "obs_url": "http://localhost:4455", "obs_scenes": { "15": "Camera" } });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(config.obs_settings(), None);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn unlock_chord_is_read_from_json_and_env() {
+        let env = |name: &str| (name == "OPENDECK_AKP05_UNLOCK_CHORD").then(|| "0, 4".to_string());
+
+        let (config, errors) = Config::load_from(None, env, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.unlock_chord, vec![0, 4]);
+
+        let settings = json!({ "unlock_chord": [5, 20] });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(config.unlock_chord.is_empty());
+        assert_eq!(
+            errors,
+            vec![
+                "OpenDeck settings: \"unlock_chord\" must be a list of positions (0-14), got [5,20]"
+            ]
+        );
+    }
+
+    #[test]
+    fn fonts_are_read_from_json_and_env() {
+        let env = |name: &str| {
+            (name == "OPENDECK_AKP05_FONTS").then(|| "/fonts/unifont.bdf, cjk.bdf".to_string())
+        };
+
+        let (config, errors) = Config::load_from(None, env, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.fonts,
+            [
+                PathBuf::from("/fonts/unifont.bdf"),
+                PathBuf::from("cjk.bdf")
+            ]
+        );
+
+        let settings = json!({ "fonts": "unifont.bdf" });
+        let (config, _) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(config.fonts, [PathBuf::from("unifont.bdf")]);
+    }
+
+    #[test]
+    fn progress_colors_are_parsed() {
+        let settings = json!({ "progress_color": "#ff8000", "progress_track": "#202020" });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.progress_bar,
+            ProgressBar {
+                fill: Rgb([255, 128, 0]),
+                track: Some(Rgb([32, 32, 32]))
+            }
+        );
+
+        // Bad colors keep the default
+        let settings = json!({ "progress_color": "orange", "progress_track": null });
+        let (config, errors) = Config::load_from(None, |_| None, Some(&settings));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(config.progress_bar, ProgressBar::default());
+    }
+}
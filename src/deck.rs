@@ -0,0 +1,459 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    capture::CaptureRecorder,
+    error::{Akp05Error, ErrorContext, Operation},
+    images::{KeyImage, KeyTransform, decode_data_url},
+    inputs::{InputReport, InputState, Updates},
+    mappings::{CandidateDevice, Kind},
+    transport::{DeviceTransport, HidTransport},
+};
+
+// Device id to transforms of keys mounted differently from the rest, by position
+static KEY_TRANSFORMS: LazyLock<RwLock<HashMap<String, HashMap<u8, KeyTransform>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Sets rotation and mirroring of single keys of a device, e.g. a column of panels that's mounted
+/// upside down on an early unit
+///
+/// They replace the ones of the device format for those keys, from the next image on. Empty
+/// transforms go back to the device format for every key.
+pub fn set_key_transforms(id: &str, transforms: HashMap<u8, KeyTransform>) {
+    let mut all = KEY_TRANSFORMS.write().unwrap();
+
+    if transforms.is_empty() {
+        all.remove(id);
+    } else {
+        all.insert(id.to_string(), transforms);
+    }
+}
+
+// Device id to keys that don't work, they're never drawn
+static DEAD_KEYS: LazyLock<RwLock<HashMap<String, HashSet<u8>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Sets keys of a device that don't work, images for them are dropped from the next one on
+pub fn set_dead_keys(id: &str, keys: HashSet<u8>) {
+    let mut all = DEAD_KEYS.write().unwrap();
+
+    if keys.is_empty() {
+        all.remove(id);
+    } else {
+        all.insert(id.to_string(), keys);
+    }
+}
+
+/// Checks if a key of a device was marked as not working
+pub fn is_dead_key(id: &str, position: u8) -> bool {
+    DEAD_KEYS
+        .read()
+        .unwrap()
+        .get(id)
+        .is_some_and(|keys| keys.contains(&position))
+}
+
+/// Connects to a device found by discovery
+pub async fn connect(candidate: &CandidateDevice) -> Result<HidTransport, Akp05Error> {
+    let result = HidTransport::connect(candidate)
+        .await
+        .context(&candidate.id, Operation::Connect);
+
+    match result {
+        Ok(device) => Ok(device),
+        Err(e) => {
+            log::error!("Error while connecting to device: {e}");
+
+            Err(e)
+        }
+    }
+}
+
+/// Puts freshly connected device into a known state
+pub async fn initialize_device(
+    id: &str,
+    device: &impl DeviceTransport,
+    brightness: u8,
+) -> Result<(), Akp05Error> {
+    device
+        .set_brightness(brightness)
+        .await
+        .context(id, Operation::Initialize)?;
+    device
+        .clear_all_button_images()
+        .await
+        .context(id, Operation::Initialize)?;
+    device.flush().await.context(id, Operation::Initialize)?;
+
+    Ok(())
+}
+
+/// Reads a single report from the device and returns resulting state updates
+pub async fn read_updates(
+    id: &str,
+    device: &impl DeviceTransport,
+    state: &mut InputState,
+    timeout: Option<Duration>,
+    recorder: Option<&mut CaptureRecorder>,
+) -> Result<Updates, Akp05Error> {
+    read_input(id, device, state, timeout, recorder)
+        .await
+        .map(|(_, updates)| updates)
+}
+
+/// Same as [read_updates], but also returns the raw input of the report
+///
+/// Reports are decoded the way `state` was set up for, see [InputState::decoder].
+pub async fn read_input(
+    id: &str,
+    device: &impl DeviceTransport,
+    state: &mut InputState,
+    timeout: Option<Duration>,
+    recorder: Option<&mut CaptureRecorder>,
+) -> Result<(Option<InputReport>, Updates), Akp05Error> {
+    // Key changes held back by debouncing are sent once their window is over, input or not
+    let timeout = match (timeout, state.settle_in(Instant::now())) {
+        (Some(timeout), Some(settle)) => Some(timeout.min(settle)),
+        (timeout, settle) => timeout.or(settle),
+    };
+
+    let report = match device
+        .read_report(timeout)
+        .await
+        .context(id, Operation::ReadInput)?
+    {
+        Some(report) => report,
+        None => return Ok((None, state.settle())),
+    };
+
+    if let Some(recorder) = recorder {
+        recorder.record(&report);
+    }
+
+    decode_input(id, state, &report)
+}
+
+/// Decodes a raw report the way [read_input] does with ones read from the device
+///
+/// Lets reports made up elsewhere, e.g. injected for testing, go through the same decoding.
+pub fn decode_input(
+    id: &str,
+    state: &mut InputState,
+    report: &[u8],
+) -> Result<(Option<InputReport>, Updates), Akp05Error> {
+    let decoder = state.decoder();
+    let input_report = decoder.report(report);
+    let input = decoder.decode(report).context(id, Operation::ReadInput)?;
+
+    Ok((input_report, state.apply(input)))
+}
+
+/// Handles different combinations of "set image" event, including clearing the specific buttons and whole device
+pub async fn handle_set_image(
+    id: &str,
+    device: &impl DeviceTransport,
+    position: Option<u8>,
+    image: Option<KeyImage>,
+    quality: u8,
+) -> Result<(), Akp05Error> {
+    match (position, image) {
+        (Some(position), Some(image)) => {
+            write_image(id, device, position, Some(image), quality).await?;
+            device.flush().await.context(id, Operation::SetImage)?;
+        }
+        (Some(position), None) => {
+            write_image(id, device, position, None, quality).await?;
+            device.flush().await.context(id, Operation::ClearImage)?;
+        }
+        (None, None) => {
+            device
+                .clear_all_button_images()
+                .await
+                .context(id, Operation::ClearImage)?;
+            device.flush().await.context(id, Operation::ClearImage)?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Sets or clears images of several buttons, flushing only once so they change together
+pub async fn handle_set_images(
+    id: &str,
+    device: &impl DeviceTransport,
+    images: Vec<(u8, Option<KeyImage>)>,
+    quality: u8,
+) -> Result<(), Akp05Error> {
+    for (position, image) in images {
+        write_image(id, device, position, image, quality).await?;
+    }
+
+    device.flush().await.context(id, Operation::SetImage)
+}
+
+/// Sets or clears image of a single button, it only shows up once the device is flushed
+pub async fn write_image(
+    id: &str,
+    device: &impl DeviceTransport,
+    position: u8,
+    image: Option<KeyImage>,
+    quality: u8,
+) -> Result<(), Akp05Error> {
+    // Map software position to physical device position (device is upside down)
+    let physical_position = physical_position(id, device, position)?;
+
+    if is_dead_key(id, position) {
+        log::debug!("Not drawing dead key {} of {}", position, id);
+        return Ok(());
+    }
+
+    let Some(image) = image else {
+        return device
+            .clear_button_image(physical_position)
+            .await
+            .context(id, Operation::ClearImage);
+    };
+
+    log::info!("Setting image for button {}", position);
+
+    let kind = device_kind(id, device)?;
+
+    log::info!(
+        "Mapping software position {} to physical position {}",
+        position,
+        physical_position
+    );
+
+    let image = match image {
+        KeyImage::DataUrl(url) => {
+            decode_data_url(&url).map_err(|reason| Akp05Error::InvalidImage {
+                id: id.to_string(),
+                reason,
+            })?
+        }
+        KeyImage::Rendered(image) => (*image).clone(),
+    };
+
+    let format = match KEY_TRANSFORMS
+        .read()
+        .unwrap()
+        .get(id)
+        .and_then(|transforms| transforms.get(&position))
+    {
+        Some(transform) => kind.image_format().with_transform(*transform),
+        None => kind.image_format(),
+    };
+
+    device
+        .set_button_image(physical_position, format, quality, image)
+        .await
+        .context(id, Operation::SetImage)
+}
+
+fn device_kind(id: &str, device: &impl DeviceTransport) -> Result<Kind, Akp05Error> {
+    Kind::from_vid_pid(device.vid(), device.pid()).ok_or(Akp05Error::UnknownDevice {
+        id: id.to_string(),
+        vid: device.vid(),
+        pid: device.pid(),
+    })
+}
+
+/// Maps position sent by OpenDeck to physical button, rejecting positions out of range
+fn physical_position(
+    id: &str,
+    device: &impl DeviceTransport,
+    position: u8,
+) -> Result<u8, Akp05Error> {
+    let kind = device_kind(id, device)?;
+
+    if position as usize >= kind.mapped_index_count() {
+        return Err(Akp05Error::InvalidPosition {
+            id: id.to_string(),
+            position,
+        });
+    }
+
+    Ok(kind.map_button_index(position as usize) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat};
+    use mirajazz::state::DeviceStateUpdate;
+
+    use super::*;
+    use crate::transport::mock::{MockTransport, MockWrite};
+
+    fn jpeg_data_url() -> String {
+        let mut body = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut body, ImageFormat::Jpeg)
+            .unwrap();
+
+        let encoded: String = body
+            .into_inner()
+            .iter()
+            .map(|byte| format!("%{:02X}", byte))
+            .collect();
+
+        format!("data:image/jpeg,{}", encoded)
+    }
+
+    #[tokio::test]
+    async fn connect_upload_and_dispatch() {
+        let device = MockTransport::new();
+        let kind = Kind::Akp05E;
+
+        initialize_device("a5-test", &device, 50).await.unwrap();
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(50),
+                MockWrite::ClearAll,
+                MockWrite::Flush
+            ]
+        );
+
+        handle_set_image(
+            "a5-test",
+            &device,
+            Some(0),
+            Some(KeyImage::DataUrl(jpeg_data_url())),
+            90,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Image {
+                    key: kind.map_button_index(0) as u8,
+                    size: (8, 8)
+                },
+                MockWrite::Flush
+            ]
+        );
+
+        handle_set_image("a5-test", &device, Some(7), None, 90)
+            .await
+            .unwrap();
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Clear(kind.map_button_index(7) as u8),
+                MockWrite::Flush
+            ]
+        );
+
+        device.inject(MockTransport::report(0x03, 0x01));
+        device.inject(MockTransport::report(0x03, 0x00));
+        device.inject(MockTransport::report(0x51, 0x00));
+        device.inject(MockTransport::report(0x35, 0x01));
+
+        let mut state = InputState::new(&kind);
+        let mut updates = vec![];
+        for _ in 0..4 {
+            updates.extend(
+                read_updates("a5-test", &device, &mut state, None, None)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        assert!(matches!(
+            updates[..],
+            [
+                DeviceStateUpdate::ButtonDown(2),
+                DeviceStateUpdate::ButtonUp(2),
+                DeviceStateUpdate::EncoderTwist(1, 1),
+                DeviceStateUpdate::EncoderDown(1),
+            ]
+        ));
+
+        // Nothing left to read
+        assert!(
+            read_updates("a5-test", &device, &mut state, None, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_set_image_requests_are_not_fatal() {
+        let device = MockTransport::new();
+
+        let err = handle_set_image("a5-test", &device, Some(200), None, 90)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Akp05Error::InvalidPosition { position: 200, .. }
+        ));
+        assert!(!err.is_fatal());
+
+        let err = handle_set_image(
+            "a5-test",
+            &device,
+            Some(0),
+            Some(KeyImage::DataUrl("data:image/png,".into())),
+            90,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Akp05Error::InvalidImage { .. }));
+        assert!(!err.is_fatal());
+
+        assert!(device.take_writes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn image_batches_are_flushed_once() {
+        let device = MockTransport::new();
+        let kind = Kind::Akp05E;
+
+        handle_set_images(
+            "a5-test",
+            &device,
+            vec![(5, Some(KeyImage::DataUrl(jpeg_data_url()))), (6, None)],
+            90,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Image {
+                    key: kind.map_button_index(5) as u8,
+                    size: (8, 8)
+                },
+                MockWrite::Clear(kind.map_button_index(6) as u8),
+                MockWrite::Flush
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_without_ack_are_ignored() {
+        let device = MockTransport::new();
+        let kind = Kind::Akp05E;
+
+        let mut report = MockTransport::report(0x01, 0x01);
+        report[0] = 0;
+        device.inject(report);
+
+        let mut state = InputState::new(&kind);
+        assert!(
+            read_updates("a5-test", &device, &mut state, None, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -1,143 +1,503 @@
-use data_url::DataUrl;
-use image::load_from_memory_with_format;
-use mirajazz::{device::Device, error::MirajazzError, state::DeviceStateUpdate};
-use openaction::{OUTBOUND_EVENT_MANAGER, SetImageEvent};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+    time::Instant,
+};
+
+use akp05::{
+    capabilities::Capabilities,
+    capture::CaptureRecorder,
+    deck::{
+        connect, decode_input, handle_set_images, initialize_device, is_dead_key, read_input,
+        set_dead_keys, set_key_transforms,
+    },
+    discovery::check_access,
+    error::{Akp05Error, ErrorContext, Operation},
+    inputs::{InputState, ProtocolErrors, UnknownInputs},
+    mappings::CandidateDevice,
+    transport::{DeviceTransport, HidTransport},
+};
+use mirajazz::state::DeviceStateUpdate;
+use openaction::OUTBOUND_EVENT_MANAGER;
+use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    DEVICES, TOKENS,
-    mappings::{CandidateDevice, Kind},
+    CONFIG, DEVICES, TOKENS, WRITERS, bundle,
+    claim::claim,
+    diagnostics, dnd, hooks, icons, inject,
+    latency::LatencyMeter,
+    lock,
+    macros::{MacroAction, MacroKeys, MacroQueue, macro_task},
+    media, menu, midi, mixer, mqtt, obs, pages, ratelimit, safemode,
+    session::{self, Event},
+    setup, sliders,
+    splash::{self, Stage, splash_images},
+    status::{self, Status},
+    volume,
+    watcher::{self, RECONNECT_DELAY},
+    writer::{
+        WriterCommand, effective_brightness, has_framebuffer, take_framebuffer, writer_channel,
+        writer_task,
+    },
 };
 
+/// Times a device that stopped answering is looked for again, and attached again in a row
+/// without it starting
+pub const REATTACH_ATTEMPTS: u32 = 3;
+
+// Devices that failed but are still connected, their task attaches them again
+static REATTACHING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+// Keys and encoders OpenDeck was told are held, by device id, so they outlive reconnects
+static HELD: LazyLock<Mutex<HashMap<String, BTreeSet<Held>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Key or encoder held down as far as OpenDeck knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Held {
+    Key(u8),
+    Encoder(u8),
+}
+
+/// Keeps track of what OpenDeck was told is held after sending it an update
+fn track_held(held: &mut BTreeSet<Held>, update: DeviceStateUpdate) {
+    match update {
+        DeviceStateUpdate::ButtonDown(key) => held.insert(Held::Key(key)),
+        DeviceStateUpdate::ButtonUp(key) => held.remove(&Held::Key(key)),
+        DeviceStateUpdate::EncoderDown(encoder) => held.insert(Held::Encoder(encoder)),
+        DeviceStateUpdate::EncoderUp(encoder) => held.remove(&Held::Encoder(encoder)),
+        DeviceStateUpdate::EncoderTwist(..) => false,
+    };
+}
+
+/// Releases that bring OpenDeck back to the all-released state a device connects with
+fn releases(held: &BTreeSet<Held>) -> Vec<DeviceStateUpdate> {
+    held.iter()
+        .map(|held| match *held {
+            Held::Key(key) => DeviceStateUpdate::ButtonUp(key),
+            Held::Encoder(encoder) => DeviceStateUpdate::EncoderUp(encoder),
+        })
+        .collect()
+}
+
+/// Releases everything OpenDeck still thinks is held on a device that just connected
+///
+/// Devices start with nothing pressed, the first real report is compared against that. Keys held
+/// while the device went away would otherwise stay down in OpenDeck.
+async fn release_held(id: &str) {
+    let held = HELD.lock().unwrap().remove(id).unwrap_or_default();
+
+    for update in releases(&held) {
+        log::info!("Releasing {:?} held before {} reconnected", update, id);
+
+        if let Err(err) = send_update(id.to_string(), update).await {
+            handle_error(err).await;
+        }
+    }
+}
+
 /// Initializes a device and listens for events
-pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
+///
+/// Devices whose firmware rebooted by itself are attached again, see [handle_error].
+pub async fn device_task(mut candidate: CandidateDevice, token: CancellationToken) {
     log::info!("Running device task for {:?}", candidate);
 
-    // Wrap in a closure so we can use `?` operator
-    let device = async || -> Result<Device, MirajazzError> {
-        let device = connect(&candidate).await?;
+    // Devices can be taken by another plugin already
+    let _claim = match claim(&candidate.claim_name()) {
+        Ok(claim) => claim,
+        Err(err) => {
+            log::warn!("Not opening {}: {}", candidate.id, err);
+            status::report(&candidate.id, Status::Removed, Some(&err));
+            return;
+        }
+    };
 
-        device.set_brightness(50).await?;
-        device.clear_all_button_images().await?;
-        device.flush().await?;
+    if let Err(err) = check_access(&candidate) {
+        log::error!("Not opening {}: {}", candidate.id, err);
+        status::report(&candidate.id, Status::Removed, Some(&err));
+        return;
+    }
+
+    session::begin(&candidate.id);
+
+    // Attempts in a row that didn't get the device running
+    let mut failed = 0;
+
+    loop {
+        failed = if attach(&candidate, &token).await {
+            0
+        } else {
+            failed + 1
+        };
+
+        // Unplugged or stopped devices were dropped already
+        let reattach = REATTACHING.lock().unwrap().remove(&candidate.id);
+
+        if !reattach || token.is_cancelled() {
+            break;
+        }
+
+        let found = if failed < REATTACH_ATTEMPTS {
+            find_again(&candidate.id, &token).await
+        } else {
+            None
+        };
+
+        match found {
+            Some(found) => candidate = found,
+            None => {
+                log::warn!("{} didn't come back, giving up on it", candidate.id);
+                forget_device(&candidate.id).await;
+                break;
+            }
+        }
+    }
+
+    session::advance(&candidate.id, Event::Closed, None);
+
+    log::info!("Device task finished for {:?}", candidate);
+}
+
+/// Waits for a device that stopped answering to show up again after its firmware rebooted
+async fn find_again(id: &str, token: &CancellationToken) -> Option<CandidateDevice> {
+    for _ in 0..REATTACH_ATTEMPTS {
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = token.cancelled() => return None,
+        }
+
+        if let Some(candidate) = watcher::find_candidate(id).await {
+            log::info!("Attaching {} again", id);
+            return Some(candidate);
+        }
+    }
 
-        Ok(device)
-    }()
+    None
+}
+
+/// Opens the device and runs it until it fails or is stopped, returns false if it never started
+///
+/// Whatever it showed is kept in its framebuffer, so attaching it again draws it right away.
+async fn attach(candidate: &CandidateDevice, token: &CancellationToken) -> bool {
+    // Reconnected devices show what they did before instead
+    let splash = CONFIG.borrow().splash && !has_framebuffer(&candidate.id);
+
+    // Before the first image, the splash included
+    let transforms = CONFIG.borrow().key_transforms(&candidate.id);
+    set_key_transforms(&candidate.id, transforms);
+    let dead_keys = CONFIG.borrow().dead_keys(&candidate.id);
+    set_dead_keys(&candidate.id, dead_keys.clone());
+
+    // Wrap in an async block so we can use `?` operator
+    let device = async {
+        let mut device = connect(candidate)
+            .await?
+            .with_tiles(icons::tiles())
+            .with_pacing(CONFIG.borrow().upload_pacing());
+
+        let vendor_interface = CONFIG.borrow().vendor_interface;
+        if vendor_interface {
+            device = device.with_vendor_interface(candidate).await;
+        }
+
+        let brightness = effective_brightness(CONFIG.borrow().brightness, dnd::dim_level());
+        initialize_device(&candidate.id, &device, brightness).await?;
+
+        let capabilities = if CONFIG.borrow().probe_features {
+            Capabilities::probe(candidate.kind, &device).await
+        } else {
+            Capabilities::of_device(candidate.kind, &device)
+        };
+        log::info!(
+            "Capabilities of {}: {}",
+            candidate.id,
+            capabilities.to_json()
+        );
+        bundle::record_device(candidate, &capabilities);
+
+        // Writer isn't there yet, the splash goes straight to the device
+        if splash {
+            let images = splash_images(Stage::Connecting, true);
+            let quality = CONFIG.borrow().jpeg_quality;
+
+            if let Err(err) = handle_set_images(&candidate.id, &device, images, quality).await {
+                log::warn!("Failed to draw splash: {}", err);
+            }
+        }
+
+        Ok::<HidTransport, Akp05Error>(device)
+    }
     .await;
 
-    let device: Device = match device {
+    let device: HidTransport = match device {
         Ok(device) => device,
         Err(err) => {
-            handle_error(&candidate.id, err).await;
+            handle_error(err).await;
 
             log::error!(
                 "Had error during device init, finishing device task: {:?}",
                 candidate
             );
 
-            return;
+            return false;
         }
     };
 
+    session::advance(&candidate.id, Event::Probed, None);
+
+    // Plugin pages stack more rows of keys below the real ones
+    let pages = pages::register(&candidate.id);
+
     log::info!("Registering device {}", candidate.id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut()
+        && let Err(err) = outbound
             .register_device(
                 candidate.id.clone(),
                 candidate.kind.human_name(),
-                candidate.kind.row_count() as u8,
+                registered_rows(
+                    candidate.kind.row_count(),
+                    candidate.kind.col_count(),
+                    &dead_keys,
+                    pages,
+                ),
                 candidate.kind.col_count() as u8,
                 candidate.kind.encoder_count() as u8,
                 0,
             )
             .await
-            .unwrap();
+    {
+        handle_error(Akp05Error::opendeck(
+            &candidate.id,
+            Operation::Register,
+            err,
+        ))
+        .await;
     }
 
-    DEVICES.write().await.insert(candidate.id.clone(), device);
+    let device = Arc::new(device);
+    let (writer, queue) = writer_channel();
+    let framebuffer = take_framebuffer(&candidate.id);
+    let (macros, macro_queue) = mpsc::unbounded_channel();
+
+    // Only the strip changes, the logo stays until the profile replaces it
+    if splash {
+        writer.send(WriterCommand::SetImages(splash_images(
+            Stage::LoadingProfile,
+            false,
+        )));
+        splash::shown(&candidate.id);
+    }
+
+    DEVICES
+        .write()
+        .await
+        .insert(candidate.id.clone(), device.clone());
+    WRITERS.write().await.insert(candidate.id.clone(), writer);
+    safemode::forget(&candidate.id);
+
+    session::advance(&candidate.id, Event::Started, None);
+    setup::start(candidate).await;
 
     tokio::select! {
-        _ = device_events_task(&candidate) => {},
+        _ = device_events_task(candidate, device.as_ref(), macros) => {},
+        _ = writer_task(&candidate.id, device.as_ref(), queue, framebuffer) => {},
+        _ = macro_task(&candidate.id, macro_queue) => {},
         _ = token.cancelled() => {}
     };
 
     log::info!("Shutting down device {:?}", candidate);
 
-    if let Some(device) = DEVICES.read().await.get(&candidate.id) {
-        device.shutdown().await.ok();
+    WRITERS.write().await.remove(&candidate.id);
+    pages::unregister(&candidate.id);
+    inject::forget(&candidate.id);
+
+    if let Err(err) = device
+        .shutdown()
+        .await
+        .context(&candidate.id, Operation::Shutdown)
+    {
+        log::warn!("{}", err);
     }
 
-    log::info!("Device task finished for {:?}", candidate);
+    true
+}
+
+/// Rows to register with OpenDeck, trailing rows with only dead keys are left out
+///
+/// Pages stack rows below each other, so with more than one every row stays.
+fn registered_rows(rows: usize, cols: usize, dead_keys: &HashSet<u8>, pages: u8) -> u8 {
+    if pages > 1 {
+        return rows as u8 * pages;
+    }
+
+    let live = (0..rows)
+        .rev()
+        .find(|row| (row * cols..(row + 1) * cols).any(|key| !dead_keys.contains(&(key as u8))))
+        .map_or(0, |row| row + 1);
+
+    if live < rows {
+        log::info!("Registering {} of {} rows, the rest are dead", live, rows);
+    }
+
+    live as u8
 }
 
 /// Handles errors, returning true if should continue, returning false if an error is fatal
-pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
-    log::error!("Device {} error: {}", id, err);
+///
+/// Protocol anomalies are only logged in lenient mode, strict mode reports each one to OpenDeck.
+pub async fn handle_error(err: Akp05Error) -> bool {
+    let id = &err.device_id().to_string();
+
+    if err.is_anomaly() && CONFIG.borrow().protocol_errors == ProtocolErrors::Lenient {
+        log::warn!("{}", err);
+        return true;
+    }
+
+    log::error!("{}", err);
 
     // Some errors are not critical and can be ignored without sending disconnected event
-    if matches!(err, MirajazzError::ImageError(_) | MirajazzError::BadData) {
+    if !err.is_fatal() {
+        if err.is_anomaly() {
+            status::report_error(id, &err.to_string());
+        }
+
+        session::advance(id, Event::Failed, Some(&err.to_string()));
         return true;
     }
 
-    log::info!("Deregistering device {}", id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound.deregister_device(id.clone()).await.unwrap();
+    // Firmware rebooting by itself leaves the old handle failing, but the device is still there
+    if matches!(err, Akp05Error::Device { .. }) && watcher::find_candidate(id).await.is_some() {
+        log::warn!("{} is still connected, attaching it again", id);
+        session::advance(id, Event::Resetting, Some(&err.to_string()));
+        REATTACHING.lock().unwrap().insert(id.clone());
+
+        return false;
     }
 
+    session::advance(id, Event::Closed, Some(&err.to_string()));
+
     log::info!("Cancelling tasks for device {}", id);
     if let Some(token) = TOKENS.read().await.get(id) {
         token.cancel();
     }
 
+    forget_device(id).await;
+
+    false
+}
+
+/// Tells OpenDeck the device is gone and drops it from the lists
+async fn forget_device(id: &str) {
+    log::info!("Deregistering device {}", id);
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut()
+        && let Err(err) = outbound.deregister_device(id.to_string()).await
+    {
+        log::error!("Failed to deregister device {}: {}", id, err);
+    }
+
     log::info!("Removing device {} from the list", id);
     DEVICES.write().await.remove(id);
+    WRITERS.write().await.remove(id);
 
     log::info!("Finished clean-up for {}", id);
-
-    false
 }
 
-pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
-    let result = Device::connect(
-        &candidate.dev,
-        candidate.kind.protocol_version(),
-        candidate.kind.key_count(),
-        candidate.kind.encoder_count(),
-    )
-    .await;
+/// Re-initializes the device, images are drawn again by the writer
+///
+/// Useful to recover displays stuck with half-drawn images without replugging the device
+pub async fn reset_device(
+    id: &str,
+    device: &impl DeviceTransport,
+    brightness: u8,
+) -> Result<(), Akp05Error> {
+    log::info!("Resetting device {}", id);
+    session::advance(id, Event::Resetting, None);
+
+    device.reset().await.context(id, Operation::Reset)?;
+    // Reset sets brightness to 100, so restore the one user has chosen
+    device
+        .set_brightness(brightness)
+        .await
+        .context(id, Operation::Reset)?;
+    device.flush().await.context(id, Operation::Reset)?;
+
+    session::advance(id, Event::Started, None);
 
-    match result {
-        Ok(device) => Ok(device),
-        Err(e) => {
-            log::error!("Error while connecting to device: {e}");
+    Ok(())
+}
 
-            Err(e)
-        }
+/// Asks OpenDeck to send every image of the device again
+pub async fn request_redraw(id: &str) -> Result<(), Akp05Error> {
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        outbound
+            .rerender_images(id.to_string())
+            .await
+            .map_err(|err| Akp05Error::opendeck(id, Operation::Redraw, err))?;
     }
+
+    Ok(())
 }
 
 /// Handles events from device to OpenDeck
-async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzError> {
+async fn device_events_task(
+    candidate: &CandidateDevice,
+    device: &impl DeviceTransport,
+    macros: MacroQueue,
+) {
     log::info!("Connecting to {} for incoming events", candidate.id);
 
-    let devices_lock = DEVICES.read().await;
-    let reader = match devices_lock.get(&candidate.id) {
-        Some(device) => device.get_reader(crate::inputs::process_input),
-        None => return Ok(()),
-    };
-    drop(devices_lock);
+    let mut state = InputState::new(&candidate.kind);
+    let mut config = CONFIG.subscribe();
+    let mut pacer = config.borrow_and_update().read_pacer();
+    state.configure(config.borrow().input_options());
+    let mut recorder = CaptureRecorder::from_env(&candidate.id)
+        .unwrap_or_default()
+        .with_history(bundle::history(&candidate.id));
+    let mut latency = LatencyMeter::from_env(&candidate.id);
+    let mut macro_keys = MacroKeys::default();
+    let mut injected = inject::register(&candidate.id);
 
-    log::info!("Connected to {} for incoming events", candidate.id);
+    release_held(&candidate.id).await;
 
     log::info!("Reader is ready for {}", candidate.id);
 
     loop {
+        // Pick up config changes without reconnecting, known key states are kept
+        if config.has_changed().unwrap_or(false) {
+            let config = config.borrow_and_update();
+
+            log::info!("Applying new input settings to {}", candidate.id);
+            state.configure(config.input_options());
+            pacer = config.read_pacer();
+        }
+
         log::info!("Reading updates...");
 
-        let updates = match reader.read(None).await {
+        // Injected reports aren't recorded, captures only hold what the device sent
+        let read = tokio::select! {
+            read = read_input(
+                &candidate.id,
+                device,
+                &mut state,
+                pacer.timeout(),
+                Some(&mut recorder),
+            ) => read,
+            Some(report) = injected.recv() => {
+                log::info!("Injected input for {}", candidate.id);
+                decode_input(&candidate.id, &mut state, &report)
+            }
+            // Reads can wait for input forever, changes shouldn't wait for the next press
+            Ok(()) = config.changed() => {
+                config.mark_changed();
+                continue;
+            }
+        };
+
+        let (report, updates) = match read {
             Ok(updates) => updates,
             Err(e) => {
-                if !handle_error(&candidate.id, e).await {
+                if !handle_error(e).await {
                     break;
                 }
 
@@ -145,82 +505,236 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
             }
         };
 
+        session::succeeded(&candidate.id);
+
+        let arrived = Instant::now();
+        let code = report.map(|report| (report.code, report.state));
+
+        // Reports that didn't change anything still mean someone's using the device
+        pacer.record(code.is_some() || !updates.is_empty());
+
+        diagnostics::record(&candidate.id, code, &updates);
+        sliders::touch(&candidate.id, report);
+
+        // Every touch goes to scripts, not only those of zones acting as sliders
+        if let Some(report) = report
+            && let Some((zone, spot)) = sliders::touch_value(report.code)
+        {
+            hooks::publish_touch(&candidate.id, zone, spot, report.contact);
+        }
+
+        setup::touch(&candidate.id, report).await;
+
+        if let Some(event) = state.decoder().system_event(code) {
+            log::info!("{} reported {}", candidate.id, event.name());
+
+            hooks::publish_system(&candidate.id, event);
+            mqtt::publish_system(&candidate.id, event);
+        }
+
+        if let Some((code, byte)) = state.decoder().unknown_code(code) {
+            let policy = CONFIG.borrow().unknown_inputs;
+
+            match policy {
+                UnknownInputs::Ignore => {}
+                UnknownInputs::Log => {
+                    log::warn!(
+                        "Unknown input code 0x{:02X} (state {}) from {}",
+                        code,
+                        byte,
+                        candidate.id
+                    );
+                }
+                UnknownInputs::Forward => {
+                    hooks::publish_raw(&candidate.id, code, byte);
+                    mqtt::publish_raw(&candidate.id, code, byte);
+                }
+            }
+        }
+
         for update in updates {
             log::info!("New update: {:#?}", update);
 
-            let id = candidate.id.clone();
+            // Decks mounted the other way round send updates as if they were the right way up
+            let update = setup::orientation(&candidate.id).update(update);
 
-            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                match update {
-                    DeviceStateUpdate::ButtonDown(key) => outbound.key_down(id, key).await.unwrap(),
-                    DeviceStateUpdate::ButtonUp(key) => outbound.key_up(id, key).await.unwrap(),
-                    DeviceStateUpdate::EncoderDown(encoder) => {
-                        outbound.encoder_down(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderUp(encoder) => {
-                        outbound.encoder_up(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderTwist(encoder, val) => {
-                        outbound
-                            .encoder_change(id, encoder, val as i16)
-                            .await
-                            .unwrap();
+            if let DeviceStateUpdate::ButtonDown(key) | DeviceStateUpdate::ButtonUp(key) = update
+                && is_dead_key(&candidate.id, key)
+            {
+                log::debug!("Key {} is dead, ignoring update", key);
+                continue;
+            }
+
+            // Releases always get through, a key pressed before the deck was locked doesn't stay
+            // pressed
+            if let DeviceStateUpdate::ButtonUp(key) = update {
+                press_feedback(&candidate.id, key, false).await;
+            }
+
+            if !setup::filter(&candidate.id, &update).await {
+                log::debug!("Update is for the setup wizard, not sending it");
+                continue;
+            }
+
+            // Locks and do-not-disturb get inputs before OpenDeck, to swallow them or unlock
+            if !lock::filter(&candidate.id, &update).await {
+                log::debug!("Device is locked, not sending update");
+                continue;
+            }
+
+            if !menu::filter(&candidate.id, &update).await {
+                log::debug!("Update is for the on-device menu, not sending it");
+                continue;
+            }
+
+            if !dnd::filter(&candidate.id, &update).await {
+                log::debug!("Do-not-disturb is on, not sending update");
+                continue;
+            }
+
+            // Presses that were swallowed above didn't do anything, so they don't look pressed
+            if let DeviceStateUpdate::ButtonDown(key) = update {
+                press_feedback(&candidate.id, key, true).await;
+            }
+
+            if !media::filter(&update) {
+                log::debug!("Update is for the media dial, not sending it");
+                continue;
+            }
+
+            if !mixer::filter(&update) {
+                log::debug!("Update is for a mixer dial, not sending it");
+                continue;
+            }
+
+            if !volume::filter(&candidate.id, &update) {
+                log::debug!("Update is for the volume dial, not sending it");
+                continue;
+            }
+
+            if !obs::filter(&candidate.id, &update) {
+                log::debug!("Update is for an OBS scene key, not sending it");
+                continue;
+            }
+
+            if !pages::filter(&candidate.id, &update).await {
+                log::debug!("Update is for a page key or the page dial, not sending it");
+                continue;
+            }
+
+            match macro_keys.filter(&update, &CONFIG.borrow().macros) {
+                MacroAction::Dispatch => {}
+                MacroAction::Swallow => continue,
+                MacroAction::Play(steps) => {
+                    // Played by its own task, so input is read while the macro waits
+                    let _ = macros.send(steps);
+                    continue;
+                }
+            }
+
+            match dispatch_update(candidate.id.clone(), update).await {
+                Ok(()) => {
+                    if let Some(latency) = latency.as_mut() {
+                        latency.record(arrived);
                     }
                 }
+                Err(err) => {
+                    handle_error(err).await;
+                }
             }
         }
     }
+}
 
-    Ok(())
+/// Shows a key pressed or released, images sent for it right after are feedback and skip the queue
+async fn press_feedback(id: &str, key: u8, pressed: bool) {
+    if let Some(writer) = WRITERS.read().await.get(id) {
+        writer.key_changed(key);
+        writer.send(WriterCommand::Pressed {
+            position: key,
+            pressed,
+        });
+    }
 }
 
-/// Handles different combinations of "set image" event, including clearing the specific buttons and whole device
-pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(), MirajazzError> {
-    match (evt.position, evt.image) {
-        (Some(position), Some(image)) => {
-            log::info!("Setting image for button {}", position);
+/// Forwards state update to OpenDeck
+///
+/// Input events are never dropped, every update is awaited until it's sent. Twists over
+/// `event_rate_limit` are the exception, they're merged and sent later by the rate limiter.
+pub async fn dispatch_update(id: String, update: DeviceStateUpdate) -> Result<(), Akp05Error> {
+    let update = pages::translate(&id, update);
 
-            // Map software position to physical device position (device is upside down)
-            let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
-            let physical_position = kind.map_button_index(position as usize) as u8;
-            
-            log::info!("Mapping software position {} to physical position {}", position, physical_position);
+    for update in ratelimit::admit(&id, update) {
+        send_update(id.clone(), update).await?;
+    }
 
-            // OpenDeck sends image as a data url, so parse it using a library
-            let url = DataUrl::process(image.as_str()).unwrap(); // Isn't expected to fail, so unwrap it is
-            let (body, _fragment) = url.decode_to_vec().unwrap(); // Same here
+    Ok(())
+}
 
-            // Allow only image/jpeg mime for now
-            if url.mime_type().subtype != "jpeg" {
-                log::error!("Incorrect mime type: {}", url.mime_type());
+/// Sends an update that already went through page translation everywhere it goes
+pub async fn send_update(id: String, update: DeviceStateUpdate) -> Result<(), Akp05Error> {
+    mqtt::publish_update(&id, update);
+    midi::send_update(&id, update);
+    hooks::publish_update(&id, update);
 
-                return Ok(()); // Not a fatal error, enough to just log it
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        let result = match update {
+            DeviceStateUpdate::ButtonDown(key) => outbound.key_down(id.clone(), key).await,
+            DeviceStateUpdate::ButtonUp(key) => outbound.key_up(id.clone(), key).await,
+            DeviceStateUpdate::EncoderDown(encoder) => {
+                outbound.encoder_down(id.clone(), encoder).await
+            }
+            DeviceStateUpdate::EncoderUp(encoder) => outbound.encoder_up(id.clone(), encoder).await,
+            DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                outbound
+                    .encoder_change(id.clone(), encoder, val as i16)
+                    .await
             }
+        };
 
-            let image = load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)?;
+        result.map_err(|err| Akp05Error::opendeck(&id, Operation::DispatchInput, err))?;
+    }
 
-            device
-                .set_button_image(
-                    physical_position,
-                    kind.image_format(),
-                    image,
-                )
-                .await?;
-            device.flush().await?;
-        }
-        (Some(position), None) => {
-            // Map position for clearing as well
-            let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
-            let physical_position = kind.map_button_index(position as usize) as u8;
-            device.clear_button_image(physical_position).await?;
-            device.flush().await?;
-        }
-        (None, None) => {
-            device.clear_all_button_images().await?;
-            device.flush().await?;
+    track_held(HELD.lock().unwrap().entry(id).or_default(), update);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_controls_are_released() {
+        let mut held = BTreeSet::new();
+
+        for update in [
+            DeviceStateUpdate::ButtonDown(7),
+            DeviceStateUpdate::EncoderDown(2),
+            DeviceStateUpdate::ButtonDown(5),
+            DeviceStateUpdate::EncoderTwist(2, 1),
+            DeviceStateUpdate::ButtonUp(7),
+        ] {
+            track_held(&mut held, update);
         }
-        _ => {}
+
+        assert!(matches!(
+            releases(&held)[..],
+            [
+                DeviceStateUpdate::ButtonUp(5),
+                DeviceStateUpdate::EncoderUp(2)
+            ]
+        ));
     }
 
-    Ok(())
+    #[test]
+    fn rows_of_dead_keys_are_left_out() {
+        let bottom: HashSet<u8> = (5..10).collect();
+
+        assert_eq!(registered_rows(2, 5, &HashSet::from([3, 7]), 1), 2);
+        assert_eq!(registered_rows(2, 5, &bottom, 1), 1);
+        // A dead top row keeps its place, the bottom one would move up otherwise
+        assert_eq!(registered_rows(2, 5, &(0..5).collect(), 1), 2);
+        assert_eq!(registered_rows(2, 5, &bottom, 3), 6);
+    }
 }
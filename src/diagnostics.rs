@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use akp05::text::{TextStyle, render_lines};
+use mirajazz::state::DeviceStateUpdate;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, writer::KeyPainter};
+
+/// Size codes are rendered at, images are resized to the device format anyway
+pub const DIAGNOSTICS_SIZE: (u32, u32) = (120, 120);
+
+// Device id and position to lines shown there
+type Codes = HashMap<(String, u8), Vec<String>>;
+
+// Every position that got input while diagnostics are on
+static CODES: LazyLock<Mutex<Codes>> = LazyLock::new(|| Mutex::new(Codes::new()));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Position the code of an update is shown on, the key itself or the strip zone of the encoder
+fn update_position(update: &DeviceStateUpdate) -> u8 {
+    match *update {
+        DeviceStateUpdate::ButtonDown(key) | DeviceStateUpdate::ButtonUp(key) => key,
+        DeviceStateUpdate::EncoderDown(encoder)
+        | DeviceStateUpdate::EncoderUp(encoder)
+        | DeviceStateUpdate::EncoderTwist(encoder, _) => encoder,
+    }
+}
+
+/// Text shown for an input code and the state byte that came with it
+fn code_lines(code: u8, state: u8) -> Vec<String> {
+    vec![format!("{:02X}", code), format!("STATE {:02X}", state)]
+}
+
+/// Shows the raw code of a report on every control it changed, while diagnostics are on
+pub fn record(device: &str, code: Option<(u8, u8)>, updates: &[DeviceStateUpdate]) {
+    let Some((code, state)) = code else {
+        return;
+    };
+
+    if !CONFIG.borrow().diagnostics {
+        return;
+    }
+
+    // Codes of unknown revisions may not map to anything, the log still tells what they are
+    if updates.is_empty() {
+        log::info!(
+            "Input code {:02X} with state {:02X} didn't change any control",
+            code,
+            state
+        );
+        return;
+    }
+
+    let mut codes = CODES.lock().unwrap();
+
+    for update in updates {
+        log::info!(
+            "Input code {:02X} with state {:02X} is {:?}",
+            code,
+            state,
+            update
+        );
+
+        codes.insert(
+            (device.to_string(), update_position(update)),
+            code_lines(code, state),
+        );
+    }
+
+    CHANGED.notify_one();
+}
+
+/// Checks if an input code is shown on the position, so OpenDeck images for it are ignored
+pub fn draws_position(device: &str, position: u8) -> bool {
+    CODES
+        .lock()
+        .unwrap()
+        .contains_key(&(device.to_string(), position))
+}
+
+/// Draws the last input code on every control that got one, while `diagnostics` is on
+pub async fn diagnostics_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        tokio::select! {
+            _ = CHANGED.notified() => {}
+            _ = config.changed() => {}
+            _ = token.cancelled() => break,
+        }
+
+        // Turning diagnostics off redraws OpenDeck images, see Config::affects_images
+        if !config.borrow_and_update().diagnostics {
+            CODES.lock().unwrap().clear();
+        }
+
+        let frames: Vec<((String, u8), Vec<String>)> = CODES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, lines)| (key.clone(), lines.clone()))
+            .collect();
+
+        painter.retain(|device, position| {
+            frames
+                .iter()
+                .any(|((id, other), _)| id == device && *other == position)
+        });
+
+        for ((device, position), lines) in frames {
+            let image_lines = lines.clone();
+
+            painter
+                .paint_device(&device, position, lines, || {
+                    let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+
+                    render_lines(DIAGNOSTICS_SIZE, &lines, &[2, 1], TextStyle::default())
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_shown_on_their_controls() {
+        assert_eq!(update_position(&DeviceStateUpdate::ButtonDown(7)), 7);
+        assert_eq!(update_position(&DeviceStateUpdate::EncoderTwist(2, -1)), 2);
+        assert_eq!(code_lines(0x37, 0x01), ["37", "STATE 01"]);
+    }
+}
@@ -0,0 +1,252 @@
+use std::path::Path;
+
+use mirajazz::{device::list_devices, error::MirajazzError, types::HidDeviceInfo};
+
+use crate::mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES, VENDOR_QUERIES};
+
+/// Builds device ID out of its serial number, the same one is used when it's disconnected
+pub fn serial_to_id(serial: &str) -> String {
+    format!("{}-{}", DEVICE_NAMESPACE, serial)
+}
+
+/// Builds device ID out of its serial number and USB port, for identical decks sharing a serial
+pub fn port_to_id(serial: &str, port: &str) -> String {
+    format!("{}-{}@{}", DEVICE_NAMESPACE, serial, port)
+}
+
+/// USB port out of a resolved sysfs path of a HID device, e.g. `1-2.3`
+///
+/// The path goes through the hub ports down to the interface (`1-2.3:1.0`), the last one of them
+/// without the interface is the port. It stays the same across boots as long as the cabling does.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn usb_port(sysfs: &Path) -> Option<String> {
+    sysfs
+        .components()
+        .filter_map(|component| component.as_os_str().to_str())
+        .filter_map(|name| name.split_once(':'))
+        .find(|(port, interface)| {
+            port.contains('-')
+                && port
+                    .chars()
+                    .all(|ch| ch.is_ascii_digit() || ch == '-' || ch == '.')
+                && interface.chars().all(|ch| ch.is_ascii_digit() || ch == '.')
+        })
+        .map(|(port, _)| port.to_string())
+}
+
+/// USB port the device is plugged into, only known on Linux
+pub fn device_port(dev: &HidDeviceInfo) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    if let async_hid::DeviceId::DevPath(node) = &dev.id {
+        let device = Path::new("/sys/class/hidraw")
+            .join(node.file_name()?)
+            .join("device");
+
+        return usb_port(&std::fs::canonicalize(device).ok()?);
+    }
+
+    let _ = dev;
+    None
+}
+
+/// Turns HID device into a candidate, [None] if it has no serial or isn't a known device
+pub fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
+    let serial = dev.serial_number.clone()?;
+    let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
+
+    Some(CandidateDevice {
+        id: serial_to_id(&serial),
+        port: device_port(&dev),
+        serial,
+        dev,
+        kind,
+    })
+}
+
+impl CandidateDevice {
+    /// Switches to an ID that includes the USB port, so identical decks sharing a serial number
+    /// keep their IDs whatever order they are found in. Nothing changes if the port isn't known.
+    pub fn with_port_id(mut self) -> Self {
+        if let Some(port) = &self.port {
+            self.id = port_to_id(&self.serial, port);
+        }
+
+        self
+    }
+
+    /// Name the device is claimed under, the serial number unless the ID includes the port
+    pub fn claim_name(&self) -> String {
+        match &self.port {
+            Some(port) if self.id == port_to_id(&self.serial, port) => {
+                format!("{}@{}", self.serial, port)
+            }
+            _ => self.serial.clone(),
+        }
+    }
+}
+
+/// OS specific path of the device, like `/dev/hidraw3` on Linux
+pub fn device_path(dev: &HidDeviceInfo) -> String {
+    match &dev.id {
+        #[cfg(target_os = "linux")]
+        async_hid::DeviceId::DevPath(path) => path.display().to_string(),
+        #[cfg(target_os = "windows")]
+        async_hid::DeviceId::UncPath(path) => path.to_string(),
+        #[cfg(target_os = "macos")]
+        async_hid::DeviceId::RegistryEntryId(id) => id.to_string(),
+        id => format!("{:?}", id),
+    }
+}
+
+/// Allow and deny lists of devices, entries are serial numbers, IDs or device paths
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceFilter {
+    /// Only these devices are used, unless it's empty
+    pub allow: Vec<String>,
+
+    /// These devices are never used, even if they are allowed
+    pub deny: Vec<String>,
+}
+
+impl DeviceFilter {
+    /// Checks if any of the names a device goes by is allowed
+    fn allows_names(&self, names: &[&str]) -> bool {
+        let listed = |list: &[String]| list.iter().any(|entry| names.contains(&entry.as_str()));
+
+        (self.allow.is_empty() || listed(&self.allow)) && !listed(&self.deny)
+    }
+
+    /// Checks if the device may be used, by its serial number, ID and device path
+    pub fn allows(&self, candidate: &CandidateDevice) -> bool {
+        let serial = candidate.dev.serial_number.clone().unwrap_or_default();
+
+        self.allows_names(&[&serial, &candidate.id, &device_path(&candidate.dev)])
+    }
+}
+
+/// Udev rules giving users access to devices, shipped next to the plugin
+pub const UDEV_RULES_FILE: &str = "40-opendeck-akp05.rules";
+
+/// Guidance for a device node the user isn't allowed to open
+pub fn permission_denied_message(node: &str) -> String {
+    format!(
+        "No permission to open {}. Copy {} into /etc/udev/rules.d/, run \
+         `sudo udevadm control --reload-rules` and plug the device in again",
+        node, UDEV_RULES_FILE
+    )
+}
+
+/// Checks that the device node can be opened for reading and writing
+///
+/// Without udev rules hidraw nodes belong to root, and opening them fails with an error that
+/// doesn't say what to do about it. Other systems don't have device nodes to check.
+#[cfg(target_os = "linux")]
+pub fn check_access(candidate: &CandidateDevice) -> Result<(), String> {
+    let async_hid::DeviceId::DevPath(node) = &candidate.dev.id else {
+        return Ok(());
+    };
+
+    match std::fs::File::options().read(true).write(true).open(node) {
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(permission_denied_message(&node.display().to_string()))
+        }
+        // Anything else shows up again when the device is opened for real
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_access(_candidate: &CandidateDevice) -> Result<(), String> {
+    Ok(())
+}
+
+/// Whether `dev` is another interface of the candidate's device, and not of an identical deck
+fn same_device(candidate: &CandidateDevice, dev: &HidDeviceInfo) -> bool {
+    dev.vendor_id == candidate.dev.vendor_id
+        && dev.product_id == candidate.dev.product_id
+        && dev.serial_number.as_ref() == Some(&candidate.serial)
+        && (candidate.port.is_none() || device_port(dev) == candidate.port)
+}
+
+/// Vendor command interface of the candidate, [None] if its model or firmware has none
+pub async fn find_vendor_interface(candidate: &CandidateDevice) -> Option<HidDeviceInfo> {
+    let devices = match list_devices(&VENDOR_QUERIES).await {
+        Ok(devices) => devices,
+        Err(err) => {
+            log::debug!("Failed to look for vendor interfaces: {}", err);
+            return None;
+        }
+    };
+
+    devices
+        .into_iter()
+        .find(|dev| same_device(candidate, dev))
+        .map(|dev| dev.to_device_info())
+}
+
+/// Returns devices that matches known pid/vid pairs
+pub async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
+    log::info!("Looking for candidate devices");
+    log::info!("Using {} device queries", QUERIES.len());
+
+    let mut candidates: Vec<CandidateDevice> = Vec::new();
+
+    let devices = list_devices(&QUERIES).await?;
+    log::info!("Found {} raw devices from queries", devices.len());
+
+    for dev in devices {
+        log::info!(
+            "Processing device: VID={:04x}, PID={:04x}, Serial={:?}",
+            dev.vendor_id,
+            dev.product_id,
+            dev.serial_number
+        );
+
+        if let Some(candidate) = device_info_to_candidate(dev.clone()) {
+            log::info!("Successfully created candidate for device");
+            candidates.push(candidate);
+        } else {
+            log::warn!(
+                "Failed to create candidate for device VID={:04x}, PID={:04x}",
+                dev.vendor_id,
+                dev.product_id
+            );
+            continue;
+        }
+    }
+
+    log::info!("Final candidates count: {}", candidates.len());
+    Ok(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_port_is_read_from_sysfs_path() {
+        let path = Path::new(
+            "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.3/1-2.3:1.0/0003:0300:3004.0005",
+        );
+        assert_eq!(usb_port(path), Some("1-2.3".to_string()));
+
+        let path = Path::new("/sys/devices/virtual/misc/uhid/0003:0300:3004.0001");
+        assert_eq!(usb_port(path), None);
+    }
+
+    #[test]
+    fn devices_are_filtered_by_any_name() {
+        let names = ["ABC123", "a5-ABC123", "/dev/hidraw3"];
+        let filter = |allow: &[&str], deny: &[&str]| DeviceFilter {
+            allow: allow.iter().map(|entry| entry.to_string()).collect(),
+            deny: deny.iter().map(|entry| entry.to_string()).collect(),
+        };
+
+        assert!(DeviceFilter::default().allows_names(&names));
+        assert!(filter(&["/dev/hidraw3"], &[]).allows_names(&names));
+        assert!(!filter(&["XYZ789"], &[]).allows_names(&names));
+        assert!(!filter(&[], &["a5-ABC123"]).allows_names(&names));
+        // Denying wins
+        assert!(!filter(&["ABC123"], &["/dev/hidraw3"]).allows_names(&names));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{LazyLock, Mutex},
+};
+
+use akp05::inputs::{Control, Verdict};
+use mirajazz::state::DeviceStateUpdate;
+
+use crate::{CONFIG, WRITERS, writer::WriterCommand};
+
+static DND: LazyLock<Mutex<DndState>> = LazyLock::new(|| Mutex::new(DndState::default()));
+
+/// Do-not-disturb state shared by every device
+///
+/// While enabled, every input is swallowed except presses of a key holding the do-not-disturb
+/// action, which turn it off again.
+#[derive(Debug, Default)]
+pub struct DndState {
+    enabled: bool,
+    // Action context to device and key the action sits on
+    unlock_keys: HashMap<String, (String, u8)>,
+    // Controls pressed while enabled, their releases are swallowed as well
+    suppressed: HashSet<(String, Control)>,
+}
+
+impl DndState {
+    fn is_unlock_key(&self, device: &str, key: u8) -> bool {
+        self.unlock_keys
+            .values()
+            .any(|(id, position)| id == device && *position == key)
+    }
+
+    /// Decides what to do with an update coming from the device
+    ///
+    /// Releases are only swallowed if the press was, so OpenDeck never sees a key stuck down.
+    pub fn filter(&mut self, device: &str, update: &DeviceStateUpdate) -> Verdict {
+        let Some((control, pressed)) = Control::of(update) else {
+            return if self.enabled {
+                Verdict::Suppress
+            } else {
+                Verdict::Dispatch
+            };
+        };
+
+        let entry = (device.to_string(), control);
+
+        if !pressed {
+            return if self.suppressed.remove(&entry) {
+                Verdict::Suppress
+            } else {
+                Verdict::Dispatch
+            };
+        }
+
+        if !self.enabled {
+            return Verdict::Dispatch;
+        }
+
+        self.suppressed.insert(entry);
+
+        match control {
+            Control::Key(key) if self.is_unlock_key(device, key) => {
+                self.enabled = false;
+                Verdict::Unlock
+            }
+            _ => Verdict::Suppress,
+        }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    DND.lock().unwrap().enabled
+}
+
+/// Brightness cap while do-not-disturb is on, [None] if it's off
+pub fn dim_level() -> Option<u8> {
+    is_enabled().then(|| CONFIG.borrow().dnd_brightness)
+}
+
+/// Remembers a key holding the do-not-disturb action, pressing it turns the mode off
+pub fn add_unlock_key(context: &str, device: String, key: u8) {
+    DND.lock()
+        .unwrap()
+        .unlock_keys
+        .insert(context.to_string(), (device, key));
+}
+
+pub fn remove_unlock_key(context: &str) {
+    DND.lock().unwrap().unlock_keys.remove(context);
+}
+
+/// Turns do-not-disturb on or off for every device
+pub async fn set_enabled(enabled: bool) {
+    DND.lock().unwrap().enabled = enabled;
+
+    apply().await;
+}
+
+/// Dims or restores every device according to current state
+async fn apply() {
+    let level = dim_level();
+
+    log::info!(
+        "Do-not-disturb is {}",
+        if level.is_some() { "on" } else { "off" }
+    );
+
+    for writer in WRITERS.read().await.values() {
+        writer.send(WriterCommand::Dim(level));
+    }
+}
+
+/// Checks if an update should reach OpenDeck, turning do-not-disturb off on the unlock key
+pub async fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let verdict = DND.lock().unwrap().filter(device, update);
+
+    match verdict {
+        Verdict::Dispatch => true,
+        Verdict::Suppress => false,
+        Verdict::Unlock => {
+            apply().await;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn inputs_are_swallowed_until_unlock_key_is_pressed() {
+        let mut state = DndState::default();
+        state
+            .unlock_keys
+            .insert("ctx".to_string(), ("a5-1".to_string(), 7));
+
+        // Key held when the mode is turned on still gets released in OpenDeck
+        assert_eq!(state.filter("a5-1", &ButtonDown(3)), Verdict::Dispatch);
+        state.enabled = true;
+        assert_eq!(state.filter("a5-1", &ButtonUp(3)), Verdict::Dispatch);
+
+        assert_eq!(state.filter("a5-1", &ButtonDown(2)), Verdict::Suppress);
+        assert_eq!(state.filter("a5-1", &EncoderTwist(0, 1)), Verdict::Suppress);
+        assert_eq!(state.filter("a5-1", &EncoderDown(0)), Verdict::Suppress);
+
+        // Same key on another device doesn't unlock
+        assert_eq!(state.filter("a5-2", &ButtonDown(7)), Verdict::Suppress);
+        assert_eq!(state.filter("a5-1", &ButtonDown(7)), Verdict::Unlock);
+        assert!(!state.enabled);
+
+        // Releases of swallowed presses stay swallowed
+        assert_eq!(state.filter("a5-1", &ButtonUp(2)), Verdict::Suppress);
+        assert_eq!(state.filter("a5-1", &ButtonUp(7)), Verdict::Suppress);
+        assert_eq!(state.filter("a5-1", &EncoderUp(0)), Verdict::Suppress);
+        assert_eq!(state.filter("a5-1", &EncoderTwist(0, 1)), Verdict::Dispatch);
+        assert_eq!(state.filter("a5-1", &ButtonDown(2)), Verdict::Dispatch);
+    }
+}
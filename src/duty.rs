@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::CONFIG;
+
+/// How long animations stay slowed down after uploads last went over the limit
+pub const DUTY_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// Animations take this many times longer between frames while uploads are over the limit
+pub const ANIMATION_SLOWDOWN: u32 = 3;
+
+/// How long after its last upload a device still gets a share of the limit
+pub const ACTIVE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Upload budget of a single device, refilled at its share of the limit and holding a second of it
+///
+/// Uploads may take more than what's left, the writer then waits until it's paid back.
+#[derive(Debug)]
+struct Budget {
+    // Bytes that can still be sent right away, negative while uploads are paid back
+    bytes: f64,
+    updated: Instant,
+    // When the limit was last hit
+    exceeded: Option<Instant>,
+}
+
+impl Budget {
+    /// Starts full, the first upload of a device doesn't wait
+    fn new(share: f64, now: Instant) -> Self {
+        Self {
+            bytes: share,
+            updated: now,
+            exceeded: None,
+        }
+    }
+
+    /// Takes `uploaded` bytes out, returns how long to wait before the next upload
+    fn spend(&mut self, uploaded: u64, share: f64, now: Instant) -> Duration {
+        let refill = now.duration_since(self.updated).as_secs_f64() * share;
+        self.bytes = (self.bytes + refill).min(share) - uploaded as f64;
+        self.updated = now;
+
+        if self.bytes >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        self.exceeded = Some(now);
+
+        Duration::from_secs_f64(-self.bytes / share)
+    }
+
+    fn is_hot(&self, now: Instant) -> bool {
+        self.exceeded
+            .is_some_and(|at| now.duration_since(at) < DUTY_COOLDOWN)
+    }
+}
+
+/// Budgets of every device, the limit is split evenly between devices that are uploading
+///
+/// Shares add up to the limit, so all devices together stay under it, while a device going over
+/// its share only waits for itself.
+#[derive(Debug, Default)]
+struct Budgets {
+    devices: HashMap<String, Budget>,
+}
+
+impl Budgets {
+    fn spend(&mut self, id: &str, uploaded: u64, limit: u64, now: Instant) -> Duration {
+        if limit == 0 {
+            return Duration::ZERO;
+        }
+
+        let others = self
+            .devices
+            .iter()
+            .filter(|(other, budget)| {
+                *other != id && now.duration_since(budget.updated) < ACTIVE_WINDOW
+            })
+            .count();
+        let share = limit as f64 / (others + 1) as f64;
+
+        self.devices
+            .entry(id.to_string())
+            .or_insert_with(|| Budget::new(share, now))
+            .spend(uploaded, share, now)
+    }
+
+    /// Whether the device, or any device for [None], went over its share lately
+    fn is_hot(&self, id: Option<&str>, now: Instant) -> bool {
+        match id {
+            Some(id) => self
+                .devices
+                .get(id)
+                .is_some_and(|budget| budget.is_hot(now)),
+            None => self.devices.values().any(|budget| budget.is_hot(now)),
+        }
+    }
+}
+
+static BUDGETS: LazyLock<Mutex<Budgets>> = LazyLock::new(|| Mutex::new(Budgets::default()));
+
+/// Counts bytes a writer uploaded to a device, waiting if it went over its share of
+/// `upload_limit_kb`
+///
+/// Keeps displays from running flat out for long, e.g. with continuous animations. Devices get
+/// their own budgets, so one busy with animations doesn't hold back writes to the others.
+pub async fn uploaded(id: &str, bytes: u64) {
+    let limit = CONFIG.borrow().upload_limit_kb * 1024;
+
+    let (wait, was_hot) = {
+        let mut budgets = BUDGETS.lock().unwrap();
+        let now = Instant::now();
+        let was_hot = budgets.is_hot(Some(id), now);
+
+        (budgets.spend(id, bytes, limit, now), was_hot)
+    };
+
+    if wait.is_zero() {
+        return;
+    }
+
+    if !was_hot {
+        log::info!(
+            "Uploads to {} went over their share of {} KB/s, slowing animations down",
+            id,
+            limit / 1024
+        );
+    }
+
+    tokio::time::sleep(wait).await;
+}
+
+/// Time between animation frames on a device, or on every device for [None], longer while
+/// uploads are over the limit
+pub fn frame_interval(device: Option<&str>, interval: Duration) -> Duration {
+    match BUDGETS.lock().unwrap().is_hot(device, Instant::now()) {
+        true => interval * ANIMATION_SLOWDOWN,
+        false => interval,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_over_the_limit_wait_and_slow_animations() {
+        let now = Instant::now();
+        let mut budgets = Budgets::default();
+        let second = Duration::from_secs(1);
+
+        // No limit, no waiting
+        assert_eq!(budgets.spend("a5-1", 1_000_000, 0, now), Duration::ZERO);
+
+        // Starts with a second of budget, and doesn't build up more
+        assert_eq!(budgets.spend("a5-1", 1000, 1000, now), Duration::ZERO);
+        let later = now + second * 5;
+        assert_eq!(budgets.spend("a5-1", 1000, 1000, later), Duration::ZERO);
+        assert!(!budgets.is_hot(Some("a5-1"), later));
+
+        // Half a second over
+        assert_eq!(
+            budgets.spend("a5-1", 500, 1000, later),
+            Duration::from_millis(500)
+        );
+        assert!(budgets.is_hot(Some("a5-1"), later));
+        assert!(budgets.is_hot(None, later));
+
+        let later = later + second;
+        assert_eq!(budgets.spend("a5-1", 0, 1000, later), Duration::ZERO);
+        assert!(budgets.is_hot(Some("a5-1"), later));
+        assert!(!budgets.is_hot(Some("a5-1"), later + DUTY_COOLDOWN));
+    }
+
+    #[test]
+    fn devices_dont_pay_for_each_other() {
+        let now = Instant::now();
+        let mut budgets = Budgets::default();
+
+        // A goes far over the limit
+        assert_eq!(
+            budgets.spend("a5-a", 3000, 1000, now),
+            Duration::from_secs(2)
+        );
+
+        // B gets half of it, A is uploading too
+        assert_eq!(budgets.spend("a5-b", 500, 1000, now), Duration::ZERO);
+        assert!(!budgets.is_hot(Some("a5-b"), now));
+        assert_eq!(
+            budgets.spend("a5-b", 500, 1000, now),
+            Duration::from_secs(1)
+        );
+
+        // Once A is idle, B has the whole limit
+        let later = now + ACTIVE_WINDOW + Duration::from_secs(1);
+        assert_eq!(budgets.spend("a5-b", 1000, 1000, later), Duration::ZERO);
+    }
+}
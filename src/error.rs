@@ -0,0 +1,175 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+};
+
+use mirajazz::error::MirajazzError;
+
+/// What the plugin was doing when an error happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Connect,
+    Initialize,
+    Register,
+    ReadInput,
+    DispatchInput,
+    SetImage,
+    ClearImage,
+    SetBrightness,
+    Reset,
+    Redraw,
+    Shutdown,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Connect => "connecting",
+            Self::Initialize => "initializing",
+            Self::Register => "registering in OpenDeck",
+            Self::ReadInput => "reading input",
+            Self::DispatchInput => "sending input to OpenDeck",
+            Self::SetImage => "setting image",
+            Self::ClearImage => "clearing image",
+            Self::SetBrightness => "setting brightness",
+            Self::Reset => "resetting",
+            Self::Redraw => "requesting redraw",
+            Self::Shutdown => "shutting down",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Errors that can happen while handling a device
+#[derive(Debug)]
+pub enum Akp05Error {
+    /// Device or HID layer reported an error
+    Device {
+        id: String,
+        operation: Operation,
+        source: MirajazzError,
+    },
+
+    /// Message couldn't be sent to OpenDeck
+    OpenDeck {
+        id: String,
+        operation: Operation,
+        message: String,
+    },
+
+    /// OpenDeck sent an image we can't use
+    InvalidImage { id: String, reason: String },
+
+    /// OpenDeck referenced a button that doesn't exist on the device
+    InvalidPosition { id: String, position: u8 },
+
+    /// VID/PID pair of a connected device doesn't match any known kind
+    UnknownDevice { id: String, vid: u16, pid: u16 },
+}
+
+impl Akp05Error {
+    /// Wraps error returned by OpenDeck connection
+    pub fn opendeck(id: &str, operation: Operation, err: impl Display) -> Self {
+        Self::OpenDeck {
+            id: id.to_string(),
+            operation,
+            message: err.to_string(),
+        }
+    }
+
+    /// Id of the device the error belongs to
+    pub fn device_id(&self) -> &str {
+        match self {
+            Self::Device { id, .. }
+            | Self::OpenDeck { id, .. }
+            | Self::InvalidImage { id, .. }
+            | Self::InvalidPosition { id, .. }
+            | Self::UnknownDevice { id, .. } => id,
+        }
+    }
+
+    /// Anomalies are non-fatal errors caused by data not following the protocol, like garbage in
+    /// reports or images that can't be used
+    pub fn is_anomaly(&self) -> bool {
+        match self {
+            Self::Device { source, .. } => matches!(
+                source,
+                MirajazzError::ImageError(_) | MirajazzError::BadData
+            ),
+            Self::InvalidImage { .. } | Self::InvalidPosition { .. } => true,
+            Self::OpenDeck { .. } | Self::UnknownDevice { .. } => false,
+        }
+    }
+
+    /// Fatal errors mean the device can't be used anymore and has to be dropped
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            // Bad images or garbage in reports are not a reason to disconnect
+            Self::Device { source, .. } => !matches!(
+                source,
+                MirajazzError::ImageError(_) | MirajazzError::BadData
+            ),
+            Self::OpenDeck { .. } | Self::InvalidImage { .. } | Self::InvalidPosition { .. } => {
+                false
+            }
+            Self::UnknownDevice { .. } => true,
+        }
+    }
+}
+
+impl Display for Akp05Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Device {
+                id,
+                operation,
+                source,
+            } => write!(f, "Device {} failed while {}: {}", id, operation, source),
+            Self::OpenDeck {
+                id,
+                operation,
+                message,
+            } => write!(
+                f,
+                "OpenDeck error for {} while {}: {}",
+                id, operation, message
+            ),
+            Self::InvalidImage { id, reason } => write!(f, "Invalid image for {}: {}", id, reason),
+            Self::InvalidPosition { id, position } => {
+                write!(f, "Device {} has no button at position {}", id, position)
+            }
+            Self::UnknownDevice { id, vid, pid } => {
+                write!(
+                    f,
+                    "Device {} has unknown VID/PID {:04x}:{:04x}",
+                    id, vid, pid
+                )
+            }
+        }
+    }
+}
+
+impl Error for Akp05Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Device { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Attaches device id and operation to lower level errors
+pub trait ErrorContext<T> {
+    fn context(self, id: &str, operation: Operation) -> Result<T, Akp05Error>;
+}
+
+impl<T> ErrorContext<T> for Result<T, MirajazzError> {
+    fn context(self, id: &str, operation: Operation) -> Result<T, Akp05Error> {
+        self.map_err(|source| Akp05Error::Device {
+            id: id.to_string(),
+            operation,
+            source,
+        })
+    }
+}
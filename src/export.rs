@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use serde_json::{Map, Value, json};
+
+use crate::config::{self, Config, KEYS};
+
+/// Marks a file as exported plugin settings, checked on import
+pub const EXPORT_FORMAT: &str = "opendeck-akp05-settings";
+
+/// Layout of exported files, newer ones can't be imported by older plugins
+pub const EXPORT_VERSION: u64 = 1;
+
+// Secrets stay on the machine they were set on, exports get shared
+const PRIVATE_KEYS: [&str; 2] = ["mqtt_password", "obs_password"];
+
+/// Settings set in the config file and in OpenDeck, the latter winning like they do on load
+///
+/// Defaults and environment variables aren't exported, they belong to the machine.
+fn exported_settings(file: Option<&Value>, opendeck: Option<&Value>) -> Map<String, Value> {
+    let layers = [opendeck, file];
+
+    KEYS.iter()
+        .filter(|key| !PRIVATE_KEYS.contains(key))
+        .filter_map(|key| {
+            let value = layers
+                .iter()
+                .find_map(|layer| layer.and_then(|layer| layer.get(*key)))?;
+
+            Some((key.to_string(), value.clone()))
+        })
+        .collect()
+}
+
+/// Whole exported file, settings along with what wrote them
+fn export_document(file: Option<&Value>, opendeck: Option<&Value>) -> Value {
+    json!({
+        "format": EXPORT_FORMAT,
+        "version": EXPORT_VERSION,
+        "plugin": env!("CARGO_PKG_VERSION"),
+        "settings": exported_settings(file, opendeck),
+    })
+}
+
+/// Settings out of an exported file, every one is checked like the config file is
+///
+/// Nothing is imported if any of them is invalid, half a setup is worse than the old one.
+fn imported_settings(document: &Value) -> Result<Map<String, Value>, String> {
+    if document["format"] != EXPORT_FORMAT {
+        return Err("not an exported settings file".to_string());
+    }
+
+    let version = document["version"]
+        .as_u64()
+        .ok_or("\"version\" is missing")?;
+
+    if version > EXPORT_VERSION {
+        return Err(format!(
+            "exported by a newer plugin (version {}), update this one first",
+            version
+        ));
+    }
+
+    let settings = document["settings"]
+        .as_object()
+        .ok_or("\"settings\" must be an object")?;
+
+    let mut errors = vec![];
+    Config::default().apply_json("import", &document["settings"], &mut errors);
+
+    if !errors.is_empty() {
+        return Err(errors.join(", "));
+    }
+
+    Ok(settings.clone())
+}
+
+/// Reads the config file as JSON, [None] if there's none yet
+async fn read_config_file(path: &Path) -> Result<Option<Value>, String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(text) => serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("{}: {}", path.display(), err)),
+    }
+}
+
+/// Writes every setting of the plugin to a single JSON file, for another machine or deck owner
+pub async fn export_settings(path: &Path) -> Result<(), String> {
+    let file = match config::config_path() {
+        Some(config) => read_config_file(&config).await?,
+        None => None,
+    };
+    let opendeck = config::opendeck_settings();
+
+    let document = export_document(file.as_ref(), opendeck.as_ref());
+    let text = serde_json::to_string_pretty(&document).map_err(|err| err.to_string())?;
+
+    tokio::fs::write(path, text)
+        .await
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    log::info!(
+        "Exported {} settings to {}",
+        document["settings"].as_object().map_or(0, Map::len),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Merges settings of an exported file into the config file and applies them
+///
+/// Settings the file doesn't have, e.g. passwords, are kept. OpenDeck settings and environment
+/// variables still override the config file, like they always do.
+pub async fn import_settings(path: &Path) -> Result<(), String> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    let document: Value =
+        serde_json::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let settings = imported_settings(&document)?;
+
+    if let Some(Value::Object(opendeck)) = config::opendeck_settings() {
+        for key in settings.keys().filter(|key| opendeck.contains_key(*key)) {
+            log::warn!("Imported {} is overridden by OpenDeck settings", key);
+        }
+    }
+
+    let count = settings.len();
+    let config = config::edit_file(|existing| existing.extend(settings)).await?;
+
+    log::info!(
+        "Imported {} settings from {} into {}",
+        count,
+        path.display(),
+        config.display()
+    );
+
+    config::reload(None).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exported_settings_can_be_imported() {
+        let file = json!({ "brightness": 30, "debounce_ms": 15, "obs_password": "secret" });
+        let opendeck = json!({ "brightness": 60, "encoder_press": "keys" });
+
+        let document = export_document(Some(&file), Some(&opendeck));
+        let settings = imported_settings(&document).unwrap();
+
+        // OpenDeck wins, passwords stay behind
+        assert_eq!(settings["brightness"], json!(60));
+        assert_eq!(settings["debounce_ms"], json!(15));
+        assert_eq!(settings["encoder_press"], json!("keys"));
+        assert!(!settings.contains_key("obs_password"));
+
+        let mut newer = document.clone();
+        newer["version"] = json!(EXPORT_VERSION + 1);
+        assert!(imported_settings(&newer).is_err());
+
+        let mut invalid = document.clone();
+        invalid["settings"]["brightness"] = json!(500);
+        assert!(imported_settings(&invalid).is_err());
+
+        assert!(imported_settings(&json!({ "brightness": 30 })).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! C ABI on top of [crate::deck], for applications that aren't written in Rust
+//!
+//! Every call blocks until the device is done. Functions returning `int` return 0 on success and
+//! -1 on failure, [akp05_last_error] tells what went wrong. See `include/akp05.h`.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ffi::{CString, c_char, c_int},
+    ptr,
+    sync::Arc,
+    time::Duration,
+};
+
+use image::{DynamicImage, RgbImage};
+use mirajazz::state::DeviceStateUpdate;
+use tokio::runtime::Runtime;
+
+use crate::{
+    deck::{connect, handle_set_image, initialize_device, read_updates},
+    discovery::get_candidates,
+    images::{DEFAULT_JPEG_QUALITY, KeyImage},
+    inputs::InputState,
+    mappings::CandidateDevice,
+    transport::{DeviceTransport, HidTransport},
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(error: impl ToString) {
+    let error = CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+}
+
+/// Connected device, opaque to C
+pub struct Akp05Device {
+    runtime: Runtime,
+    candidate: CandidateDevice,
+    device: HidTransport,
+    state: InputState,
+    // A single report can produce more than one event, they are handed out one at a time
+    pending: VecDeque<Akp05Event>,
+}
+
+/// What happened to a control
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Akp05EventKind {
+    KeyDown = 0,
+    KeyUp = 1,
+    EncoderDown = 2,
+    EncoderUp = 3,
+    EncoderTwist = 4,
+}
+
+/// Input event, `index` is the key or encoder, `ticks` is only set for twists
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Akp05Event {
+    pub kind: Akp05EventKind,
+    pub index: u8,
+    pub ticks: i8,
+}
+
+impl From<DeviceStateUpdate> for Akp05Event {
+    fn from(update: DeviceStateUpdate) -> Self {
+        let (kind, index, ticks) = match update {
+            DeviceStateUpdate::ButtonDown(key) => (Akp05EventKind::KeyDown, key, 0),
+            DeviceStateUpdate::ButtonUp(key) => (Akp05EventKind::KeyUp, key, 0),
+            DeviceStateUpdate::EncoderDown(encoder) => (Akp05EventKind::EncoderDown, encoder, 0),
+            DeviceStateUpdate::EncoderUp(encoder) => (Akp05EventKind::EncoderUp, encoder, 0),
+            DeviceStateUpdate::EncoderTwist(encoder, ticks) => {
+                (Akp05EventKind::EncoderTwist, encoder, ticks)
+            }
+        };
+
+        Self { kind, index, ticks }
+    }
+}
+
+fn open(brightness: u8) -> Result<Akp05Device, String> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(|err| format!("Failed to start runtime: {}", err))?;
+
+    let (candidate, device) = runtime.block_on(async {
+        let candidate = get_candidates()
+            .await
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .next()
+            .ok_or("No device found")?;
+
+        let device = connect(&candidate).await.map_err(|err| err.to_string())?;
+        initialize_device(&candidate.id, &device, brightness)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        Ok::<_, String>((candidate, device))
+    })?;
+
+    let state = InputState::new(&candidate.kind);
+
+    Ok(Akp05Device {
+        runtime,
+        candidate,
+        device,
+        state,
+        pending: VecDeque::new(),
+    })
+}
+
+fn result(result: Result<(), String>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_error(err);
+            -1
+        }
+    }
+}
+
+/// Connects to the first device found and blanks it, returns NULL on failure
+///
+/// `brightness` is 0 - 100.
+#[unsafe(no_mangle)]
+pub extern "C" fn akp05_open(brightness: u8) -> *mut Akp05Device {
+    match open(brightness.min(100)) {
+        Ok(device) => Box::into_raw(Box::new(device)),
+        Err(err) => {
+            set_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Blanks the device and disconnects from it
+///
+/// # Safety
+///
+/// `device` must come from [akp05_open] and not be used afterwards. NULL is ignored.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_close(device: *mut Akp05Device) {
+    if device.is_null() {
+        return;
+    }
+
+    let device = unsafe { Box::from_raw(device) };
+
+    if let Err(err) = device.runtime.block_on(device.device.shutdown()) {
+        log::warn!("Failed to shut down {}: {}", device.candidate.id, err);
+    }
+}
+
+/// Device ID, e.g. `a5-<serial>`, free it with [akp05_free_string]
+///
+/// # Safety
+///
+/// `device` must come from [akp05_open].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_device_id(device: *const Akp05Device) -> *mut c_char {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        set_error("Device is NULL");
+        return ptr::null_mut();
+    };
+
+    CString::new(device.candidate.id.clone()).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Frees a string returned by the library
+///
+/// # Safety
+///
+/// `string` must come from this library and not be used afterwards. NULL is ignored.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Sets brightness, 0 - 100
+///
+/// # Safety
+///
+/// `device` must come from [akp05_open].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_set_brightness(device: *mut Akp05Device, brightness: u8) -> c_int {
+    let Some(device) = (unsafe { device.as_mut() }) else {
+        return result(Err("Device is NULL".to_string()));
+    };
+
+    result(
+        device
+            .runtime
+            .block_on(device.device.set_brightness(brightness.min(100)))
+            .map_err(|err| err.to_string()),
+    )
+}
+
+/// Draws an image on a position, numbered like in OpenDeck: strip zones 0 - 4, then keys 5 - 14
+///
+/// `rgb` holds `width * height` pixels, 3 bytes each, row by row. NULL `rgb` clears the position.
+///
+/// # Safety
+///
+/// `device` must come from [akp05_open], `rgb` must be NULL or point to `width * height * 3` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_set_image(
+    device: *mut Akp05Device,
+    position: u8,
+    rgb: *const u8,
+    width: u32,
+    height: u32,
+) -> c_int {
+    let Some(device) = (unsafe { device.as_mut() }) else {
+        return result(Err("Device is NULL".to_string()));
+    };
+
+    let image = if rgb.is_null() {
+        None
+    } else {
+        let length = width as usize * height as usize * 3;
+        let pixels = unsafe { std::slice::from_raw_parts(rgb, length) }.to_vec();
+
+        match RgbImage::from_raw(width, height, pixels) {
+            Some(image) => Some(KeyImage::Rendered(Arc::new(DynamicImage::ImageRgb8(image)))),
+            None => return result(Err("Image is empty".to_string())),
+        }
+    };
+
+    let id = &device.candidate.id;
+
+    result(
+        device
+            .runtime
+            .block_on(handle_set_image(
+                id,
+                &device.device,
+                Some(position),
+                image,
+                DEFAULT_JPEG_QUALITY,
+            ))
+            .map_err(|err| err.to_string()),
+    )
+}
+
+/// Blanks every display
+///
+/// # Safety
+///
+/// `device` must come from [akp05_open].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_clear_all(device: *mut Akp05Device) -> c_int {
+    let Some(device) = (unsafe { device.as_mut() }) else {
+        return result(Err("Device is NULL".to_string()));
+    };
+
+    let id = &device.candidate.id;
+
+    result(
+        device
+            .runtime
+            .block_on(handle_set_image(
+                id,
+                &device.device,
+                None,
+                None,
+                DEFAULT_JPEG_QUALITY,
+            ))
+            .map_err(|err| err.to_string()),
+    )
+}
+
+/// Waits up to `timeout_ms` for an input event, negative timeout waits forever
+///
+/// Returns 1 if `event` was filled in, 0 on timeout and -1 on failure.
+///
+/// # Safety
+///
+/// `device` must come from [akp05_open], `event` must point to writable [Akp05Event].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn akp05_poll_input(
+    device: *mut Akp05Device,
+    timeout_ms: c_int,
+    event: *mut Akp05Event,
+) -> c_int {
+    let (Some(device), Some(event)) = (unsafe { device.as_mut() }, unsafe { event.as_mut() })
+    else {
+        return result(Err("Device or event is NULL".to_string()));
+    };
+
+    if device.pending.is_empty() {
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+
+        let updates = device.runtime.block_on(read_updates(
+            &device.candidate.id,
+            &device.device,
+            &mut device.state,
+            timeout,
+            None,
+        ));
+
+        match updates {
+            Ok(updates) => device
+                .pending
+                .extend(updates.into_iter().map(Akp05Event::from)),
+            Err(err) => return result(Err(err.to_string())),
+        }
+    }
+
+    match device.pending.pop_front() {
+        Some(next) => {
+            *event = next;
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Message of the last failure on this thread, valid until the next failing call
+#[unsafe(no_mangle)]
+pub extern "C" fn akp05_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    #[test]
+    fn updates_become_events() {
+        assert_eq!(
+            Akp05Event::from(DeviceStateUpdate::EncoderTwist(2, -3)),
+            Akp05Event {
+                kind: Akp05EventKind::EncoderTwist,
+                index: 2,
+                ticks: -3
+            }
+        );
+        assert_eq!(
+            Akp05Event::from(DeviceStateUpdate::ButtonUp(7)),
+            Akp05Event {
+                kind: Akp05EventKind::KeyUp,
+                index: 7,
+                ticks: 0
+            }
+        );
+    }
+
+    #[test]
+    fn null_device_is_an_error() {
+        let code = unsafe { akp05_set_brightness(ptr::null_mut(), 50) };
+        let error = unsafe { CStr::from_ptr(akp05_last_error()) };
+
+        assert_eq!(code, -1);
+        assert_eq!(error.to_str().unwrap(), "Device is NULL");
+    }
+}
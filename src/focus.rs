@@ -0,0 +1,409 @@
+use std::{collections::BTreeMap, process::Command, time::Duration};
+
+use akp05::mappings::PLUGIN_UUID;
+use openaction::OUTBOUND_EVENT_MANAGER;
+use serde_json::{Value, json};
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, DEVICES, WRITERS, config::Config};
+
+/// How often focused application is checked
+pub const FOCUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Profiles for every device, device id (or `*` for any device) to application name to profile
+pub type AppProfiles = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Source of the currently focused application name
+pub trait FocusBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns name of the focused application, [None] if it can't be determined
+    fn focused_app(&self) -> Option<String>;
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Runs a user provided shell command which prints the application name
+pub struct CommandBackend {
+    pub command: String,
+}
+
+impl FocusBackend for CommandBackend {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn focused_app(&self) -> Option<String> {
+        let output = if cfg!(target_os = "windows") {
+            run("cmd", &["/C", &self.command])
+        } else {
+            run("sh", &["-c", &self.command])
+        }?;
+
+        (!output.is_empty()).then_some(output)
+    }
+}
+
+/// Reads WM_CLASS of the active window using `xprop`
+pub struct X11Backend;
+
+/// Extracts window id from `_NET_ACTIVE_WINDOW(WINDOW): window id # 0x4a00003`
+fn parse_active_window(output: &str) -> Option<&str> {
+    let id = output.rsplit('#').next()?.trim();
+
+    (id.starts_with("0x") && id != "0x0").then_some(id)
+}
+
+/// Extracts class from `WM_CLASS(STRING) = "navigator", "firefox"`, class is the last value
+fn parse_wm_class(output: &str) -> Option<String> {
+    let values = output.split_once('=')?.1;
+    let class = values.rsplit(',').next()?.trim().trim_matches('"');
+
+    (!class.is_empty()).then(|| class.to_string())
+}
+
+impl FocusBackend for X11Backend {
+    fn name(&self) -> &'static str {
+        "x11"
+    }
+
+    fn focused_app(&self) -> Option<String> {
+        let active = run("xprop", &["-root", "_NET_ACTIVE_WINDOW"])?;
+        let id = parse_active_window(&active)?;
+
+        parse_wm_class(&run("xprop", &["-id", id, "WM_CLASS"])?)
+    }
+}
+
+/// Asks Hyprland for the class of the active window
+pub struct HyprlandBackend;
+
+/// Extracts class from `hyprctl activewindow -j`, which prints `{}` without an active window
+fn parse_hyprland_window(output: &str) -> Option<String> {
+    let window: Value = serde_json::from_str(output).ok()?;
+    let class = window.get("class")?.as_str()?;
+
+    (!class.is_empty()).then(|| class.to_string())
+}
+
+impl FocusBackend for HyprlandBackend {
+    fn name(&self) -> &'static str {
+        "hyprland"
+    }
+
+    fn focused_app(&self) -> Option<String> {
+        parse_hyprland_window(&run("hyprctl", &["activewindow", "-j"])?)
+    }
+}
+
+/// Finds the focused window in the tree of Sway
+pub struct SwayBackend;
+
+/// App id of the focused node of `swaymsg -t get_tree`, or the class for XWayland windows
+fn parse_sway_tree(output: &str) -> Option<String> {
+    fn focused(node: &Value) -> Option<&Value> {
+        if node.get("focused").and_then(Value::as_bool) == Some(true) {
+            return Some(node);
+        }
+
+        ["nodes", "floating_nodes"]
+            .iter()
+            .filter_map(|key| node.get(key)?.as_array())
+            .flatten()
+            .find_map(focused)
+    }
+
+    let tree: Value = serde_json::from_str(output).ok()?;
+    let node = focused(&tree)?;
+
+    node.get("app_id")
+        .and_then(Value::as_str)
+        .or_else(|| node.pointer("/window_properties/class")?.as_str())
+        .filter(|app| !app.is_empty())
+        .map(str::to_string)
+}
+
+impl FocusBackend for SwayBackend {
+    fn name(&self) -> &'static str {
+        "sway"
+    }
+
+    fn focused_app(&self) -> Option<String> {
+        parse_sway_tree(&run("swaymsg", &["-t", "get_tree"])?)
+    }
+}
+
+/// Looks up the executable of the process owning the foreground window
+pub struct WindowsBackend;
+
+impl FocusBackend for WindowsBackend {
+    fn name(&self) -> &'static str {
+        "windows"
+    }
+
+    #[cfg(windows)]
+    fn focused_app(&self) -> Option<String> {
+        use windows::{
+            Win32::{
+                Foundation::CloseHandle,
+                System::Threading::{
+                    OpenProcess, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+                    QueryFullProcessImageNameW,
+                },
+                UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId},
+            },
+            core::PWSTR,
+        };
+
+        let mut pid = 0;
+        unsafe { GetWindowThreadProcessId(GetForegroundWindow(), Some(&mut pid)) };
+
+        if pid == 0 {
+            return None;
+        }
+
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) }.ok()?;
+        let mut path = [0u16; 1024];
+        let mut len = path.len() as u32;
+        let queried = unsafe {
+            QueryFullProcessImageNameW(
+                process,
+                PROCESS_NAME_WIN32,
+                PWSTR(path.as_mut_ptr()),
+                &mut len,
+            )
+        };
+        unsafe { CloseHandle(process) }.ok();
+        queried.ok()?;
+
+        // Executable name without the extension, e.g. `firefox` for `C:\...\firefox.exe`
+        let path = String::from_utf16_lossy(&path[..len as usize]);
+        let name = std::path::Path::new(&path).file_stem()?.to_string_lossy();
+
+        (!name.is_empty()).then(|| name.to_string())
+    }
+
+    #[cfg(not(windows))]
+    fn focused_app(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Asks System Events for the frontmost process
+pub struct MacBackend;
+
+impl FocusBackend for MacBackend {
+    fn name(&self) -> &'static str {
+        "macos"
+    }
+
+    fn focused_app(&self) -> Option<String> {
+        let script = "tell application \"System Events\" to get name of first application process whose frontmost is true";
+
+        run("osascript", &["-e", script]).filter(|name| !name.is_empty())
+    }
+}
+
+/// Picks a backend for the current platform, custom command always wins
+///
+/// Wayland has no common way to query focused window, so only compositors with one of their own
+/// are known: Hyprland and Sway. Others need a command, or only see XWayland windows.
+pub fn detect_backend(config: &Config) -> Option<Box<dyn FocusBackend>> {
+    if let Some(command) = &config.focus_command {
+        return Some(Box::new(CommandBackend {
+            command: command.clone(),
+        }));
+    }
+
+    if cfg!(windows) {
+        return Some(Box::new(WindowsBackend));
+    }
+
+    if cfg!(target_os = "macos") {
+        return Some(Box::new(MacBackend));
+    }
+
+    if cfg!(target_os = "linux") {
+        if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+            return Some(Box::new(HyprlandBackend));
+        }
+
+        if std::env::var_os("SWAYSOCK").is_some() {
+            return Some(Box::new(SwayBackend));
+        }
+
+        if std::env::var_os("DISPLAY").is_some() {
+            return Some(Box::new(X11Backend));
+        }
+    }
+
+    None
+}
+
+/// Finds profile for the application on a device, device specific mapping goes first
+///
+/// Application names are matched case-insensitively, `*` matches any application.
+pub fn profile_for<'a>(profiles: &'a AppProfiles, device: &str, app: &str) -> Option<&'a str> {
+    let app = app.to_lowercase();
+
+    let lookup = |apps: &'a BTreeMap<String, String>| {
+        apps.iter()
+            .find(|(name, _)| name.to_lowercase() == app)
+            .or_else(|| apps.get_key_value("*"))
+            .map(|(_, profile)| profile.as_str())
+    };
+
+    profiles
+        .get(device)
+        .and_then(lookup)
+        .or_else(|| profiles.get("*").and_then(lookup))
+}
+
+pub async fn switch_profile(device: &str, profile: &str) {
+    log::info!("Switching {} to profile {}", device, profile);
+
+    // OpenDeck sends images of the new profile one by one, they show up all at once instead
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        writer.begin_frame();
+    }
+
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        let event = json!({
+            "event": "switchToProfile",
+            "context": PLUGIN_UUID,
+            "device": device,
+            "payload": { "profile": profile },
+        });
+
+        if let Err(err) = outbound.send_event(event).await {
+            log::error!("Failed to switch profile of {}: {}", device, err);
+        }
+    }
+}
+
+/// Watches focused application and switches profiles of connected devices accordingly
+///
+/// Does nothing while `app_profiles` setting is empty, so it's cheap to keep running.
+pub async fn focus_task(token: CancellationToken) {
+    let mut last_app: Option<String> = None;
+    let mut warned = false;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(FOCUS_POLL_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let config = CONFIG.borrow().clone();
+
+        if config.app_profiles.is_empty() {
+            last_app = None;
+            continue;
+        }
+
+        let Some(backend) = detect_backend(&config) else {
+            if !warned {
+                log::warn!("Can't detect focused application on this system, set focus_command");
+                warned = true;
+            }
+
+            continue;
+        };
+
+        let backend_name = backend.name();
+        let app = tokio::task::spawn_blocking(move || backend.focused_app())
+            .await
+            .ok()
+            .flatten();
+
+        if app.is_none() || app == last_app {
+            continue;
+        }
+
+        let app = app.unwrap();
+        log::debug!("Focused application changed to {} ({})", app, backend_name);
+
+        let devices: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+        for device in devices {
+            if let Some(profile) = profile_for(&config.app_profiles, &device, &app) {
+                switch_profile(&device, profile).await;
+            }
+        }
+
+        last_app = Some(app);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles() -> AppProfiles {
+        serde_json::from_value(json!({
+            "*": { "firefox": "Browser", "*": "Default" },
+            "a5-123": { "Code": "Coding" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn device_mapping_goes_before_common_one() {
+        let profiles = profiles();
+
+        assert_eq!(profile_for(&profiles, "a5-123", "code"), Some("Coding"));
+        assert_eq!(profile_for(&profiles, "a5-123", "Firefox"), Some("Browser"));
+        assert_eq!(profile_for(&profiles, "a5-456", "code"), Some("Default"));
+        assert_eq!(profile_for(&AppProfiles::new(), "a5-456", "code"), None);
+    }
+
+    #[test]
+    fn xprop_output_is_parsed() {
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x4a00003"),
+            Some("0x4a00003")
+        );
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0"),
+            None
+        );
+        assert_eq!(
+            parse_wm_class("WM_CLASS(STRING) = \"Navigator\", \"firefox\""),
+            Some("firefox".to_string())
+        );
+        assert_eq!(parse_wm_class("WM_CLASS:  not found."), None);
+    }
+
+    #[test]
+    fn wayland_compositor_output_is_parsed() {
+        assert_eq!(
+            parse_hyprland_window(r#"{ "class": "firefox", "title": "Mozilla Firefox" }"#),
+            Some("firefox".to_string())
+        );
+        assert_eq!(parse_hyprland_window("{}"), None);
+
+        let tree = json!({
+            "nodes": [{
+                "nodes": [
+                    { "focused": false, "app_id": "foot", "nodes": [] },
+                    { "focused": false, "nodes": [] },
+                ],
+                "floating_nodes": [{
+                    "focused": true,
+                    "app_id": null,
+                    "window_properties": { "class": "Code" },
+                }],
+            }],
+        });
+        assert_eq!(parse_sway_tree(&tree.to_string()), Some("Code".to_string()));
+        assert_eq!(parse_sway_tree(r#"{ "nodes": [] }"#), None);
+    }
+}
@@ -0,0 +1,687 @@
+use std::{process::Stdio, sync::LazyLock, time::Duration};
+
+use akp05::{images::KeyImage, inputs::SystemEvent, simulator::panel_reports};
+use mirajazz::state::DeviceStateUpdate;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    sync::broadcast,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, WRITERS,
+    badge::{Badge, BadgeKind, Corner, parse_color},
+    blink::{self, Blink, BlinkImage, DEFAULT_BLINK_INTERVAL, MAX_BLINK_SECONDS},
+    export,
+    focus::switch_profile,
+    inject,
+    labels::set_variable,
+    toast::{self, Toast},
+    transient::{self, MAX_TRANSIENT_SECONDS},
+    writer::WriterCommand,
+};
+
+/// Wait before starting the hook script again after it exits
+pub const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Events waiting for the script, older ones are dropped if it's this far behind
+const EVENT_BUFFER: usize = 256;
+
+static EVENTS: LazyLock<broadcast::Sender<(String, HookEvent)>> =
+    LazyLock::new(|| broadcast::channel(EVENT_BUFFER).0);
+
+/// Event passed on to the hook script and event sinks
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent {
+    Update(DeviceStateUpdate),
+    /// Strip zone, its new value and contact size of the touch
+    Slider(u8, u8, Option<u8>),
+    /// Strip zone, spot within it from 0 to 100 and contact size of the touch
+    Touch(u8, u8, Option<u8>),
+    /// Unknown input code and its state byte
+    Raw(u8, u8),
+    /// Status notification of the device
+    System(SystemEvent),
+}
+
+impl HookEvent {
+    /// JSON line the event is written as, without the newline
+    pub fn line(&self, device: &str) -> String {
+        match *self {
+            Self::Update(update) => event_line(device, update),
+            Self::Slider(zone, value, contact) => slider_line(device, zone, value, contact),
+            Self::Touch(zone, spot, contact) => touch_line(device, zone, spot, contact),
+            Self::Raw(code, state) => raw_line(device, code, state),
+            Self::System(event) => system_line(device, event),
+        }
+    }
+}
+
+/// Command printed by the hook script
+#[derive(Debug, Clone, PartialEq)]
+enum HookCommand {
+    SetBrightness(u8),
+    SetImage(u8, Option<KeyImage>),
+    SwitchProfile(String),
+    SetVariable(String, String),
+    Blink(u8, Blink),
+    /// Image shown on a position for a while, then the key image again
+    ShowImage(u8, KeyImage, Duration),
+    Toast(Toast),
+    /// Badge over the image of a position, [None] removes it
+    Badge(u8, Option<Badge>),
+    /// Path to save a PNG of the device to, `{device}` is replaced with the device id
+    Screenshot(String),
+    /// Raw reports handed to the reader as if the device sent them
+    Inject(Vec<Vec<u8>>),
+    /// Path to write every plugin setting to
+    ExportSettings(String),
+    /// Path of exported settings to merge into the config file
+    ImportSettings(String),
+}
+
+/// Passes an input that reaches OpenDeck on to the hook script, if one is running
+pub fn publish_update(id: &str, update: DeviceStateUpdate) {
+    // Nobody listening just means there's no script or event sink
+    let _ = EVENTS.send((id.to_string(), HookEvent::Update(update)));
+}
+
+/// Passes a new slider value on to the hook script, if one is running
+pub fn publish_slider(id: &str, zone: u8, value: u8, contact: Option<u8>) {
+    let _ = EVENTS.send((id.to_string(), HookEvent::Slider(zone, value, contact)));
+}
+
+/// Passes a touch of the strip on to the hook script, if one is running
+pub fn publish_touch(id: &str, zone: u8, spot: u8, contact: Option<u8>) {
+    let _ = EVENTS.send((id.to_string(), HookEvent::Touch(zone, spot, contact)));
+}
+
+/// Passes an unknown input code on to the hook script, if one is running
+pub fn publish_raw(id: &str, code: u8, state: u8) {
+    let _ = EVENTS.send((id.to_string(), HookEvent::Raw(code, state)));
+}
+
+/// Passes a status notification of the device on to the hook script, if one is running
+pub fn publish_system(id: &str, event: SystemEvent) {
+    let _ = EVENTS.send((id.to_string(), HookEvent::System(event)));
+}
+
+/// Gets every event from now on along with the device it's from, like the hook script does
+pub fn subscribe() -> broadcast::Receiver<(String, HookEvent)> {
+    EVENTS.subscribe()
+}
+
+/// JSON line sent to the script's stdin for an update
+fn event_line(device: &str, update: DeviceStateUpdate) -> String {
+    let mut event = match update {
+        DeviceStateUpdate::ButtonDown(key) => {
+            json!({ "event": "key", "key": key, "pressed": true })
+        }
+        DeviceStateUpdate::ButtonUp(key) => json!({ "event": "key", "key": key, "pressed": false }),
+        DeviceStateUpdate::EncoderDown(dial) => {
+            json!({ "event": "dial", "dial": dial, "pressed": true })
+        }
+        DeviceStateUpdate::EncoderUp(dial) => {
+            json!({ "event": "dial", "dial": dial, "pressed": false })
+        }
+        DeviceStateUpdate::EncoderTwist(dial, ticks) => {
+            json!({ "event": "dial", "dial": dial, "ticks": ticks })
+        }
+    };
+
+    event["device"] = json!(device);
+
+    event.to_string()
+}
+
+/// JSON line sent to the script's stdin for a slider value
+fn slider_line(device: &str, zone: u8, value: u8, contact: Option<u8>) -> String {
+    let mut event = json!({ "event": "slider", "device": device, "zone": zone, "value": value });
+
+    // Only some firmware sends it
+    if let Some(contact) = contact {
+        event["contact"] = json!(contact);
+    }
+
+    event.to_string()
+}
+
+/// JSON line sent to the script's stdin for a touch of the strip
+fn touch_line(device: &str, zone: u8, spot: u8, contact: Option<u8>) -> String {
+    let mut event = json!({ "event": "touch", "device": device, "zone": zone, "spot": spot });
+
+    if let Some(contact) = contact {
+        event["contact"] = json!(contact);
+    }
+
+    event.to_string()
+}
+
+/// JSON line sent to the script's stdin for an unknown input code
+fn raw_line(device: &str, code: u8, state: u8) -> String {
+    json!({ "event": "raw", "device": device, "code": code, "state": state }).to_string()
+}
+
+/// JSON line sent to the script's stdin for a status notification
+fn system_line(device: &str, event: SystemEvent) -> String {
+    json!({ "event": "system", "device": device, "type": event.name() }).to_string()
+}
+
+/// Parses a line printed by the script, `device` is [None] for commands meant for every device
+fn parse_command(line: &str) -> Result<(Option<String>, HookCommand), String> {
+    let value: Value = serde_json::from_str(line).map_err(|err| err.to_string())?;
+    let device = value["device"].as_str().map(str::to_string);
+
+    let command = match value["command"].as_str() {
+        Some("set_brightness") => HookCommand::SetBrightness(
+            value["brightness"]
+                .as_u64()
+                .filter(|brightness| *brightness <= 100)
+                .ok_or("\"brightness\" must be between 0 and 100")? as u8,
+        ),
+        Some("set_image") => {
+            let position = value["position"]
+                .as_u64()
+                .and_then(|position| u8::try_from(position).ok())
+                .ok_or("\"position\" must be a number")?;
+
+            // Data URL like OpenDeck sends, null clears the position
+            let image = match &value["image"] {
+                Value::Null => None,
+                Value::String(url) if url.starts_with("data:") => {
+                    Some(KeyImage::DataUrl(url.clone()))
+                }
+                _ => return Err("\"image\" must be a data URL or null".to_string()),
+            };
+
+            HookCommand::SetImage(position, image)
+        }
+        Some("blink") => {
+            let position = value["position"]
+                .as_u64()
+                .and_then(|position| u8::try_from(position).ok())
+                .ok_or("\"position\" must be a number")?;
+            let seconds = value["seconds"]
+                .as_u64()
+                .filter(|seconds| *seconds <= MAX_BLINK_SECONDS)
+                .ok_or(format!(
+                    "\"seconds\" must be between 0 and {}",
+                    MAX_BLINK_SECONDS
+                ))?;
+            let interval = match &value["interval_ms"] {
+                Value::Null => DEFAULT_BLINK_INTERVAL,
+                interval => Duration::from_millis(
+                    interval
+                        .as_u64()
+                        .ok_or("\"interval_ms\" must be a number")?,
+                ),
+            };
+
+            // Without an image the key blinks with a darker variant of its own
+            let image = match &value["image"] {
+                Value::Null => BlinkImage::Dimmed,
+                Value::String(url) if url.starts_with("data:") => {
+                    BlinkImage::Image(KeyImage::DataUrl(url.clone()))
+                }
+                _ => return Err("\"image\" must be a data URL or null".to_string()),
+            };
+
+            HookCommand::Blink(
+                position,
+                Blink {
+                    image,
+                    interval,
+                    duration: Duration::from_secs(seconds),
+                },
+            )
+        }
+        Some("show_image") => {
+            let position = value["position"]
+                .as_u64()
+                .and_then(|position| u8::try_from(position).ok())
+                .ok_or("\"position\" must be a number")?;
+            let seconds = value["seconds"]
+                .as_f64()
+                .filter(|seconds| (0.0..=MAX_TRANSIENT_SECONDS).contains(seconds))
+                .ok_or(format!(
+                    "\"seconds\" must be between 0 and {}",
+                    MAX_TRANSIENT_SECONDS
+                ))?;
+            let image = match &value["image"] {
+                Value::String(url) if url.starts_with("data:") => KeyImage::DataUrl(url.clone()),
+                _ => return Err("\"image\" must be a data URL".to_string()),
+            };
+
+            HookCommand::ShowImage(position, image, Duration::from_secs_f64(seconds))
+        }
+        Some("toast") => HookCommand::Toast(Toast {
+            text: value["text"]
+                .as_str()
+                .ok_or("\"text\" must be a string")?
+                .to_string(),
+            icon: match &value["icon"] {
+                Value::Null => None,
+                Value::String(url) if url.starts_with("data:") => {
+                    Some(KeyImage::DataUrl(url.clone()))
+                }
+                _ => return Err("\"icon\" must be a data URL or null".to_string()),
+            },
+        }),
+        Some("badge") => {
+            let position = value["position"]
+                .as_u64()
+                .and_then(|position| u8::try_from(position).ok())
+                .ok_or("\"position\" must be a number")?;
+            let corner = match &value["corner"] {
+                Value::Null => Corner::default(),
+                corner => corner.as_str().and_then(Corner::parse).ok_or(
+                    "\"corner\" must be \"top_left\", \"top_right\", \"bottom_left\" or \"bottom_right\"",
+                )?,
+            };
+
+            // Without a count, dot or icon the badge is removed
+            let kind = match (&value["count"], &value["dot"], &value["icon"]) {
+                (Value::Null, Value::Null, Value::Null) => None,
+                (count, Value::Null, Value::Null) => Some(BadgeKind::Count(
+                    count
+                        .as_u64()
+                        .and_then(|count| u32::try_from(count).ok())
+                        .ok_or("\"count\" must be a number")?,
+                )),
+                (Value::Null, dot, Value::Null) => Some(BadgeKind::Dot(
+                    dot.as_str()
+                        .and_then(parse_color)
+                        .ok_or("\"dot\" must be a color like \"#ff0000\"")?,
+                )),
+                (Value::Null, Value::Null, Value::String(url)) if url.starts_with("data:") => {
+                    Some(BadgeKind::Icon(KeyImage::DataUrl(url.clone())))
+                }
+                (Value::Null, Value::Null, _) => {
+                    return Err("\"icon\" must be a data URL".to_string());
+                }
+                _ => return Err("only one of \"count\", \"dot\" and \"icon\" can be set".into()),
+            };
+
+            HookCommand::Badge(position, kind.map(|kind| Badge { kind, corner }))
+        }
+        Some("screenshot") => HookCommand::Screenshot(
+            value["path"]
+                .as_str()
+                .filter(|path| !path.is_empty())
+                .ok_or("\"path\" must be a string")?
+                .to_string(),
+        ),
+        Some("export_settings") => HookCommand::ExportSettings(
+            value["path"]
+                .as_str()
+                .filter(|path| !path.is_empty())
+                .ok_or("\"path\" must be a string")?
+                .to_string(),
+        ),
+        Some("import_settings") => HookCommand::ImportSettings(
+            value["path"]
+                .as_str()
+                .filter(|path| !path.is_empty())
+                .ok_or("\"path\" must be a string")?
+                .to_string(),
+        ),
+        // Same inputs the simulator panel sends
+        Some("inject") => HookCommand::Inject(panel_reports(line)?),
+        Some("switch_profile") => HookCommand::SwitchProfile(
+            value["profile"]
+                .as_str()
+                .ok_or("\"profile\" must be a string")?
+                .to_string(),
+        ),
+        Some("set_variable") => HookCommand::SetVariable(
+            value["name"]
+                .as_str()
+                .ok_or("\"name\" must be a string")?
+                .to_string(),
+            match &value["value"] {
+                Value::String(value) => value.clone(),
+                Value::Null => String::new(),
+                value => value.to_string(),
+            },
+        ),
+        Some(command) => return Err(format!("unknown command \"{}\"", command)),
+        None => return Err("\"command\" is missing".to_string()),
+    };
+
+    Ok((device, command))
+}
+
+async fn apply_command(device: Option<String>, command: HookCommand) {
+    // Variables are the same for every device
+    if let HookCommand::SetVariable(name, value) = command {
+        set_variable(&name, value);
+        return;
+    }
+
+    if let HookCommand::Inject(reports) = &command {
+        if let Err(err) = inject::inject(device.as_deref(), reports) {
+            log::warn!("Not injecting input: {}", err);
+        }
+
+        return;
+    }
+
+    // Settings are the same for every device too
+    match &command {
+        HookCommand::ExportSettings(path) => {
+            if let Err(err) = export::export_settings(path.as_ref()).await {
+                log::error!("Failed to export settings: {}", err);
+            }
+
+            return;
+        }
+        HookCommand::ImportSettings(path) => {
+            if let Err(err) = export::import_settings(path.as_ref()).await {
+                log::error!("Failed to import settings: {}", err);
+            }
+
+            return;
+        }
+        _ => {}
+    }
+
+    let devices: Vec<String> = match device {
+        Some(device) => vec![device],
+        None => WRITERS.read().await.keys().cloned().collect(),
+    };
+
+    for device in devices {
+        if let HookCommand::SwitchProfile(profile) = &command {
+            switch_profile(&device, profile).await;
+            continue;
+        }
+
+        let Some(writer) = WRITERS.read().await.get(&device).cloned() else {
+            log::warn!("Hook command for unknown device {}", device);
+            continue;
+        };
+
+        match command.clone() {
+            HookCommand::SetBrightness(brightness) => {
+                writer.send(WriterCommand::SetBrightness(brightness))
+            }
+            HookCommand::SetImage(position, image) => writer.send(WriterCommand::SetImage {
+                position: Some(position),
+                image,
+            }),
+            HookCommand::Blink(position, blink) => blink::start(&device, position, blink),
+            HookCommand::ShowImage(position, _, duration) if duration.is_zero() => {
+                transient::revert(&device, position)
+            }
+            HookCommand::ShowImage(position, image, duration) => {
+                transient::show(&device, position, image, duration)
+            }
+            HookCommand::Toast(toast) => toast::show(&device, toast),
+            HookCommand::Badge(position, badge) => {
+                writer.send(WriterCommand::Badge { position, badge })
+            }
+            // Several devices would overwrite each other's screenshot without the placeholder
+            HookCommand::Screenshot(path) => writer.send(WriterCommand::Screenshot(
+                path.replace("{device}", &device).into(),
+            )),
+            HookCommand::SwitchProfile(_)
+            | HookCommand::SetVariable(..)
+            | HookCommand::Inject(_)
+            | HookCommand::ExportSettings(_)
+            | HookCommand::ImportSettings(_) => {}
+        }
+    }
+}
+
+/// Applies a command line like the ones the hook script prints
+pub async fn run_command(line: &str) -> Result<(), String> {
+    let (device, command) = parse_command(line)?;
+    apply_command(device, command).await;
+
+    Ok(())
+}
+
+/// Runs the script until it exits or the command changes
+async fn run_script(command: &str, token: &CancellationToken) -> Result<(), String> {
+    log::info!("Starting hook script {}", command);
+
+    let mut shell = if cfg!(target_os = "windows") {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    };
+
+    let mut child = shell
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to start: {}", err))?;
+
+    let mut stdin = child.stdin.take().ok_or("No stdin")?;
+    let mut lines = BufReader::new(child.stdout.take().ok_or("No stdout")?).lines();
+
+    let mut events = EVENTS.subscribe();
+    let mut config = CONFIG.subscribe();
+
+    let result = loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => {
+                    if let Err(err) = run_command(&line).await {
+                        log::warn!("Ignoring hook script output {}: {}", line, err);
+                    }
+                }
+                Ok(None) => break Err("Script closed its output".to_string()),
+                Err(err) => break Err(err.to_string()),
+            },
+            event = events.recv() => match event {
+                Ok((id, event)) => {
+                    let line = event.line(&id) + "\n";
+
+                    if let Err(err) = stdin.write_all(line.as_bytes()).await {
+                        break Err(format!("Script stopped reading: {}", err));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Hook script is too slow, dropped {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break Ok(()),
+            },
+            _ = config.changed() => {
+                if config.borrow_and_update().hook_command.as_deref() != Some(command) {
+                    log::info!("Hook script changed, stopping the old one");
+                    break Ok(());
+                }
+            }
+            _ = token.cancelled() => break Ok(()),
+        }
+    };
+
+    let _ = child.kill().await;
+
+    result
+}
+
+/// Keeps the hook script running while one is configured
+pub async fn hooks_task(token: CancellationToken) {
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        let command = config.borrow_and_update().hook_command.clone();
+
+        let failed = match command {
+            None => false,
+            Some(command) => match run_script(&command, &token).await {
+                Ok(()) => false,
+                Err(err) => {
+                    log::error!("Hook script failed: {}", err);
+                    true
+                }
+            },
+        };
+
+        if token.is_cancelled() {
+            break;
+        }
+
+        // Start again after a delay, or once the command changes
+        tokio::select! {
+            _ = tokio::time::sleep(RESTART_DELAY), if failed => {}
+            _ = config.changed() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use akp05::transport::build_report;
+
+    use super::*;
+
+    #[test]
+    fn events_are_serialized() {
+        let line = event_line("a5-1", DeviceStateUpdate::EncoderTwist(2, -1));
+        let event: Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(
+            event,
+            json!({ "event": "dial", "device": "a5-1", "dial": 2, "ticks": -1 })
+        );
+
+        let event: Value = serde_json::from_str(&slider_line("a5-1", 1, 66, None)).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "slider", "device": "a5-1", "zone": 1, "value": 66 })
+        );
+
+        let event: Value = serde_json::from_str(&slider_line("a5-1", 1, 66, Some(28))).unwrap();
+        assert_eq!(event["contact"], json!(28));
+
+        let event: Value = serde_json::from_str(&touch_line("a5-1", 3, 50, Some(16))).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "touch", "device": "a5-1", "zone": 3, "spot": 50, "contact": 16 })
+        );
+
+        let event: Value = serde_json::from_str(&raw_line("a5-1", 0xE3, 1)).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "raw", "device": "a5-1", "code": 227, "state": 1 })
+        );
+
+        let event: Value =
+            serde_json::from_str(&system_line("a5-1", SystemEvent::OverTemperature)).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "system", "device": "a5-1", "type": "over_temperature" })
+        );
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(
+            parse_command(r#"{ "command": "set_brightness", "brightness": 30 }"#),
+            Ok((None, HookCommand::SetBrightness(30)))
+        );
+        assert_eq!(
+            parse_command(
+                r#"{ "command": "set_image", "device": "a5-1", "position": 5, "image": null }"#
+            ),
+            Ok((Some("a5-1".to_string()), HookCommand::SetImage(5, None)))
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "switch_profile", "profile": "Layer B" }"#),
+            Ok((None, HookCommand::SwitchProfile("Layer B".to_string())))
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "set_variable", "name": "scene", "value": 2 }"#),
+            Ok((
+                None,
+                HookCommand::SetVariable("scene".to_string(), "2".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "blink", "position": 3, "seconds": 10 }"#),
+            Ok((
+                None,
+                HookCommand::Blink(
+                    3,
+                    Blink {
+                        image: BlinkImage::Dimmed,
+                        interval: DEFAULT_BLINK_INTERVAL,
+                        duration: Duration::from_secs(10)
+                    }
+                )
+            ))
+        );
+        assert_eq!(
+            parse_command(
+                r#"{ "command": "show_image", "position": 4, "image": "data:,", "seconds": 1.5 }"#
+            ),
+            Ok((
+                None,
+                HookCommand::ShowImage(
+                    4,
+                    KeyImage::DataUrl("data:,".to_string()),
+                    Duration::from_millis(1500)
+                )
+            ))
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "toast", "device": "a5-1", "text": "Call" }"#),
+            Ok((
+                Some("a5-1".to_string()),
+                HookCommand::Toast(Toast::text("Call"))
+            ))
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "screenshot", "path": "/tmp/{device}.png" }"#),
+            Ok((
+                None,
+                HookCommand::Screenshot("/tmp/{device}.png".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_command(
+                r#"{ "command": "badge", "position": 2, "count": 4, "corner": "top_left" }"#
+            ),
+            Ok((
+                None,
+                HookCommand::Badge(
+                    2,
+                    Some(Badge {
+                        kind: BadgeKind::Count(4),
+                        corner: Corner::TopLeft
+                    })
+                )
+            ))
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "badge", "position": 2 }"#),
+            Ok((None, HookCommand::Badge(2, None)))
+        );
+        assert!(
+            parse_command(r##"{ "command": "badge", "position": 2, "count": 1, "dot": "#fff" }"##)
+                .is_err()
+        );
+        assert_eq!(
+            parse_command(r#"{ "command": "inject", "event": "key", "key": 4, "pressed": false }"#),
+            Ok((None, HookCommand::Inject(vec![build_report(5, 0)])))
+        );
+        assert!(parse_command(r#"{ "command": "inject", "event": "key", "key": 40 }"#).is_err());
+        assert_eq!(
+            parse_command(r#"{ "command": "reboot" }"#),
+            Err("unknown command \"reboot\"".to_string())
+        );
+        assert!(parse_command(r#"{ "command": "set_brightness", "brightness": 300 }"#).is_err());
+    }
+}
@@ -0,0 +1,433 @@
+use openaction::SettingsValue;
+
+/// Modifier held while the key is pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    Ctrl,
+    Shift,
+    Alt,
+    /// Windows key, Command on macOS
+    Super,
+}
+
+/// Keys that don't type a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamedKey {
+    Enter,
+    Tab,
+    Space,
+    Escape,
+    Backspace,
+    Delete,
+    Insert,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    PlayPause,
+    NextTrack,
+    PreviousTrack,
+}
+
+/// Key pressed by a hotkey
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// Letter or digit, letters are lowercase
+    Char(char),
+    /// Function key, F1 - F24
+    Function(u8),
+    Named(NamedKey),
+}
+
+/// Key combination like `ctrl+shift+m`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotkey {
+    pub modifiers: Vec<Modifier>,
+    pub key: Key,
+}
+
+impl Hotkey {
+    /// Parses `+` separated modifiers followed by a single key, case doesn't matter
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim().to_lowercase();
+        let mut parts: Vec<&str> = value.split('+').map(str::trim).collect();
+
+        let key = parts.pop().filter(|key| !key.is_empty()).ok_or("no key")?;
+        let key = parse_key(key).ok_or_else(|| format!("unknown key \"{}\"", key))?;
+
+        let modifiers = parts
+            .into_iter()
+            .map(|modifier| match modifier {
+                "ctrl" | "control" => Ok(Modifier::Ctrl),
+                "shift" => Ok(Modifier::Shift),
+                "alt" | "option" => Ok(Modifier::Alt),
+                "super" | "win" | "cmd" | "meta" => Ok(Modifier::Super),
+                _ => Err(format!("unknown modifier \"{}\"", modifier)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { modifiers, key })
+    }
+}
+
+fn parse_key(key: &str) -> Option<Key> {
+    let mut chars = key.chars();
+
+    if let (Some(ch), None) = (chars.next(), chars.next())
+        && ch.is_ascii_alphanumeric()
+    {
+        return Some(Key::Char(ch));
+    }
+
+    if let Some(number) = key.strip_prefix('f')
+        && let Ok(number) = number.parse::<u8>()
+    {
+        return (1..=24).contains(&number).then_some(Key::Function(number));
+    }
+
+    let named = match key {
+        "enter" | "return" => NamedKey::Enter,
+        "tab" => NamedKey::Tab,
+        "space" => NamedKey::Space,
+        "escape" | "esc" => NamedKey::Escape,
+        "backspace" => NamedKey::Backspace,
+        "delete" | "del" => NamedKey::Delete,
+        "insert" => NamedKey::Insert,
+        "up" => NamedKey::Up,
+        "down" => NamedKey::Down,
+        "left" => NamedKey::Left,
+        "right" => NamedKey::Right,
+        "home" => NamedKey::Home,
+        "end" => NamedKey::End,
+        "pageup" => NamedKey::PageUp,
+        "pagedown" => NamedKey::PageDown,
+        "volumeup" => NamedKey::VolumeUp,
+        "volumedown" => NamedKey::VolumeDown,
+        "mute" => NamedKey::Mute,
+        "playpause" => NamedKey::PlayPause,
+        "nexttrack" => NamedKey::NextTrack,
+        "previoustrack" => NamedKey::PreviousTrack,
+        _ => return None,
+    };
+
+    Some(Key::Named(named))
+}
+
+/// Hotkey stored in action settings, [None] if it's missing
+pub fn settings_hotkey(settings: &SettingsValue) -> Option<Result<Hotkey, String>> {
+    let hotkey = settings["hotkey"].as_str()?.trim();
+
+    (!hotkey.is_empty()).then(|| Hotkey::parse(hotkey))
+}
+
+/// Key names as `xdotool key` takes them, e.g. `ctrl+shift+m`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn xdotool_keys(hotkey: &Hotkey) -> String {
+    let key = match hotkey.key {
+        Key::Char(ch) => ch.to_string(),
+        Key::Function(number) => format!("F{}", number),
+        Key::Named(named) => match named {
+            NamedKey::Enter => "Return",
+            NamedKey::Tab => "Tab",
+            NamedKey::Space => "space",
+            NamedKey::Escape => "Escape",
+            NamedKey::Backspace => "BackSpace",
+            NamedKey::Delete => "Delete",
+            NamedKey::Insert => "Insert",
+            NamedKey::Up => "Up",
+            NamedKey::Down => "Down",
+            NamedKey::Left => "Left",
+            NamedKey::Right => "Right",
+            NamedKey::Home => "Home",
+            NamedKey::End => "End",
+            NamedKey::PageUp => "Prior",
+            NamedKey::PageDown => "Next",
+            NamedKey::VolumeUp => "XF86AudioRaiseVolume",
+            NamedKey::VolumeDown => "XF86AudioLowerVolume",
+            NamedKey::Mute => "XF86AudioMute",
+            NamedKey::PlayPause => "XF86AudioPlay",
+            NamedKey::NextTrack => "XF86AudioNext",
+            NamedKey::PreviousTrack => "XF86AudioPrev",
+        }
+        .to_string(),
+    };
+
+    let mut keys: Vec<String> = hotkey
+        .modifiers
+        .iter()
+        .map(|modifier| match modifier {
+            Modifier::Ctrl => "ctrl",
+            Modifier::Shift => "shift",
+            Modifier::Alt => "alt",
+            Modifier::Super => "super",
+        })
+        .map(str::to_string)
+        .collect();
+    keys.push(key);
+
+    keys.join("+")
+}
+
+/// Windows virtual key code of a key
+#[cfg_attr(not(windows), allow(dead_code))]
+fn virtual_key(key: Key) -> u16 {
+    match key {
+        Key::Char(ch) => ch.to_ascii_uppercase() as u16,
+        Key::Function(number) => 0x6F + number as u16,
+        Key::Named(named) => match named {
+            NamedKey::Enter => 0x0D,
+            NamedKey::Tab => 0x09,
+            NamedKey::Space => 0x20,
+            NamedKey::Escape => 0x1B,
+            NamedKey::Backspace => 0x08,
+            NamedKey::Delete => 0x2E,
+            NamedKey::Insert => 0x2D,
+            NamedKey::Up => 0x26,
+            NamedKey::Down => 0x28,
+            NamedKey::Left => 0x25,
+            NamedKey::Right => 0x27,
+            NamedKey::Home => 0x24,
+            NamedKey::End => 0x23,
+            NamedKey::PageUp => 0x21,
+            NamedKey::PageDown => 0x22,
+            NamedKey::VolumeUp => 0xAF,
+            NamedKey::VolumeDown => 0xAE,
+            NamedKey::Mute => 0xAD,
+            NamedKey::PlayPause => 0xB3,
+            NamedKey::NextTrack => 0xB0,
+            NamedKey::PreviousTrack => 0xB1,
+        },
+    }
+}
+
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn key_code(code: u8) -> String {
+    format!("key code {}", code)
+}
+
+/// AppleScript pressing the hotkey through System Events, [None] for keys macOS can't script
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn apple_script(hotkey: &Hotkey) -> Option<String> {
+    let key = match hotkey.key {
+        Key::Char(ch) => format!("keystroke \"{}\"", ch),
+        Key::Function(number) => key_code(match number {
+            1 => 122,
+            2 => 120,
+            3 => 99,
+            4 => 118,
+            5 => 96,
+            6 => 97,
+            7 => 98,
+            8 => 100,
+            9 => 101,
+            10 => 109,
+            11 => 103,
+            12 => 111,
+            _ => return None,
+        }),
+        Key::Named(named) => key_code(match named {
+            NamedKey::Enter => 36,
+            NamedKey::Tab => 48,
+            NamedKey::Space => 49,
+            NamedKey::Escape => 53,
+            NamedKey::Backspace => 51,
+            NamedKey::Delete => 117,
+            NamedKey::Up => 126,
+            NamedKey::Down => 125,
+            NamedKey::Left => 123,
+            NamedKey::Right => 124,
+            NamedKey::Home => 115,
+            NamedKey::End => 119,
+            NamedKey::PageUp => 116,
+            NamedKey::PageDown => 121,
+            _ => return None,
+        }),
+    };
+
+    let modifiers: Vec<&str> = hotkey
+        .modifiers
+        .iter()
+        .map(|modifier| match modifier {
+            Modifier::Ctrl => "control down",
+            Modifier::Shift => "shift down",
+            Modifier::Alt => "option down",
+            Modifier::Super => "command down",
+        })
+        .collect();
+
+    let using = if modifiers.is_empty() {
+        String::new()
+    } else {
+        format!(" using {{{}}}", modifiers.join(", "))
+    };
+
+    Some(format!(
+        "tell application \"System Events\" to {}{}",
+        key, using
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn send(hotkey: &Hotkey) -> Result<(), String> {
+    let output = std::process::Command::new("xdotool")
+        .args(["key", "--clearmodifiers", &xdotool_keys(hotkey)])
+        .output()
+        .map_err(|err| format!("Failed to run xdotool: {}", err))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn send(hotkey: &Hotkey) -> Result<(), String> {
+    let script = apple_script(hotkey).ok_or("Key can't be sent on macOS")?;
+    let output = std::process::Command::new("osascript")
+        .args(["-e", &script])
+        .output()
+        .map_err(|err| format!("Failed to run osascript: {}", err))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn send(hotkey: &Hotkey) -> Result<(), String> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        INPUT, INPUT_0, INPUT_KEYBOARD, KEYBD_EVENT_FLAGS, KEYBDINPUT, KEYEVENTF_KEYUP, SendInput,
+        VIRTUAL_KEY,
+    };
+
+    let input = |key: u16, up: bool| INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: VIRTUAL_KEY(key),
+                wScan: 0,
+                dwFlags: if up {
+                    KEYEVENTF_KEYUP
+                } else {
+                    KEYBD_EVENT_FLAGS(0)
+                },
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    };
+
+    let modifiers: Vec<u16> = hotkey
+        .modifiers
+        .iter()
+        .map(|modifier| match modifier {
+            Modifier::Ctrl => 0x11,
+            Modifier::Shift => 0x10,
+            Modifier::Alt => 0x12,
+            Modifier::Super => 0x5B,
+        })
+        .collect();
+    let key = virtual_key(hotkey.key);
+
+    // Modifiers down, key down and up, modifiers up in reverse
+    let mut inputs: Vec<INPUT> = modifiers.iter().map(|key| input(*key, false)).collect();
+    inputs.push(input(key, false));
+    inputs.push(input(key, true));
+    inputs.extend(modifiers.iter().rev().map(|key| input(*key, true)));
+
+    let sent = unsafe { SendInput(&inputs, std::mem::size_of::<INPUT>() as i32) };
+
+    if sent as usize != inputs.len() {
+        return Err("Input was blocked by another application".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn send(_hotkey: &Hotkey) -> Result<(), String> {
+    Err("Hotkeys aren't supported on this platform".to_string())
+}
+
+/// Presses the hotkey as if it was typed on a keyboard
+///
+/// Linux needs `xdotool` and an X11 session (XWayland windows included), macOS needs the
+/// accessibility permission for OpenDeck.
+pub async fn press(hotkey: Hotkey) {
+    let result = tokio::task::spawn_blocking(move || send(&hotkey))
+        .await
+        .unwrap_or_else(|err| Err(err.to_string()));
+
+    if let Err(err) = result {
+        log::warn!("Failed to send hotkey: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn hotkeys_are_parsed() {
+        let hotkey = Hotkey::parse("Ctrl+Shift+M").unwrap();
+
+        assert_eq!(hotkey.modifiers, [Modifier::Ctrl, Modifier::Shift]);
+        assert_eq!(hotkey.key, Key::Char('m'));
+        assert_eq!(Hotkey::parse("f13").unwrap().key, Key::Function(13));
+        assert_eq!(
+            Hotkey::parse("cmd + pageup").unwrap(),
+            Hotkey {
+                modifiers: vec![Modifier::Super],
+                key: Key::Named(NamedKey::PageUp)
+            }
+        );
+
+        assert_eq!(Hotkey::parse("ctrl+").unwrap_err(), "no key");
+        assert_eq!(Hotkey::parse("f25").unwrap_err(), "unknown key \"f25\"");
+        assert_eq!(
+            Hotkey::parse("hyper+a").unwrap_err(),
+            "unknown modifier \"hyper\""
+        );
+
+        assert_eq!(settings_hotkey(&json!({})), None);
+        assert_eq!(settings_hotkey(&json!({ "hotkey": "" })), None);
+        assert!(matches!(
+            settings_hotkey(&json!({ "hotkey": "alt+tab" })),
+            Some(Ok(_))
+        ));
+    }
+
+    #[test]
+    fn hotkeys_are_converted() {
+        let hotkey = Hotkey::parse("ctrl+alt+pagedown").unwrap();
+
+        assert_eq!(xdotool_keys(&hotkey), "ctrl+alt+Next");
+        assert_eq!(
+            apple_script(&hotkey).unwrap(),
+            "tell application \"System Events\" to key code 121 using {control down, option down}"
+        );
+        assert_eq!(virtual_key(hotkey.key), 0x22);
+
+        let hotkey = Hotkey::parse("super+a").unwrap();
+
+        assert_eq!(
+            apple_script(&hotkey).unwrap(),
+            "tell application \"System Events\" to keystroke \"a\" using {command down}"
+        );
+        assert_eq!(virtual_key(hotkey.key), 0x41);
+        assert_eq!(virtual_key(Key::Function(1)), 0x70);
+        assert_eq!(apple_script(&Hotkey::parse("mute").unwrap()), None);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use akp05::{
+    images::ColorCorrection, mappings::Kind, tiles::TileCache, transport::DeviceTransport,
+};
+use image::DynamicImage;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, DEVICES, writer::KeyPainter};
+
+/// How often icons are checked, a changed pack or a new device is picked up by the next check
+pub const ICON_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Directory in the system temp directory encoded tiles are kept in between runs
+pub const TILE_DIR_NAME: &str = "opendeck-akp05-tiles";
+
+/// Icons shown on positions, position to icon name
+pub type Icons = BTreeMap<u8, String>;
+
+/// Icons of a pack by file name without extension
+pub type IconPack = HashMap<String, Arc<DynamicImage>>;
+
+// Every image written to a device goes through here, not only icons
+static TILES: LazyLock<Arc<TileCache>> = LazyLock::new(|| {
+    Arc::new(TileCache::new(Some(
+        std::env::temp_dir().join(TILE_DIR_NAME),
+    )))
+});
+
+/// Encoded image cache shared by every device
+pub fn tiles() -> Arc<TileCache> {
+    TILES.clone()
+}
+
+/// Reads every JPEG and BMP image in the directory, files that can't be decoded are skipped
+pub fn load_pack(dir: &Path) -> Result<IconPack, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?;
+
+    let mut pack = IconPack::new();
+
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        if !matches!(extension.as_deref(), Some("jpg" | "jpeg" | "bmp")) {
+            continue;
+        }
+
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        match image::open(&path) {
+            Ok(icon) => {
+                pack.insert(name.to_string(), Arc::new(icon));
+            }
+            Err(err) => log::warn!("Skipping icon {}: {}", path.display(), err),
+        }
+    }
+
+    Ok(pack)
+}
+
+/// How a device gets its images encoded, tiles made for one don't fit another
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Encoding {
+    kind: Kind,
+    quality: u8,
+    correction: ColorCorrection,
+}
+
+/// Encodes every icon the way the writer would for a device, so showing them costs no encoding
+fn pre_encode(pack: &IconPack, encoding: Encoding) {
+    for icon in pack.values() {
+        // Same steps as the writer, which corrects colors before the transport encodes
+        let icon = encoding.correction.apply(icon);
+
+        if let Err(err) = TILES.persist(encoding.kind.image_format(), encoding.quality, &icon) {
+            log::warn!("Failed to encode icon: {}", err);
+        }
+    }
+}
+
+/// Encodings of connected devices
+async fn device_encodings() -> Vec<(String, Encoding)> {
+    let kinds: Vec<(String, Kind)> = DEVICES
+        .read()
+        .await
+        .iter()
+        .filter_map(|(id, device)| {
+            Some((id.clone(), Kind::from_vid_pid(device.vid(), device.pid())?))
+        })
+        .collect();
+
+    let config = CONFIG.borrow();
+
+    kinds
+        .into_iter()
+        .map(|(id, kind)| {
+            let encoding = Encoding {
+                kind,
+                quality: config.jpeg_quality,
+                correction: config.color_correction(&id),
+            };
+
+            (id, encoding)
+        })
+        .collect()
+}
+
+/// Loads the icon pack, keeps its tiles encoded for connected devices and draws `icons`
+pub async fn icons_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut loaded: Option<(PathBuf, Arc<IconPack>)> = None;
+    let mut encoded: HashMap<String, Encoding> = HashMap::new();
+    let mut generation = 0u64;
+
+    loop {
+        let (dir, icons) = {
+            let config = CONFIG.borrow();
+            (config.icon_pack.clone(), config.icons.clone())
+        };
+
+        if loaded.as_ref().map(|(path, _)| path) != dir.as_ref() {
+            encoded.clear();
+            generation += 1;
+
+            loaded = match dir {
+                Some(dir) => {
+                    let path = dir.clone();
+
+                    match tokio::task::spawn_blocking(move || load_pack(&path)).await {
+                        Ok(Ok(pack)) => {
+                            log::info!("Loaded {} icons from {}", pack.len(), dir.display());
+                            Some((dir, Arc::new(pack)))
+                        }
+                        Ok(Err(err)) => {
+                            log::error!("{}", err);
+                            Some((dir, Arc::new(IconPack::new())))
+                        }
+                        Err(_) => None,
+                    }
+                }
+                None => None,
+            };
+        }
+
+        painter.retain(|_, position| icons.contains_key(&position));
+
+        if let Some((_, pack)) = &loaded {
+            for (id, encoding) in device_encodings().await {
+                if encoded.get(&id) == Some(&encoding) {
+                    continue;
+                }
+
+                let started = std::time::Instant::now();
+                let icons = pack.clone();
+                tokio::task::spawn_blocking(move || pre_encode(&icons, encoding))
+                    .await
+                    .ok();
+
+                log::info!(
+                    "Encoded {} icons for {} in {:?}",
+                    pack.len(),
+                    id,
+                    started.elapsed()
+                );
+                encoded.insert(id, encoding);
+            }
+
+            for (position, name) in &icons {
+                let Some(icon) = pack.get(name) else {
+                    continue;
+                };
+
+                let lines = vec![generation.to_string(), name.clone()];
+                painter.paint(*position, lines, || (**icon).clone()).await;
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(ICON_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn packs_load_images_by_name() {
+        let dir = std::env::temp_dir().join(format!("akp05-icons-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let icon = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([0, 128, 255])));
+        icon.save(dir.join("mute.jpg")).unwrap();
+        icon.save(dir.join("Play.BMP")).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not an icon").unwrap();
+        std::fs::write(dir.join("broken.jpg"), "not a jpeg").unwrap();
+
+        let pack = load_pack(&dir).unwrap();
+        let mut names: Vec<&String> = pack.keys().collect();
+        names.sort();
+        assert_eq!(names, ["Play", "mute"]);
+        assert_eq!(
+            pack["Play"].as_rgb8().unwrap().get_pixel(3, 3).0,
+            [0, 128, 255]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(load_pack(&dir).is_err());
+    }
+}
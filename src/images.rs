@@ -0,0 +1,354 @@
+use std::sync::Arc;
+
+use data_url::DataUrl;
+use image::{
+    ColorType, DynamicImage, ImageError,
+    codecs::{bmp::BmpEncoder, jpeg::JpegEncoder},
+    imageops::FilterType,
+    load_from_memory_with_format,
+};
+use mirajazz::types::{ImageMirroring, ImageRotation};
+
+/// JPEG quality used unless configured otherwise
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// How images are encoded for the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageMode {
+    Jpeg,
+    Bmp,
+    /// Uncompressed 16 bit pixels, 5 bits red, 6 green and 5 blue, little endian
+    Rgb565,
+    /// Uncompressed red, green and blue bytes
+    Rgb888,
+}
+
+impl ImageMode {
+    /// Extension of files holding encoded images
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Bmp => "bmp",
+            Self::Rgb565 => "rgb565",
+            Self::Rgb888 => "rgb888",
+        }
+    }
+}
+
+/// Format a device takes images in
+///
+/// Same as the mirajazz one, with raw pixel modes for revisions that don't accept JPEG.
+#[derive(Debug, Clone, Copy, Hash)]
+pub struct ImageFormat {
+    pub mode: ImageMode,
+    pub size: (usize, usize),
+    pub rotation: ImageRotation,
+    pub mirror: ImageMirroring,
+}
+
+impl ImageFormat {
+    /// Same format with the rotation and mirroring of a single key
+    pub fn with_transform(self, transform: KeyTransform) -> Self {
+        let rotation = match transform.rotation {
+            90 => ImageRotation::Rot90,
+            180 => ImageRotation::Rot180,
+            270 => ImageRotation::Rot270,
+            _ => ImageRotation::Rot0,
+        };
+
+        let mirror = match (transform.mirror_x, transform.mirror_y) {
+            (false, false) => ImageMirroring::None,
+            (true, false) => ImageMirroring::X,
+            (false, true) => ImageMirroring::Y,
+            (true, true) => ImageMirroring::Both,
+        };
+
+        Self {
+            rotation,
+            mirror,
+            ..self
+        }
+    }
+}
+
+/// Rotation and mirroring of a key whose panel is mounted differently from the rest, replacing
+/// the ones of the device format for that key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyTransform {
+    /// Clockwise degrees, 0, 90, 180 or 270
+    pub rotation: u16,
+    pub mirror_x: bool,
+    pub mirror_y: bool,
+}
+
+impl KeyTransform {
+    /// Transform from degrees and `none`, `x`, `y` or `both`, [None] if either is invalid
+    pub fn new(rotation: u16, mirror: &str) -> Option<Self> {
+        if !matches!(rotation, 0 | 90 | 180 | 270) {
+            return None;
+        }
+
+        let (mirror_x, mirror_y) = match mirror {
+            "none" => (false, false),
+            "x" => (true, false),
+            "y" => (false, true),
+            "both" => (true, true),
+            _ => return None,
+        };
+
+        Some(Self {
+            rotation,
+            mirror_x,
+            mirror_y,
+        })
+    }
+}
+
+/// Image to be written to a button
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyImage {
+    /// Data url sent by OpenDeck
+    DataUrl(String),
+    /// Image drawn by the plugin itself, e.g. the clock
+    Rendered(Arc<DynamicImage>),
+}
+
+/// Color correction for panels that don't show colors quite right, applied before encoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorCorrection {
+    /// Exponent applied to every channel, above 1 darkens mid tones and below 1 lightens them
+    pub gamma: f32,
+    /// Red, green and blue multipliers applied after gamma, e.g. `[1.0, 0.95, 0.85]` cools
+    /// panels that show images too warm
+    pub gain: [f32; 3],
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            gain: [1.0; 3],
+        }
+    }
+}
+
+impl ColorCorrection {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Lookup tables of every channel, so images cost a table read per pixel
+    fn tables(&self) -> [[u8; 256]; 3] {
+        let mut tables = [[0; 256]; 3];
+
+        for (table, gain) in tables.iter_mut().zip(self.gain) {
+            for (value, out) in table.iter_mut().enumerate() {
+                let corrected = (value as f32 / 255.0).powf(self.gamma) * gain;
+                *out = (corrected * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+
+        tables
+    }
+
+    /// Corrected copy of an image
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        if self.is_identity() {
+            return image.clone();
+        }
+
+        let tables = self.tables();
+        let mut image = image.to_rgba8();
+
+        for pixel in image.pixels_mut() {
+            for (channel, table) in pixel.0[..3].iter_mut().zip(&tables) {
+                *channel = table[*channel as usize];
+            }
+        }
+
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+/// Decodes image sent by OpenDeck as a data url, returning reason if it can't be used
+pub fn decode_data_url(image: &str) -> Result<DynamicImage, String> {
+    // OpenDeck sends image as a data url, so parse it using a library
+    let url = DataUrl::process(image).map_err(|err| format!("Bad data url: {:?}", err))?;
+    let (body, _fragment) = url
+        .decode_to_vec()
+        .map_err(|err| format!("Bad data url body: {:?}", err))?;
+
+    // Allow only image/jpeg mime for now
+    if url.mime_type().subtype != "jpeg" {
+        return Err(format!("Incorrect mime type: {}", url.mime_type()));
+    }
+
+    load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)
+        .map_err(|err| err.to_string())
+}
+
+/// Runs image work on the blocking pool, so it neither stalls the runtime nor needs a
+/// multi-threaded one
+pub async fn spawn_encode<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, ImageError> + Send + 'static,
+) -> Result<T, ImageError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|err| ImageError::IoError(std::io::Error::other(err)))?
+}
+
+/// Resizes, rotates and encodes image the way device expects it
+///
+/// Same as mirajazz conversion, except JPEG quality is configurable and raw pixel modes are
+/// supported. Quality only matters for JPEG.
+pub fn encode_image(
+    format: ImageFormat,
+    quality: u8,
+    image: DynamicImage,
+) -> Result<Vec<u8>, ImageError> {
+    let (width, height) = (format.size.0 as u32, format.size.1 as u32);
+
+    let image = image.resize_exact(width, height, FilterType::Nearest);
+
+    let image = match format.rotation {
+        ImageRotation::Rot0 => image,
+        ImageRotation::Rot90 => image.rotate90(),
+        ImageRotation::Rot180 => image.rotate180(),
+        ImageRotation::Rot270 => image.rotate270(),
+    };
+
+    let image = match format.mirror {
+        ImageMirroring::None => image,
+        ImageMirroring::X => image.fliph(),
+        ImageMirroring::Y => image.flipv(),
+        ImageMirroring::Both => image.fliph().flipv(),
+    };
+
+    let data = image.into_rgb8().into_raw();
+    let mut buf = vec![];
+
+    match format.mode {
+        ImageMode::Bmp => {
+            BmpEncoder::new(&mut buf).encode(&data, width, height, ColorType::Rgb8.into())?
+        }
+        ImageMode::Jpeg => JpegEncoder::new_with_quality(&mut buf, quality).encode(
+            &data,
+            width,
+            height,
+            ColorType::Rgb8.into(),
+        )?,
+        ImageMode::Rgb565 => buf = rgb565(&data),
+        ImageMode::Rgb888 => buf = data,
+    }
+
+    Ok(buf)
+}
+
+/// Packs RGB bytes into 16 bit pixels, dropping the lowest bits of every channel
+fn rgb565(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(3)
+        .flat_map(|pixel| {
+            let (r, g, b) = (pixel[0] as u16, pixel[1] as u16, pixel[2] as u16);
+
+            ((r >> 3) << 11 | (g >> 2) << 5 | b >> 3).to_le_bytes()
+        })
+        .collect()
+}
+
+/// Standard base64 with padding
+pub fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn colors_are_corrected() {
+        let image =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([200, 200, 64, 255])));
+
+        let correction = ColorCorrection::default();
+        assert_eq!(correction.apply(&image), image);
+
+        let correction = ColorCorrection {
+            gamma: 1.0,
+            gain: [0.5, 1.0, 2.0],
+        };
+        assert_eq!(
+            correction.apply(&image).get_pixel(1, 1).0,
+            [100, 200, 128, 255]
+        );
+
+        let correction = ColorCorrection {
+            gamma: 2.0,
+            gain: [1.0; 3],
+        };
+        assert_eq!(
+            correction.apply(&image).get_pixel(1, 1).0,
+            [157, 157, 16, 255]
+        );
+    }
+
+    #[test]
+    fn raw_modes_send_every_pixel() {
+        let format = |mode| ImageFormat {
+            mode,
+            size: (2, 2),
+            rotation: ImageRotation::Rot0,
+            mirror: ImageMirroring::None,
+        };
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 128, 8, 255])));
+
+        let rgb888 = encode_image(format(ImageMode::Rgb888), 90, image.clone()).unwrap();
+        assert_eq!(rgb888, [255, 128, 8].repeat(4));
+
+        // 11111 100000 00001, low byte first
+        let rgb565 = encode_image(format(ImageMode::Rgb565), 90, image).unwrap();
+        assert_eq!(rgb565, [0x01, 0xfc].repeat(4));
+    }
+
+    #[test]
+    fn key_transforms_replace_rotation_and_mirroring() {
+        let format = ImageFormat {
+            mode: ImageMode::Jpeg,
+            size: (120, 120),
+            rotation: ImageRotation::Rot180,
+            mirror: ImageMirroring::None,
+        };
+
+        let transform = KeyTransform::new(0, "x").unwrap();
+        let keyed = format.with_transform(transform);
+
+        assert!(matches!(keyed.rotation, ImageRotation::Rot0));
+        assert!(matches!(keyed.mirror, ImageMirroring::X));
+        assert_eq!(keyed.size, (120, 120));
+
+        assert_eq!(KeyTransform::new(45, "none"), None);
+        assert_eq!(KeyTransform::new(90, "z"), None);
+    }
+}
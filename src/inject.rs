@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+// Device id to the queue its reader takes injected reports from
+static INJECTED: LazyLock<Mutex<HashMap<String, UnboundedSender<Vec<u8>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Starts taking injected reports for the device, they arrive through the returned queue
+///
+/// A reconnected device replaces the queue of the previous connection.
+pub fn register(device: &str) -> UnboundedReceiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    INJECTED.lock().unwrap().insert(device.to_string(), sender);
+
+    receiver
+}
+
+/// Stops taking injected reports for a device that's gone
+pub fn forget(device: &str) {
+    INJECTED.lock().unwrap().remove(device);
+}
+
+/// Hands raw reports to the reader of the device, as if the device had sent them
+///
+/// They're decoded and go through locks, pages, macros and everything else like real input.
+/// Sends to every connected device if `device` is [None].
+pub fn inject(device: Option<&str>, reports: &[Vec<u8>]) -> Result<(), String> {
+    let injected = INJECTED.lock().unwrap();
+    let targets: Vec<&UnboundedSender<Vec<u8>>> = match device {
+        Some(device) => vec![
+            injected
+                .get(device)
+                .ok_or_else(|| format!("unknown device \"{}\"", device))?,
+        ],
+        None => injected.values().collect(),
+    };
+
+    for target in targets {
+        for report in reports {
+            // Reader is gone, the device is being dropped
+            let _ = target.send(report.clone());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_reach_registered_devices() {
+        let mut first = register("a5-inject-1");
+        let mut second = register("a5-inject-2");
+
+        inject(Some("a5-inject-1"), &[vec![1], vec![2]]).unwrap();
+        assert_eq!(first.try_recv(), Ok(vec![1]));
+        assert_eq!(first.try_recv(), Ok(vec![2]));
+        assert!(second.try_recv().is_err());
+
+        forget("a5-inject-1");
+        assert!(inject(Some("a5-inject-1"), &[vec![3]]).is_err());
+
+        forget("a5-inject-2");
+    }
+}
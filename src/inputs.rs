@@ -1,46 +1,774 @@
-use mirajazz::{error::MirajazzError, types::DeviceInput};
+use std::time::{Duration, Instant};
 
-use crate::mappings::{ENCODER_COUNT, KEY_COUNT};
+use mirajazz::{error::MirajazzError, state::DeviceStateUpdate};
+use smallvec::SmallVec;
 
-// Simplified input processing for AKP05 devices only
-pub fn process_input(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    // All supported devices are AKP05 variants, so use AKP05E processing
-    process_akp05e_input(input, state)
+use crate::mappings::{ENCODER_COUNT, KEY_COUNT, Kind};
+
+/// Decoded input report, stored inline so decoding doesn't allocate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// No data was passed from the device
+    NoData,
+
+    /// New states of every button
+    ButtonStateChange([bool; KEY_COUNT]),
+
+    /// New states of every encoder button
+    EncoderStateChange([bool; ENCODER_COUNT]),
+
+    /// Encoders twist values
+    EncoderTwist([i8; ENCODER_COUNT]),
+
+    /// Touch of the strip, with the contact size if the firmware sends one
+    Touch { code: u8, contact: Option<u8> },
+
+    /// Status notification of the device itself
+    System(SystemEvent),
+
+    /// Code no control is known to send, never changes any state
+    Unknown { code: u8, state: u8 },
 }
 
-fn process_akp05e_input(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    match input {
-        // 10 buttons for AKP05E (1-10, using 1-based indexing)
-        0x01..=0x0A => read_akp05e_button_press(input, state),
-        // Primary encoder rotations
-        0x90 | 0x91 | 0x50 | 0x51 | 0x60 | 0x61 | 0x70 | 0x71 => read_akp05e_encoder_value(input),
-        // Additional encoder 1 rotations (knob 1)
-        0xA0 | 0xA1 => read_akp05e_encoder_value_alt(input),
-        // Encoder button presses (including the new knob 1 click)
-        0x33..=0x37 => read_akp05e_encoder_press(input, state),
-        // Touchscreen inputs (ignore for now, but don't error)
-        0x40..=0x4F => read_akp05e_touchscreen(input, state),
-        // Unknown inputs - silently ignore to prevent disconnections
+/// Status notification the device sends on its own, not caused by any control
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemEvent {
+    /// Woke up from sleep
+    Wake,
+
+    /// Got too hot
+    OverTemperature,
+}
+
+impl SystemEvent {
+    /// Parses event name, `wake` or `over_temperature`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "wake" => Some(Self::Wake),
+            "over_temperature" => Some(Self::OverTemperature),
+            _ => None,
+        }
+    }
+
+    /// Name the event is parsed from and passed on with
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Wake => "wake",
+            Self::OverTemperature => "over_temperature",
+        }
+    }
+}
+
+/// Most updates a single report can produce: down and up for every key on protocols
+/// that only report presses
+pub const MAX_UPDATES: usize = KEY_COUNT * 2;
+
+/// Updates produced by a single report, never spills to the heap
+pub type Updates = SmallVec<[DeviceStateUpdate; MAX_UPDATES]>;
+
+/// How encoder presses are reported to OpenDeck
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncoderPress {
+    /// As dial presses (default)
+    #[default]
+    Dial,
+
+    /// As presses of keys with the same index, so they can be used as extra buttons
+    Keys,
+}
+
+impl EncoderPress {
+    /// Parses mode name, either `dial` or `keys`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "dial" => Some(Self::Dial),
+            "keys" => Some(Self::Keys),
+            _ => None,
+        }
+    }
+}
+
+/// What happens with input codes no control is known to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownInputs {
+    /// Drop them silently (default)
+    #[default]
+    Ignore,
+
+    /// Log and drop them
+    Log,
+
+    /// Pass them on as raw events to hooks and MQTT
+    Forward,
+}
+
+impl UnknownInputs {
+    /// Parses policy name, `ignore`, `log` or `forward`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "ignore" => Some(Self::Ignore),
+            "log" => Some(Self::Log),
+            "forward" => Some(Self::Forward),
+            _ => None,
+        }
+    }
+}
+
+/// What happens with reports that don't follow the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolErrors {
+    /// Log them and keep going, for daily use (default)
+    #[default]
+    Lenient,
+
+    /// Fail decoding them, so every anomaly shows up as an error, for development
+    Strict,
+}
+
+impl ProtocolErrors {
+    /// Parses policy name, `lenient` or `strict`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "lenient" => Some(Self::Lenient),
+            "strict" => Some(Self::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// Twists of the same encoder closer than this to each other are accelerated
+pub const ACCELERATION_WINDOW: Duration = Duration::from_millis(60);
+
+/// Input code an encoder turn is reported with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderCode {
+    pub code: u8,
+    pub encoder: u8,
+    /// -1 for left, 1 for right
+    pub ticks: i8,
+}
+
+impl EncoderCode {
+    pub const fn new(code: u8, encoder: u8, ticks: i8) -> Self {
+        Self {
+            code,
+            encoder,
+            ticks,
+        }
+    }
+
+    fn input(&self) -> Input {
+        let mut twist = [0; ENCODER_COUNT];
+
+        match twist.get_mut(self.encoder as usize) {
+            Some(ticks) => {
+                *ticks = self.ticks;
+                Input::EncoderTwist(twist)
+            }
+            None => Input::Unknown {
+                code: self.code,
+                state: 0,
+            },
+        }
+    }
+}
+
+/// Every code AKP05E encoders are known to turn with, nothing else decodes encoder turns
+///
+/// Encoder 1 turns with 0xA0/0xA1 on tested units, 0x30/0x31 and 0x60/0x61 come from older notes
+/// and are kept as its aliases until a capture says otherwise.
+pub const AKP05E_ENCODER_CODES: [EncoderCode; 12] = [
+    EncoderCode::new(0xA0, 0, -1),
+    EncoderCode::new(0xA1, 0, 1),
+    EncoderCode::new(0x30, 0, -1),
+    EncoderCode::new(0x31, 0, 1),
+    EncoderCode::new(0x60, 0, -1),
+    EncoderCode::new(0x61, 0, 1),
+    EncoderCode::new(0x50, 1, -1),
+    EncoderCode::new(0x51, 1, 1),
+    EncoderCode::new(0x90, 2, -1),
+    EncoderCode::new(0x91, 2, 1),
+    EncoderCode::new(0x70, 3, -1),
+    EncoderCode::new(0x71, 3, 1),
+];
+
+/// Grace window the plugin uses for the first report after connecting, see [InputOptions::ghost_window]
+pub const GHOST_WINDOW: Duration = Duration::from_millis(300);
+
+/// Settings that change how inputs are turned into updates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputOptions {
+    pub encoder_press: EncoderPress,
+
+    /// Key changes closer than this to the previous one wait until it's over, only the state the
+    /// key ends up in is sent then, zero disables it
+    pub debounce: Duration,
+
+    /// Multiplier for fast encoder twists, 1 disables it
+    pub acceleration: u8,
+
+    /// Reverses direction of every encoder
+    pub invert_encoders: bool,
+
+    /// Lone ticks of these encoders are dropped unless another one in the same direction follows
+    /// within this time, zero disables it
+    pub noise_filter: [Duration; ENCODER_COUNT],
+
+    /// Extra codes encoders turn with, checked before the built-in ones
+    pub encoder_codes: Vec<EncoderCode>,
+
+    /// Codes the device sends status notifications with, none of the AKP05E ones are known yet
+    pub system_codes: Vec<(u8, SystemEvent)>,
+
+    /// The first key or dial state reported this soon after connecting is taken as the state the
+    /// device is in, presses in it are stale ones replayed by the device and dropped along with
+    /// their release, zero disables it
+    pub ghost_window: Duration,
+
+    /// Whether reports without the ACK prefix and unknown codes fail decoding
+    pub protocol_errors: ProtocolErrors,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        Self {
+            encoder_press: EncoderPress::default(),
+            debounce: Duration::ZERO,
+            acceleration: 1,
+            invert_encoders: false,
+            noise_filter: [Duration::ZERO; ENCODER_COUNT],
+            encoder_codes: vec![],
+            system_codes: vec![],
+            ghost_window: Duration::ZERO,
+            protocol_errors: ProtocolErrors::default(),
+        }
+    }
+}
+
+/// Last tick of an encoder with the noise filter on
+#[derive(Debug, Clone, Copy)]
+struct NoiseTick {
+    direction: i8,
+    at: Instant,
+    // Change held back until the tick is confirmed by another one, 0 once it is
+    held: i8,
+}
+
+/// Keeps the last known button/encoder states and turns decoded inputs into updates
+pub struct InputState {
+    supports_both_states: bool,
+    decoder: ReportDecoder,
+    options: InputOptions,
+    buttons: [bool; KEY_COUNT],
+    encoders: [bool; ENCODER_COUNT],
+    button_changes: [Option<Instant>; KEY_COUNT],
+    // States keys changed to within the debounce window, sent once it's over if they still differ
+    bounces: [Option<bool>; KEY_COUNT],
+    twists: [Option<Instant>; ENCODER_COUNT],
+    noise: [Option<NoiseTick>; ENCODER_COUNT],
+    connected_at: Instant,
+    // Whether a key or dial state was reported since connecting, the first one is the snapshot
+    snapshot_taken: bool,
+    // Keys and encoders whose stale press was dropped, so is their release
+    ghost_keys: [bool; KEY_COUNT],
+    ghost_encoders: [bool; ENCODER_COUNT],
+}
+
+impl InputState {
+    /// State of a device with nothing pressed, using default options
+    pub fn new(kind: &Kind) -> Self {
+        Self {
+            // Protocol v3 reports both press and release, older ones only report presses
+            supports_both_states: kind.protocol_version() > 2,
+            decoder: ReportDecoder::for_kind(kind),
+            options: InputOptions::default(),
+            buttons: [false; KEY_COUNT],
+            encoders: [false; ENCODER_COUNT],
+            button_changes: [None; KEY_COUNT],
+            bounces: [None; KEY_COUNT],
+            twists: [None; ENCODER_COUNT],
+            noise: [None; ENCODER_COUNT],
+            connected_at: Instant::now(),
+            snapshot_taken: false,
+            ghost_keys: [false; KEY_COUNT],
+            ghost_encoders: [false; ENCODER_COUNT],
+        }
+    }
+
+    /// Applies input related settings, can be called at any time without losing known states
+    pub fn configure(&mut self, options: InputOptions) {
+        self.decoder.encoder_codes = options.encoder_codes.clone();
+        self.decoder.system_codes = options.system_codes.clone();
+        self.decoder.protocol_errors = options.protocol_errors;
+        self.options = options;
+    }
+
+    /// Decoder for reports of the device, with extra encoder codes from the options
+    pub fn decoder(&self) -> &ReportDecoder {
+        &self.decoder
+    }
+
+    fn encoder_update(&self, index: u8, pressed: bool) -> DeviceStateUpdate {
+        match (self.options.encoder_press, pressed) {
+            (EncoderPress::Dial, true) => DeviceStateUpdate::EncoderDown(index),
+            (EncoderPress::Dial, false) => DeviceStateUpdate::EncoderUp(index),
+            (EncoderPress::Keys, true) => DeviceStateUpdate::ButtonDown(index),
+            (EncoderPress::Keys, false) => DeviceStateUpdate::ButtonUp(index),
+        }
+    }
+
+    /// Compares decoded input with known states, returning what changed
+    pub fn apply(&mut self, input: Input) -> Updates {
+        self.apply_at(input, Instant::now())
+    }
+
+    /// Time left until a key change held back by debouncing is decided, [None] if there's none
+    pub fn settle_in(&self, now: Instant) -> Option<Duration> {
+        (0..KEY_COUNT)
+            .filter(|index| self.bounces[*index].is_some())
+            .filter_map(|index| self.button_changes[index])
+            .map(|last| (last + self.options.debounce).saturating_duration_since(now))
+            .min()
+    }
+
+    /// Sends key changes held back by debouncing whose window is over
+    pub fn settle(&mut self) -> Updates {
+        self.settle_at(Instant::now())
+    }
+
+    /// Same as [InputState::settle], with explicit current time
+    pub fn settle_at(&mut self, now: Instant) -> Updates {
+        let mut updates = Updates::new();
+
+        for index in 0..KEY_COUNT {
+            let over = self.button_changes[index]
+                .is_none_or(|last| now.duration_since(last) >= self.options.debounce);
+
+            if over && let Some(pressed) = self.bounces[index].take() {
+                self.change_button(index, pressed, now, &mut updates);
+            }
+        }
+
+        updates
+    }
+
+    fn change_button(&mut self, index: usize, pressed: bool, now: Instant, updates: &mut Updates) {
+        self.buttons[index] = pressed;
+        self.button_changes[index] = Some(now);
+
+        // OpenDeck never saw the press, so it doesn't get the release either
+        if !pressed && std::mem::take(&mut self.ghost_keys[index]) {
+            return;
+        }
+
+        if !self.supports_both_states {
+            updates.push(DeviceStateUpdate::ButtonDown(index as u8));
+            updates.push(DeviceStateUpdate::ButtonUp(index as u8));
+        } else if pressed {
+            updates.push(DeviceStateUpdate::ButtonDown(index as u8));
+        } else {
+            updates.push(DeviceStateUpdate::ButtonUp(index as u8));
+        }
+    }
+
+    /// Same as [InputState::apply], with explicit time the input was received at
+    pub fn apply_at(&mut self, input: Input, now: Instant) -> Updates {
+        // Changes whose window is over go first, they happened before this input
+        let mut updates = self.settle_at(now);
+
+        if self.take_snapshot(&input, now) {
+            return updates;
+        }
+
+        match input {
+            Input::ButtonStateChange(buttons) => {
+                for (index, their) in buttons.iter().enumerate() {
+                    let bouncing = self.button_changes[index]
+                        .is_some_and(|last| now.duration_since(last) < self.options.debounce);
+
+                    if !self.supports_both_states {
+                        // Presses are all that's reported, so one in the window is a bounce
+                        if *their && !bouncing {
+                            self.change_button(index, true, now, &mut updates);
+                        }
+                    } else if bouncing {
+                        // Releases can't be dropped, the key would stay held. Whatever state it
+                        // ends up in is sent once the window is over.
+                        self.bounces[index] = (*their != self.buttons[index]).then_some(*their);
+                    } else if *their != self.buttons[index] {
+                        self.change_button(index, *their, now, &mut updates);
+                    }
+                }
+            }
+            Input::EncoderStateChange(encoders) => {
+                for (index, (their, mine)) in encoders.iter().zip(self.encoders.iter()).enumerate()
+                {
+                    if !self.supports_both_states {
+                        if *their {
+                            updates.push(self.encoder_update(index as u8, true));
+                            updates.push(self.encoder_update(index as u8, false));
+                        }
+                    } else if their != mine {
+                        if *their {
+                            updates.push(self.encoder_update(index as u8, true));
+                        } else if !std::mem::take(&mut self.ghost_encoders[index]) {
+                            updates.push(self.encoder_update(index as u8, false));
+                        }
+                    }
+                }
+
+                self.encoders = encoders;
+            }
+            Input::EncoderTwist(twist) => {
+                for (index, change) in twist.iter().enumerate() {
+                    if *change == 0 {
+                        continue;
+                    }
+
+                    let window = self.options.noise_filter[index];
+                    let Some(change) = filter_noise(&mut self.noise[index], *change, window, now)
+                    else {
+                        continue;
+                    };
+
+                    let fast = self.twists[index]
+                        .is_some_and(|last| now.duration_since(last) < ACCELERATION_WINDOW);
+                    self.twists[index] = Some(now);
+
+                    let mut change = if fast {
+                        change.saturating_mul(self.options.acceleration.max(1) as i8)
+                    } else {
+                        change
+                    };
+
+                    if self.options.invert_encoders {
+                        change = change.saturating_neg();
+                    }
+
+                    updates.push(DeviceStateUpdate::EncoderTwist(index as u8, change));
+                }
+            }
+            Input::NoData | Input::Touch { .. } | Input::System(_) | Input::Unknown { .. } => {}
+        }
+
+        updates
+    }
+
+    /// Takes the first key or dial state after connecting as the one the device is in, returns
+    /// whether this input was it
+    ///
+    /// The device can't be asked which keys are held, but the first report after a reconnect is
+    /// the one it replays from before. What it has down is stored as already known instead of
+    /// being sent as a press, and the release of it is dropped later. Presses after it are
+    /// real ones and pass, even within the window.
+    fn take_snapshot(&mut self, input: &Input, now: Instant) -> bool {
+        if self.snapshot_taken
+            || !matches!(
+                input,
+                Input::ButtonStateChange(_) | Input::EncoderStateChange(_)
+            )
+        {
+            return false;
+        }
+
+        self.snapshot_taken = true;
+
+        if now.saturating_duration_since(self.connected_at) >= self.options.ghost_window {
+            return false;
+        }
+
+        // Without releases in the protocol there's nothing to keep track of, the press is dropped
+        let (reported, known, ghosts): (&[bool], &mut [bool], &mut [bool]) = match input {
+            Input::ButtonStateChange(buttons) => (buttons, &mut self.buttons, &mut self.ghost_keys),
+            Input::EncoderStateChange(encoders) => {
+                (encoders, &mut self.encoders, &mut self.ghost_encoders)
+            }
+            _ => return false,
+        };
+
+        for (index, pressed) in reported.iter().enumerate() {
+            if *pressed && !known[index] && self.supports_both_states {
+                log::info!("Dropping press in the first report after connecting, it's a stale one");
+
+                known[index] = true;
+                ghosts[index] = true;
+            }
+        }
+
+        true
+    }
+}
+
+/// Holds back a lone encoder tick, [None] until another one in the same direction confirms it
+///
+/// Once confirmed, ticks in the same direction pass right away as long as they keep coming
+/// within `window`.
+fn filter_noise(
+    last: &mut Option<NoiseTick>,
+    change: i8,
+    window: Duration,
+    now: Instant,
+) -> Option<i8> {
+    if window.is_zero() {
+        return Some(change);
+    }
+
+    match last {
+        Some(tick) if tick.direction == change.signum() && now.duration_since(tick.at) < window => {
+            tick.at = now;
+
+            Some(change.saturating_add(std::mem::take(&mut tick.held)))
+        }
         _ => {
-            // Return empty state change instead of error to prevent disconnections
-            Ok(DeviceInput::ButtonStateChange(vec![false; 10]))
-        },
+            *last = Some(NoiseTick {
+                direction: change.signum(),
+                at: now,
+                held: change,
+            });
+
+            None
+        }
+    }
+}
+
+/// Physical control an update comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    Key(u8),
+    Encoder(u8),
+}
+
+impl Control {
+    /// Control of a press or release update along with whether it got pressed, [None] for twists
+    pub fn of(update: &DeviceStateUpdate) -> Option<(Self, bool)> {
+        match *update {
+            DeviceStateUpdate::ButtonDown(key) => Some((Self::Key(key), true)),
+            DeviceStateUpdate::ButtonUp(key) => Some((Self::Key(key), false)),
+            DeviceStateUpdate::EncoderDown(encoder) => Some((Self::Encoder(encoder), true)),
+            DeviceStateUpdate::EncoderUp(encoder) => Some((Self::Encoder(encoder), false)),
+            DeviceStateUpdate::EncoderTwist(..) => None,
+        }
+    }
+}
+
+/// What should happen with an update before it reaches OpenDeck
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Forward to OpenDeck as usual
+    Dispatch,
+    /// Swallow it
+    Suppress,
+    /// Swallow it, it completed an unlock gesture
+    Unlock,
+}
+
+/// Prefix of input reports, firmware with protocol version 0 doesn't send it
+const ACK: [u8; 3] = [65, 67, 75];
+
+/// Shortest report that still has an input code and a state byte
+pub const MIN_REPORT_LENGTH: usize = 11;
+
+/// Input code and state byte of a raw report, only made from reports long enough to hold them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputReport {
+    pub code: u8,
+    pub state: u8,
+
+    /// Byte after the state, some firmware puts the contact size of touches there
+    ///
+    /// [None] when it's missing or zero, which is all tested AKP05E units ever send.
+    pub contact: Option<u8>,
+}
+
+impl InputReport {
+    /// Reads a raw report, [None] if it doesn't carry an input and an error if it's cut short
+    pub fn parse(report: &[u8], protocol_version: usize) -> Result<Option<Self>, MirajazzError> {
+        if !report.starts_with(&ACK) && protocol_version > 0 {
+            return Ok(None);
+        }
+
+        match report {
+            [_, _, _, _, _, _, _, _, _, code, state, rest @ ..] => Ok(Some(Self {
+                code: *code,
+                state: *state,
+                contact: rest.first().copied().filter(|contact| *contact != 0),
+            })),
+            _ => Err(MirajazzError::BadData),
+        }
+    }
+
+    /// State byte as decoding sees it, protocols before version 3 only report presses
+    pub fn effective_state(&self, protocol_version: usize) -> u8 {
+        if protocol_version > 2 {
+            self.state
+        } else {
+            0x01
+        }
+    }
+}
+
+/// Maps a parsed report to an input, gets the whole report too for inputs carrying more than the
+/// code and state byte
+pub type DecodeTable = fn(&InputReport, &[u8]) -> Result<Input, MirajazzError>;
+
+/// Decodes raw reports of a device, with the code table picked for its protocol version
+///
+/// Every supported device speaks version 3 with AKP05E codes for now. Variants with other
+/// protocols or firmware get their own table here, callers keep using the same API.
+#[derive(Debug, Clone)]
+pub struct ReportDecoder {
+    protocol_version: usize,
+    table: DecodeTable,
+    encoder_codes: Vec<EncoderCode>,
+    system_codes: Vec<(u8, SystemEvent)>,
+    protocol_errors: ProtocolErrors,
+}
+
+impl ReportDecoder {
+    /// Decoder for reports sent with this protocol version
+    pub fn new(protocol_version: usize) -> Self {
+        // All supported devices are AKP05 variants using AKP05E codes on every version so far
+        let table: DecodeTable = decode_akp05e_report;
+
+        Self {
+            protocol_version,
+            table,
+            encoder_codes: vec![],
+            system_codes: vec![],
+            protocol_errors: ProtocolErrors::default(),
+        }
+    }
+
+    /// Decodes reports with another table, for variants whose reports carry more than AKP05E ones
+    pub fn with_table(self, table: DecodeTable) -> Self {
+        Self { table, ..self }
+    }
+
+    /// Decoder for reports of this kind of device
+    pub fn for_kind(kind: &Kind) -> Self {
+        Self::new(kind.protocol_version())
+    }
+
+    pub fn protocol_version(&self) -> usize {
+        self.protocol_version
+    }
+
+    /// Parses a raw input report the same way mirajazz reader does, then decodes it
+    ///
+    /// Short reports, e.g. from flaky cables, are [MirajazzError::BadData] instead of a panic.
+    /// With [ProtocolErrors::Strict], so are reports without the ACK prefix and unknown codes.
+    pub fn decode(&self, report: &[u8]) -> Result<Input, MirajazzError> {
+        let strict = self.protocol_errors == ProtocolErrors::Strict;
+
+        let Some(input) = InputReport::parse(report, self.protocol_version)? else {
+            if strict && !report.is_empty() {
+                log::debug!("Report without ACK prefix: {:02X?}", report);
+                return Err(MirajazzError::BadData);
+            }
+
+            return Ok(Input::NoData);
+        };
+
+        let decoded = self.decode_parsed(
+            &InputReport {
+                state: input.effective_state(self.protocol_version),
+                ..input
+            },
+            report,
+        )?;
+
+        match decoded {
+            Input::Unknown { code, state } if strict => {
+                log::debug!("Unknown input code 0x{:02X} (state {})", code, state);
+                Err(MirajazzError::BadData)
+            }
+            decoded => Ok(decoded),
+        }
+    }
+
+    fn decode_parsed(&self, input: &InputReport, report: &[u8]) -> Result<Input, MirajazzError> {
+        if let Some(turn) = self
+            .encoder_codes
+            .iter()
+            .find(|turn| turn.code == input.code)
+        {
+            return Ok(turn.input());
+        }
+
+        if let Some((_, event)) = self
+            .system_codes
+            .iter()
+            .find(|(code, _)| *code == input.code)
+        {
+            return Ok(Input::System(*event));
+        }
+
+        (self.table)(input, report)
+    }
+
+    /// Input carried by a raw report, [None] if the report doesn't carry one or is cut short
+    pub fn report(&self, report: &[u8]) -> Option<InputReport> {
+        InputReport::parse(report, self.protocol_version)
+            .ok()
+            .flatten()
+    }
+
+    /// Maps input code and state byte to an input, as if the rest of the report was empty
+    pub fn decode_input(&self, code: u8, state: u8) -> Result<Input, MirajazzError> {
+        let input = InputReport {
+            code,
+            state,
+            contact: None,
+        };
+
+        self.decode_parsed(&input, &[])
+    }
+
+    /// Input code and state byte of a raw report, [None] if the report doesn't carry an input
+    pub fn code(&self, report: &[u8]) -> Option<(u8, u8)> {
+        self.report(report).map(|input| (input.code, input.state))
+    }
+
+    /// Code and state byte of a report, only if the code is one no control is known to send
+    pub fn unknown_code(&self, code: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let (code, state) = code?;
+
+        matches!(self.decode_input(code, state), Ok(Input::Unknown { .. })).then_some((code, state))
+    }
+
+    /// Status notification sent with this code and state byte, if it is one
+    pub fn system_event(&self, code: Option<(u8, u8)>) -> Option<SystemEvent> {
+        let (code, state) = code?;
+
+        match self.decode_input(code, state) {
+            Ok(Input::System(event)) => Some(event),
+            _ => None,
+        }
     }
 }
 
-// AKP05E button state reading
-fn read_button_states(states: &[u8]) -> Vec<bool> {
-    let mut bools = vec![];
+fn decode_akp05e_report(report: &InputReport, _raw: &[u8]) -> Result<Input, MirajazzError> {
+    let (input, state) = (report.code, report.state);
 
-    for i in 0..KEY_COUNT {
-        bools.push(states[i + 1] != 0);
+    // Encoder rotations
+    if let Some(turn) = AKP05E_ENCODER_CODES.iter().find(|turn| turn.code == input) {
+        return Ok(turn.input());
     }
 
-    bools
+    match input {
+        // 10 buttons for AKP05E (1-10, using 1-based indexing)
+        0x01..=0x0A => read_akp05e_button_press(input, state),
+        // Encoder button presses (including the new knob 1 click)
+        0x33..=0x37 => read_akp05e_encoder_press(input, state),
+        // Touchscreen inputs, with the contact size if the firmware sends it
+        0x40..=0x4F => read_akp05e_touchscreen(report),
+        // Unknown inputs - don't error to prevent disconnections, but don't touch any state either
+        _ => Ok(Input::Unknown { code: input, state }),
+    }
 }
 
 // AKP05E button press handling
-fn read_akp05e_button_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+fn read_akp05e_button_press(input: u8, state: u8) -> Result<Input, MirajazzError> {
     // Convert 1-based input (0x01-0x0A) to 0-based physical button index (0-9)
     let physical_button = (input - 1) as usize;
     
@@ -52,81 +780,501 @@ fn read_akp05e_button_press(input: u8, state: u8) -> Result<DeviceInput, Mirajaz
     // For button presses, use 1:1 mapping - physical button equals UI position
     let ui_position = physical_button;
 
-    let mut button_states = vec![0x01];
-    button_states.extend(vec![0u8; KEY_COUNT + 1]); // 10 buttons + 1
-    
-    button_states[ui_position + 1] = state;
-
-    Ok(DeviceInput::ButtonStateChange(read_button_states(
-        &button_states,
-    )))
-}
-
-// AKP05E encoder value handling  
-fn read_akp05e_encoder_value(input: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut encoder_values = vec![0i8; ENCODER_COUNT]; // AKP05E has 4 encoders
-
-    let (encoder, value): (usize, i8) = match input {
-        // Encoder 1 (primary codes)
-        0x30 => (0, -1), // encoder 1 left
-        0x31 => (0, 1),  // encoder 1 right
-        // Encoder 2 (from your testing: 0x50/0x51)
-        0x50 => (1, -1), // encoder 2 left
-        0x51 => (1, 1),  // encoder 2 right
-        // Encoder 3 (from your testing: 0x90/0x91)
-        0x90 => (2, -1), // encoder 3 left
-        0x91 => (2, 1),  // encoder 3 right
-        // Encoder 4 (needs testing)
-        0x70 => (3, -1), // encoder 4 left
-        0x71 => (3, 1),  // encoder 4 right
-        _ => return Err(MirajazzError::BadData),
-    };
-
-    encoder_values[encoder] = value;
-    Ok(DeviceInput::EncoderTwist(encoder_values))
-}
-
-// Alternative encoder mappings (encoder 1 has alternate codes)
-fn read_akp05e_encoder_value_alt(input: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut encoder_values = vec![0i8; ENCODER_COUNT]; // AKP05E has 4 encoders
-
-    let (encoder, value): (usize, i8) = match input {
-        // Encoder 1 alternate mappings (from your testing)
-        0xA0 => (0, -1), // encoder 1 left
-        0xA1 => (0, 1),  // encoder 1 right
-        _ => {
-            log::warn!("Unknown alternative encoder input: 0x{:02X}", input);
-            return Ok(DeviceInput::ButtonStateChange(vec![false; 10]));
-        }
-    };
+    let mut button_states = [false; KEY_COUNT];
+    button_states[ui_position] = state != 0;
 
-    encoder_values[encoder] = value;
-    Ok(DeviceInput::EncoderTwist(encoder_values))
+    Ok(Input::ButtonStateChange(button_states))
 }
 
-// Touchscreen input handler - just log for now
-fn read_akp05e_touchscreen(_input: u8, _state: u8) -> Result<DeviceInput, MirajazzError> {
-    // Touchscreen input detected but not implemented for UI interaction
-    
-    // Return empty state to avoid interfering with main button layout
-    Ok(DeviceInput::ButtonStateChange(vec![false; 10]))
+// Touchscreen input handler, contact size comes from the byte after the state
+fn read_akp05e_touchscreen(report: &InputReport) -> Result<Input, MirajazzError> {
+    // Touches don't change key or dial states, sliders pick them up from the report
+    Ok(Input::Touch {
+        code: report.code,
+        contact: report.contact,
+    })
 }
 
 // AKP05E encoder press handling (corrected based on testing)
-fn read_akp05e_encoder_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut encoder_states = vec![false; ENCODER_COUNT]; // AKP05E has 4 encoders
+fn read_akp05e_encoder_press(input: u8, state: u8) -> Result<Input, MirajazzError> {
+    let mut encoder_states = [false; ENCODER_COUNT]; // AKP05E has 4 encoders
 
     let encoder: usize = match input {
         0x37 => 0, // Knob 1 click 
         0x35 => 1, // Knob 2 click
         0x33 => 2, // Knob 3 click
         0x36 => 3, // Knob 4 click
-        _ => {
-            log::warn!("Unknown encoder button: 0x{:02X}", input);
-            return Ok(DeviceInput::ButtonStateChange(vec![false; 10]));
-        }
+        _ => return Ok(Input::Unknown { code: input, state }),
     };
 
     encoder_states[encoder] = state != 0;
-    Ok(DeviceInput::EncoderStateChange(encoder_states))
+    Ok(Input::EncoderStateChange(encoder_states))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoder_presses_can_be_reported_as_keys() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            encoder_press: EncoderPress::Keys,
+            ..InputOptions::default()
+        });
+
+        let mut pressed = [false; ENCODER_COUNT];
+        pressed[1] = true;
+
+        let updates = state.apply(Input::EncoderStateChange(pressed));
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonDown(1)]));
+
+        let updates = state.apply(Input::EncoderStateChange([false; ENCODER_COUNT]));
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonUp(1)]));
+    }
+
+    #[test]
+    fn lone_encoder_ticks_are_filtered() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        let mut noise_filter = [Duration::ZERO; ENCODER_COUNT];
+        noise_filter[2] = Duration::from_millis(100);
+        state.configure(InputOptions {
+            noise_filter,
+            ..InputOptions::default()
+        });
+
+        let twist = |encoder: usize, change: i8| {
+            let mut twist = [0; ENCODER_COUNT];
+            twist[encoder] = change;
+            Input::EncoderTwist(twist)
+        };
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // Spurious tick, then one the other way and one too late
+        assert!(state.apply_at(twist(2, 1), at(0)).is_empty());
+        assert!(state.apply_at(twist(2, -1), at(50)).is_empty());
+        assert!(state.apply_at(twist(2, -1), at(200)).is_empty());
+
+        // Second tick brings the held one along, the rest pass right away
+        let updates = state.apply_at(twist(2, -1), at(250));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(2, -2)]
+        ));
+        let updates = state.apply_at(twist(2, -1), at(330));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(2, -1)]
+        ));
+
+        // Other encoders aren't filtered
+        let updates = state.apply_at(twist(1, 1), at(340));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(1, 1)]
+        ));
+    }
+
+    #[test]
+    fn report_codes_are_read() {
+        let mut report = vec![65, 67, 75, 0, 0, 0, 0, 0, 0, 0x37, 0x01, 0];
+
+        assert_eq!(ReportDecoder::new(3).code(&report), Some((0x37, 0x01)));
+
+        report[0] = 0;
+        assert_eq!(ReportDecoder::new(3).code(&report), None);
+        assert_eq!(ReportDecoder::new(0).code(&report), Some((0x37, 0x01)));
+        assert_eq!(ReportDecoder::new(0).code(&report[..10]), None);
+
+        // Protocols before version 3 only report presses, whatever the state byte says
+        report[10] = 0x00;
+        assert_eq!(
+            ReportDecoder::new(0).decode(&report).unwrap(),
+            Input::EncoderStateChange([true, false, false, false])
+        );
+    }
+
+    #[test]
+    fn short_reports_are_errors() {
+        let report = crate::transport::build_report(0x04, 0x01);
+
+        for len in 0..MIN_REPORT_LENGTH {
+            // Without the whole ACK prefix they are just not input reports
+            match ReportDecoder::new(3).decode(&report[..len]) {
+                Ok(Input::NoData) => assert!(len < ACK.len()),
+                Err(MirajazzError::BadData) => assert!(len >= ACK.len()),
+                other => panic!("Unexpected {:?} for {} bytes", other, len),
+            }
+            assert_eq!(ReportDecoder::new(3).code(&report[..len]), None);
+        }
+
+        assert_eq!(
+            InputReport::parse(&report[..MIN_REPORT_LENGTH], 3).unwrap(),
+            Some(InputReport {
+                code: 0x04,
+                state: 0x01,
+                contact: None,
+            })
+        );
+        assert!(ReportDecoder::new(0).decode(&[]).is_err());
+    }
+
+    #[test]
+    fn strict_decoding_fails_on_anomalies() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        let report = crate::transport::build_report(0xE3, 0x01);
+        let mut foreign = report.clone();
+        foreign[0] = 0;
+
+        // Lenient decoding lets them through as inputs that change nothing
+        assert!(matches!(
+            state.decoder().decode(&report),
+            Ok(Input::Unknown { code: 0xE3, .. })
+        ));
+        assert_eq!(state.decoder().decode(&foreign).unwrap(), Input::NoData);
+
+        state.configure(InputOptions {
+            protocol_errors: ProtocolErrors::Strict,
+            ..InputOptions::default()
+        });
+
+        let decoder = state.decoder();
+        assert!(matches!(
+            decoder.decode(&report),
+            Err(MirajazzError::BadData)
+        ));
+        assert!(matches!(
+            decoder.decode(&foreign),
+            Err(MirajazzError::BadData)
+        ));
+        assert_eq!(decoder.decode(&[]).unwrap(), Input::NoData);
+        assert!(matches!(
+            decoder.decode(&crate::transport::build_report(0x04, 0x01)),
+            Ok(Input::ButtonStateChange(_))
+        ));
+        assert_eq!(
+            ProtocolErrors::parse(" Strict"),
+            Some(ProtocolErrors::Strict)
+        );
+        assert_eq!(ProtocolErrors::parse("pedantic"), None);
+    }
+
+    #[test]
+    fn encoder_codes_decode_to_their_encoder() {
+        let decoder = ReportDecoder::for_kind(&Kind::Akp05E);
+
+        for (index, turn) in AKP05E_ENCODER_CODES.iter().enumerate() {
+            // Codes are listed once and don't clash with keys, presses or the touchscreen
+            assert!(
+                AKP05E_ENCODER_CODES[index + 1..]
+                    .iter()
+                    .all(|other| other.code != turn.code)
+            );
+            assert!(!matches!(turn.code, 0x01..=0x0A | 0x33..=0x37 | 0x40..=0x4F));
+
+            let mut twist = [0; ENCODER_COUNT];
+            twist[turn.encoder as usize] = turn.ticks;
+            assert_eq!(
+                decoder.decode_input(turn.code, 0x01).unwrap(),
+                Input::EncoderTwist(twist)
+            );
+        }
+
+        // Every encoder turns both ways
+        for encoder in 0..ENCODER_COUNT as u8 {
+            for ticks in [-1, 1] {
+                assert!(
+                    AKP05E_ENCODER_CODES
+                        .iter()
+                        .any(|turn| turn.encoder == encoder && turn.ticks == ticks)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn extra_encoder_codes_come_first() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            encoder_codes: vec![EncoderCode::new(0x62, 1, -1), EncoderCode::new(0x51, 1, -1)],
+            ..InputOptions::default()
+        });
+
+        let decoder = state.decoder();
+        assert_eq!(
+            decoder.decode_input(0x62, 0x01).unwrap(),
+            Input::EncoderTwist([0, -1, 0, 0])
+        );
+        assert_eq!(
+            decoder.decode_input(0x51, 0x01).unwrap(),
+            Input::EncoderTwist([0, -1, 0, 0])
+        );
+        assert_eq!(decoder.unknown_code(Some((0x62, 0x01))), None);
+    }
+
+    #[test]
+    fn system_codes_are_not_inputs() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            system_codes: vec![(0xE0, SystemEvent::Wake)],
+            ..InputOptions::default()
+        });
+
+        let updates = state.apply(state.decoder().decode_input(0x04, 0x01).unwrap());
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonDown(3)]));
+
+        let input = state.decoder().decode_input(0xE0, 0x01).unwrap();
+        assert_eq!(input, Input::System(SystemEvent::Wake));
+        assert!(state.apply(input).is_empty());
+
+        let decoder = state.decoder();
+        assert_eq!(
+            decoder.system_event(Some((0xE0, 0x00))),
+            Some(SystemEvent::Wake)
+        );
+        assert_eq!(decoder.system_event(Some((0x04, 0x01))), None);
+        assert_eq!(decoder.unknown_code(Some((0xE0, 0x00))), None);
+    }
+
+    #[test]
+    fn unknown_codes_dont_release_held_keys() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        let decoder = ReportDecoder::for_kind(&Kind::Akp05E);
+
+        let updates = state.apply(decoder.decode_input(0x04, 0x01).unwrap());
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonDown(3)]));
+
+        for (code, byte) in [(0xE3, 0x00), (0x34, 0x01), (0x42, 0x01)] {
+            assert!(
+                state
+                    .apply(decoder.decode_input(code, byte).unwrap())
+                    .is_empty()
+            );
+        }
+
+        assert_eq!(decoder.unknown_code(Some((0xE3, 0x00))), Some((0xE3, 0x00)));
+        assert_eq!(decoder.unknown_code(Some((0x42, 0x01))), None);
+        assert_eq!(decoder.unknown_code(Some((0x04, 0x01))), None);
+
+        let updates = state.apply(decoder.decode_input(0x04, 0x00).unwrap());
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonUp(3)]));
+    }
+
+    #[test]
+    fn touch_contact_size_is_read_if_sent() {
+        let decoder = ReportDecoder::for_kind(&Kind::Akp05E);
+        let mut report = crate::transport::build_report(0x42, 0x01);
+
+        assert_eq!(
+            decoder.decode(&report).unwrap(),
+            Input::Touch {
+                code: 0x42,
+                contact: None
+            }
+        );
+
+        report[11] = 0x1C;
+        assert_eq!(
+            decoder.decode(&report).unwrap(),
+            Input::Touch {
+                code: 0x42,
+                contact: Some(0x1C)
+            }
+        );
+        assert_eq!(decoder.report(&report).unwrap().contact, Some(0x1C));
+        assert_eq!(decoder.report(&report[..11]).unwrap().contact, None);
+    }
+
+    #[test]
+    fn tables_get_the_whole_report() {
+        // Variant sending the touched step as a coordinate after the state byte
+        let decoder = ReportDecoder::new(3).with_table(|input, raw| match raw.get(12) {
+            Some(step) if input.code == 0x40 => Ok(Input::Touch {
+                code: 0x40 + step,
+                contact: input.contact,
+            }),
+            _ => Ok(Input::NoData),
+        });
+
+        let mut report = crate::transport::build_report(0x40, 0x01);
+        report[11] = 0x10;
+        report[12] = 0x05;
+        assert_eq!(
+            decoder.decode(&report).unwrap(),
+            Input::Touch {
+                code: 0x45,
+                contact: Some(0x10)
+            }
+        );
+    }
+
+    #[test]
+    fn stale_presses_after_connecting_are_dropped() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            ghost_window: Duration::from_millis(300),
+            ..InputOptions::default()
+        });
+
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let decoder = ReportDecoder::for_kind(&Kind::Akp05E);
+        let mut apply = |code, byte, millis| {
+            state.apply_at(decoder.decode_input(code, byte).unwrap(), at(millis))
+        };
+
+        // Twists don't count as the snapshot
+        assert!(matches!(
+            apply(0x51, 0x01, 5)[..],
+            [DeviceStateUpdate::EncoderTwist(1, 1)]
+        ));
+
+        // Replayed press of a key in the first report, and its release
+        assert!(apply(0x04, 0x01, 10).is_empty());
+        assert!(apply(0x04, 0x00, 400).is_empty());
+
+        // A real press right after the snapshot passes, still within the window
+        assert!(matches!(
+            apply(0x37, 0x01, 20)[..],
+            [DeviceStateUpdate::EncoderDown(_)]
+        ));
+        assert!(matches!(
+            apply(0x37, 0x00, 80)[..],
+            [DeviceStateUpdate::EncoderUp(_)]
+        ));
+        assert!(matches!(
+            apply(0x04, 0x01, 500)[..],
+            [DeviceStateUpdate::ButtonDown(3)]
+        ));
+        assert!(matches!(
+            apply(0x04, 0x00, 600)[..],
+            [DeviceStateUpdate::ButtonUp(3)]
+        ));
+
+        // Nothing is dropped when the first report comes after the window
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            ghost_window: Duration::from_millis(300),
+            ..InputOptions::default()
+        });
+        assert!(matches!(
+            state.apply_at(decoder.decode_input(0x04, 0x01).unwrap(), at(400))[..],
+            [DeviceStateUpdate::ButtonDown(3)]
+        ));
+    }
+
+    #[test]
+    fn encoder_press_mode_is_parsed() {
+        assert_eq!(EncoderPress::parse("Keys"), Some(EncoderPress::Keys));
+        assert_eq!(EncoderPress::parse(" dial"), Some(EncoderPress::Dial));
+        assert_eq!(EncoderPress::parse("buttons"), None);
+    }
+
+    #[test]
+    fn key_chatter_is_debounced() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            debounce: Duration::from_millis(20),
+            ..InputOptions::default()
+        });
+
+        let mut pressed = [false; KEY_COUNT];
+        pressed[3] = true;
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let updates = state.apply_at(Input::ButtonStateChange(pressed), at(0));
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonDown(3)]));
+
+        // Bounces right after the press wait for the window to be over
+        assert!(
+            state
+                .apply_at(Input::ButtonStateChange([false; KEY_COUNT]), at(5))
+                .is_empty()
+        );
+        assert!(
+            state
+                .apply_at(Input::ButtonStateChange(pressed), at(10))
+                .is_empty()
+        );
+        assert_eq!(state.settle_in(at(10)), None);
+        assert!(state.settle_at(at(30)).is_empty());
+
+        let updates = state.apply_at(Input::ButtonStateChange([false; KEY_COUNT]), at(50));
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonUp(3)]));
+
+        // A quick tap still gets its release, once the window is over
+        let updates = state.apply_at(Input::ButtonStateChange(pressed), at(100));
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonDown(3)]));
+        assert!(
+            state
+                .apply_at(Input::ButtonStateChange([false; KEY_COUNT]), at(110))
+                .is_empty()
+        );
+        assert_eq!(state.settle_in(at(110)), Some(Duration::from_millis(10)));
+        assert!(state.settle_at(at(115)).is_empty());
+
+        let updates = state.settle_at(at(120));
+        assert!(matches!(updates[..], [DeviceStateUpdate::ButtonUp(3)]));
+        assert_eq!(state.settle_in(at(120)), None);
+    }
+
+    #[test]
+    fn fast_twists_are_accelerated() {
+        let mut state = InputState::new(&Kind::Akp05E);
+        state.configure(InputOptions {
+            acceleration: 3,
+            ..InputOptions::default()
+        });
+
+        let mut twist = [0i8; ENCODER_COUNT];
+        twist[2] = -1;
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let updates = state.apply_at(Input::EncoderTwist(twist), at(0));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(2, -1)]
+        ));
+
+        let updates = state.apply_at(Input::EncoderTwist(twist), at(30));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(2, -3)]
+        ));
+
+        let updates = state.apply_at(Input::EncoderTwist(twist), at(500));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(2, -1)]
+        ));
+    }
+
+    #[test]
+    fn encoders_can_be_inverted_without_losing_state() {
+        let mut state = InputState::new(&Kind::Akp05E);
+
+        let mut pressed = [false; ENCODER_COUNT];
+        pressed[0] = true;
+        state.apply(Input::EncoderStateChange(pressed));
+
+        state.configure(InputOptions {
+            invert_encoders: true,
+            ..InputOptions::default()
+        });
+
+        let mut twist = [0i8; ENCODER_COUNT];
+        twist[0] = 1;
+        let updates = state.apply(Input::EncoderTwist(twist));
+        assert!(matches!(
+            updates[..],
+            [DeviceStateUpdate::EncoderTwist(0, -1)]
+        ));
+
+        // Encoder is still known to be held, so releasing it is reported
+        let updates = state.apply(Input::EncoderStateChange([false; ENCODER_COUNT]));
+        assert!(matches!(updates[..], [DeviceStateUpdate::EncoderUp(0)]));
+    }
 }
@@ -0,0 +1,176 @@
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::text::{TextStyle, render_lines};
+use time::{OffsetDateTime, UtcOffset};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG,
+    clock::{OFFSET_REFRESH_TICKS, clock_lines, local_offset},
+    stats::Sampler,
+    writer::KeyPainter,
+};
+
+/// How often labels are checked for changes, changed variables are drawn right away
+pub const LABEL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size labels are rendered at, images are resized to the device format anyway
+pub const LABEL_SIZE: (u32, u32) = (120, 120);
+
+/// Templates for every position they are shown on
+pub type Labels = BTreeMap<u8, String>;
+
+/// Values of `{var:name}` placeholders
+pub type Variables = BTreeMap<String, String>;
+
+// Variables set while running, they win over the ones from config
+static VARIABLES: LazyLock<Mutex<Variables>> = LazyLock::new(|| Mutex::new(Variables::new()));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Sets a variable until the plugin exits, labels using it are drawn again right away
+pub fn set_variable(name: &str, value: String) {
+    VARIABLES.lock().unwrap().insert(name.to_string(), value);
+    CHANGED.notify_one();
+}
+
+/// Replaces every `{name}` in the template with what `value` returns for it
+///
+/// Placeholders `value` doesn't know are kept as they are, so typos show up on the key.
+pub fn expand(template: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut text = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+
+        let placeholder = &rest[..=end];
+
+        match value(&placeholder[1..end]) {
+            Some(value) => text.push_str(&value),
+            None => text.push_str(placeholder),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    text.push_str(rest);
+
+    text
+}
+
+/// Draws `labels` on every connected device
+///
+/// Labels are drawn again only when their text changes, e.g. `{time}` once a minute.
+pub async fn labels_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut sampler = Sampler::default();
+    let mut offset = UtcOffset::UTC;
+    let mut ticks = 0u64;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(LABEL_INTERVAL) => {}
+            _ = CHANGED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+
+        let (labels, h24, mut variables) = {
+            let config = CONFIG.borrow();
+
+            (
+                config.labels.clone(),
+                config.clock_24h,
+                config.variables.clone(),
+            )
+        };
+
+        painter.retain(|_, position| labels.contains_key(&position));
+
+        if labels.is_empty() {
+            continue;
+        }
+
+        variables.extend(VARIABLES.lock().unwrap().clone());
+
+        if ticks.is_multiple_of(OFFSET_REFRESH_TICKS) {
+            offset = tokio::task::spawn_blocking(local_offset)
+                .await
+                .unwrap_or(UtcOffset::UTC);
+        }
+        ticks += 1;
+
+        let [time, date] = clock_lines(OffsetDateTime::now_utc().to_offset(offset), h24);
+
+        // Stats are only read if a label shows them, and only once per refresh
+        let mut cpu = None;
+        let mut ram = None;
+
+        for (position, template) in labels {
+            let text = expand(&template, |name| match name {
+                "time" => Some(time.clone()),
+                "date" => Some(date.clone()),
+                "cpu" => Some(
+                    cpu.get_or_insert_with(|| {
+                        sampler
+                            .cpu()
+                            .map_or("--".to_string(), |percent| format!("{}%", percent))
+                    })
+                    .clone(),
+                ),
+                "ram" => Some(
+                    ram.get_or_insert_with(|| match sampler.ram() {
+                        Some((used, total)) if total > 0 => format!("{}%", used * 100 / total),
+                        _ => "--".to_string(),
+                    })
+                    .clone(),
+                ),
+                // Unset variables are empty, they may just not be known yet
+                name => name
+                    .strip_prefix("var:")
+                    .map(|name| variables.get(name).cloned().unwrap_or_default()),
+            });
+
+            let lines: Vec<String> = text.lines().map(str::to_string).collect();
+            let image_lines = lines.clone();
+
+            painter
+                .paint(position, lines, || {
+                    let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+
+                    render_lines(LABEL_SIZE, &lines, &[], TextStyle::default())
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced() {
+        let value = |name: &str| match name {
+            "time" => Some("14:05".to_string()),
+            "var:scene" => Some("Live".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(expand("{time}", value), "14:05");
+        assert_eq!(expand("Scene: {var:scene}!", value), "Scene: Live!");
+        assert_eq!(expand("{time}\n{var:scene}", value), "14:05\nLive");
+        assert_eq!(expand("{nope} {time", value), "{nope} {time");
+        assert_eq!(expand("{{time}}", value), "{{time}}");
+    }
+}
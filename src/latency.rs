@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+/// Environment variable that turns latency measurement on when set to anything
+pub const LATENCY_ENV: &str = "OPENDECK_AKP05_MEASURE_LATENCY";
+
+/// Inputs taking longer than this from report to OpenDeck are logged right away
+pub const SPIKE_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// How many inputs a logged summary covers
+pub const SUMMARY_INPUTS: usize = 100;
+
+/// Percentiles of input latencies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Summary {
+    p50: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+/// Nearest-rank percentiles, [None] without samples
+fn summarize(samples: &[Duration]) -> Option<Summary> {
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+
+    let max = *sorted.last()?;
+    let rank = |percent: usize| sorted[(sorted.len() * percent).div_ceil(100).max(1) - 1];
+
+    Some(Summary {
+        p50: rank(50),
+        p99: rank(99),
+        max,
+    })
+}
+
+/// Times inputs from the arrival of their report until they are sent to OpenDeck
+///
+/// Meant for reports of laggy buttons, the logged numbers can be attached to an issue.
+#[derive(Debug)]
+pub struct LatencyMeter {
+    id: String,
+    samples: Vec<Duration>,
+}
+
+impl LatencyMeter {
+    /// Starts measuring if [LATENCY_ENV] is set
+    pub fn from_env(id: &str) -> Option<Self> {
+        std::env::var_os(LATENCY_ENV)?;
+
+        log::info!("Measuring input latency of {}", id);
+
+        Some(Self {
+            id: id.to_string(),
+            samples: Vec::with_capacity(SUMMARY_INPUTS),
+        })
+    }
+
+    /// Records an input whose report arrived at `arrived` and was just dispatched
+    pub fn record(&mut self, arrived: Instant) {
+        let latency = arrived.elapsed();
+
+        if latency >= SPIKE_THRESHOLD {
+            log::warn!("Input latency spike on {}: {:?}", self.id, latency);
+        }
+
+        self.samples.push(latency);
+
+        if self.samples.len() < SUMMARY_INPUTS {
+            return;
+        }
+
+        if let Some(summary) = summarize(&self.samples) {
+            log::info!(
+                "Input latency of {} over {} inputs: p50 {:?}, p99 {:?}, max {:?}",
+                self.id,
+                self.samples.len(),
+                summary.p50,
+                summary.p99,
+                summary.max
+            );
+        }
+
+        self.samples.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let samples: Vec<Duration> = (1..=200).rev().map(Duration::from_millis).collect();
+
+        assert_eq!(
+            summarize(&samples),
+            Some(Summary {
+                p50: Duration::from_millis(100),
+                p99: Duration::from_millis(198),
+                max: Duration::from_millis(200),
+            })
+        );
+
+        let single = [Duration::from_millis(3)];
+        assert_eq!(
+            summarize(&single).map(|summary| summary.p99),
+            Some(single[0])
+        );
+        assert_eq!(summarize(&[]), None);
+    }
+}
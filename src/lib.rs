@@ -0,0 +1,66 @@
+//! Driver for the Ajazz AKP05E stream deck, the part of the OpenDeck plugin that talks to the device
+//!
+//! Finding devices, decoding their input reports and uploading images doesn't depend on OpenDeck,
+//! so it can be used to drive the deck from any application:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use akp05::{deck, discovery, images::KeyImage, inputs::InputState};
+//! use image::DynamicImage;
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let candidate = discovery::get_candidates().await?.remove(0);
+//! let device = deck::connect(&candidate).await?;
+//! deck::initialize_device(&candidate.id, &device, 50).await?;
+//!
+//! // Positions are numbered the way OpenDeck does it, strip zones first and then keys
+//! let image = KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(120, 120)));
+//! deck::handle_set_image(&candidate.id, &device, Some(5), Some(image), 90).await?;
+//!
+//! let mut state = InputState::new(&candidate.kind);
+//! loop {
+//!     let updates = deck::read_updates(&candidate.id, &device, &mut state, None, None).await?;
+//!
+//!     for update in updates {
+//!         println!("{:?}", update);
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! Code that drives the deck can be written against [transport::DeviceTransport] and tested with
+//! `transport::mock::MockTransport` instead of real hardware, with the `mock` feature enabled.
+
+/// Reordering right-to-left text and shaping Arabic for drawing
+pub mod bidi;
+/// Optional features of connected devices, from their model or tried on the device
+pub mod capabilities;
+/// Recording raw input reports to files and reading them back
+pub mod capture;
+/// Connecting to a device, uploading images and reading inputs
+pub mod deck;
+/// Finding connected devices
+pub mod discovery;
+/// Errors of device operations
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+/// Decoding and encoding button images
+pub mod images;
+/// Decoding input reports into key and encoder updates
+pub mod inputs;
+/// Device models, their layout and IDs
+pub mod mappings;
+/// Level bars drawn over rendered images
+pub mod progress;
+/// Encoding text as QR codes, for sharing links and Wi-Fi details from the deck
+pub mod qrcode;
+/// Fake device shown in a browser, for development without the hardware
+pub mod simulator;
+/// Bitmap text rendering for images drawn on the device itself
+pub mod text;
+/// Cache of images encoded for the device, in memory and on disk
+pub mod tiles;
+/// HID calls made to the device, and a fake device for tests
+pub mod transport;
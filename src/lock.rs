@@ -0,0 +1,317 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::{
+    images::KeyImage,
+    inputs::{Control, Verdict},
+    mappings::COL_COUNT,
+};
+use image::{DynamicImage, Rgb, RgbImage};
+use mirajazz::state::DeviceStateUpdate;
+
+use crate::{CONFIG, WRITERS, writer::WriterCommand};
+
+/// Size the padlock is rendered at, images are resized to the device format anyway
+pub const PADLOCK_SIZE: (u32, u32) = (120, 120);
+
+// Lock state of every device that was locked at least once
+static LOCKS: LazyLock<Mutex<HashMap<String, LockState>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// What unlocks a locked device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnlockGesture {
+    /// Keys to hold down together, empty disables the chord
+    pub chord: Vec<u8>,
+    /// How long an encoder has to be held before releasing it unlocks the device
+    pub hold: Duration,
+}
+
+/// Input lock of a single device
+///
+/// While locked, every input is swallowed and only watched for the unlock gesture.
+#[derive(Debug, Default)]
+pub struct LockState {
+    locked: bool,
+    // Keys pressed while locked, for the chord
+    held_keys: BTreeSet<u8>,
+    // Encoders pressed while locked and when
+    encoder_presses: HashMap<u8, Instant>,
+    // Controls pressed while locked, their releases are swallowed as well
+    suppressed: HashSet<Control>,
+}
+
+impl LockState {
+    /// Decides what to do with an update coming from the device
+    pub fn filter(
+        &mut self,
+        update: &DeviceStateUpdate,
+        now: Instant,
+        gesture: &UnlockGesture,
+    ) -> Verdict {
+        let Some((control, pressed)) = Control::of(update) else {
+            return if self.locked {
+                Verdict::Suppress
+            } else {
+                Verdict::Dispatch
+            };
+        };
+
+        if !pressed {
+            let unlock = match control {
+                Control::Key(key) => {
+                    self.held_keys.remove(&key);
+                    false
+                }
+                Control::Encoder(encoder) => self
+                    .encoder_presses
+                    .remove(&encoder)
+                    .is_some_and(|at| self.locked && now.duration_since(at) >= gesture.hold),
+            };
+
+            if unlock {
+                self.unlock();
+            }
+
+            return match (self.suppressed.remove(&control), unlock) {
+                (_, true) => Verdict::Unlock,
+                (true, false) => Verdict::Suppress,
+                (false, false) => Verdict::Dispatch,
+            };
+        }
+
+        if !self.locked {
+            return Verdict::Dispatch;
+        }
+
+        self.suppressed.insert(control);
+
+        match control {
+            Control::Key(key) => {
+                self.held_keys.insert(key);
+
+                if !gesture.chord.is_empty()
+                    && gesture.chord.iter().all(|key| self.held_keys.contains(key))
+                {
+                    self.unlock();
+                    return Verdict::Unlock;
+                }
+            }
+            Control::Encoder(encoder) => {
+                self.encoder_presses.insert(encoder, now);
+            }
+        }
+
+        Verdict::Suppress
+    }
+
+    fn unlock(&mut self) {
+        self.locked = false;
+        self.held_keys.clear();
+        self.encoder_presses.clear();
+    }
+}
+
+pub fn is_locked(device: &str) -> bool {
+    LOCKS
+        .lock()
+        .unwrap()
+        .get(device)
+        .is_some_and(|state| state.locked)
+}
+
+/// Checks if the padlock is drawn on the position, so OpenDeck images for it are ignored
+pub fn draws_position(device: &str, position: u8) -> bool {
+    (position as usize) < COL_COUNT && is_locked(device)
+}
+
+/// Draws a padlock, white on black
+pub fn render_padlock(size: (u32, u32)) -> DynamicImage {
+    let (width, height) = size;
+    let mut image = RgbImage::new(width, height);
+    let white = Rgb([255, 255, 255]);
+
+    let (w, h) = (width as f32, height as f32);
+    let (cx, cy) = (w * 0.5, h * 0.45);
+    let (outer, inner) = (w * 0.17, w * 0.10);
+    let (body_top, body_bottom) = (h * 0.45, h * 0.82);
+    let (body_left, body_right) = (w * 0.27, w * 0.73);
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        let (dx, dy) = (x - cx, y - cy);
+
+        // Upper half of a ring, legs go straight down into the body
+        let shackle = if y <= cy {
+            (inner..=outer).contains(&(dx * dx + dy * dy).sqrt())
+        } else {
+            y < body_top && (inner..=outer).contains(&dx.abs())
+        };
+
+        let body = (body_left..=body_right).contains(&x) && (body_top..=body_bottom).contains(&y);
+
+        // Keyhole, a dot with a slot under it
+        let (kx, ky) = (x - cx, y - h * 0.6);
+        let keyhole = (kx * kx + ky * ky).sqrt() <= w * 0.05
+            || (kx.abs() <= w * 0.02 && (h * 0.6..=h * 0.72).contains(&y));
+
+        if shackle || (body && !keyhole) {
+            *pixel = white;
+        }
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Locks inputs of a device and draws a padlock on its strip
+pub async fn lock(device: &str) {
+    LOCKS
+        .lock()
+        .unwrap()
+        .entry(device.to_string())
+        .or_default()
+        .locked = true;
+
+    log::info!("Locked {}", device);
+
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        let padlock = Arc::new(render_padlock(PADLOCK_SIZE));
+
+        for position in 0..COL_COUNT as u8 {
+            writer.send(WriterCommand::SetImage {
+                position: Some(position),
+                image: Some(KeyImage::Rendered(padlock.clone())),
+            });
+        }
+    }
+}
+
+/// Removes the padlock and asks OpenDeck to draw the strip again
+async fn unlocked(device: &str) {
+    log::info!("Unlocked {}", device);
+
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        for position in 0..COL_COUNT as u8 {
+            writer.send(WriterCommand::SetImage {
+                position: Some(position),
+                image: None,
+            });
+        }
+
+        writer.send(WriterCommand::Redraw);
+    }
+}
+
+/// Checks if an update should reach OpenDeck, unlocking the device on the unlock gesture
+pub async fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let gesture = CONFIG.borrow().unlock_gesture();
+
+    let verdict = match LOCKS.lock().unwrap().get_mut(device) {
+        Some(state) => state.filter(update, Instant::now(), &gesture),
+        None => Verdict::Dispatch,
+    };
+
+    match verdict {
+        Verdict::Dispatch => true,
+        Verdict::Suppress => false,
+        Verdict::Unlock => {
+            unlocked(device).await;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    fn locked() -> LockState {
+        LockState {
+            locked: true,
+            ..LockState::default()
+        }
+    }
+
+    #[test]
+    fn chord_unlocks() {
+        let now = Instant::now();
+        let gesture = UnlockGesture {
+            chord: vec![0, 4],
+            hold: Duration::from_secs(2),
+        };
+        let mut state = locked();
+
+        assert_eq!(
+            state.filter(&ButtonDown(0), now, &gesture),
+            Verdict::Suppress
+        );
+        assert_eq!(state.filter(&ButtonUp(0), now, &gesture), Verdict::Suppress);
+        assert_eq!(
+            state.filter(&ButtonDown(4), now, &gesture),
+            Verdict::Suppress
+        );
+        assert_eq!(state.filter(&ButtonDown(0), now, &gesture), Verdict::Unlock);
+
+        // Chord keys are still released quietly
+        assert_eq!(state.filter(&ButtonUp(4), now, &gesture), Verdict::Suppress);
+        assert_eq!(state.filter(&ButtonUp(0), now, &gesture), Verdict::Suppress);
+        assert_eq!(
+            state.filter(&ButtonDown(4), now, &gesture),
+            Verdict::Dispatch
+        );
+    }
+
+    #[test]
+    fn long_encoder_hold_unlocks() {
+        let now = Instant::now();
+        let gesture = UnlockGesture {
+            chord: vec![],
+            hold: Duration::from_secs(2),
+        };
+        let mut state = locked();
+
+        assert_eq!(
+            state.filter(&EncoderTwist(1, 3), now, &gesture),
+            Verdict::Suppress
+        );
+
+        // Short press does nothing
+        assert_eq!(
+            state.filter(&EncoderDown(1), now, &gesture),
+            Verdict::Suppress
+        );
+        let later = now + Duration::from_millis(500);
+        assert_eq!(
+            state.filter(&EncoderUp(1), later, &gesture),
+            Verdict::Suppress
+        );
+
+        assert_eq!(
+            state.filter(&EncoderDown(1), later, &gesture),
+            Verdict::Suppress
+        );
+        let later = later + Duration::from_secs(2);
+        assert_eq!(
+            state.filter(&EncoderUp(1), later, &gesture),
+            Verdict::Unlock
+        );
+        assert_eq!(
+            state.filter(&EncoderTwist(1, 3), now, &gesture),
+            Verdict::Dispatch
+        );
+    }
+
+    #[test]
+    fn padlock_is_drawn_in_the_middle() {
+        let image = render_padlock((120, 120)).into_rgb8();
+
+        assert_eq!(image.get_pixel(40, 90).0, [255, 255, 255]);
+        assert_eq!(image.get_pixel(60, 72).0, [0, 0, 0], "keyhole");
+        assert_eq!(image.get_pixel(5, 5).0, [0, 0, 0]);
+    }
+}
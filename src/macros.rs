@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
+
+use mirajazz::state::DeviceStateUpdate;
+use serde_json::Value;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::device::{dispatch_update, handle_error};
+
+/// Longest wait a single step can have
+pub const MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// Single step of a macro
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Down(u8),
+    Up(u8),
+    Wait(Duration),
+}
+
+/// Macros for every key that triggers one
+pub type Macros = BTreeMap<u8, Vec<Step>>;
+
+/// Sending half of a device's macro queue
+pub type MacroQueue = UnboundedSender<Vec<Step>>;
+
+/// Parses steps like `[{ "press": 6 }, { "wait": 100 }, { "down": 7 }, { "up": 7 }]`
+///
+/// `press` is a shorthand for `down` followed by `up`, waits are in milliseconds.
+fn parse_steps(value: &Value, last_key: u8) -> Result<Vec<Step>, String> {
+    let mut steps = vec![];
+
+    for step in value.as_array().ok_or("steps must be a list")? {
+        let (kind, argument) = step
+            .as_object()
+            .filter(|step| step.len() == 1)
+            .and_then(|step| step.iter().next())
+            .ok_or_else(|| format!("step must have a single field, got {}", step))?;
+
+        let argument = argument
+            .as_u64()
+            .ok_or_else(|| format!("\"{}\" needs a number, got {}", kind, argument))?;
+
+        if kind == "wait" {
+            let wait = Duration::from_millis(argument);
+
+            if wait > MAX_WAIT {
+                return Err(format!("waits can be {} ms at most", MAX_WAIT.as_millis()));
+            }
+
+            steps.push(Step::Wait(wait));
+            continue;
+        }
+
+        let key = u8::try_from(argument)
+            .ok()
+            .filter(|key| *key <= last_key)
+            .ok_or_else(|| format!("keys must be between 0 and {}, got {}", last_key, argument))?;
+
+        match kind.as_str() {
+            "press" => steps.extend([Step::Down(key), Step::Up(key)]),
+            "down" => steps.push(Step::Down(key)),
+            "up" => steps.push(Step::Up(key)),
+            _ => return Err(format!("unknown step \"{}\"", kind)),
+        }
+    }
+
+    Ok(steps)
+}
+
+/// Parses macros keyed by the key that triggers them
+pub fn parse_macros(value: &Value, last_key: u8) -> Result<Macros, String> {
+    let mut macros = Macros::new();
+
+    for (key, steps) in value
+        .as_object()
+        .ok_or("expected an object of keys to steps")?
+    {
+        let key = key
+            .parse::<u8>()
+            .ok()
+            .filter(|key| *key <= last_key)
+            .ok_or_else(|| format!("keys must be between 0 and {}, got \"{}\"", last_key, key))?;
+        let steps = parse_steps(steps, last_key).map_err(|err| format!("key {}: {}", key, err))?;
+
+        macros.insert(key, steps);
+    }
+
+    Ok(macros)
+}
+
+/// What should happen with an update of a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroAction {
+    /// Not a macro key, forward to OpenDeck as usual
+    Dispatch,
+    /// Swallow it, e.g. release of a macro key
+    Swallow,
+    /// Swallow it and play the steps
+    Play(Vec<Step>),
+}
+
+/// Keys with macros that are held down on a device
+#[derive(Debug, Default)]
+pub struct MacroKeys {
+    held: HashSet<u8>,
+}
+
+impl MacroKeys {
+    /// Decides what to do with an update, presses of macro keys play their macro
+    pub fn filter(&mut self, update: &DeviceStateUpdate, macros: &Macros) -> MacroAction {
+        match *update {
+            DeviceStateUpdate::ButtonDown(key) => match macros.get(&key) {
+                Some(steps) => {
+                    self.held.insert(key);
+                    MacroAction::Play(steps.clone())
+                }
+                None => MacroAction::Dispatch,
+            },
+            // Release of a key that was a macro key when pressed, even if config changed since
+            DeviceStateUpdate::ButtonUp(key) if self.held.remove(&key) => MacroAction::Swallow,
+            _ => MacroAction::Dispatch,
+        }
+    }
+}
+
+/// Plays macros of a device one after another, so their steps never interleave
+pub async fn macro_task(id: &str, mut queue: UnboundedReceiver<Vec<Step>>) {
+    while let Some(steps) = queue.recv().await {
+        log::info!("Playing macro of {} steps on {}", steps.len(), id);
+
+        for step in steps {
+            let update = match step {
+                Step::Down(key) => DeviceStateUpdate::ButtonDown(key),
+                Step::Up(key) => DeviceStateUpdate::ButtonUp(key),
+                Step::Wait(wait) => {
+                    tokio::time::sleep(wait).await;
+                    continue;
+                }
+            };
+
+            if let Err(err) = dispatch_update(id.to_string(), update).await {
+                handle_error(err).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn steps_are_parsed() {
+        let macros = parse_macros(
+            &json!({ "5": [{ "press": 6 }, { "wait": 100 }, { "down": 7 }, { "up": 7 }] }),
+            14,
+        )
+        .unwrap();
+
+        assert_eq!(
+            macros[&5],
+            vec![
+                Step::Down(6),
+                Step::Up(6),
+                Step::Wait(Duration::from_millis(100)),
+                Step::Down(7),
+                Step::Up(7),
+            ]
+        );
+
+        for (value, error) in [
+            (
+                json!({ "5": [{ "press": 15 }] }),
+                "key 5: keys must be between 0 and 14, got 15",
+            ),
+            (
+                json!({ "5": [{ "wait": 20000 }] }),
+                "key 5: waits can be 10000 ms at most",
+            ),
+            (
+                json!({ "5": [{ "tap": 1 }] }),
+                "key 5: unknown step \"tap\"",
+            ),
+            (
+                json!({ "x": [] }),
+                "keys must be between 0 and 14, got \"x\"",
+            ),
+        ] {
+            assert_eq!(parse_macros(&value, 14).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn macro_keys_are_swallowed() {
+        let macros = Macros::from([(5, vec![Step::Down(6), Step::Up(6)])]);
+        let mut keys = MacroKeys::default();
+
+        assert_eq!(
+            keys.filter(&DeviceStateUpdate::ButtonDown(5), &macros),
+            MacroAction::Play(vec![Step::Down(6), Step::Up(6)])
+        );
+        assert_eq!(
+            keys.filter(&DeviceStateUpdate::ButtonDown(6), &macros),
+            MacroAction::Dispatch
+        );
+
+        // Macro removed while the key is held, release is still swallowed
+        assert_eq!(
+            keys.filter(&DeviceStateUpdate::ButtonUp(5), &Macros::new()),
+            MacroAction::Swallow
+        );
+        assert_eq!(
+            keys.filter(&DeviceStateUpdate::ButtonUp(6), &macros),
+            MacroAction::Dispatch
+        );
+    }
+}
@@ -1,42 +1,145 @@
-use device::{handle_error, handle_set_image};
-use mirajazz::device::Device;
+use akp05::{
+    images::KeyImage,
+    mappings::{
+        DND_ACTION_UUID, HOTKEY_ACTION_UUID, LOCK_ACTION_UUID, MIDI_ACTION_UUID, QR_ACTION_UUID,
+        REPORT_ACTION_UUID, RESET_ACTION_UUID, TIMER_ACTION_UUID, VALUE_ACTION_UUID,
+        action_position,
+    },
+    transport::HidTransport,
+};
+use config::Config;
 use openaction::*;
-use std::{collections::HashMap, process::exit, sync::LazyLock};
-use tokio::sync::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    process::exit,
+    sync::{Arc, LazyLock},
+    time::Instant,
+};
+use tokio::sync::{Mutex, RwLock, watch};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use watcher::watcher_task;
+use writer::{WriterCommand, WriterHandle};
 
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{SignalKind, signal};
 
+mod badge;
+mod blink;
+mod bundle;
+mod claim;
+mod clock;
+mod config;
 mod device;
-mod inputs;
-mod mappings;
+mod diagnostics;
+mod dnd;
+mod duty;
+mod export;
+mod focus;
+mod hooks;
+mod hotkey;
+mod icons;
+mod inject;
+mod labels;
+mod latency;
+mod lock;
+mod macros;
+mod manifest;
+mod marquee;
+mod media;
+mod menu;
+mod midi;
+mod mixer;
+mod mqtt;
+mod obs;
+mod orientation;
+mod pacing;
+mod pages;
+mod power;
+mod press;
+mod qr;
+mod ratelimit;
+mod safemode;
+mod screenshot;
+mod script;
+mod session;
+mod setup;
+mod sinks;
+mod sliders;
+mod splash;
+mod stats;
+mod status;
+mod timer;
+mod toast;
+mod transient;
+mod transition;
+mod valuedial;
+mod volume;
 mod watcher;
+mod writer;
 
-pub static DEVICES: LazyLock<RwLock<HashMap<String, Device>>> =
+pub static DEVICES: LazyLock<RwLock<HashMap<String, Arc<HidTransport>>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 pub static TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static WRITERS: LazyLock<RwLock<HashMap<String, WriterHandle>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static CONFIG: LazyLock<watch::Sender<Config>> =
+    LazyLock::new(|| watch::Sender::new(Config::default()));
 pub static TRACKER: LazyLock<Mutex<TaskTracker>> = LazyLock::new(|| Mutex::new(TaskTracker::new()));
 
 struct GlobalEventHandler {}
 impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn plugin_ready(
         &self,
-        _outbound: &mut openaction::OutboundEventManager,
+        outbound: &mut openaction::OutboundEventManager,
     ) -> EventHandlerResult {
-        let tracker = TRACKER.lock().await.clone();
+        // Settings stored by OpenDeck override everything else, they come in a separate event
+        outbound.get_global_settings().await?;
+
+        spawn_global("watcher", watcher_task).await;
+        spawn_global("config_watcher", config::file_watcher_task).await;
+        spawn_global("focus", focus::focus_task).await;
+        spawn_global("clock", clock::clock_task).await;
+        spawn_global("stats", stats::stats_task).await;
+        spawn_global("labels", labels::labels_task).await;
+        spawn_global("icons", icons::icons_task).await;
+        spawn_global("pages", pages::pages_task).await;
+        spawn_global("diagnostics", diagnostics::diagnostics_task).await;
+        spawn_global("sliders", sliders::sliders_task).await;
+        spawn_global("status", status::status_task).await;
+        spawn_global("blink", blink::blink_task).await;
+        spawn_global("toast", toast::toast_task).await;
+        spawn_global("transient", transient::transient_task).await;
+        spawn_global("rate_limit", ratelimit::rate_limit_task).await;
+        spawn_global("menu", menu::menu_task).await;
+        spawn_global("setup", setup::setup_task).await;
+        spawn_global("power", power::power_task).await;
+        spawn_global("timer", timer::timer_task).await;
+        spawn_global("value", valuedial::value_task).await;
+        spawn_global("qr", qr::qr_task).await;
+        spawn_global("media", media::media_task).await;
+        spawn_global("mixer", mixer::mixer_task).await;
+        spawn_global("volume", volume::volume_task).await;
+        spawn_global("obs", obs::obs_task).await;
+        spawn_global("midi", midi::midi_task).await;
+        spawn_global("hooks", hooks::hooks_task).await;
+        spawn_global("script", script::script_task).await;
+        spawn_global("mqtt", mqtt::mqtt_task).await;
+        spawn_global("sinks", sinks::sinks_task).await;
 
-        let token = CancellationToken::new();
-        tracker.spawn(watcher_task(token.clone()));
+        log::info!("Plugin initialized");
 
-        TOKENS
-            .write()
-            .await
-            .insert("_watcher_task".to_string(), token);
+        Ok(())
+    }
 
-        log::info!("Plugin initialized");
+    async fn did_receive_global_settings(
+        &self,
+        event: DidReceiveGlobalSettingsEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        log::debug!("Received global settings: {:#?}", event);
+
+        config::reload(Some(event.payload.settings)).await;
 
         Ok(())
     }
@@ -54,13 +157,29 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
             return Ok(());
         }
 
-        let id = event.device.clone();
+        let image = event.image.map(KeyImage::DataUrl);
+
+        // Images of pages that aren't shown are kept until their page is
+        let Some(position) = pages::route_image(&event.device, event.position, image.clone())
+        else {
+            log::debug!("Position is on another page, keeping the image for later");
+            return Ok(());
+        };
+
+        // Positions the plugin draws itself would only flicker with OpenDeck images over them
+        if let Some(position) = position
+            && draws_position(&event.device, position)
+        {
+            log::debug!("Position is drawn by the plugin, skipping");
+            return Ok(());
+        }
+
+        // First image of the profile takes the splash down, the rest comes in the same frame
+        splash::finish(&event.device).await;
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            handle_set_image(device, event)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
+        // Writer takes care of it, so OpenDeck messages are never blocked by a slow device
+        if let Some(writer) = WRITERS.read().await.get(&event.device) {
+            writer.send(WriterCommand::SetImage { position, image });
         } else {
             log::error!("Received event for unknown device: {}", event.device);
         }
@@ -75,14 +194,8 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
     ) -> EventHandlerResult {
         log::debug!("Asked to set brightness: {:#?}", event);
 
-        let id = event.device.clone();
-
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            device
-                .set_brightness(event.brightness)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
+        if let Some(writer) = WRITERS.read().await.get(&event.device) {
+            writer.send(WriterCommand::SetBrightness(event.brightness));
         } else {
             log::error!("Received event for unknown device: {}", event.device);
         }
@@ -92,7 +205,236 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
 }
 
 struct ActionEventHandler {}
-impl openaction::ActionEventHandler for ActionEventHandler {}
+impl openaction::ActionEventHandler for ActionEventHandler {
+    async fn will_appear(
+        &self,
+        event: AppearEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        let coordinates = event.payload.coordinates;
+        let position = action_position(
+            &event.payload.controller,
+            coordinates.row,
+            coordinates.column,
+        );
+
+        if event.action == TIMER_ACTION_UUID {
+            let minutes = timer::settings_minutes(&event.payload.settings);
+
+            timer::add_timer(
+                &event.context,
+                timer::Timer::new(event.device, position, minutes),
+            );
+        } else if event.action == VALUE_ACTION_UUID {
+            valuedial::add_dial(
+                &event.context,
+                valuedial::ValueDial::from_settings(
+                    event.device,
+                    position,
+                    &event.payload.settings,
+                ),
+            );
+        } else if event.action == DND_ACTION_UUID {
+            dnd::add_unlock_key(&event.context, event.device, position);
+        } else if event.action == MIDI_ACTION_UUID {
+            midi::add_action(&event.context, event.device);
+        } else if event.action == QR_ACTION_UUID {
+            let (text, strip) = qr::settings_code(&event.payload.settings);
+
+            qr::add_code(
+                &event.context,
+                qr::QrAction {
+                    device: event.device,
+                    position,
+                    text,
+                    strip,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn will_disappear(
+        &self,
+        event: AppearEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        if event.action == TIMER_ACTION_UUID {
+            timer::remove_timer(&event.context);
+        } else if event.action == VALUE_ACTION_UUID {
+            valuedial::remove_dial(&event.context);
+        } else if event.action == DND_ACTION_UUID {
+            dnd::remove_unlock_key(&event.context);
+        } else if event.action == MIDI_ACTION_UUID {
+            midi::remove_action(&event.context);
+        } else if event.action == QR_ACTION_UUID {
+            qr::remove_code(&event.context);
+        }
+
+        Ok(())
+    }
+
+    async fn did_receive_settings(
+        &self,
+        event: DidReceiveSettingsEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        if event.action == QR_ACTION_UUID {
+            qr::update_settings(&event.context, &event.payload.settings);
+        } else if event.action == VALUE_ACTION_UUID {
+            valuedial::update_settings(&event.context, &event.payload.settings);
+        }
+
+        Ok(())
+    }
+
+    async fn key_down(
+        &self,
+        event: KeyEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        if event.action == TIMER_ACTION_UUID {
+            timer::update_timer(&event.context, |timer| timer.toggle(Instant::now()));
+            return Ok(());
+        }
+
+        // Only turns the mode on, presses are intercepted before reaching OpenDeck while it's on
+        if event.action == DND_ACTION_UUID {
+            dnd::set_enabled(true).await;
+            return Ok(());
+        }
+
+        if event.action == LOCK_ACTION_UUID {
+            lock::lock(&event.device).await;
+            return Ok(());
+        }
+
+        if event.action == HOTKEY_ACTION_UUID {
+            match hotkey::settings_hotkey(&event.payload.settings) {
+                // Not awaited, typing must not hold up other OpenDeck events
+                Some(Ok(hotkey)) => drop(tokio::spawn(hotkey::press(hotkey))),
+                Some(Err(err)) => log::warn!("Invalid hotkey: {}", err),
+                None => log::warn!("Hotkey action has no hotkey set"),
+            }
+
+            return Ok(());
+        }
+
+        if event.action == REPORT_ACTION_UUID {
+            let device = event.device.clone();
+
+            // Not awaited, zipping must not hold up other OpenDeck events
+            drop(tokio::spawn(async move {
+                match bundle::save_report().await {
+                    Ok(path) => {
+                        log::info!("Saved bug report to {}", path.display());
+                        toast::show(&device, toast::Toast::text("REPORT SAVED"));
+                    }
+                    Err(err) => log::error!("{}", err),
+                }
+            }));
+
+            return Ok(());
+        }
+
+        if event.action != RESET_ACTION_UUID {
+            return Ok(());
+        }
+
+        log::info!("Asked to reset device {}", event.device);
+
+        // Action can be placed on any device, reset only the ones we own
+        if let Some(writer) = WRITERS.read().await.get(&event.device) {
+            writer.send(WriterCommand::Reset);
+        } else {
+            log::warn!("Device {} isn't handled by this plugin", event.device);
+        }
+
+        Ok(())
+    }
+
+    async fn dial_down(
+        &self,
+        event: DialPressEvent,
+        outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        if event.action == TIMER_ACTION_UUID {
+            timer::update_timer(&event.context, |timer| timer.toggle(Instant::now()));
+        }
+
+        // Only a confirmed value goes to OpenDeck, turning alone just shows it
+        if event.action == VALUE_ACTION_UUID
+            && let Some(Some(settings)) = valuedial::update_dial(&event.context, |dial| {
+                dial.confirm().then(|| dial.to_settings())
+            })
+        {
+            outbound.set_settings(event.context, settings).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn dial_rotate(
+        &self,
+        event: DialRotateEvent,
+        outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        let ticks = event.payload.ticks;
+
+        if event.action == VALUE_ACTION_UUID {
+            valuedial::update_dial(&event.context, |dial| dial.rotate(ticks, Instant::now()));
+            return Ok(());
+        }
+
+        if event.action != TIMER_ACTION_UUID {
+            return Ok(());
+        }
+
+        // Remembered in action settings, so the timer is set the same after a restart
+        if let Some(minutes) = timer::update_timer(&event.context, |timer| timer.rotate(ticks)) {
+            outbound
+                .set_settings(event.context, timer::to_settings(minutes))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks if the plugin draws on the position of the device, so OpenDeck images for it are ignored
+///
+/// Positions configured for the clock, widgets, labels, icons, media, mixer and OBS keys or strip
+/// sliders are, as are timers, value dials, QR codes, volume levels, page keys, the padlock, input
+/// codes shown by diagnostics and whatever the menu, setup wizard and toasts cover.
+pub fn draws_position(device: &str, position: u8) -> bool {
+    CONFIG.borrow().draws_position(position)
+        || timer::draws_position(device, position)
+        || valuedial::draws_position(device, position)
+        || qr::draws_position(device, position)
+        || volume::draws_position(device, position)
+        || pages::draws_position(device, position)
+        || lock::draws_position(device, position)
+        || menu::draws_position(device, position)
+        || setup::draws_position(device, position)
+        || diagnostics::draws_position(device, position)
+        || toast::draws_position(device, position)
+}
+
+/// Spawns a task that runs as long as the plugin does, its token is kept as `_<name>_task`
+async fn spawn_global<F>(name: &str, task: impl FnOnce(CancellationToken) -> F)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let token = CancellationToken::new();
+    TRACKER.lock().await.spawn(task(token.clone()));
+
+    TOKENS
+        .write()
+        .await
+        .insert(format!("_{}_task", name), token);
+}
 
 async fn shutdown() {
     let tokens = TOKENS.write().await;
@@ -129,14 +471,32 @@ async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    simplelog::TermLogger::init(
-        simplelog::LevelFilter::Info,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Stdout,
-        simplelog::ColorChoice::Never,
-    )
+    // Recent lines are also kept in memory for bug reports
+    simplelog::CombinedLogger::init(vec![
+        simplelog::TermLogger::new(
+            simplelog::LevelFilter::Info,
+            simplelog::Config::default(),
+            simplelog::TerminalMode::Stdout,
+            simplelog::ColorChoice::Never,
+        ),
+        simplelog::WriteLogger::new(
+            simplelog::LevelFilter::Info,
+            simplelog::Config::default(),
+            bundle::LogBuffer,
+        ),
+    ])
     .unwrap();
 
+    // Wrong namespace makes every device silently do nothing, better to stop right away
+    if let Err(err) = manifest::validate() {
+        log::error!("{}", err);
+
+        return Err(err.into());
+    }
+
+    // Defaults, config file and env for now, OpenDeck settings are applied once received
+    config::reload(None).await;
+
     tokio::select! {
         _ = connect() => {},
         _ = sigterm() => {},
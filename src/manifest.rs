@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use akp05::{discovery::serial_to_id, mappings::DEVICE_NAMESPACE};
+use serde_json::Value;
+
+/// Manifest OpenDeck reads the plugin from, it sits next to the plugin executable
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+fn manifest_path() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+
+    Some(exe.parent()?.join(MANIFEST_FILE_NAME))
+}
+
+/// Checks that IDs of registered devices use the namespace the manifest declares
+///
+/// OpenDeck drops events for devices outside of it without a word, so a mismatch leaves every
+/// device registered but dead.
+pub fn check_namespace(manifest: &str) -> Result<(), String> {
+    let manifest: Value = serde_json::from_str(manifest)
+        .map_err(|err| format!("{} is not valid JSON: {}", MANIFEST_FILE_NAME, err))?;
+
+    let Some(namespace) = manifest["DeviceNamespace"].as_str() else {
+        return Err(format!(
+            "{} has no DeviceNamespace, OpenDeck won't accept any device from this plugin",
+            MANIFEST_FILE_NAME
+        ));
+    };
+
+    if namespace != DEVICE_NAMESPACE {
+        return Err(format!(
+            "{} declares DeviceNamespace \"{}\", but the plugin was built for \"{}\". OpenDeck \
+             would ignore every device, reinstall the plugin so both come from the same release",
+            MANIFEST_FILE_NAME, namespace, DEVICE_NAMESPACE
+        ));
+    }
+
+    let id = serial_to_id("SERIAL");
+
+    if !id.starts_with(&format!("{}-", namespace)) {
+        return Err(format!(
+            "Device IDs like {} are outside of DeviceNamespace \"{}\"",
+            id, namespace
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the manifest next to the executable, a missing one is only a warning
+///
+/// Without a manifest the plugin isn't running from an OpenDeck plugin folder, e.g. in
+/// development, and there's nothing to compare with.
+pub fn validate() -> Result<(), String> {
+    let Some(path) = manifest_path() else {
+        return Ok(());
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(manifest) => check_namespace(&manifest),
+        Err(err) => {
+            log::warn!("Can't read {} to check it: {}", path.display(), err);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_manifest_matches() {
+        assert_eq!(check_namespace(include_str!("../manifest.json")), Ok(()));
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let error = check_namespace(r#"{ "DeviceNamespace": "n3" }"#).unwrap_err();
+        assert!(error.contains("\"n3\""), "{}", error);
+
+        assert!(check_namespace(r#"{ "Name": "Ajazz" }"#).is_err());
+        assert!(check_namespace("{ nope").is_err());
+    }
+}
@@ -1,18 +1,36 @@
 use mirajazz::{
     device::DeviceQuery,
-    types::{HidDeviceInfo, ImageFormat, ImageMirroring, ImageMode, ImageRotation},
+    types::{HidDeviceInfo, ImageMirroring, ImageRotation},
 };
 
-// Must match DeviceNamespace field in manifest.json
+use crate::images::{ImageFormat, ImageMode};
+
+/// Prefix of device IDs, must match DeviceNamespace field in manifest.json (the plugin checks it
+/// at startup)
 pub const DEVICE_NAMESPACE: &str = "a5";
 
+/// Must match PluginUUID field in manifest.json
+pub const PLUGIN_UUID: &str = "st.lynx.plugins.opendeck-akp05";
+
+// Must match UUID of the action in manifest.json
+pub const RESET_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.reset";
+pub const TIMER_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.timer";
+pub const DND_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.dnd";
+pub const LOCK_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.lock";
+pub const HOTKEY_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.hotkey";
+pub const MIDI_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.midi";
+pub const REPORT_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.report";
+pub const QR_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.qr";
+pub const VALUE_ACTION_UUID: &str = "st.lynx.plugins.opendeck-akp05.value";
+
 // AKP05E series constants - custom layout: 10 physical buttons + 4 touchscreen zones
 pub const ROW_COUNT: usize = 2;  // 2 rows of physical buttons only
 pub const COL_COUNT: usize = 5;  // 5 columns for physical buttons
 pub const KEY_COUNT: usize = 10; // Back to 10 physical buttons for main grid
 pub const ENCODER_COUNT: usize = 4;
 
-#[derive(Debug, Clone)]
+/// Model of a supported device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
     Akp05E,  // AKP05E variant
     // Future AKP05 variants (AKP05F, AKP05G, etc.) can be added here
@@ -26,11 +44,20 @@ pub const AKP05E_PID: u16 = 0x3004;        // Your actual device PID
 pub const AKP05E_QUERY_REV2: DeviceQuery = DeviceQuery::new(65440, 1, AJAZZ_VID, AKP03E_REV2_PID);
 pub const AKP05E_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, AJAZZ_VID, AKP05E_PID);
 
+/// HID queries matching every supported device
 pub const QUERIES: [DeviceQuery; 2] = [
     AKP05E_QUERY_REV2,  // Try original working PID first
     AKP05E_QUERY,       // Then try your actual PID
 ];
 
+/// Secondary interface of the AKP05E taking configuration commands, e.g. persisting brightness
+/// or setting the standby image. Same vendor page as the main one, the usage is a guess that
+/// hasn't been checked against a device, which is why it's only opened when asked for.
+pub const AKP05E_VENDOR_QUERY: DeviceQuery = DeviceQuery::new(65440, 2, AJAZZ_VID, AKP05E_PID);
+
+/// HID queries matching vendor command interfaces, not every model or revision has one
+pub const VENDOR_QUERIES: [DeviceQuery; 1] = [AKP05E_VENDOR_QUERY];
+
 impl Kind {
     /// Returns the number of rows for this device
     pub fn row_count(&self) -> usize {
@@ -82,6 +109,29 @@ impl Kind {
         }
     }
 
+    /// Maps physical device button index back to software button index
+    pub fn physical_to_software(&self, physical_index: usize) -> usize {
+        match self {
+            Self::Akp05E => {
+                match physical_index {
+                    // Physical 10-14 (encoders) -> Software 0-4
+                    10 => 0, 11 => 1, 12 => 2, 13 => 3, 14 => 4,
+                    // Physical 5-9 (middle row) -> Software 5-9
+                    5 => 5, 6 => 6, 7 => 7, 8 => 8, 9 => 9,
+                    // Physical 0-4 (top row) -> Software 10-14
+                    0 => 10, 1 => 11, 2 => 12, 3 => 13, 4 => 14,
+                    // Invalid index - panic
+                    _ => panic!("Invalid physical index: {}", physical_index),
+                }
+            }
+        }
+    }
+
+    /// Returns the number of indices covered by the button index mapping
+    pub fn mapped_index_count(&self) -> usize {
+        KEY_COUNT + COL_COUNT // 2 button rows + the encoder row
+    }
+
     /// Returns human-readable device name
     pub fn human_name(&self) -> String {
         match &self {
@@ -96,9 +146,12 @@ impl Kind {
     }
 
     /// Returns image format configuration
+    ///
+    /// Revisions that don't accept JPEG would use [ImageMode::Rgb565] or [ImageMode::Rgb888]
+    /// here, images are sent uncompressed then.
     pub fn image_format(&self) -> ImageFormat {
         ImageFormat {
-            mode: ImageMode::JPEG,
+            mode: ImageMode::Jpeg,
             size: (120, 120),
             rotation: ImageRotation::Rot180,  // 90 degrees more from Rot90
             mirror: ImageMirroring::None,
@@ -106,9 +159,91 @@ impl Kind {
     }
 }
 
+/// Position of an action placed in OpenDeck, the one images for it are sent to
+///
+/// Keys are numbered row by row, encoders use the strip zone with the same index.
+pub fn action_position(controller: &str, row: u8, column: u8) -> u8 {
+    if controller == "Encoder" {
+        column
+    } else {
+        row * COL_COUNT as u8 + column
+    }
+}
+
+/// Supported device that was found, but not connected to yet
 #[derive(Debug, Clone)]
 pub struct CandidateDevice {
+    /// Stable ID built from the serial number, e.g. `a5-<serial>`, or also from the USB port
     pub id: String,
+    /// Serial number the device reports, identical decks can share one
+    pub serial: String,
+    /// USB port the device is plugged into, e.g. `1-2.3`, [None] where it can't be read
+    pub port: Option<String>,
     pub dev: HidDeviceInfo,
     pub kind: Kind,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // Every kind must be listed here so its remap table gets checked
+    const KINDS: [Kind; 1] = [Kind::Akp05E];
+
+    #[test]
+    fn software_to_physical_round_trips() {
+        for kind in KINDS {
+            for software in 0..kind.mapped_index_count() {
+                let physical = kind.map_button_index(software);
+
+                assert!(physical < kind.mapped_index_count(), "{:?}: {} out of range", kind, physical);
+                assert_eq!(kind.physical_to_software(physical), software, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn physical_to_software_round_trips() {
+        for kind in KINDS {
+            for physical in 0..kind.mapped_index_count() {
+                let software = kind.physical_to_software(physical);
+
+                assert!(software < kind.mapped_index_count(), "{:?}: {} out of range", kind, software);
+                assert_eq!(kind.map_button_index(software), physical, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn mapping_has_no_collisions() {
+        for kind in KINDS {
+            let count = kind.mapped_index_count();
+
+            let physical: HashSet<usize> = (0..count).map(|i| kind.map_button_index(i)).collect();
+            let software: HashSet<usize> = (0..count).map(|i| kind.physical_to_software(i)).collect();
+
+            assert_eq!(physical.len(), count, "{:?}: software indices collide", kind);
+            assert_eq!(software.len(), count, "{:?}: physical indices collide", kind);
+        }
+    }
+
+    #[test]
+    fn action_positions_follow_controller() {
+        assert_eq!(action_position("Keypad", 1, 3), 8);
+        assert_eq!(action_position("Encoder", 0, 2), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range_software_index_panics() {
+        Kind::Akp05E.map_button_index(Kind::Akp05E.mapped_index_count());
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range_physical_index_panics() {
+        Kind::Akp05E.physical_to_software(Kind::Akp05E.mapped_index_count());
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+/// Time between frames of scrolling text, about 10 a second
+pub const MARQUEE_FRAME: Duration = Duration::from_millis(100);
+
+/// How long scrolling text stays still at either end
+pub const MARQUEE_PAUSE: Duration = Duration::from_millis(1500);
+
+/// Scrolling speed in pixels of the rendered image a second
+pub const MARQUEE_SPEED: u32 = 30;
+
+/// How far a line reaching `overflow` pixels past the image is scrolled `elapsed` after it
+/// started
+///
+/// Lines wait at the start, scroll until their end shows, wait there and start over.
+pub fn scroll_offset(overflow: u32, elapsed: Duration) -> u32 {
+    if overflow == 0 {
+        return 0;
+    }
+
+    let scroll = Duration::from_millis(overflow as u64 * 1000 / MARQUEE_SPEED as u64);
+    let cycle = MARQUEE_PAUSE * 2 + scroll;
+    let elapsed = Duration::from_nanos((elapsed.as_nanos() % cycle.as_nanos()) as u64);
+
+    match elapsed.checked_sub(MARQUEE_PAUSE) {
+        None => 0,
+        Some(scrolled) if scrolled >= scroll => overflow,
+        Some(scrolled) => (scrolled.as_millis() as u64 * MARQUEE_SPEED as u64 / 1000) as u32,
+    }
+}
+
+/// Scroll positions of lines drawn over and over, they start over whenever the lines change
+#[derive(Debug)]
+pub struct Marquee {
+    lines: Vec<String>,
+    started: Instant,
+}
+
+impl Default for Marquee {
+    fn default() -> Self {
+        Self {
+            lines: vec![],
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Marquee {
+    /// Offsets of `lines` for [akp05::text::render_marquee], given their overflow
+    pub fn offsets(&mut self, lines: &[String], overflow: &[u32]) -> Vec<u32> {
+        if self.lines != lines {
+            self.lines = lines.to_vec();
+            self.started = Instant::now();
+        }
+
+        let elapsed = self.started.elapsed();
+
+        overflow
+            .iter()
+            .map(|overflow| scroll_offset(*overflow, elapsed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_pause_at_both_ends() {
+        let at = |millis| scroll_offset(60, Duration::from_millis(millis));
+
+        // 60 pixels take 2 seconds, with a pause before and after
+        assert_eq!(at(0), 0);
+        assert_eq!(at(1500), 0);
+        assert_eq!(at(2500), 30);
+        assert_eq!(at(3500), 60);
+        assert_eq!(at(4900), 60);
+        // Then it starts over
+        assert_eq!(at(5000), 0);
+        assert_eq!(at(7500), 30);
+
+        assert_eq!(scroll_offset(0, Duration::from_secs(3)), 0);
+    }
+}
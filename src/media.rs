@@ -0,0 +1,487 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::text::{TextStyle, marquee_overflow, render_marquee};
+use mirajazz::state::DeviceStateUpdate;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, duty,
+    marquee::{MARQUEE_FRAME, Marquee},
+    safemode,
+    writer::KeyPainter,
+};
+
+/// How often the playing track is checked
+pub const MEDIA_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size track info is rendered at, images are resized to the device format anyway
+pub const MEDIA_SIZE: (u32, u32) = (120, 120);
+
+/// Volume change of a single tick, players take volume as 0.0 - 1.0
+pub const VOLUME_STEP: f64 = 0.02;
+
+/// Seconds skipped by a single tick when seeking
+pub const SEEK_STEP: u32 = 5;
+
+/// What turning the media dial does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MediaRotate {
+    /// Changes volume of the player (default)
+    #[default]
+    Volume,
+
+    /// Seeks forwards and backwards in the track
+    Seek,
+}
+
+impl MediaRotate {
+    /// Parses mode name, either `volume` or `seek`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "volume" => Some(Self::Volume),
+            "seek" => Some(Self::Seek),
+            _ => None,
+        }
+    }
+}
+
+/// Dial inputs not sent to the player yet, so fast turns become one call instead of one per tick
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Pending {
+    presses: u32,
+    ticks: i32,
+}
+
+static PENDING: LazyLock<Mutex<Pending>> = LazyLock::new(|| Mutex::new(Pending::default()));
+
+/// Wakes [media_task] up when there are inputs for the player
+static INPUT: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// What the player is told to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    PlayPause,
+    /// Change of the volume, players take volume as 0.0 - 1.0
+    Volume(f64),
+    /// Microseconds to seek forwards, backwards if negative
+    Seek(i64),
+}
+
+/// Track the player is on
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Track {
+    /// `Playing`, `Paused` or `Stopped`
+    pub status: String,
+    pub title: String,
+    pub artist: String,
+}
+
+/// Calls to the player for inputs of the media dial since the last ones
+fn controls(pending: Pending, rotate: MediaRotate) -> Vec<Control> {
+    let mut controls = vec![];
+
+    // Two presses in the meantime leave the player as it was
+    if pending.presses % 2 == 1 {
+        controls.push(Control::PlayPause);
+    }
+
+    if pending.ticks != 0 {
+        controls.push(match rotate {
+            MediaRotate::Volume => Control::Volume(VOLUME_STEP * pending.ticks as f64),
+            MediaRotate::Seek => Control::Seek(SEEK_STEP as i64 * pending.ticks as i64 * 1_000_000),
+        });
+    }
+
+    controls
+}
+
+fn is_media_input(update: &DeviceStateUpdate, dial: u8) -> bool {
+    match *update {
+        DeviceStateUpdate::EncoderDown(encoder)
+        | DeviceStateUpdate::EncoderUp(encoder)
+        | DeviceStateUpdate::EncoderTwist(encoder, _) => encoder == dial,
+        _ => false,
+    }
+}
+
+/// Checks if an update should reach OpenDeck, inputs of the media dial control the player instead
+pub fn filter(update: &DeviceStateUpdate) -> bool {
+    let dial = CONFIG.borrow().media_dial;
+
+    if !dial.is_some_and(|dial| is_media_input(update, dial)) {
+        return true;
+    }
+
+    // Not sent from here, input keeps flowing while the player reacts
+    let mut pending = PENDING.lock().unwrap();
+
+    match *update {
+        DeviceStateUpdate::EncoderDown(_) => pending.presses += 1,
+        DeviceStateUpdate::EncoderTwist(_, ticks) if ticks != 0 => pending.ticks += ticks as i32,
+        _ => return false,
+    }
+
+    INPUT.notify_one();
+
+    false
+}
+
+/// Lines to draw for the track of the player, title and artist scroll if they're too long
+fn media_lines(track: Option<&Track>) -> Vec<String> {
+    let Some(track) = track else {
+        return vec!["NO PLAYER".to_string()];
+    };
+
+    let status = match track.status.as_str() {
+        "Playing" => "PLAY",
+        "Paused" => "PAUSE",
+        _ => "STOP",
+    };
+
+    let mut result: Vec<String> = [&track.title, &track.artist]
+        .into_iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    result.push(status.to_string());
+
+    result
+}
+
+#[cfg(target_os = "linux")]
+mod mpris {
+    use std::collections::HashMap;
+
+    use zbus::{
+        Connection,
+        zvariant::{OwnedValue, Value},
+    };
+
+    use super::{Control, Track};
+
+    const PREFIX: &str = "org.mpris.MediaPlayer2.";
+    const PATH: &str = "/org/mpris/MediaPlayer2";
+    const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+    const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+    /// Connection to the session bus players are on
+    pub struct Session(Connection);
+
+    impl Session {
+        pub async fn connect() -> Result<Self, String> {
+            Connection::session()
+                .await
+                .map(Self)
+                .map_err(|err| err.to_string())
+        }
+
+        async fn property(&self, player: &str, name: &str) -> zbus::Result<OwnedValue> {
+            self.0
+                .call_method(Some(player), PATH, Some(PROPERTIES), "Get", &(PLAYER, name))
+                .await?
+                .body()
+                .deserialize()
+        }
+
+        /// Player to show and control, the first playing one or else the first one at all
+        async fn player(&self) -> zbus::Result<Option<String>> {
+            let names: Vec<String> = self
+                .0
+                .call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus"),
+                    "ListNames",
+                    &(),
+                )
+                .await?
+                .body()
+                .deserialize()?;
+            let players: Vec<String> = names
+                .into_iter()
+                .filter(|name| name.starts_with(PREFIX))
+                .collect();
+
+            for player in &players {
+                let status = self.property(player, "PlaybackStatus").await;
+
+                if status.is_ok_and(|status| status.downcast_ref::<&str>() == Ok("Playing")) {
+                    return Ok(Some(player.clone()));
+                }
+            }
+
+            Ok(players.into_iter().next())
+        }
+
+        pub async fn track(&self) -> Result<Option<Track>, String> {
+            let Some(player) = self.player().await.map_err(|err| err.to_string())? else {
+                return Ok(None);
+            };
+
+            let status = self
+                .property(&player, "PlaybackStatus")
+                .await
+                .ok()
+                .and_then(|status| String::try_from(status).ok())
+                .unwrap_or_default();
+            let metadata: HashMap<String, OwnedValue> = self
+                .property(&player, "Metadata")
+                .await
+                .ok()
+                .and_then(|metadata| metadata.try_into().ok())
+                .unwrap_or_default();
+
+            let title = metadata
+                .get("xesam:title")
+                .and_then(|title| title.downcast_ref::<&str>().ok())
+                .unwrap_or_default();
+            let artists = metadata
+                .get("xesam:artist")
+                .and_then(|artists| artists.try_clone().ok())
+                .and_then(|artists| Vec::<String>::try_from(artists).ok())
+                .unwrap_or_default();
+
+            Ok(Some(Track {
+                status,
+                title: title.to_string(),
+                artist: artists.join(", "),
+            }))
+        }
+
+        pub async fn control(&self, control: Control) -> Result<(), String> {
+            let Some(player) = self.player().await.map_err(|err| err.to_string())? else {
+                return Ok(());
+            };
+
+            let result = match control {
+                Control::PlayPause => self
+                    .0
+                    .call_method(Some(player.as_str()), PATH, Some(PLAYER), "PlayPause", &())
+                    .await
+                    .map(drop),
+                Control::Seek(offset) => self
+                    .0
+                    .call_method(
+                        Some(player.as_str()),
+                        PATH,
+                        Some(PLAYER),
+                        "Seek",
+                        &(offset,),
+                    )
+                    .await
+                    .map(drop),
+                Control::Volume(change) => {
+                    let volume = self
+                        .property(&player, "Volume")
+                        .await
+                        .and_then(|volume| Ok(f64::try_from(volume)?))
+                        .map_err(|err| err.to_string())?;
+                    let volume = Value::from((volume + change).clamp(0.0, 1.0));
+
+                    self.0
+                        .call_method(
+                            Some(player.as_str()),
+                            PATH,
+                            Some(PROPERTIES),
+                            "Set",
+                            &(PLAYER, "Volume", volume),
+                        )
+                        .await
+                        .map(drop)
+                }
+            };
+
+            result.map_err(|err| err.to_string())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod mpris {
+    use super::{Control, Track};
+
+    pub struct Session;
+
+    impl Session {
+        pub async fn connect() -> Result<Self, String> {
+            Err("MPRIS is only on Linux".to_string())
+        }
+
+        pub async fn track(&self) -> Result<Option<Track>, String> {
+            Ok(None)
+        }
+
+        pub async fn control(&self, _control: Control) -> Result<(), String> {
+            Ok(())
+        }
+    }
+}
+
+/// Connects to the session bus, unless it's connected already
+async fn connect(session: &mut Option<mpris::Session>) -> Result<&mpris::Session, String> {
+    if session.is_none() {
+        *session = Some(mpris::Session::connect().await?);
+    }
+
+    Ok(session.as_ref().unwrap())
+}
+
+/// Draws the playing track on the strip zone above `media_dial` of every connected device
+///
+/// Players are found through MPRIS on the D-Bus session bus, so this only works on Linux. Titles
+/// too long for the zone scroll, the player is still only checked every [MEDIA_INTERVAL].
+pub async fn media_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut marquee = Marquee::default();
+    let mut session = None;
+    let mut warned = false;
+    let mut lines = vec![];
+    let mut checked: Option<Instant> = None;
+    let mut next_frame = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_frame.into()) => {}
+            _ = INPUT.notified() => {
+                let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+                let rotate = CONFIG.borrow().media_rotate;
+
+                for control in controls(pending, rotate) {
+                    let result = match connect(&mut session).await {
+                        Ok(session) => session.control(control).await,
+                        Err(err) => Err(err),
+                    };
+
+                    if let Err(err) = result {
+                        log::warn!("Failed to send {:?} to the player: {}", control, err);
+                        session = None;
+                    }
+                }
+
+                // Shows a new state of the player right away
+                checked = None;
+                continue;
+            }
+            _ = token.cancelled() => break,
+        }
+
+        let dial = CONFIG.borrow().media_dial;
+
+        painter.retain(|_, position| Some(position) == dial);
+
+        let Some(dial) = dial else {
+            next_frame = Instant::now() + MEDIA_INTERVAL;
+            continue;
+        };
+
+        if checked.is_none_or(|at| at.elapsed() >= MEDIA_INTERVAL) {
+            let track = match connect(&mut session).await {
+                Ok(session) => session.track().await,
+                Err(err) => Err(err),
+            };
+
+            let track = track.unwrap_or_else(|err| {
+                if !warned {
+                    log::warn!(
+                        "No MPRIS players, media dial needs a D-Bus session: {}",
+                        err
+                    );
+                    warned = true;
+                }
+
+                session = None;
+                None
+            });
+
+            lines = media_lines(track.as_ref());
+            checked = Some(Instant::now());
+        }
+
+        let image_lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let overflow = marquee_overflow(MEDIA_SIZE, &image_lines, &[]);
+        let offsets = marquee.offsets(&lines, &overflow);
+        let scrolling = overflow.iter().any(|overflow| *overflow > 0);
+
+        // Devices in safe mode keep the start of the text still
+        let still = vec![0; offsets.len()];
+
+        for (offsets, safe) in [(&offsets, false), (&still, true)] {
+            // Every scroll position is a frame of its own, pauses at the ends don't send anything
+            let mut frame = lines.clone();
+            frame.extend(offsets.iter().map(|offset| format!("@{}", offset)));
+
+            painter
+                .paint_where(
+                    |id| safemode::is_active(id) == safe,
+                    dial,
+                    frame,
+                    || render_marquee(MEDIA_SIZE, &image_lines, &[], offsets, TextStyle::default()),
+                )
+                .await;
+        }
+
+        next_frame = Instant::now()
+            + if scrolling {
+                duty::frame_interval(None, MARQUEE_FRAME)
+            } else {
+                MEDIA_INTERVAL
+            };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn dial_inputs_become_player_commands() {
+        let pending = |presses, ticks| Pending { presses, ticks };
+
+        assert_eq!(
+            controls(pending(1, 0), MediaRotate::Volume),
+            [Control::PlayPause]
+        );
+        assert_eq!(controls(pending(2, 0), MediaRotate::Volume), []);
+
+        // Ticks in between calls add up to one change
+        assert_eq!(
+            controls(pending(0, -2), MediaRotate::Volume),
+            [Control::Volume(-0.04)]
+        );
+        assert_eq!(
+            controls(pending(1, 3), MediaRotate::Seek),
+            [Control::PlayPause, Control::Seek(15_000_000)]
+        );
+
+        assert!(is_media_input(&EncoderUp(1), 1));
+        assert!(!is_media_input(&EncoderTwist(2, 1), 1));
+        assert!(!is_media_input(&ButtonDown(1), 1));
+    }
+
+    #[test]
+    fn tracks_become_lines() {
+        let track = |status: &str, title: &str, artist: &str| Track {
+            status: status.to_string(),
+            title: title.to_string(),
+            artist: artist.to_string(),
+        };
+
+        // Long titles scroll instead of being cut off
+        assert_eq!(
+            media_lines(Some(&track("Playing", "Bohemian Rhapsody", "Queen"))),
+            ["Bohemian Rhapsody", "Queen", "PLAY"]
+        );
+        assert_eq!(
+            media_lines(Some(&track("Paused", "Talk", ""))),
+            ["Talk", "PAUSE"]
+        );
+        assert_eq!(media_lines(None), ["NO PLAYER"]);
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::{images::KeyImage, inputs::Control, mappings::COL_COUNT, text::render_banner};
+use image::DynamicImage;
+use mirajazz::state::DeviceStateUpdate;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, WRITERS, lock, pages, writer::WriterCommand};
+
+/// The menu closes by itself after this long without input
+pub const MENU_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often open menus are checked for the timeout
+const MENU_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Brightness change of a single dial step
+pub const BRIGHTNESS_STEP: u8 = 5;
+
+/// Entries of the menu, in the order the dial goes through them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Item {
+    Brightness,
+    Layout,
+    Lock,
+    Info,
+    Close,
+}
+
+const ITEMS: [Item; 5] = [
+    Item::Brightness,
+    Item::Layout,
+    Item::Lock,
+    Item::Info,
+    Item::Close,
+];
+
+impl Item {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brightness => "BRIGHTNESS",
+            Self::Layout => "LAYOUT",
+            Self::Lock => "LOCK",
+            Self::Info => "INFO",
+            Self::Close => "CLOSE",
+        }
+    }
+}
+
+/// What the menu does with an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Not for the menu, goes to OpenDeck
+    Dispatch,
+    /// Swallowed without changing anything
+    Swallow,
+    /// Menu opened or changed, it's drawn again
+    Redraw,
+    /// Device gets this brightness
+    Brightness(u8),
+    /// Pages are turned by this much
+    Turn(i32),
+    /// Menu closed to lock the device
+    Lock,
+    /// Menu closed, the strip shows what it did before
+    Close,
+}
+
+/// Menu of a single device
+///
+/// While it's open, every input is swallowed. The dial moves through items and presses pick
+/// them, brightness and layout then take the dial until it's pressed again. Pressing any key
+/// closes the menu.
+#[derive(Debug)]
+struct Menu {
+    open: bool,
+    selected: usize,
+    // Picked item takes dial turns
+    editing: bool,
+    brightness: u8,
+    last_input: Instant,
+    // Controls pressed while open, their releases are swallowed as well
+    suppressed: HashSet<Control>,
+}
+
+impl Menu {
+    fn new(brightness: u8) -> Self {
+        Self {
+            open: false,
+            selected: 0,
+            editing: false,
+            brightness,
+            last_input: Instant::now(),
+            suppressed: HashSet::new(),
+        }
+    }
+
+    fn item(&self) -> Item {
+        ITEMS[self.selected]
+    }
+
+    /// Decides what to do with an update coming from the device, `dial` belongs to the menu
+    fn input(&mut self, update: &DeviceStateUpdate, dial: u8, now: Instant) -> Action {
+        let control = Control::of(update);
+
+        if let Some((control, false)) = control {
+            return match self.suppressed.remove(&control) || control == Control::Encoder(dial) {
+                true => Action::Swallow,
+                false => Action::Dispatch,
+            };
+        }
+
+        if !self.open {
+            return match *update {
+                DeviceStateUpdate::EncoderDown(encoder) if encoder == dial => {
+                    self.suppressed.insert(Control::Encoder(dial));
+                    self.open = true;
+                    self.selected = 0;
+                    self.editing = false;
+                    self.last_input = now;
+                    Action::Redraw
+                }
+                DeviceStateUpdate::EncoderTwist(encoder, _) if encoder == dial => Action::Swallow,
+                _ => Action::Dispatch,
+            };
+        }
+
+        self.last_input = now;
+
+        if let Some((control, _)) = control {
+            self.suppressed.insert(control);
+        }
+
+        match *update {
+            DeviceStateUpdate::EncoderTwist(encoder, ticks) if encoder == dial => {
+                let step = ticks.signum() as i32;
+
+                match (self.editing, self.item()) {
+                    (true, Item::Brightness) => {
+                        let change = step * BRIGHTNESS_STEP as i32;
+                        self.brightness = (self.brightness as i32 + change).clamp(0, 100) as u8;
+                        Action::Brightness(self.brightness)
+                    }
+                    (true, Item::Layout) => Action::Turn(step),
+                    _ => {
+                        self.selected =
+                            (self.selected as i32 + step).rem_euclid(ITEMS.len() as i32) as usize;
+                        Action::Redraw
+                    }
+                }
+            }
+            DeviceStateUpdate::EncoderDown(encoder) if encoder == dial => match self.item() {
+                Item::Brightness | Item::Layout => {
+                    self.editing = !self.editing;
+                    Action::Redraw
+                }
+                Item::Lock => {
+                    self.open = false;
+                    Action::Lock
+                }
+                Item::Info => Action::Swallow,
+                Item::Close => {
+                    self.open = false;
+                    Action::Close
+                }
+            },
+            DeviceStateUpdate::ButtonDown(_) => {
+                self.open = false;
+                Action::Close
+            }
+            _ => Action::Swallow,
+        }
+    }
+}
+
+// Device id to its menu
+static MENUS: LazyLock<Mutex<HashMap<String, Menu>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_open(device: &str) -> bool {
+    MENUS
+        .lock()
+        .unwrap()
+        .get(device)
+        .is_some_and(|menu| menu.open)
+}
+
+/// Checks if the menu is drawn on the position, so other images for it are held back
+pub fn draws_position(device: &str, position: u8) -> bool {
+    (position as usize) < COL_COUNT && is_open(device)
+}
+
+/// Lines the menu shows for its selected item
+fn menu_lines(menu: &Menu, device: &str) -> Vec<String> {
+    let item = menu.item();
+
+    let detail = match item {
+        Item::Brightness => format!("{}%", menu.brightness),
+        Item::Layout => match pages::indicator(device) {
+            Some(indicator) => format!("PAGE {}", indicator),
+            None => "1 PAGE".to_string(),
+        },
+        Item::Lock => "PRESS TO LOCK".to_string(),
+        Item::Info => format!("V{} {}", env!("CARGO_PKG_VERSION"), device),
+        Item::Close => "PRESS TO CLOSE".to_string(),
+    };
+
+    // Arrows show the dial changes the value instead of the item
+    let detail = match menu.editing {
+        true => format!("< {} >", detail),
+        false => detail,
+    };
+
+    vec![
+        format!("{} {}/{}", item.name(), menu.selected + 1, ITEMS.len()),
+        detail,
+    ]
+}
+
+/// Images of the strip zones, from left to right
+fn render_menu(menu: &Menu, device: &str) -> Vec<DynamicImage> {
+    let lines = menu_lines(menu, device);
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+    let mut zones = render_banner(COL_COUNT as u32, &lines, &[2, 3]);
+
+    // Every zone gets a piece of the bar, filled once the ones left of it are full
+    if menu.item() == Item::Brightness {
+        let bar = CONFIG.borrow().progress_bar;
+        let filled = menu.brightness as u32 * COL_COUNT as u32;
+
+        for (zone, image) in zones.iter_mut().enumerate() {
+            let percent = filled.saturating_sub(zone as u32 * 100).min(100);
+            bar.draw_bottom(image, percent);
+        }
+    }
+
+    zones
+}
+
+async fn draw(device: &str) {
+    let images = match MENUS.lock().unwrap().get(device) {
+        Some(menu) if menu.open => render_menu(menu, device),
+        _ => return,
+    };
+
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        let images = images
+            .into_iter()
+            .enumerate()
+            .map(|(position, image)| (position as u8, Some(KeyImage::Rendered(Arc::new(image)))))
+            .collect();
+
+        writer.send(WriterCommand::SetImages(images));
+    }
+}
+
+/// Asks for what the strip showed before the menu opened
+async fn closed(device: &str) {
+    log::info!("Closed the menu of {}", device);
+
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        let images = (0..COL_COUNT as u8).map(|position| (position, None));
+
+        writer.send(WriterCommand::SetImages(images.collect()));
+        writer.send(WriterCommand::Redraw);
+    }
+}
+
+/// Checks if an update should reach OpenDeck, the menu dial and everything while the menu is
+/// open are handled by the menu instead
+pub async fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let (dial, brightness) = {
+        let config = CONFIG.borrow();
+
+        (config.menu_dial, config.brightness)
+    };
+
+    let Some(dial) = dial else {
+        return true;
+    };
+
+    let action = MENUS
+        .lock()
+        .unwrap()
+        .entry(device.to_string())
+        .or_insert_with(|| Menu::new(brightness))
+        .input(update, dial, Instant::now());
+
+    match action {
+        Action::Dispatch => return true,
+        Action::Swallow => return false,
+        Action::Redraw => {}
+        Action::Brightness(brightness) => {
+            if let Some(writer) = WRITERS.read().await.get(device) {
+                writer.send(WriterCommand::SetBrightness(brightness));
+            }
+        }
+        Action::Turn(delta) => pages::turn(device, delta).await,
+        // Padlock takes the strip over, unlocking draws it again anyway
+        Action::Lock => {
+            lock::lock(device).await;
+            return false;
+        }
+        Action::Close => {
+            closed(device).await;
+            return false;
+        }
+    }
+
+    draw(device).await;
+
+    false
+}
+
+/// Closes menus nobody touched for a while
+pub async fn menu_task(token: CancellationToken) {
+    loop {
+        let now = Instant::now();
+        let mut expired = vec![];
+
+        for (device, menu) in MENUS.lock().unwrap().iter_mut() {
+            if menu.open && now.duration_since(menu.last_input) >= MENU_TIMEOUT {
+                menu.open = false;
+                expired.push(device.clone());
+            }
+        }
+
+        for device in expired {
+            closed(&device).await;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(MENU_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn dial_moves_through_items_and_picks_them() {
+        let now = Instant::now();
+        let mut menu = Menu::new(50);
+
+        // Closed, only the dial press does something
+        assert_eq!(menu.input(&ButtonDown(3), 3, now), Action::Dispatch);
+        assert_eq!(menu.input(&EncoderTwist(3, 1), 3, now), Action::Swallow);
+        assert_eq!(menu.input(&EncoderDown(3), 3, now), Action::Redraw);
+        assert_eq!(menu.input(&EncoderUp(3), 3, now), Action::Swallow);
+        assert!(menu.open);
+
+        // Brightness takes the dial until it's pressed again
+        assert_eq!(menu.input(&EncoderDown(3), 3, now), Action::Redraw);
+        assert_eq!(
+            menu.input(&EncoderTwist(3, 2), 3, now),
+            Action::Brightness(55)
+        );
+        assert_eq!(menu.input(&EncoderDown(3), 3, now), Action::Redraw);
+
+        // Going back from the first item wraps around to the last
+        assert_eq!(menu.input(&EncoderTwist(3, -1), 3, now), Action::Redraw);
+        assert_eq!(menu.item(), Item::Close);
+        assert_eq!(menu.input(&EncoderTwist(3, -2), 3, now), Action::Redraw);
+        assert_eq!(menu.item(), Item::Info);
+        assert_eq!(menu.input(&EncoderTwist(3, -1), 3, now), Action::Redraw);
+        assert_eq!(menu.input(&EncoderDown(3), 3, now), Action::Lock);
+        assert!(!menu.open);
+    }
+
+    #[test]
+    fn inputs_are_swallowed_while_open() {
+        let now = Instant::now();
+        let mut menu = Menu::new(50);
+
+        assert_eq!(menu.input(&EncoderDown(3), 3, now), Action::Redraw);
+        assert_eq!(menu.input(&EncoderTwist(0, 1), 3, now), Action::Swallow);
+        assert_eq!(menu.input(&EncoderDown(1), 3, now), Action::Swallow);
+        assert_eq!(menu.input(&EncoderUp(1), 3, now), Action::Swallow);
+
+        // Keys close the menu, their releases don't reach OpenDeck either
+        assert_eq!(menu.input(&ButtonDown(7), 3, now), Action::Close);
+        assert_eq!(menu.input(&ButtonUp(7), 3, now), Action::Swallow);
+        assert_eq!(menu.input(&ButtonDown(7), 3, now), Action::Dispatch);
+        assert_eq!(menu.input(&ButtonUp(7), 3, now), Action::Dispatch);
+
+        let lines = menu_lines(&menu, "a5-test");
+        assert_eq!(lines, ["BRIGHTNESS 1/5", "50%"]);
+        assert_eq!(render_menu(&menu, "a5-test").len(), COL_COUNT);
+    }
+}
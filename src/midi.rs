@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, mpsc},
+    time::Duration,
+};
+
+use akp05::mappings::ENCODER_COUNT;
+use mirajazz::state::DeviceStateUpdate;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::CONFIG;
+
+/// Controller number of the first encoder, the rest follow. 20 - 31 are undefined in the MIDI spec
+pub const FIRST_CC: u8 = 20;
+
+/// Note of key 0, 36 is where drum pads usually start
+pub const FIRST_NOTE: u8 = 36;
+
+/// Note of the first encoder press, after every key
+pub const FIRST_ENCODER_NOTE: u8 = FIRST_NOTE + 16;
+
+/// Value encoders start at, in the middle so they can go both ways
+pub const CENTER_VALUE: u8 = 64;
+
+/// Wait before opening the output again after it fails
+pub const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+/// Messages waiting for the output, inputs are dropped if it's this far behind
+const MESSAGE_BUFFER: usize = 256;
+
+static MESSAGES: LazyLock<broadcast::Sender<[u8; 3]>> =
+    LazyLock::new(|| broadcast::channel(MESSAGE_BUFFER).0);
+
+static MIDI: LazyLock<Mutex<MidiState>> = LazyLock::new(|| Mutex::new(MidiState::default()));
+
+#[derive(Debug, Default)]
+struct MidiState {
+    // Action context to device showing the MIDI Mode action
+    actions: HashMap<String, String>,
+    // Last value sent for every encoder of a device
+    values: HashMap<String, [u8; ENCODER_COUNT]>,
+}
+
+/// Turns MIDI mode on for the device while the action is shown, i.e. while its profile is active
+pub fn add_action(context: &str, device: String) {
+    MIDI.lock()
+        .unwrap()
+        .actions
+        .insert(context.to_string(), device);
+}
+
+pub fn remove_action(context: &str) {
+    MIDI.lock().unwrap().actions.remove(context);
+}
+
+/// MIDI message for an update, encoders send absolute values kept in `values`
+fn message(update: DeviceStateUpdate, channel: u8, values: &mut [u8; ENCODER_COUNT]) -> [u8; 3] {
+    let channel = channel & 0x0F;
+
+    match update {
+        DeviceStateUpdate::ButtonDown(key) => [0x90 | channel, (FIRST_NOTE + key) & 0x7F, 127],
+        DeviceStateUpdate::ButtonUp(key) => [0x80 | channel, (FIRST_NOTE + key) & 0x7F, 0],
+        DeviceStateUpdate::EncoderDown(encoder) => {
+            [0x90 | channel, FIRST_ENCODER_NOTE + encoder, 127]
+        }
+        DeviceStateUpdate::EncoderUp(encoder) => [0x80 | channel, FIRST_ENCODER_NOTE + encoder, 0],
+        DeviceStateUpdate::EncoderTwist(encoder, ticks) => {
+            let value = &mut values[encoder as usize % ENCODER_COUNT];
+            *value = (*value as i16 + ticks as i16).clamp(0, 127) as u8;
+
+            [0xB0 | channel, FIRST_CC + encoder, *value]
+        }
+    }
+}
+
+/// Sends an update as MIDI if the device is in MIDI mode, OpenDeck still gets it as well
+pub fn send_update(device: &str, update: DeviceStateUpdate) {
+    let mut midi = MIDI.lock().unwrap();
+
+    if !midi.actions.values().any(|id| id == device) {
+        return;
+    }
+
+    let channel = CONFIG.borrow().midi_channel - 1;
+    let values = midi
+        .values
+        .entry(device.to_string())
+        .or_insert([CENTER_VALUE; ENCODER_COUNT]);
+
+    // Nobody listening just means no output is configured
+    let _ = MESSAGES.send(message(update, channel, values));
+}
+
+#[cfg(target_os = "linux")]
+mod output {
+    use std::{fs::File, io::Write};
+
+    /// ALSA raw MIDI device, e.g. `/dev/snd/midiC1D0` of the `snd-virmidi` module
+    pub struct Output(File);
+
+    impl Output {
+        pub fn open(name: &str) -> Result<Self, String> {
+            File::options()
+                .write(true)
+                .open(name)
+                .map(Self)
+                .map_err(|err| format!("Failed to open {}: {}", name, err))
+        }
+
+        pub fn send(&mut self, message: [u8; 3]) -> Result<(), String> {
+            self.0.write_all(&message).map_err(|err| err.to_string())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod output {
+    use windows::Win32::Media::Audio::{
+        CALLBACK_NULL, HMIDIOUT, MIDIOUTCAPSW, midiOutClose, midiOutGetDevCapsW, midiOutGetNumDevs,
+        midiOutOpen, midiOutShortMsg,
+    };
+
+    /// MIDI output device whose name contains the configured one, e.g. a loopMIDI port
+    pub struct Output(HMIDIOUT);
+
+    impl Output {
+        pub fn open(name: &str) -> Result<Self, String> {
+            let wanted = name.to_lowercase();
+
+            for id in 0..unsafe { midiOutGetNumDevs() } {
+                let mut caps = MIDIOUTCAPSW::default();
+                let size = std::mem::size_of::<MIDIOUTCAPSW>() as u32;
+
+                if unsafe { midiOutGetDevCapsW(id as usize, &mut caps, size) } != 0 {
+                    continue;
+                }
+
+                // Struct is packed, the name has to be copied out before borrowing it
+                let pname = caps.szPname;
+                let length = pname.iter().position(|ch| *ch == 0).unwrap_or(pname.len());
+                let device = String::from_utf16_lossy(&pname[..length]);
+
+                if !device.to_lowercase().contains(&wanted) {
+                    continue;
+                }
+
+                let mut handle = HMIDIOUT::default();
+                let result = unsafe { midiOutOpen(&mut handle, id, None, None, CALLBACK_NULL) };
+
+                if result != 0 {
+                    return Err(format!("Failed to open {}, error {}", device, result));
+                }
+
+                return Ok(Self(handle));
+            }
+
+            Err(format!("No MIDI output named like {}", name))
+        }
+
+        pub fn send(&mut self, message: [u8; 3]) -> Result<(), String> {
+            let packed = u32::from_le_bytes([message[0], message[1], message[2], 0]);
+
+            match unsafe { midiOutShortMsg(self.0, packed) } {
+                0 => Ok(()),
+                error => Err(format!("error {}", error)),
+            }
+        }
+    }
+
+    impl Drop for Output {
+        fn drop(&mut self) {
+            unsafe { midiOutClose(self.0) };
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod output {
+    pub struct Output;
+
+    impl Output {
+        pub fn open(_name: &str) -> Result<Self, String> {
+            Err("MIDI output isn't supported on this platform".to_string())
+        }
+
+        pub fn send(&mut self, _message: [u8; 3]) -> Result<(), String> {
+            Ok(())
+        }
+    }
+}
+
+/// Writes messages until the sending half is dropped, output handles can't leave their thread
+fn output_thread(name: String, messages: mpsc::Receiver<[u8; 3]>) -> Result<(), String> {
+    let mut output = output::Output::open(&name)?;
+
+    log::info!("Sending MIDI to {}", name);
+
+    for message in messages {
+        output.send(message)?;
+    }
+
+    Ok(())
+}
+
+/// Forwards MIDI messages of devices in MIDI mode to `midi_output` while one is configured
+pub async fn midi_task(token: CancellationToken) {
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        let name = config.borrow_and_update().midi_output.clone();
+        let mut failed = false;
+
+        if let Some(name) = name {
+            let mut messages = MESSAGES.subscribe();
+            let mut changes = CONFIG.subscribe();
+            let (sender, receiver) = mpsc::channel();
+            let thread_name = name.clone();
+            let mut thread =
+                tokio::task::spawn_blocking(move || output_thread(thread_name, receiver));
+
+            let result = loop {
+                tokio::select! {
+                    message = messages.recv() => match message {
+                        Ok(message) => {
+                            // Fails once the thread is gone, the error comes from joining it
+                            let _ = sender.send(message);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("MIDI output is too slow, dropped {} messages", missed);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break Ok(()),
+                    },
+                    result = &mut thread => {
+                        break result.unwrap_or_else(|err| Err(err.to_string()));
+                    }
+                    _ = changes.changed() => {
+                        if changes.borrow_and_update().midi_output.as_ref() != Some(&name) {
+                            break Ok(());
+                        }
+                    }
+                    _ = token.cancelled() => break Ok(()),
+                }
+            };
+
+            drop(sender);
+
+            if let Err(err) = result {
+                log::error!("MIDI output failed: {}", err);
+                failed = true;
+            }
+        }
+
+        if token.is_cancelled() {
+            break;
+        }
+
+        // Open again after a delay, or once settings change
+        tokio::select! {
+            _ = tokio::time::sleep(REOPEN_DELAY), if failed => {}
+            _ = config.changed() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn updates_become_messages() {
+        let mut values = [CENTER_VALUE; ENCODER_COUNT];
+
+        assert_eq!(message(ButtonDown(5), 0, &mut values), [0x90, 41, 127]);
+        assert_eq!(message(ButtonUp(5), 0, &mut values), [0x80, 41, 0]);
+        assert_eq!(message(EncoderDown(1), 9, &mut values), [0x99, 53, 127]);
+        assert_eq!(message(EncoderTwist(2, 3), 0, &mut values), [0xB0, 22, 67]);
+        assert_eq!(
+            message(EncoderTwist(2, -100), 0, &mut values),
+            [0xB0, 22, 0]
+        );
+        assert_eq!(values, [64, 64, 0, 64]);
+    }
+}
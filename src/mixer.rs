@@ -0,0 +1,328 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use akp05::text::{TextStyle, render_lines};
+use mirajazz::state::DeviceStateUpdate;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, writer::KeyPainter};
+
+/// How often volumes of the applications are checked
+pub const MIXER_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Size levels are rendered at, images are resized to the device format anyway
+pub const MIXER_SIZE: (u32, u32) = (120, 120);
+
+/// Volume change of a single tick in percent
+pub const VOLUME_STEP: u32 = 2;
+
+/// Longest application name drawn
+pub const MAX_NAME_CHARS: usize = 10;
+
+/// Applications for every encoder controlling their volume
+pub type MixerDials = BTreeMap<u8, String>;
+
+/// Audio stream of an application, `pactl` calls these sink inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SinkInput {
+    index: u32,
+    name: String,
+    binary: Option<String>,
+    volume: u32,
+    muted: bool,
+}
+
+impl SinkInput {
+    /// Checks if the stream belongs to `app`, either its name or its executable
+    fn matches(&self, app: &str) -> bool {
+        self.name.eq_ignore_ascii_case(app)
+            || self
+                .binary
+                .as_ref()
+                .is_some_and(|binary| binary.eq_ignore_ascii_case(app))
+    }
+}
+
+fn property(line: &str, name: &str) -> Option<String> {
+    let value = line.strip_prefix(name)?.trim().strip_prefix('=')?.trim();
+
+    Some(value.trim_matches('"').to_string())
+}
+
+/// Parses `pactl list sink-inputs` output, volume is the loudest channel
+fn parse_sink_inputs(output: &str) -> Vec<SinkInput> {
+    let mut inputs: Vec<SinkInput> = vec![];
+
+    for line in output.lines() {
+        let line = line.trim();
+
+        if let Some(index) = line.strip_prefix("Sink Input #") {
+            if let Ok(index) = index.trim().parse() {
+                inputs.push(SinkInput {
+                    index,
+                    name: String::new(),
+                    binary: None,
+                    volume: 0,
+                    muted: false,
+                });
+            }
+
+            continue;
+        }
+
+        let Some(input) = inputs.last_mut() else {
+            continue;
+        };
+
+        if let Some(mute) = line.strip_prefix("Mute:") {
+            input.muted = mute.trim() == "yes";
+        } else if let Some(volume) = line.strip_prefix("Volume:") {
+            // `front-left: 39322 /  60% / -13.31 dB,   front-right: 39322 /  60% / -13.31 dB`
+            input.volume = volume
+                .split('/')
+                .filter_map(|part| part.trim().strip_suffix('%'))
+                .filter_map(|percent| percent.parse().ok())
+                .max()
+                .unwrap_or(0);
+        } else if let Some(name) = property(line, "application.name") {
+            input.name = name;
+        } else if let Some(binary) = property(line, "application.process.binary") {
+            input.binary = Some(binary);
+        }
+    }
+
+    inputs
+}
+
+/// Volume after turning the dial, kept between 0 and 100 %
+pub fn target_volume(volume: u32, ticks: i8) -> u32 {
+    let change = VOLUME_STEP as i64 * ticks as i64;
+
+    (volume as i64 + change).clamp(0, 100) as u32
+}
+
+async fn list_sink_inputs() -> Option<Vec<SinkInput>> {
+    let output = Command::new("pactl")
+        .args(["list", "sink-inputs"])
+        // Output is parsed, it must not be translated
+        .env("LC_ALL", "C")
+        .output()
+        .await
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| parse_sink_inputs(&String::from_utf8_lossy(&output.stdout)))
+}
+
+async fn pactl(args: &[String]) {
+    match Command::new("pactl").args(args).output().await {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => log::warn!(
+            "pactl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(err) => log::warn!("Failed to run pactl: {}", err),
+    }
+}
+
+/// Changes volume or mute of every stream of `app`
+async fn apply(app: String, update: DeviceStateUpdate) {
+    let Some(inputs) = list_sink_inputs().await else {
+        log::warn!("Failed to list audio streams, mixer dials need pactl");
+        return;
+    };
+
+    for input in inputs.iter().filter(|input| input.matches(&app)) {
+        let index = input.index.to_string();
+
+        let args = match update {
+            DeviceStateUpdate::EncoderTwist(_, ticks) => [
+                "set-sink-input-volume".to_string(),
+                index,
+                format!("{}%", target_volume(input.volume, ticks)),
+            ],
+            DeviceStateUpdate::EncoderDown(_) => [
+                "set-sink-input-mute".to_string(),
+                index,
+                "toggle".to_string(),
+            ],
+            _ => return,
+        };
+
+        pactl(&args).await;
+    }
+}
+
+/// Encoder the update is for, [None] for keys
+fn encoder(update: &DeviceStateUpdate) -> Option<u8> {
+    match *update {
+        DeviceStateUpdate::EncoderDown(encoder)
+        | DeviceStateUpdate::EncoderUp(encoder)
+        | DeviceStateUpdate::EncoderTwist(encoder, _) => Some(encoder),
+        _ => None,
+    }
+}
+
+/// Checks if an update should reach OpenDeck, inputs of mixer dials change volumes instead
+pub fn filter(update: &DeviceStateUpdate) -> bool {
+    let app =
+        encoder(update).and_then(|encoder| CONFIG.borrow().mixer_dials.get(&encoder).cloned());
+
+    let Some(app) = app else {
+        return true;
+    };
+
+    // Not awaited, input keeps flowing while pactl runs
+    tokio::spawn(apply(app, *update));
+
+    false
+}
+
+fn shorten(name: &str) -> String {
+    if name.chars().count() <= MAX_NAME_CHARS {
+        return name.to_string();
+    }
+
+    let mut short: String = name.chars().take(MAX_NAME_CHARS - 2).collect();
+    short.push_str("..");
+
+    short
+}
+
+/// Lines to draw for `app`, using the loudest of its streams
+fn mixer_lines(app: &str, inputs: &[SinkInput]) -> Vec<String> {
+    let name = shorten(app);
+
+    let loudest = inputs
+        .iter()
+        .filter(|input| input.matches(app))
+        .max_by_key(|input| input.volume);
+
+    match loudest {
+        None => vec![name, "-".to_string()],
+        Some(input) if input.muted => vec![name, "MUTE".to_string()],
+        Some(input) => vec![name, format!("{}%", input.volume)],
+    }
+}
+
+/// Draws application names and volumes on the strip zones above mixer dials of every device
+///
+/// Volumes come from `pactl`, which PulseAudio and PipeWire both provide, so this only works on
+/// Linux.
+pub async fn mixer_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut warned = false;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(MIXER_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let (dials, bar) = {
+            let config = CONFIG.borrow();
+            (config.mixer_dials.clone(), config.progress_bar)
+        };
+
+        painter.retain(|_, position| dials.contains_key(&position));
+
+        if dials.is_empty() {
+            continue;
+        }
+
+        let inputs = match list_sink_inputs().await {
+            Some(inputs) => inputs,
+            None => {
+                if !warned {
+                    log::warn!("Failed to list audio streams, mixer dials need pactl");
+                    warned = true;
+                }
+
+                vec![]
+            }
+        };
+
+        for (dial, app) in dials {
+            let lines = mixer_lines(&app, &inputs);
+            let volume = inputs
+                .iter()
+                .filter(|input| input.matches(&app) && !input.muted)
+                .map(|input| input.volume)
+                .max()
+                .unwrap_or(0);
+            let image_lines = lines.clone();
+
+            painter
+                .paint(dial, lines, || {
+                    let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+                    let mut image = render_lines(MIXER_SIZE, &lines, &[], TextStyle::default());
+
+                    bar.draw_bottom(&mut image, volume);
+
+                    image
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "Sink Input #42
+	Driver: protocol-native.c
+	Mute: no
+	Volume: front-left: 39322 /  60% / -13.31 dB,   front-right: 45875 /  70% / -9.29 dB
+	        balance 0.14
+	Properties:
+		application.name = \"Firefox\"
+		application.process.binary = \"firefox\"
+
+Sink Input #57
+	Mute: yes
+	Volume: mono: 65536 / 100% / 0.00 dB
+	Properties:
+		application.name = \"Spotify\"
+";
+
+    #[test]
+    fn sink_inputs_are_parsed() {
+        let inputs = parse_sink_inputs(OUTPUT);
+
+        assert_eq!(
+            inputs,
+            [
+                SinkInput {
+                    index: 42,
+                    name: "Firefox".to_string(),
+                    binary: Some("firefox".to_string()),
+                    volume: 70,
+                    muted: false,
+                },
+                SinkInput {
+                    index: 57,
+                    name: "Spotify".to_string(),
+                    binary: None,
+                    volume: 100,
+                    muted: true,
+                },
+            ]
+        );
+
+        assert_eq!(mixer_lines("firefox", &inputs), ["firefox", "70%"]);
+        assert_eq!(mixer_lines("spotify", &inputs), ["spotify", "MUTE"]);
+        assert_eq!(mixer_lines("discord", &inputs), ["discord", "-"]);
+        assert_eq!(mixer_lines("thunderbird", &inputs), ["thunderb..", "-"]);
+    }
+
+    #[test]
+    fn volume_stays_in_range() {
+        assert_eq!(target_volume(60, 3), 66);
+        assert_eq!(target_volume(99, 2), 100);
+        assert_eq!(target_volume(3, -4), 0);
+    }
+}
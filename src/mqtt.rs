@@ -0,0 +1,610 @@
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use akp05::{images::KeyImage, inputs::SystemEvent};
+use image::{ImageFormat, load_from_memory_with_format};
+use mirajazz::state::DeviceStateUpdate;
+use rumqttc::{AsyncClient, ConnectionError, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, WRITERS,
+    badge::{Badge, BadgeKind, Corner, parse_color},
+    blink::{self, Blink, BlinkImage, DEFAULT_BLINK_INTERVAL, MAX_BLINK_SECONDS},
+    toast::{self, Toast},
+    writer::WriterCommand,
+};
+
+/// Port used when the broker address doesn't have one
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// How long to wait before connecting again after the broker went away
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Keep alive sent to the broker, a ping goes out each time it passes
+pub const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Largest packet sent or received, images come in whole as JPEGs or data URLs
+const MAX_PACKET_SIZE: usize = 4 * 1024 * 1024;
+
+/// How many input events can wait for the broker before they start being dropped
+const EVENT_BUFFER: usize = 256;
+
+// Inputs sent to OpenDeck, mirrored to the broker while the bridge is connected
+static EVENTS: LazyLock<broadcast::Sender<(String, Event)>> =
+    LazyLock::new(|| broadcast::channel(EVENT_BUFFER).0);
+
+/// Event published to the broker
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    Update(DeviceStateUpdate),
+    /// Strip zone and its new value
+    Slider(u8, u8),
+    /// Unknown input code and its state byte
+    Raw(u8, u8),
+    /// Status notification of the device
+    System(SystemEvent),
+}
+
+/// Broker settings, the bridge reconnects whenever they change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttSettings {
+    /// `host:port` or just `host`
+    pub broker: String,
+    /// Prefix of every topic, e.g. `akp05` for `akp05/a5-<serial>/key/5`
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Command received from the broker
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    SetBrightness(u8),
+    /// Image for a position, [None] clears it
+    SetImage(u8, Option<KeyImage>),
+    /// Blinks a position with its dimmed image for this many seconds, 0 stops it
+    Blink(u8, u64),
+    /// Text to show on the strip for a few seconds
+    Toast(String),
+    /// Badge in the top right corner of a position, [None] removes it
+    Badge(u8, Option<BadgeKind>),
+}
+
+/// Mirrors an input to the broker, does nothing unless the bridge is connected
+pub fn publish_update(id: &str, update: DeviceStateUpdate) {
+    if EVENTS.receiver_count() > 0 {
+        let _ = EVENTS.send((id.to_string(), Event::Update(update)));
+    }
+}
+
+/// Publishes a new slider value, does nothing unless the bridge is connected
+pub fn publish_slider(id: &str, zone: u8, value: u8) {
+    if EVENTS.receiver_count() > 0 {
+        let _ = EVENTS.send((id.to_string(), Event::Slider(zone, value)));
+    }
+}
+
+/// Publishes an unknown input code, does nothing unless the bridge is connected
+pub fn publish_raw(id: &str, code: u8, state: u8) {
+    if EVENTS.receiver_count() > 0 {
+        let _ = EVENTS.send((id.to_string(), Event::Raw(code, state)));
+    }
+}
+
+/// Publishes a status notification of the device, does nothing unless the bridge is connected
+pub fn publish_system(id: &str, event: SystemEvent) {
+    if EVENTS.receiver_count() > 0 {
+        let _ = EVENTS.send((id.to_string(), Event::System(event)));
+    }
+}
+
+/// Topic and payload an input is published with
+fn update_message(prefix: &str, id: &str, update: DeviceStateUpdate) -> (String, String) {
+    let state = |pressed: bool| if pressed { "down" } else { "up" }.to_string();
+
+    match update {
+        DeviceStateUpdate::ButtonDown(key) => {
+            (format!("{}/{}/key/{}", prefix, id, key), state(true))
+        }
+        DeviceStateUpdate::ButtonUp(key) => {
+            (format!("{}/{}/key/{}", prefix, id, key), state(false))
+        }
+        DeviceStateUpdate::EncoderDown(dial) => {
+            (format!("{}/{}/dial/{}", prefix, id, dial), state(true))
+        }
+        DeviceStateUpdate::EncoderUp(dial) => {
+            (format!("{}/{}/dial/{}", prefix, id, dial), state(false))
+        }
+        DeviceStateUpdate::EncoderTwist(dial, ticks) => (
+            format!("{}/{}/dial/{}/rotate", prefix, id, dial),
+            ticks.to_string(),
+        ),
+    }
+}
+
+/// Topic and payload a slider value is published with
+fn slider_message(prefix: &str, id: &str, zone: u8, value: u8) -> (String, String) {
+    (
+        format!("{}/{}/slider/{}", prefix, id, zone),
+        value.to_string(),
+    )
+}
+
+/// Topic and payload of an unknown input code, the code is in hex like in logs
+fn raw_message(prefix: &str, id: &str, code: u8, state: u8) -> (String, String) {
+    (
+        format!("{}/{}/raw/{:02X}", prefix, id, code),
+        state.to_string(),
+    )
+}
+
+/// Parses a command sent to `<prefix>/<device>/set/...`, returning the device it's for
+fn parse_command(prefix: &str, topic: &str, payload: &[u8]) -> Result<(String, Command), String> {
+    let rest = topic
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .ok_or_else(|| format!("Unexpected topic {}", topic))?;
+    let parts: Vec<&str> = rest.split('/').collect();
+
+    let command = match parts[1..] {
+        ["set", "brightness"] => {
+            let brightness = std::str::from_utf8(payload)
+                .ok()
+                .and_then(|payload| payload.trim().parse::<u8>().ok())
+                .filter(|brightness| *brightness <= 100)
+                .ok_or("Brightness must be a number between 0 and 100")?;
+
+            Command::SetBrightness(brightness)
+        }
+        ["set", "image", position] => {
+            let position = position
+                .parse::<u8>()
+                .map_err(|_| format!("Bad position {}", position))?;
+
+            // Data URL like OpenDeck sends, or plain JPEG bytes, empty payload clears the position
+            let image = if payload.is_empty() {
+                None
+            } else if payload.starts_with(b"data:") {
+                Some(KeyImage::DataUrl(
+                    String::from_utf8_lossy(payload).into_owned(),
+                ))
+            } else {
+                let image = load_from_memory_with_format(payload, ImageFormat::Jpeg)
+                    .map_err(|err| format!("Image is not a JPEG: {}", err))?;
+
+                Some(KeyImage::Rendered(Arc::new(image)))
+            };
+
+            Command::SetImage(position, image)
+        }
+        ["set", "blink", position] => {
+            let position = position
+                .parse::<u8>()
+                .map_err(|_| format!("Bad position {}", position))?;
+            let seconds = std::str::from_utf8(payload)
+                .ok()
+                .and_then(|payload| payload.trim().parse::<u64>().ok())
+                .filter(|seconds| *seconds <= MAX_BLINK_SECONDS)
+                .ok_or(format!(
+                    "Blink must be a number of seconds between 0 and {}",
+                    MAX_BLINK_SECONDS
+                ))?;
+
+            Command::Blink(position, seconds)
+        }
+        ["set", "badge", position] => {
+            let position = position
+                .parse::<u8>()
+                .map_err(|_| format!("Bad position {}", position))?;
+            let payload = String::from_utf8_lossy(payload);
+            let payload = payload.trim();
+
+            // Count, color of a dot or data URL of an icon, empty payload removes the badge
+            let kind = if payload.is_empty() {
+                None
+            } else if payload.starts_with("data:") {
+                Some(BadgeKind::Icon(KeyImage::DataUrl(payload.to_string())))
+            } else if let Some(color) = parse_color(payload) {
+                Some(BadgeKind::Dot(color))
+            } else {
+                Some(BadgeKind::Count(payload.parse().map_err(
+                    |_| "Badge must be a count, a color like #ff0000 or a data URL",
+                )?))
+            };
+
+            Command::Badge(position, kind)
+        }
+        ["set", "toast"] => Command::Toast(String::from_utf8_lossy(payload).into_owned()),
+        _ => return Err(format!("Unknown command topic {}", topic)),
+    };
+
+    Ok((parts[0].to_string(), command))
+}
+
+/// Client options for the broker, `will` is published retained by the broker if we vanish
+fn options(client_id: &str, settings: &MqttSettings, will: (&str, &str)) -> MqttOptions {
+    let (host, port) = address(&settings.broker);
+
+    let mut options = MqttOptions::new(client_id, host, port);
+    options
+        .set_keep_alive(KEEP_ALIVE)
+        .set_clean_session(true)
+        .set_last_will(LastWill::new(will.0, will.1, QoS::AtMostOnce, true))
+        .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+
+    // Password can't be sent without a username
+    if let Some(username) = &settings.username {
+        options.set_credentials(username, settings.password.clone().unwrap_or_default());
+    }
+
+    options
+}
+
+fn address(broker: &str) -> (String, u16) {
+    match broker
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+    {
+        Some((host, port)) => (host.to_string(), port),
+        None => (broker.to_string(), DEFAULT_PORT),
+    }
+}
+
+async fn apply_command(device: &str, command: Command) {
+    let Some(writer) = WRITERS.read().await.get(device).cloned() else {
+        log::warn!("MQTT command for unknown device {}", device);
+        return;
+    };
+
+    match command {
+        Command::SetBrightness(brightness) => writer.send(WriterCommand::SetBrightness(brightness)),
+        Command::SetImage(position, image) => writer.send(WriterCommand::SetImage {
+            position: Some(position),
+            image,
+        }),
+        Command::Blink(position, seconds) => blink::start(
+            device,
+            position,
+            Blink {
+                image: BlinkImage::Dimmed,
+                interval: DEFAULT_BLINK_INTERVAL,
+                duration: Duration::from_secs(seconds),
+            },
+        ),
+        Command::Toast(text) => toast::show(device, Toast::text(text)),
+        Command::Badge(position, kind) => writer.send(WriterCommand::Badge {
+            position,
+            badge: kind.map(|kind| Badge {
+                kind,
+                corner: Corner::default(),
+            }),
+        }),
+    }
+}
+
+/// Connects to the broker and bridges events until the connection breaks or settings change
+async fn session(
+    settings: &MqttSettings,
+    token: &CancellationToken,
+) -> Result<(), ConnectionError> {
+    let status = format!("{}/status", settings.topic);
+    let client_id = format!("opendeck-akp05-{}", std::process::id());
+    let options = options(&client_id, settings, (&status, "offline"));
+    let (host, port) = options.broker_address();
+
+    log::info!("Connecting to MQTT broker {}:{}", host, port);
+
+    let (client, mut eventloop) = AsyncClient::new(options, EVENT_BUFFER);
+
+    // The first poll connects and waits for the broker to accept us
+    eventloop.poll().await?;
+
+    let mut events = EVENTS.subscribe();
+
+    // Both wait in the client until the event loop sends them
+    let _ = client.try_subscribe(format!("{}/+/set/#", settings.topic), QoS::AtMostOnce);
+    let _ = client.try_publish(&status, QoS::AtMostOnce, true, "online");
+
+    log::info!("Connected to MQTT broker {}:{}", host, port);
+
+    // The event loop runs in its own task, polling it isn't cancel safe
+    let (messages, mut incoming) = mpsc::unbounded_channel();
+    let mut poller = tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(rumqttc::Event::Incoming(Packet::Publish(publish))) => {
+                    if messages.send(publish).is_err() {
+                        break Ok(());
+                    }
+                }
+                // Goodbye is out, nothing more to send
+                Ok(rumqttc::Event::Outgoing(Outgoing::Disconnect)) => break Ok(()),
+                Ok(_) => {}
+                Err(err) => break Err(err),
+            }
+        }
+    });
+
+    let mut config = CONFIG.subscribe();
+
+    let leaving = loop {
+        tokio::select! {
+            publish = incoming.recv() => {
+                let Some(publish) = publish else {
+                    break false;
+                };
+
+                match parse_command(&settings.topic, &publish.topic, &publish.payload) {
+                    Ok((device, command)) => apply_command(&device, command).await,
+                    Err(err) => log::warn!("Ignoring MQTT message: {}", err),
+                }
+            }
+            event = events.recv() => match event {
+                Ok((id, event)) => {
+                    let (topic, payload) = match event {
+                        Event::Update(update) => update_message(&settings.topic, &id, update),
+                        Event::Slider(zone, value) => {
+                            slider_message(&settings.topic, &id, zone, value)
+                        }
+                        Event::Raw(code, state) => raw_message(&settings.topic, &id, code, state),
+                        Event::System(event) => (
+                            format!("{}/{}/system", settings.topic, id),
+                            event.name().to_string(),
+                        ),
+                    };
+
+                    if client.try_publish(topic, QoS::AtMostOnce, false, payload).is_err() {
+                        log::warn!("MQTT broker is too slow, dropped an event");
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("MQTT broker is too slow, dropped {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break true,
+            },
+            _ = config.changed() => {
+                if config.borrow_and_update().mqtt_settings().as_ref() != Some(settings) {
+                    log::info!("MQTT settings changed, disconnecting");
+                    break true;
+                }
+            }
+            _ = token.cancelled() => break true,
+        }
+    };
+
+    if leaving {
+        // Leaving on purpose, so the will isn't published, say it ourselves
+        let _ = client.try_publish(&status, QoS::AtMostOnce, true, "offline");
+        let _ = client.try_disconnect();
+
+        if tokio::time::timeout(Duration::from_secs(5), &mut poller)
+            .await
+            .is_err()
+        {
+            poller.abort();
+        }
+
+        return Ok(());
+    }
+
+    // The event loop only stops on its own when the connection broke
+    match poller.await {
+        Ok(result) => result,
+        Err(_) => Ok(()),
+    }
+}
+
+/// Bridges inputs and commands to the MQTT broker while one is configured
+pub async fn mqtt_task(token: CancellationToken) {
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        let settings = config.borrow_and_update().mqtt_settings();
+
+        let failed = match settings {
+            None => false,
+            Some(settings) => match session(&settings, &token).await {
+                Ok(()) => false,
+                Err(err) => {
+                    log::error!("MQTT bridge failed: {}", err);
+                    true
+                }
+            },
+        };
+
+        if token.is_cancelled() {
+            break;
+        }
+
+        // Connect again after a delay, or once settings change
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY), if failed => {}
+            _ = config.changed() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn options_have_credentials_and_will() {
+        let settings = MqttSettings {
+            broker: "localhost".to_string(),
+            topic: "akp05".to_string(),
+            username: Some("ha".to_string()),
+            password: Some("pw".to_string()),
+        };
+
+        let client = options("id", &settings, ("akp05/status", "offline"));
+
+        assert_eq!(client.broker_address(), ("localhost".to_string(), 1883));
+        assert!(client.clean_session());
+        assert_eq!(
+            client.last_will(),
+            Some(LastWill::new(
+                "akp05/status",
+                "offline",
+                QoS::AtMostOnce,
+                true
+            ))
+        );
+        assert_eq!(client.credentials(), Some(rumqttc::Login::new("ha", "pw")));
+
+        // Password alone isn't sent
+        let settings = MqttSettings {
+            username: None,
+            ..settings
+        };
+        assert_eq!(
+            options("id", &settings, ("akp05/status", "offline")).credentials(),
+            None
+        );
+    }
+
+    #[test]
+    fn updates_and_commands_use_device_topics() {
+        assert_eq!(
+            update_message("akp05", "a5-1", DeviceStateUpdate::ButtonDown(6)),
+            ("akp05/a5-1/key/6".to_string(), "down".to_string())
+        );
+        assert_eq!(
+            update_message("akp05", "a5-1", DeviceStateUpdate::EncoderTwist(2, -3)),
+            ("akp05/a5-1/dial/2/rotate".to_string(), "-3".to_string())
+        );
+        assert_eq!(
+            slider_message("akp05", "a5-1", 1, 66),
+            ("akp05/a5-1/slider/1".to_string(), "66".to_string())
+        );
+        assert_eq!(
+            raw_message("akp05", "a5-1", 0xE3, 1),
+            ("akp05/a5-1/raw/E3".to_string(), "1".to_string())
+        );
+
+        assert_eq!(
+            parse_command("akp05", "akp05/a5-1/set/brightness", b"40"),
+            Ok(("a5-1".to_string(), Command::SetBrightness(40)))
+        );
+        assert_eq!(
+            parse_command("akp05", "akp05/a5-1/set/image/7", b""),
+            Ok(("a5-1".to_string(), Command::SetImage(7, None)))
+        );
+        assert_eq!(
+            parse_command(
+                "akp05",
+                "akp05/a5-1/set/image/7",
+                b"data:image/jpeg;base64,"
+            ),
+            Ok((
+                "a5-1".to_string(),
+                Command::SetImage(7, Some(KeyImage::DataUrl("data:image/jpeg;base64,".into())))
+            ))
+        );
+        assert_eq!(
+            parse_command("akp05", "akp05/a5-1/set/blink/2", b"30"),
+            Ok(("a5-1".to_string(), Command::Blink(2, 30)))
+        );
+        assert_eq!(
+            parse_command("akp05", "akp05/a5-1/set/toast", b"Doorbell"),
+            Ok(("a5-1".to_string(), Command::Toast("Doorbell".to_string())))
+        );
+        assert_eq!(
+            parse_command("akp05", "akp05/a5-1/set/badge/4", b"12"),
+            Ok((
+                "a5-1".to_string(),
+                Command::Badge(4, Some(BadgeKind::Count(12)))
+            ))
+        );
+        assert_eq!(
+            parse_command("akp05", "akp05/a5-1/set/badge/4", b""),
+            Ok(("a5-1".to_string(), Command::Badge(4, None)))
+        );
+        assert!(parse_command("akp05", "akp05/a5-1/set/brightness", b"200").is_err());
+        assert!(parse_command("akp05", "akp05/a5-1/set/reboot", b"").is_err());
+    }
+
+    /// Next packet the client sent to the fake broker
+    async fn read_packet(stream: &mut tokio::net::TcpStream, buffer: &mut BytesMut) -> Packet {
+        loop {
+            match Packet::read(buffer, MAX_PACKET_SIZE) {
+                Ok(packet) => return packet,
+                Err(rumqttc::Error::InsufficientBytes(_)) => {
+                    assert!(stream.read_buf(buffer).await.unwrap() > 0)
+                }
+                Err(err) => panic!("Bad packet: {}", err),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn session_subscribes_and_says_goodbye() {
+        let broker = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let settings = MqttSettings {
+            broker: broker.local_addr().unwrap().to_string(),
+            topic: "akp05".to_string(),
+            username: None,
+            password: None,
+        };
+        let token = CancellationToken::new();
+
+        let client = tokio::spawn({
+            let (settings, token) = (settings.clone(), token.clone());
+
+            async move { session(&settings, &token).await }
+        });
+
+        let (mut stream, _) = broker.accept().await.unwrap();
+        let mut buffer = BytesMut::new();
+        assert!(matches!(
+            read_packet(&mut stream, &mut buffer).await,
+            Packet::Connect(..)
+        ));
+        stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+
+        let Packet::Subscribe(subscribe) = read_packet(&mut stream, &mut buffer).await else {
+            panic!("Expected a subscription");
+        };
+        assert_eq!(subscribe.filters[0].path, "akp05/+/set/#");
+
+        let Packet::Publish(online) = read_packet(&mut stream, &mut buffer).await else {
+            panic!("Expected the online status");
+        };
+        assert_eq!(
+            (online.topic.as_str(), &online.payload[..]),
+            ("akp05/status", b"online".as_slice())
+        );
+        assert!(online.retain);
+
+        token.cancel();
+        client.await.unwrap().unwrap();
+
+        // Pings may come in between, the offline status goes out right before disconnecting
+        let mut packets = vec![];
+        loop {
+            let packet = read_packet(&mut stream, &mut buffer).await;
+            if packet == Packet::Disconnect {
+                break;
+            }
+            packets.push(packet);
+        }
+        assert!(matches!(
+            packets.last(),
+            Some(Packet::Publish(offline)) if &offline.payload[..] == b"offline"
+        ));
+    }
+
+    #[test]
+    fn broker_gets_default_port() {
+        assert_eq!(address("localhost"), ("localhost".to_string(), 1883));
+        assert_eq!(address("10.0.0.2:8883"), ("10.0.0.2".to_string(), 8883));
+    }
+}
@@ -0,0 +1,519 @@
+use std::{collections::BTreeMap, sync::LazyLock, time::Duration};
+
+use akp05::{
+    images::encode_base64,
+    text::{TextStyle, render_lines},
+};
+use futures_util::{SinkExt, StreamExt};
+use image::Rgb;
+use mirajazz::state::DeviceStateUpdate;
+use serde_json::{Value, json};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG,
+    toast::{self, Toast},
+    transient,
+    writer::KeyPainter,
+};
+
+/// Address obs-websocket listens on by default
+pub const DEFAULT_URL: &str = "ws://127.0.0.1:4455";
+
+/// Wait before connecting again after OBS goes away
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Size scene keys are rendered at, images are resized to the device format anyway
+pub const TALLY_SIZE: (u32, u32) = (120, 120);
+
+/// Longest scene name drawn
+pub const MAX_NAME_CHARS: usize = 10;
+
+// Scenes event subscription, program and preview changes are part of it
+const SCENE_EVENTS: u64 = 1 << 2;
+
+/// Scenes for every position that shows and switches to them
+pub type ObsScenes = BTreeMap<u8, String>;
+
+/// Scene switches requested from the device, picked up by the running session
+static SWITCHES: LazyLock<broadcast::Sender<String>> = LazyLock::new(|| broadcast::channel(16).0);
+
+/// Connection settings, the session reconnects whenever they change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObsSettings {
+    /// e.g. `ws://127.0.0.1:4455`
+    pub url: String,
+    pub password: Option<String>,
+}
+
+/// Scenes OBS currently shows, [None] while not connected
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Tally {
+    program: Option<String>,
+    preview: Option<String>,
+}
+
+/// Message from OBS that matters for the tally
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ObsMessage {
+    /// First message after connecting, with challenge and salt if a password is needed
+    Hello {
+        auth: Option<(String, String)>,
+    },
+    Identified,
+    Program(String),
+    Preview(String),
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of `data`, only used for the OBS password so speed doesn't matter
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // Padding is a single 1 bit, zeros and the length in bits, to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = hash;
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (value, add) in hash.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, value) in digest.chunks_mut(4).zip(hash) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+
+    digest
+}
+
+/// Answer to the authentication challenge, as described in the obs-websocket protocol
+fn auth_string(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = encode_base64(&sha256(format!("{}{}", password, salt).as_bytes()));
+
+    encode_base64(&sha256(format!("{}{}", secret, challenge).as_bytes()))
+}
+
+fn parse_message(text: &str) -> Option<ObsMessage> {
+    let message: Value = serde_json::from_str(text).ok()?;
+    let data = &message["d"];
+
+    match message["op"].as_u64()? {
+        0 => Some(ObsMessage::Hello {
+            auth: data.get("authentication").and_then(|auth| {
+                Some((
+                    auth["challenge"].as_str()?.to_string(),
+                    auth["salt"].as_str()?.to_string(),
+                ))
+            }),
+        }),
+        2 => Some(ObsMessage::Identified),
+        // Events
+        5 => {
+            let data = &data["eventData"];
+            let name = data["sceneName"].as_str()?.to_string();
+
+            match message["d"]["eventType"].as_str()? {
+                "CurrentProgramSceneChanged" => Some(ObsMessage::Program(name)),
+                "CurrentPreviewSceneChanged" => Some(ObsMessage::Preview(name)),
+                _ => None,
+            }
+        }
+        // Responses to the requests sent after identifying
+        7 => {
+            let data = &data["responseData"];
+
+            match message["d"]["requestType"].as_str()? {
+                "GetCurrentProgramScene" => data["currentProgramSceneName"]
+                    .as_str()
+                    .map(|name| ObsMessage::Program(name.to_string())),
+                "GetCurrentPreviewScene" => data["currentPreviewSceneName"]
+                    .as_str()
+                    .map(|name| ObsMessage::Preview(name.to_string())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn identify_message(auth: Option<String>) -> String {
+    let mut data = json!({ "rpcVersion": 1, "eventSubscriptions": SCENE_EVENTS });
+
+    if let Some(auth) = auth {
+        data["authentication"] = json!(auth);
+    }
+
+    json!({ "op": 1, "d": data }).to_string()
+}
+
+fn request_message(request_type: &str, data: Value) -> String {
+    json!({
+        "op": 6,
+        "d": { "requestType": request_type, "requestId": request_type, "requestData": data },
+    })
+    .to_string()
+}
+
+fn shorten(name: &str) -> String {
+    if name.chars().count() <= MAX_NAME_CHARS {
+        return name.to_string();
+    }
+
+    let mut short: String = name.chars().take(MAX_NAME_CHARS - 2).collect();
+    short.push_str("..");
+
+    short
+}
+
+/// Lines and colors to draw for a scene key
+fn tally_lines(scene: &str, tally: Option<&Tally>) -> (Vec<String>, TextStyle) {
+    let (status, background) = match tally {
+        None => ("OFFLINE", Rgb([40, 40, 40])),
+        Some(tally) if tally.program.as_deref() == Some(scene) => ("LIVE", Rgb([200, 0, 0])),
+        Some(tally) if tally.preview.as_deref() == Some(scene) => ("PREVIEW", Rgb([0, 150, 0])),
+        Some(_) => ("", Rgb([0, 0, 0])),
+    };
+
+    let style = TextStyle {
+        background,
+        ..TextStyle::default()
+    };
+
+    (vec![shorten(scene), status.to_string()], style)
+}
+
+/// Draws every scene key, frames that didn't change are skipped by the painter
+async fn paint_scenes(painter: &mut KeyPainter, tally: Option<&Tally>) {
+    let scenes = CONFIG.borrow().obs_scenes.clone();
+
+    painter.retain(|_, position| scenes.contains_key(&position));
+
+    for (position, scene) in scenes {
+        let (lines, style) = tally_lines(&scene, tally);
+        let image_lines = lines.clone();
+
+        painter
+            .paint(position, lines, || {
+                let lines: Vec<&str> = image_lines
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|line| !line.is_empty())
+                    .collect();
+
+                render_lines(TALLY_SIZE, &lines, &[], style)
+            })
+            .await;
+    }
+}
+
+/// Checks if an update should reach OpenDeck, presses of scene keys switch scenes instead
+pub fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let (key, down) = match *update {
+        DeviceStateUpdate::ButtonDown(key) => (key, true),
+        DeviceStateUpdate::ButtonUp(key) => (key, false),
+        _ => return true,
+    };
+
+    let Some(scene) = CONFIG.borrow().obs_scenes.get(&key).cloned() else {
+        return true;
+    };
+
+    if down && SWITCHES.send(scene).is_err() {
+        log::warn!("Not connected to OBS, can't switch scenes");
+        transient::flash_error(device, key, "NO OBS");
+    }
+
+    false
+}
+
+/// Connects to OBS and keeps scene keys in sync until the connection breaks or settings change
+async fn session(
+    settings: &ObsSettings,
+    painter: &mut KeyPainter,
+    token: &CancellationToken,
+) -> Result<(), String> {
+    log::info!("Connecting to OBS at {}", settings.url);
+
+    let (mut socket, _) = tokio::time::timeout(
+        Duration::from_secs(10),
+        tokio_tungstenite::connect_async(settings.url.as_str()),
+    )
+    .await
+    .map_err(|_| "Connecting timed out".to_string())?
+    .map_err(|err| err.to_string())?;
+
+    let mut switches = SWITCHES.subscribe();
+    let mut config = CONFIG.subscribe();
+    let mut tally: Option<Tally> = None;
+
+    loop {
+        tokio::select! {
+            message = socket.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(frame))) => {
+                        return Err(format!(
+                            "OBS closed the connection: {}",
+                            frame.map(|frame| frame.reason.to_string()).unwrap_or_default()
+                        ));
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return Err(err.to_string()),
+                    None => return Err("OBS closed the connection".to_string()),
+                };
+
+                let Some(message) = parse_message(&text) else {
+                    continue;
+                };
+
+                let reply = match message {
+                    ObsMessage::Hello { auth } => {
+                        let auth = match (auth, &settings.password) {
+                            (Some((challenge, salt)), Some(password)) => {
+                                Some(auth_string(password, &salt, &challenge))
+                            }
+                            (Some(_), None) => return Err("OBS needs a password".to_string()),
+                            (None, _) => None,
+                        };
+
+                        vec![identify_message(auth)]
+                    }
+                    ObsMessage::Identified => {
+                        log::info!("Connected to OBS at {}", settings.url);
+
+                        tally = Some(Tally::default());
+
+                        // Preview fails outside of studio mode, that's fine
+                        vec![
+                            request_message("GetCurrentProgramScene", json!({})),
+                            request_message("GetCurrentPreviewScene", json!({})),
+                        ]
+                    }
+                    ObsMessage::Program(scene) => {
+                        if let Some(tally) = &mut tally {
+                            // Scene OBS had when connecting isn't news
+                            if tally.program.is_some() {
+                                toast::show_all(Toast::text(format!("LIVE: {}", scene))).await;
+                            }
+
+                            tally.program = Some(scene);
+                        }
+
+                        vec![]
+                    }
+                    ObsMessage::Preview(scene) => {
+                        if let Some(tally) = &mut tally {
+                            tally.preview = Some(scene);
+                        }
+
+                        vec![]
+                    }
+                };
+
+                for reply in reply {
+                    socket
+                        .send(Message::Text(reply.into()))
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+            }
+            scene = switches.recv() => {
+                let Ok(scene) = scene else {
+                    continue;
+                };
+
+                log::info!("Switching OBS to scene {}", scene);
+
+                let request = request_message(
+                    "SetCurrentProgramScene",
+                    json!({ "sceneName": scene }),
+                );
+                socket
+                    .send(Message::Text(request.into()))
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+            _ = config.changed() => {
+                if config.borrow_and_update().obs_settings().as_ref() != Some(settings) {
+                    log::info!("OBS settings changed, disconnecting");
+                    let _ = socket.close(None).await;
+
+                    return Ok(());
+                }
+            }
+            _ = token.cancelled() => {
+                let _ = socket.close(None).await;
+
+                return Ok(());
+            }
+        }
+
+        paint_scenes(painter, tally.as_ref()).await;
+    }
+}
+
+/// Shows OBS program and preview scenes on scene keys while OBS is configured
+pub async fn obs_task(token: CancellationToken) {
+    let mut config = CONFIG.subscribe();
+    let mut painter = KeyPainter::default();
+
+    loop {
+        let settings = config.borrow_and_update().obs_settings();
+
+        let failed = match settings {
+            None => false,
+            Some(settings) => match session(&settings, &mut painter, &token).await {
+                Ok(()) => false,
+                Err(err) => {
+                    log::error!("OBS connection failed: {}", err);
+                    true
+                }
+            },
+        };
+
+        if token.is_cancelled() {
+            break;
+        }
+
+        paint_scenes(&mut painter, None).await;
+
+        // Connect again after a delay, or once settings change
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY), if failed => {}
+            _ = config.changed() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_is_hashed() {
+        assert_eq!(
+            encode_base64(&sha256(b"")),
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        assert_eq!(sha256(&[b'a'; 64])[..4], [0xff, 0xe0, 0x54, 0xfe]);
+        assert_eq!(encode_base64(b"a"), "YQ==");
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(encode_base64(b"abc"), "YWJj");
+
+        // Example from the obs-websocket protocol docs
+        assert_eq!(
+            auth_string(
+                "supersecretpassword",
+                "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=",
+                "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY="
+            ),
+            "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+        );
+    }
+
+    #[test]
+    fn messages_are_parsed() {
+        assert_eq!(
+            parse_message(
+                r#"{"op":0,"d":{"rpcVersion":1,"authentication":{"challenge":"c","salt":"s"}}}"#
+            ),
+            Some(ObsMessage::Hello {
+                auth: Some(("c".to_string(), "s".to_string()))
+            })
+        );
+        assert_eq!(
+            parse_message(
+                r#"{"op":5,"d":{"eventType":"CurrentProgramSceneChanged","eventData":{"sceneName":"Camera"}}}"#
+            ),
+            Some(ObsMessage::Program("Camera".to_string()))
+        );
+        assert_eq!(
+            parse_message(
+                r#"{"op":7,"d":{"requestType":"GetCurrentPreviewScene","responseData":{"currentPreviewSceneName":"Screen"}}}"#
+            ),
+            Some(ObsMessage::Preview("Screen".to_string()))
+        );
+        assert_eq!(
+            parse_message(r#"{"op":5,"d":{"eventType":"StudioModeStateChanged","eventData":{}}}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn tally_follows_scenes() {
+        let tally = Tally {
+            program: Some("Camera".to_string()),
+            preview: Some("Screen".to_string()),
+        };
+
+        assert_eq!(tally_lines("Camera", Some(&tally)).0, ["Camera", "LIVE"]);
+        assert_eq!(tally_lines("Screen", Some(&tally)).0, ["Screen", "PREVIEW"]);
+        assert_eq!(
+            tally_lines("Be right back", Some(&tally)).0,
+            ["Be right..", ""]
+        );
+        assert_eq!(tally_lines("Camera", None).0, ["Camera", "OFFLINE"]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use akp05::{
+    images::{KeyImage, decode_data_url},
+    mappings::{COL_COUNT, ENCODER_COUNT, KEY_COUNT},
+};
+use mirajazz::state::DeviceStateUpdate;
+
+/// Which way up the deck is mounted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Orientation {
+    /// Strip and dials on top, like the vendor photos
+    #[default]
+    Normal,
+    /// Turned around, e.g. to keep the cable out of the way, keys and dials count from the other
+    /// end and images are upside down
+    Flipped,
+}
+
+impl Orientation {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "normal" => Some(Self::Normal),
+            "flipped" => Some(Self::Flipped),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Flipped => "flipped",
+        }
+    }
+
+    /// The other one, orientations are picked by turning between them
+    pub fn toggled(self) -> Self {
+        match self {
+            Self::Normal => Self::Flipped,
+            Self::Flipped => Self::Normal,
+        }
+    }
+
+    /// Position an image for `position` goes to, keys swap ends and rows beyond them are mirrored
+    pub fn position(self, position: u8) -> u8 {
+        let keys = KEY_COUNT as u8;
+        let columns = COL_COUNT as u8;
+
+        match self {
+            Self::Normal => position,
+            Self::Flipped if position < keys => keys - 1 - position,
+            Self::Flipped => {
+                let row_start = position - position % columns;
+                row_start + columns - 1 - position % columns
+            }
+        }
+    }
+
+    /// Update as it would've come from a deck the right way up
+    pub fn update(self, update: DeviceStateUpdate) -> DeviceStateUpdate {
+        if self == Self::Normal {
+            return update;
+        }
+
+        let key = |key: u8| (KEY_COUNT as u8).saturating_sub(key + 1);
+        let encoder = |encoder: u8| (ENCODER_COUNT as u8).saturating_sub(encoder + 1);
+
+        // Dials turned around still turn clockwise
+        match update {
+            DeviceStateUpdate::ButtonDown(k) => DeviceStateUpdate::ButtonDown(key(k)),
+            DeviceStateUpdate::ButtonUp(k) => DeviceStateUpdate::ButtonUp(key(k)),
+            DeviceStateUpdate::EncoderDown(e) => DeviceStateUpdate::EncoderDown(encoder(e)),
+            DeviceStateUpdate::EncoderUp(e) => DeviceStateUpdate::EncoderUp(encoder(e)),
+            DeviceStateUpdate::EncoderTwist(e, ticks) => {
+                DeviceStateUpdate::EncoderTwist(encoder(e), ticks)
+            }
+        }
+    }
+
+    /// Image turned upside down if the deck is, images that can't be decoded fail when they're
+    /// written anyway
+    pub fn image(self, image: Option<KeyImage>) -> Option<KeyImage> {
+        if self == Self::Normal {
+            return image;
+        }
+
+        let decoded = match &image {
+            Some(KeyImage::DataUrl(url)) => decode_data_url(url).ok(),
+            Some(KeyImage::Rendered(image)) => Some((**image).clone()),
+            None => None,
+        };
+
+        match decoded {
+            Some(decoded) => Some(KeyImage::Rendered(Arc::new(decoded.rotate180()))),
+            None => image,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn flipped_decks_count_from_the_other_end() {
+        let flipped = Orientation::Flipped;
+
+        assert_eq!(flipped.position(0), 9);
+        assert_eq!(flipped.position(7), 2);
+        assert_eq!(flipped.position(10), 14);
+        assert_eq!(flipped.position(12), 12);
+        assert_eq!(Orientation::Normal.position(7), 7);
+
+        assert!(matches!(flipped.update(ButtonDown(0)), ButtonDown(9)));
+        assert!(matches!(
+            flipped.update(EncoderTwist(0, -2)),
+            EncoderTwist(3, -2)
+        ));
+        assert!(matches!(
+            Orientation::Normal.update(ButtonUp(3)),
+            ButtonUp(3)
+        ));
+
+        assert_eq!(Orientation::parse(" Flipped"), Some(flipped));
+        assert_eq!(Orientation::parse("sideways"), None);
+        assert_eq!(flipped.toggled(), Orientation::Normal);
+    }
+}
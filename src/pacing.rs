@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+/// Longest the read loop waits for input once it slowed down, no matter the pacing
+pub const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the read loop trades input latency for power when there's no input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pacing {
+    /// Always wakes up every `poll_interval_ms`
+    Latency,
+    /// Slows down after a few idle wakeups
+    #[default]
+    Balanced,
+    /// Slows down sooner and further
+    Power,
+}
+
+impl Pacing {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "latency" => Some(Self::Latency),
+            "balanced" => Some(Self::Balanced),
+            "power" => Some(Self::Power),
+            _ => None,
+        }
+    }
+
+    /// Longest timeout as a multiple of the poll interval
+    fn max_factor(self) -> u32 {
+        match self {
+            Self::Latency => 1,
+            Self::Balanced => 8,
+            Self::Power => 32,
+        }
+    }
+
+    /// Idle wakeups in a row before the timeout doubles
+    fn idle_wakeups(self) -> u32 {
+        match self {
+            Self::Latency => u32::MAX,
+            Self::Balanced => 4,
+            Self::Power => 2,
+        }
+    }
+}
+
+/// Read timeout that grows while the device is idle and drops back as soon as input comes
+///
+/// Growing takes several idle wakeups in a row, so short pauses in a burst keep the timeout short.
+#[derive(Debug)]
+pub struct ReadPacer {
+    pacing: Pacing,
+    interval: Option<Duration>,
+    factor: u32,
+    idle: u32,
+}
+
+impl ReadPacer {
+    /// `interval` is the shortest timeout, [None] waits for input indefinitely
+    pub fn new(interval: Option<Duration>, pacing: Pacing) -> Self {
+        Self {
+            pacing,
+            interval,
+            factor: 1,
+            idle: 0,
+        }
+    }
+
+    /// Timeout for the next read
+    pub fn timeout(&self) -> Option<Duration> {
+        self.interval
+            .map(|interval| (interval * self.factor).min(MAX_IDLE_TIMEOUT).max(interval))
+    }
+
+    /// Called after every read, `active` is false if it timed out without a report
+    pub fn record(&mut self, active: bool) {
+        if active {
+            self.factor = 1;
+            self.idle = 0;
+            return;
+        }
+
+        self.idle += 1;
+
+        if self.idle >= self.pacing.idle_wakeups() {
+            self.factor = (self.factor * 2).min(self.pacing.max_factor());
+            self.idle = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_reads_slow_down_until_input() {
+        let interval = Duration::from_millis(100);
+        let mut pacer = ReadPacer::new(Some(interval), Pacing::Balanced);
+
+        // Hysteresis, a few idle wakeups don't change anything yet
+        for _ in 0..3 {
+            pacer.record(false);
+        }
+        assert_eq!(pacer.timeout(), Some(interval));
+
+        pacer.record(false);
+        assert_eq!(pacer.timeout(), Some(interval * 2));
+
+        for _ in 0..40 {
+            pacer.record(false);
+        }
+        assert_eq!(pacer.timeout(), Some(interval * 8));
+
+        pacer.record(true);
+        assert_eq!(pacer.timeout(), Some(interval));
+    }
+
+    #[test]
+    fn pacing_limits_timeouts() {
+        let mut latency = ReadPacer::new(Some(Duration::from_millis(100)), Pacing::Latency);
+        let mut power = ReadPacer::new(Some(Duration::from_millis(500)), Pacing::Power);
+        let mut forever = ReadPacer::new(None, Pacing::Power);
+
+        for _ in 0..100 {
+            latency.record(false);
+            power.record(false);
+            forever.record(false);
+        }
+
+        assert_eq!(latency.timeout(), Some(Duration::from_millis(100)));
+        assert_eq!(power.timeout(), Some(MAX_IDLE_TIMEOUT));
+        assert_eq!(forever.timeout(), None);
+
+        // Intervals longer than the cap are kept as they are
+        let slow = ReadPacer::new(Some(Duration::from_secs(10)), Pacing::Power);
+        assert_eq!(slow.timeout(), Some(Duration::from_secs(10)));
+    }
+}
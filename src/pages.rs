@@ -0,0 +1,599 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::{
+    images::KeyImage,
+    mappings::KEY_COUNT,
+    text::{TextStyle, render_lines},
+};
+use image::{DynamicImage, Rgb, RgbImage};
+use mirajazz::state::DeviceStateUpdate;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, draws_position as plugin_draws, safemode,
+    transition::{self, Transition},
+    writer::KeyPainter,
+};
+
+/// Positions on every page, OpenDeck numbers the ones on page N from `N * PAGE_SIZE`
+pub const PAGE_SIZE: u8 = KEY_COUNT as u8;
+
+/// Most pages a device can have
+pub const MAX_PAGES: u8 = 10;
+
+/// Key showing the back arrow inside a folder
+pub const BACK_KEY: u8 = 0;
+
+/// How often page indicators are checked, turning a page draws them right away
+pub const INDICATOR_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Size indicators are rendered at, images are resized to the device format anyway
+pub const INDICATOR_SIZE: (u32, u32) = (120, 120);
+
+static PAGES: LazyLock<Mutex<HashMap<String, Paging>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Folder keys, position as OpenDeck knows it to the page it opens, counted from 1
+pub type Folders = BTreeMap<u8, u8>;
+
+static TURNED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Inputs that change pages, from config
+#[derive(Debug, Clone, Default)]
+struct Controls {
+    // Previous and next keys
+    keys: Vec<u8>,
+    dial: Option<u8>,
+    folders: Folders,
+}
+
+impl Controls {
+    fn current() -> Self {
+        let config = CONFIG.borrow();
+
+        Self {
+            keys: config.page_keys.clone(),
+            dial: config.page_dial,
+            folders: config.folders.clone(),
+        }
+    }
+
+    fn is_folder(&self, page: u8) -> bool {
+        self.folders.values().any(|folder| *folder == page + 1)
+    }
+}
+
+/// What an input does to pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageInput {
+    Turn(i32),
+    First,
+    Open(u8),
+    Back,
+    Swallow,
+}
+
+/// Pages of a device, the one shown and images OpenDeck sent for every one of them
+#[derive(Debug)]
+struct Paging {
+    count: u8,
+    page: u8,
+    // Page a folder was opened from, while it's open
+    parent: Option<u8>,
+    // Keys whose presses turned pages, so their releases don't reach OpenDeck either
+    held: HashSet<u8>,
+    // Position as OpenDeck knows it to its image
+    images: HashMap<u8, KeyImage>,
+    // Keys held down to the position their press was sent as, so releases go to the same one
+    pressed: HashMap<u8, u8>,
+}
+
+impl Paging {
+    fn new(count: u8) -> Self {
+        Self {
+            count,
+            page: 0,
+            parent: None,
+            held: HashSet::new(),
+            images: HashMap::new(),
+            pressed: HashMap::new(),
+        }
+    }
+
+    /// Remembers an image, returns the position to set it on if its page is shown
+    fn set_image(&mut self, position: Option<u8>, image: Option<KeyImage>) -> Option<Option<u8>> {
+        let Some(position) = position else {
+            self.images.clear();
+            return Some(None);
+        };
+
+        match image {
+            Some(image) => self.images.insert(position, image),
+            None => self.images.remove(&position),
+        };
+
+        (position / PAGE_SIZE == self.page).then_some(Some(position % PAGE_SIZE))
+    }
+
+    /// Moves by `delta` pages, wrapping around at both ends, folders are skipped
+    fn turn(&mut self, delta: i32, controls: &Controls) {
+        // Turning from a folder goes on from the page it was opened from
+        if let Some(parent) = self.parent.take() {
+            self.page = parent;
+        }
+
+        for _ in 0..self.count {
+            self.page = (self.page as i32 + delta).rem_euclid(self.count as i32) as u8;
+
+            if !controls.is_folder(self.page) {
+                break;
+            }
+        }
+    }
+
+    /// Checks if an update is for page keys, the page dial, a folder key or the back key
+    fn input(&mut self, update: &DeviceStateUpdate, controls: &Controls) -> Option<PageInput> {
+        let input = match *update {
+            DeviceStateUpdate::ButtonDown(key) if self.parent.is_some() && key == BACK_KEY => {
+                PageInput::Back
+            }
+            DeviceStateUpdate::ButtonDown(key) if controls.keys.first() == Some(&key) => {
+                PageInput::Turn(-1)
+            }
+            DeviceStateUpdate::ButtonDown(key) if controls.keys.get(1) == Some(&key) => {
+                PageInput::Turn(1)
+            }
+            DeviceStateUpdate::ButtonDown(key) if key < PAGE_SIZE && self.parent.is_none() => {
+                // Folders pointing past the last page are plain keys
+                let folder = controls
+                    .folders
+                    .get(&(self.page * PAGE_SIZE + key))
+                    .filter(|folder| **folder <= self.count)?;
+
+                PageInput::Open(folder - 1)
+            }
+            DeviceStateUpdate::ButtonUp(key) if self.held.remove(&key) => PageInput::Swallow,
+            // One page per twist event, however fast the knob spins
+            DeviceStateUpdate::EncoderTwist(encoder, ticks) if controls.dial == Some(encoder) => {
+                PageInput::Turn(ticks.signum() as i32)
+            }
+            DeviceStateUpdate::EncoderDown(encoder) if controls.dial == Some(encoder) => {
+                PageInput::First
+            }
+            DeviceStateUpdate::EncoderUp(encoder) if controls.dial == Some(encoder) => {
+                PageInput::Swallow
+            }
+            _ => return None,
+        };
+
+        if let DeviceStateUpdate::ButtonDown(key) = *update {
+            self.held.insert(key);
+        }
+
+        Some(input)
+    }
+
+    fn apply(&mut self, input: PageInput, controls: &Controls) {
+        match input {
+            PageInput::Turn(delta) => self.turn(delta, controls),
+            PageInput::First => {
+                self.parent = None;
+                self.page = 0;
+            }
+            PageInput::Open(folder) => {
+                self.parent = Some(self.page);
+                self.page = folder;
+            }
+            PageInput::Back => self.page = self.parent.take().unwrap_or(0),
+            PageInput::Swallow => {}
+        }
+    }
+
+    /// Images of the shown page, [None] for positions without one
+    fn page_images(&self) -> Vec<(u8, Option<KeyImage>)> {
+        (0..PAGE_SIZE)
+            .map(|position| {
+                let image = self.images.get(&(self.page * PAGE_SIZE + position));
+
+                (position, image.cloned())
+            })
+            .collect()
+    }
+
+    /// Moves key updates to the shown page
+    fn translate(&mut self, update: DeviceStateUpdate) -> DeviceStateUpdate {
+        match update {
+            DeviceStateUpdate::ButtonDown(key) if key < PAGE_SIZE => {
+                let position = self.page * PAGE_SIZE + key;
+                self.pressed.insert(key, position);
+
+                DeviceStateUpdate::ButtonDown(position)
+            }
+            DeviceStateUpdate::ButtonUp(key) if key < PAGE_SIZE => {
+                let position = self
+                    .pressed
+                    .remove(&key)
+                    .unwrap_or(self.page * PAGE_SIZE + key);
+
+                DeviceStateUpdate::ButtonUp(position)
+            }
+            update => update,
+        }
+    }
+
+    fn indicator(&self) -> String {
+        format!("{}/{}", self.page + 1, self.count)
+    }
+}
+
+/// Starts paging a newly connected device, returns how many pages it should be registered with
+///
+/// Page count is only read here, so changing it needs the device to connect again.
+pub fn register(device: &str) -> u8 {
+    let count = CONFIG.borrow().pages;
+    let mut pages = PAGES.lock().unwrap();
+
+    if count > 1 {
+        log::info!("Device {} has {} pages", device, count);
+        pages.insert(device.to_string(), Paging::new(count));
+    } else {
+        pages.remove(device);
+    }
+
+    count
+}
+
+pub fn unregister(device: &str) {
+    PAGES.lock().unwrap().remove(device);
+}
+
+/// Remembers an image from OpenDeck, returns the position to set it on if its page is shown
+pub fn route_image(
+    device: &str,
+    position: Option<u8>,
+    image: Option<KeyImage>,
+) -> Option<Option<u8>> {
+    match PAGES.lock().unwrap().get_mut(device) {
+        Some(paging) => paging.set_image(position, image),
+        None => Some(position),
+    }
+}
+
+/// Moves key updates of paged devices to the shown page
+pub fn translate(device: &str, update: DeviceStateUpdate) -> DeviceStateUpdate {
+    match PAGES.lock().unwrap().get_mut(device) {
+        Some(paging) => paging.translate(update),
+        None => update,
+    }
+}
+
+/// Draws a left pointing arrow, the back key of folders
+pub fn render_back_arrow(size: (u32, u32)) -> DynamicImage {
+    let (width, height) = size;
+    let mut image = RgbImage::new(width, height);
+    let white = Rgb([255, 255, 255]);
+
+    let (w, h) = (width as f32, height as f32);
+    let (tip, head_end, tail) = (w * 0.22, w * 0.5, w * 0.78);
+    let (cy, head, shaft) = (h * 0.5, h * 0.28, h * 0.08);
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        let dy = (y - cy).abs();
+
+        // Triangle getting taller away from the tip, then a bar
+        let in_head = (tip..=head_end).contains(&x) && dy <= head * (x - tip) / (head_end - tip);
+        let in_shaft = (head_end..=tail).contains(&x) && dy <= shaft;
+
+        if in_head || in_shaft {
+            *pixel = white;
+        }
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Checks if an update should reach OpenDeck, page keys, the page dial, folder keys and the back
+/// key change pages instead
+///
+/// The new page is uploaded as a single batch, so every key changes at once.
+pub async fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let controls = Controls::current();
+
+    let input = match PAGES.lock().unwrap().get_mut(device) {
+        Some(paging) => paging.input(update, &controls),
+        None => return true,
+    };
+
+    match input {
+        Some(input) => {
+            change(device, input, &controls).await;
+            false
+        }
+        None => true,
+    }
+}
+
+/// Turns pages of a device by `delta` like the page dial does, e.g. from the on-device menu
+pub async fn turn(device: &str, delta: i32) {
+    change(device, PageInput::Turn(delta), &Controls::current()).await;
+}
+
+/// Shown page and page count of a device, like "2/3", [None] if it isn't paged
+pub fn indicator(device: &str) -> Option<String> {
+    PAGES.lock().unwrap().get(device).map(Paging::indicator)
+}
+
+/// Applies a page input and uploads the new page if it changed
+async fn change(device: &str, input: PageInput, controls: &Controls) {
+    let (old_images, was_in_folder, images, in_folder, forward) = {
+        let mut pages = PAGES.lock().unwrap();
+
+        let Some(paging) = pages.get_mut(device) else {
+            return;
+        };
+
+        let previous = (paging.page, paging.parent);
+        let old_images = paging.page_images();
+
+        paging.apply(input, controls);
+
+        if (paging.page, paging.parent) == previous {
+            return;
+        }
+
+        log::info!("Showing page {} on {}", paging.indicator(), device);
+
+        // Deeper pages come in from the right, going back comes from the left
+        let forward = match input {
+            PageInput::Turn(delta) => delta > 0,
+            PageInput::Open(_) => true,
+            _ => false,
+        };
+
+        (
+            old_images,
+            previous.1.is_some(),
+            paging.page_images(),
+            paging.parent.is_some(),
+            forward,
+        )
+    };
+
+    TURNED.notify_one();
+
+    let back = KeyImage::Rendered(Arc::new(render_back_arrow(INDICATOR_SIZE)));
+    let batch: Vec<(u8, Option<KeyImage>)> = images
+        .into_iter()
+        .filter_map(|(position, image)| match position {
+            BACK_KEY if in_folder => Some((position, Some(back.clone()))),
+            position if plugin_draws(device, position) => None,
+            position => Some((position, image)),
+        })
+        .collect();
+
+    // Devices in safe mode don't get the extra frames
+    let transition = match safemode::is_active(device) {
+        true => Transition::None,
+        false => CONFIG.borrow().page_transition,
+    };
+
+    if transition == Transition::None {
+        transition::play(device.to_string(), transition, forward, vec![], batch).await;
+        return;
+    }
+
+    let old_batch = batch
+        .iter()
+        .map(|(position, _)| match *position {
+            BACK_KEY if was_in_folder => (BACK_KEY, Some(back.clone())),
+            position => (position, old_images[position as usize].1.clone()),
+        })
+        .collect();
+
+    // Frames take a while, inputs keep coming in meanwhile
+    drop(tokio::spawn(transition::play(
+        device.to_string(),
+        transition,
+        forward,
+        old_batch,
+        batch,
+    )));
+}
+
+/// Checks if a page key, the page indicator or the back key of a folder is drawn on the position
+pub fn draws_position(device: &str, position: u8) -> bool {
+    let in_folder = match PAGES.lock().unwrap().get(device) {
+        Some(paging) => paging.parent.is_some(),
+        None => return false,
+    };
+
+    let config = CONFIG.borrow();
+
+    config.page_keys.contains(&position)
+        || config.page_dial == Some(position)
+        || (in_folder && position == BACK_KEY)
+}
+
+/// Draws page keys and the page indicator above the page dial of paged devices
+pub async fn pages_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(INDICATOR_INTERVAL) => {}
+            _ = TURNED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+
+        let (keys, dial) = {
+            let config = CONFIG.borrow();
+
+            (config.page_keys.clone(), config.page_dial)
+        };
+
+        let indicators: Vec<(String, String)> = PAGES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(device, paging)| (device.clone(), paging.indicator()))
+            .collect();
+
+        painter.retain(|id, position| {
+            indicators.iter().any(|(device, _)| device == id)
+                && (keys.contains(&position) || dial == Some(position))
+        });
+
+        let mut drawn: Vec<(u8, &str)> = vec![];
+        drawn.extend(keys.first().map(|key| (*key, "PREV")));
+        drawn.extend(keys.get(1).map(|key| (*key, "NEXT")));
+        drawn.extend(dial.map(|dial| (dial, "PAGE")));
+
+        for (device, indicator) in &indicators {
+            for (position, title) in &drawn {
+                let lines = vec![title.to_string(), indicator.clone()];
+
+                painter
+                    .paint_device(device, *position, lines, || {
+                        render_lines(
+                            INDICATOR_SIZE,
+                            &[title, indicator],
+                            &[1, 2],
+                            TextStyle::default(),
+                        )
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    fn image(name: &str) -> KeyImage {
+        KeyImage::DataUrl(name.to_string())
+    }
+
+    #[test]
+    fn images_are_kept_per_page() {
+        let controls = Controls::default();
+        let mut paging = Paging::new(3);
+
+        assert_eq!(paging.set_image(Some(2), Some(image("a"))), Some(Some(2)));
+        assert_eq!(paging.set_image(Some(12), Some(image("b"))), None);
+
+        paging.turn(1, &controls);
+        assert_eq!(paging.page, 1);
+        assert_eq!(paging.page_images()[2], (2, Some(image("b"))));
+        assert_eq!(paging.page_images()[3], (3, None));
+        assert_eq!(paging.set_image(Some(12), None), Some(Some(2)));
+
+        paging.turn(-2, &controls);
+        assert_eq!(paging.page, 2);
+        paging.turn(1, &controls);
+        assert_eq!(paging.page, 0);
+    }
+
+    #[test]
+    fn releases_follow_presses() {
+        let controls = Controls::default();
+        let mut paging = Paging::new(2);
+        paging.turn(1, &controls);
+
+        assert!(matches!(paging.translate(ButtonDown(4)), ButtonDown(14)));
+
+        paging.turn(1, &controls);
+        assert!(matches!(paging.translate(ButtonUp(4)), ButtonUp(14)));
+        assert!(matches!(paging.translate(ButtonDown(4)), ButtonDown(4)));
+        assert!(matches!(
+            paging.translate(EncoderTwist(1, 2)),
+            EncoderTwist(1, 2)
+        ));
+    }
+
+    #[test]
+    fn page_inputs_are_recognized() {
+        let controls = Controls {
+            keys: vec![5, 9],
+            dial: Some(3),
+            folders: Folders::new(),
+        };
+        let mut paging = Paging::new(2);
+
+        assert_eq!(
+            paging.input(&ButtonDown(5), &controls),
+            Some(PageInput::Turn(-1))
+        );
+        assert_eq!(
+            paging.input(&ButtonUp(5), &controls),
+            Some(PageInput::Swallow)
+        );
+        assert_eq!(paging.input(&ButtonDown(6), &controls), None);
+        assert_eq!(paging.input(&ButtonUp(6), &controls), None);
+        assert_eq!(
+            paging.input(&EncoderTwist(3, -4), &controls),
+            Some(PageInput::Turn(-1))
+        );
+        assert_eq!(
+            paging.input(&EncoderDown(3), &controls),
+            Some(PageInput::First)
+        );
+        assert_eq!(paging.input(&EncoderDown(2), &controls), None);
+    }
+
+    #[test]
+    fn folders_open_and_go_back() {
+        let controls = Controls {
+            keys: vec![],
+            dial: Some(3),
+            folders: Folders::from([(17, 4), (8, 9)]),
+        };
+        let mut paging = Paging::new(4);
+
+        // Page 4 is a folder, so turning skips it
+        paging.turn(-1, &controls);
+        assert_eq!(paging.page, 2);
+        paging.turn(1, &controls);
+        paging.turn(1, &controls);
+        assert_eq!(paging.page, 1);
+
+        let input = paging.input(&ButtonDown(7), &controls);
+        assert_eq!(input, Some(PageInput::Open(3)));
+        paging.apply(input.unwrap(), &controls);
+        assert_eq!((paging.page, paging.parent), (3, Some(1)));
+
+        // Release of the folder key doesn't reach the folder's key
+        assert_eq!(
+            paging.input(&ButtonUp(7), &controls),
+            Some(PageInput::Swallow)
+        );
+
+        let input = paging.input(&ButtonDown(BACK_KEY), &controls);
+        assert_eq!(input, Some(PageInput::Back));
+        paging.apply(input.unwrap(), &controls);
+        assert_eq!((paging.page, paging.parent), (1, None));
+
+        // Folder past the last page is a plain key
+        paging.turn(-1, &controls);
+        assert_eq!(paging.input(&ButtonDown(8), &controls), None);
+    }
+
+    #[test]
+    fn back_arrow_points_left() {
+        let image = render_back_arrow((120, 120)).into_rgb8();
+
+        assert_eq!(image.get_pixel(30, 60).0, [255, 255, 255], "tip");
+        assert_eq!(image.get_pixel(30, 40).0, [0, 0, 0]);
+        assert_eq!(image.get_pixel(80, 60).0, [255, 255, 255], "shaft");
+        assert_eq!(image.get_pixel(80, 45).0, [0, 0, 0]);
+    }
+}
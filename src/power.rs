@@ -0,0 +1,87 @@
+use std::sync::LazyLock;
+
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::watcher::reconnect_all;
+
+// Woken up by the system when it comes back from sleep or hibernation
+static RESUMED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+#[cfg(windows)]
+mod notifications {
+    use std::ffi::c_void;
+
+    use windows::Win32::{
+        Foundation::{ERROR_SUCCESS, HANDLE},
+        System::Power::{
+            DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS, PowerRegisterSuspendResumeNotification,
+        },
+        UI::WindowsAndMessaging::{DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC},
+    };
+
+    // Called by Windows on its own thread, only passes the news on
+    unsafe extern "system" fn callback(
+        _context: *const c_void,
+        kind: u32,
+        _setting: *const c_void,
+    ) -> u32 {
+        // Sent for every resume, including the ones nobody touched the keyboard for
+        if kind == PBT_APMRESUMEAUTOMATIC {
+            super::RESUMED.notify_one();
+        }
+
+        ERROR_SUCCESS.0
+    }
+
+    /// Asks Windows to tell about resumes for as long as the plugin runs
+    pub fn register() -> Result<(), String> {
+        // Registration is never undone, so the parameters live as long as the plugin
+        let parameters = Box::leak(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+            Callback: Some(callback),
+            Context: std::ptr::null_mut(),
+        }));
+        let mut registration = std::ptr::null_mut();
+
+        unsafe {
+            PowerRegisterSuspendResumeNotification(
+                DEVICE_NOTIFY_CALLBACK,
+                HANDLE(parameters as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as *mut c_void),
+                &mut registration,
+            )
+        }
+        .ok()
+        .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(not(windows))]
+mod notifications {
+    /// Other systems report devices as unplugged and plugged in again, the watcher handles that
+    pub fn register() -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Reopens every device after the system resumes, their handles silently die on Windows
+///
+/// This includes fast startup, which is hibernation under the hood.
+pub async fn power_task(token: CancellationToken) {
+    if let Err(err) = notifications::register() {
+        log::warn!("Failed to register for resume notifications: {}", err);
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            _ = RESUMED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+
+        log::info!("System resumed, reconnecting devices");
+
+        if let Err(err) = reconnect_all().await {
+            log::error!("Failed to reconnect devices after resume: {}", err);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage, imageops};
+
+/// Share of the key a shrunk image still covers
+pub const SHRINK_SCALE: f32 = 0.8;
+
+/// How key images change while the key is held
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PressEffect {
+    /// Images stay as they are
+    #[default]
+    None,
+    /// Images get darker
+    Darken,
+    /// Colors of images are inverted
+    Invert,
+    /// Images get smaller, as if the key was pushed in
+    Shrink,
+}
+
+impl PressEffect {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Self::None),
+            "darken" => Some(Self::Darken),
+            "invert" => Some(Self::Invert),
+            "shrink" => Some(Self::Shrink),
+            _ => None,
+        }
+    }
+
+    /// Pressed variant of a key image
+    pub fn apply(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Self::None => image.clone(),
+            Self::Darken => {
+                let mut image = image.to_rgba8();
+
+                for pixel in image.pixels_mut() {
+                    for channel in &mut pixel.0[..3] {
+                        *channel /= 2;
+                    }
+                }
+
+                DynamicImage::ImageRgba8(image)
+            }
+            Self::Invert => {
+                let mut image = image.clone();
+                image.invert();
+
+                image
+            }
+            Self::Shrink => {
+                let (width, height) = image.dimensions();
+                let small = image.resize_exact(
+                    ((width as f32 * SHRINK_SCALE).round() as u32).max(1),
+                    ((height as f32 * SHRINK_SCALE).round() as u32).max(1),
+                    imageops::FilterType::Triangle,
+                );
+
+                // Centered on black, like the device shows an empty key
+                let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+                imageops::overlay(
+                    &mut canvas,
+                    &small.to_rgba8(),
+                    ((width - small.width()) / 2) as i64,
+                    ((height - small.height()) / 2) as i64,
+                );
+
+                DynamicImage::ImageRgba8(canvas)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(value: u8) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            10,
+            10,
+            Rgba([value, value, value, 255]),
+        ))
+    }
+
+    #[test]
+    fn effects_change_images() {
+        let image = filled(200);
+
+        assert_eq!(
+            PressEffect::Darken.apply(&image).get_pixel(4, 4).0,
+            [100, 100, 100, 255]
+        );
+        assert_eq!(
+            PressEffect::Invert.apply(&image).get_pixel(4, 4).0,
+            [55, 55, 55, 255]
+        );
+
+        let shrunk = PressEffect::Shrink.apply(&image);
+        assert_eq!(shrunk.dimensions(), (10, 10));
+        assert_eq!(shrunk.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert_eq!(shrunk.get_pixel(5, 5).0, [200, 200, 200, 255]);
+
+        assert_eq!(PressEffect::parse("shrink"), Some(PressEffect::Shrink));
+        assert_eq!(PressEffect::parse("blink"), None);
+    }
+}
@@ -0,0 +1,108 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Height of a bar drawn by [ProgressBar::draw_bottom], in pixels of a 120 pixel key
+pub const BAR_HEIGHT: u32 = 6;
+
+/// Horizontal bar filling up from the left, for volumes, timers, downloads and the like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressBar {
+    /// Color of the filled part
+    pub fill: Rgb<u8>,
+    /// Color of the rest of the bar, [None] leaves the image as it is there
+    pub track: Option<Rgb<u8>>,
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self {
+            fill: Rgb([0, 200, 80]),
+            track: None,
+        }
+    }
+}
+
+impl ProgressBar {
+    /// Draws the bar filled to `percent` into the area at `x`, `y`, clipping at image edges
+    pub fn draw(
+        &self,
+        image: &mut RgbImage,
+        (x, y): (u32, u32),
+        (width, height): (u32, u32),
+        percent: u32,
+    ) {
+        let filled = width * percent.min(100) / 100;
+        let right = (x + width).min(image.width());
+        let bottom = (y + height).min(image.height());
+
+        for px in x..right {
+            let color = match px - x < filled {
+                true => self.fill,
+                false => match self.track {
+                    Some(track) => track,
+                    None => continue,
+                },
+            };
+
+            for py in y..bottom {
+                image.put_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Draws the bar along the bottom edge, above the part displays cut off
+    ///
+    /// Only images drawn with [crate::text] get a bar, others are left as they are.
+    pub fn draw_bottom(&self, image: &mut DynamicImage, percent: u32) {
+        let Some(image) = image.as_mut_rgb8() else {
+            return;
+        };
+
+        let (width, height) = image.dimensions();
+        let margin = width / 12;
+        let bar_height = (BAR_HEIGHT * height / 120).max(1);
+
+        self.draw(
+            image,
+            (margin, height.saturating_sub(margin + bar_height)),
+            (width.saturating_sub(margin * 2), bar_height),
+            percent,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_fill_from_the_left() {
+        let black = Rgb([0, 0, 0]);
+        let bar = ProgressBar {
+            fill: Rgb([255, 0, 0]),
+            track: Some(Rgb([0, 0, 255])),
+        };
+        let mut image = RgbImage::new(20, 4);
+
+        bar.draw(&mut image, (5, 1), (10, 2), 30);
+
+        assert_eq!(image.get_pixel(5, 1), &bar.fill);
+        assert_eq!(image.get_pixel(7, 2), &bar.fill);
+        assert_eq!(image.get_pixel(8, 1), &Rgb([0, 0, 255]));
+        assert_eq!(image.get_pixel(14, 2), &Rgb([0, 0, 255]));
+        // Nothing outside the area
+        assert_eq!(image.get_pixel(4, 1), &black);
+        assert_eq!(image.get_pixel(15, 1), &black);
+        assert_eq!(image.get_pixel(5, 0), &black);
+        assert_eq!(image.get_pixel(5, 3), &black);
+
+        // Without a track only the filled part is drawn, values past 100 are full
+        let mut image = DynamicImage::new_rgb8(120, 120);
+        ProgressBar::default().draw_bottom(&mut image, 250);
+
+        let image = image.into_rgb8();
+        assert_eq!(image.get_pixel(10, 105), &Rgb([0, 200, 80]));
+        assert_eq!(image.get_pixel(109, 105), &Rgb([0, 200, 80]));
+        assert_eq!(image.get_pixel(110, 105), &black);
+        assert_eq!(image.get_pixel(10, 99), &black);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
+
+use akp05::{
+    mappings::COL_COUNT,
+    qrcode::QrCode,
+    text::{TextStyle, render_lines},
+};
+use image::DynamicImage;
+use openaction::SettingsValue;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::writer::KeyPainter;
+
+/// How often codes are drawn on devices that connected since, changed ones are drawn at once
+pub const QR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size a code is rendered at on a key or strip zone, images are resized to the device format
+/// anyway
+pub const QR_SIZE: (u32, u32) = (120, 120);
+
+/// QR code action, drawn on its key or across the whole strip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrAction {
+    pub device: String,
+    pub position: u8,
+    /// Text or URL in the code
+    pub text: String,
+    /// Draws the code across the strip instead of on the key
+    pub strip: bool,
+}
+
+// QR code actions by their OpenDeck context
+static CODES: LazyLock<Mutex<HashMap<String, QrAction>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Text and strip mode stored in action settings
+pub fn settings_code(settings: &SettingsValue) -> (String, bool) {
+    let text = settings
+        .get("text")
+        .and_then(|text| text.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let strip = settings
+        .get("strip")
+        .and_then(|strip| strip.as_bool())
+        .unwrap_or(false);
+
+    (text, strip)
+}
+
+pub fn add_code(context: &str, code: QrAction) {
+    CODES.lock().unwrap().insert(context.to_string(), code);
+    CHANGED.notify_one();
+}
+
+pub fn remove_code(context: &str) {
+    CODES.lock().unwrap().remove(context);
+    CHANGED.notify_one();
+}
+
+/// Takes new settings of an action, e.g. after the text was changed in OpenDeck
+pub fn update_settings(context: &str, settings: &SettingsValue) {
+    if let Some(code) = CODES.lock().unwrap().get_mut(context) {
+        (code.text, code.strip) = settings_code(settings);
+    }
+
+    CHANGED.notify_one();
+}
+
+/// Checks if a code is drawn on the position, so OpenDeck images for it are ignored
+pub fn draws_position(device: &str, position: u8) -> bool {
+    CODES.lock().unwrap().values().any(|code| {
+        code.device == device
+            && match code.strip {
+                true => (position as usize) < COL_COUNT,
+                false => code.position == position,
+            }
+    })
+}
+
+/// Images of a code for every position it covers
+///
+/// Text that doesn't fit shows an error instead, so it's clear why nothing can be scanned.
+fn render_code(code: &QrAction) -> Vec<(u8, DynamicImage)> {
+    let (width, height) = QR_SIZE;
+    let zones = if code.strip { COL_COUNT as u32 } else { 1 };
+    let size = (width * zones, height);
+
+    let image = match QrCode::encode(code.text.as_bytes()) {
+        _ if code.text.is_empty() => {
+            render_lines(size, &["SET", "TEXT"], &[], TextStyle::default())
+        }
+        Ok(qr) => qr.render(size),
+        Err(err) => {
+            log::warn!("Failed to draw QR code: {}", err);
+            render_lines(size, &["TOO", "LONG"], &[], TextStyle::default())
+        }
+    };
+
+    if !code.strip {
+        return vec![(code.position, image)];
+    }
+
+    // Drawn as one image, so the code runs across zone borders
+    (0..zones)
+        .map(|zone| (zone as u8, image.crop_imm(zone * width, 0, width, height)))
+        .collect()
+}
+
+/// Draws every QR code action on its device
+pub async fn qr_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+
+    loop {
+        let codes: Vec<QrAction> = CODES.lock().unwrap().values().cloned().collect();
+
+        painter.retain(|device, position| {
+            codes.iter().any(|code| {
+                code.device == device
+                    && (code.position == position
+                        || (code.strip && (position as usize) < COL_COUNT))
+            })
+        });
+
+        for code in codes {
+            // Only rendered if some position needs it, and once for all strip zones
+            let images = OnceLock::new();
+            let positions: Vec<u8> = match code.strip {
+                true => (0..COL_COUNT as u8).collect(),
+                false => vec![code.position],
+            };
+
+            for (index, position) in positions.into_iter().enumerate() {
+                let lines = vec![code.text.clone(), code.strip.to_string()];
+
+                painter
+                    .paint_device(&code.device, position, lines, || {
+                        images.get_or_init(|| render_code(&code))[index].1.clone()
+                    })
+                    .await;
+            }
+        }
+
+        tokio::select! {
+            _ = CHANGED.notified() => {}
+            _ = tokio::time::sleep(QR_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn strip_codes_cover_every_zone() {
+        let code = QrAction {
+            device: "a5-test".to_string(),
+            position: 7,
+            text: "WIFI:S:Home;T:WPA;P:secret;;".to_string(),
+            strip: false,
+        };
+
+        let images = render_code(&code);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].0, 7);
+
+        let images = render_code(&QrAction {
+            strip: true,
+            ..code
+        });
+        let positions: Vec<u8> = images.iter().map(|(position, _)| *position).collect();
+        assert_eq!(positions, [0, 1, 2, 3, 4]);
+
+        // Middle of the strip has the code, the ends are outside of it
+        let middle = images[2].1.to_rgb8();
+        assert!(middle.pixels().any(|pixel| pixel.0 == [255, 255, 255]));
+        assert!(
+            images[0]
+                .1
+                .to_rgb8()
+                .pixels()
+                .all(|pixel| pixel.0 == [0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn settings_have_text_and_mode() {
+        assert_eq!(
+            settings_code(&json!({ "text": "https://example.com", "strip": true })),
+            ("https://example.com".to_string(), true)
+        );
+        assert_eq!(settings_code(&json!({})), (String::new(), false));
+    }
+}
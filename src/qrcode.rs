@@ -0,0 +1,555 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Highest version encoded, 57 modules a side is about what a key can show legibly
+pub const MAX_VERSION: usize = 10;
+
+/// Light modules kept around the code, scanners need some space to find it
+pub const QUIET_ZONE: u32 = 2;
+
+// Error correction codewords per block, then count and data codewords of blocks in both
+// groups, for level M of versions 1 to 10
+const BLOCKS: [(usize, usize, usize, usize, usize); MAX_VERSION] = [
+    (10, 1, 16, 0, 0),
+    (16, 1, 28, 0, 0),
+    (26, 1, 44, 0, 0),
+    (18, 2, 32, 0, 0),
+    (24, 2, 43, 0, 0),
+    (16, 4, 27, 0, 0),
+    (18, 4, 31, 0, 0),
+    (22, 2, 38, 2, 39),
+    (22, 3, 36, 2, 37),
+    (26, 4, 43, 1, 44),
+];
+
+// Centers of alignment patterns, in both directions
+const ALIGNMENT: [&[usize]; MAX_VERSION] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+/// QR code of some bytes, with error correction level M
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+}
+
+/// Multiplies in GF(256) with the polynomial QR codes use
+fn gf_mul(mut x: u8, mut y: u8) -> u8 {
+    let mut product = 0u8;
+
+    while y != 0 {
+        if y & 1 != 0 {
+            product ^= x;
+        }
+
+        x = (x << 1) ^ if x & 0x80 != 0 { 0x1D } else { 0 };
+        y >>= 1;
+    }
+
+    product
+}
+
+/// Reed-Solomon generator polynomial of `degree`, without its leading 1
+fn rs_generator(degree: usize) -> Vec<u8> {
+    let mut generator = vec![0u8; degree];
+    generator[degree - 1] = 1;
+    let mut root = 1u8;
+
+    for _ in 0..degree {
+        for j in 0..degree {
+            generator[j] = gf_mul(generator[j], root);
+
+            if j + 1 < degree {
+                generator[j] ^= generator[j + 1];
+            }
+        }
+
+        root = gf_mul(root, 2);
+    }
+
+    generator
+}
+
+/// Error correction codewords of a block
+fn rs_remainder(data: &[u8], generator: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0u8; generator.len()];
+
+    for byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+
+        for (value, coefficient) in remainder.iter_mut().zip(generator) {
+            *value ^= gf_mul(*coefficient, factor);
+        }
+    }
+
+    remainder
+}
+
+/// Remainder of the BCH code protecting format and version bits
+fn bch(data: u32, bits: u32, generator: u32) -> u32 {
+    let mut remainder = data;
+
+    for _ in 0..bits {
+        remainder = (remainder << 1) ^ ((remainder >> (bits - 1)) * generator);
+    }
+
+    data << bits | remainder
+}
+
+fn data_capacity(version: usize) -> usize {
+    let (_, count1, data1, count2, data2) = BLOCKS[version - 1];
+
+    count1 * data1 + count2 * data2
+}
+
+/// Data codewords in byte mode, padded to fill the version
+fn data_codewords(data: &[u8], version: usize) -> Vec<u8> {
+    let capacity = data_capacity(version);
+    let count_bits = if version < 10 { 8 } else { 16 };
+    let mut bits: Vec<bool> = vec![];
+
+    let mut push = |value: usize, count: usize| {
+        for i in (0..count).rev() {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+
+    push(0b0100, 4);
+    push(data.len(), count_bits);
+
+    for byte in data {
+        push(*byte as usize, 8);
+    }
+
+    // Terminator, as much of it as fits, then up to a whole byte
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.extend(std::iter::repeat_n(false, (8 - bits.len() % 8) % 8));
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |value, bit| value << 1 | *bit as u8))
+        .collect();
+
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity {
+            break;
+        }
+
+        codewords.push(pad);
+    }
+
+    codewords
+}
+
+/// Splits data into blocks, adds their error correction and interleaves everything
+fn interleave(data: &[u8], version: usize) -> Vec<u8> {
+    let (ec, count1, data1, count2, data2) = BLOCKS[version - 1];
+    let generator = rs_generator(ec);
+
+    let mut blocks = vec![];
+    let mut start = 0;
+
+    for length in std::iter::repeat_n(data1, count1).chain(std::iter::repeat_n(data2, count2)) {
+        let block = &data[start..start + length];
+        blocks.push((block, rs_remainder(block, &generator)));
+        start += length;
+    }
+
+    let mut codewords = vec![];
+
+    for i in 0..data1.max(data2) {
+        codewords.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+    }
+
+    for i in 0..ec {
+        codewords.extend(blocks.iter().map(|(_, ec)| ec[i]));
+    }
+
+    codewords
+}
+
+fn masked(mask: u8, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (x / 3 + y / 2).is_multiple_of(2),
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+    }
+}
+
+/// Modules being placed, with the ones of patterns kept apart from data
+struct Matrix {
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl Matrix {
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn finder(&mut self, cx: usize, cy: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+
+                if x < 0 || y < 0 || x >= self.size as i32 || y >= self.size as i32 {
+                    continue;
+                }
+
+                // Rings of 1, 3 and 5 modules are dark, the separator around them light
+                let ring = dx.abs().max(dy.abs());
+                self.set_function(x as usize, y as usize, ring != 2 && ring != 4);
+            }
+        }
+    }
+
+    fn alignment(&mut self, cx: usize, cy: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let ring = dx.abs().max(dy.abs());
+                let (x, y) = ((cx as i32 + dx) as usize, (cy as i32 + dy) as usize);
+
+                self.set_function(x, y, ring != 1);
+            }
+        }
+    }
+
+    fn format(&mut self, mask: u8) {
+        // Level M is 00
+        let bits = bch(mask as u32, 10, 0x537) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        let size = self.size;
+
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+
+        self.set_function(8, size - 8, true);
+    }
+
+    fn version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+
+        let bits = bch(version as u32, 12, 0x1F25);
+
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Puts codewords in the zigzag order, two columns at a time from the bottom right
+    fn place(&mut self, codewords: &[u8]) {
+        let mut index = 0;
+        let mut right = self.size as i32 - 1;
+
+        while right >= 1 {
+            // Vertical timing pattern is skipped
+            if right == 6 {
+                right = 5;
+            }
+
+            for vertical in 0..self.size {
+                for column in 0..2 {
+                    let x = right as usize - column;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        self.size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+
+                    if !self.function[y * self.size + x] && index < codewords.len() * 8 {
+                        self.modules[y * self.size + x] =
+                            (codewords[index / 8] >> (7 - index % 8)) & 1 != 0;
+                        index += 1;
+                    }
+                }
+            }
+
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if !self.function[y * self.size + x] && masked(mask, x, y) {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to scan, the mask with the lowest one is used
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let at = |x: usize, y: usize| self.modules[y * size + x];
+        let mut penalty = 0;
+
+        // Runs of the same color and finder-like patterns, in rows and columns
+        const FINDER: [bool; 11] = [
+            true, false, true, true, true, false, true, false, false, false, false,
+        ];
+
+        for transposed in [false, true] {
+            let line = |a: usize, b: usize| if transposed { at(b, a) } else { at(a, b) };
+
+            for b in 0..size {
+                let mut run = 1;
+
+                for a in 1..size {
+                    if line(a, b) == line(a - 1, b) {
+                        run += 1;
+                    } else {
+                        run = 1;
+                    }
+
+                    if run == 5 {
+                        penalty += 3;
+                    } else if run > 5 {
+                        penalty += 1;
+                    }
+                }
+
+                for a in 0..size.saturating_sub(10) {
+                    let window: Vec<bool> = (a..a + 11).map(|a| line(a, b)).collect();
+
+                    if window == FINDER || window.iter().rev().eq(FINDER.iter()) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        // Blocks of 2 by 2 of the same color
+        for y in 1..size {
+            for x in 1..size {
+                let color = at(x, y);
+
+                if at(x - 1, y) == color && at(x, y - 1) == color && at(x - 1, y - 1) == color {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Balance of dark and light
+        let dark = self.modules.iter().filter(|dark| **dark).count();
+        let total = size * size;
+        penalty += (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1)
+            * 10;
+
+        penalty
+    }
+}
+
+impl QrCode {
+    /// Encodes bytes in the smallest version they fit, fails if they don't fit in [MAX_VERSION]
+    pub fn encode(data: &[u8]) -> Result<Self, String> {
+        let version = (1..=MAX_VERSION)
+            .find(|version| {
+                let count_bits = if *version < 10 { 8 } else { 16 };
+                4 + count_bits + data.len() * 8 <= data_capacity(*version) * 8
+            })
+            .ok_or(format!(
+                "{} bytes don't fit into a QR code of version {}, {} bytes fit",
+                data.len(),
+                MAX_VERSION,
+                data_capacity(MAX_VERSION) - 3
+            ))?;
+
+        let size = version * 4 + 17;
+        let mut matrix = Matrix {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+
+        for i in 0..size {
+            matrix.set_function(6, i, i % 2 == 0);
+            matrix.set_function(i, 6, i % 2 == 0);
+        }
+
+        matrix.finder(3, 3);
+        matrix.finder(size - 4, 3);
+        matrix.finder(3, size - 4);
+
+        let centers = ALIGNMENT[version - 1];
+        let last = centers.len().saturating_sub(1);
+
+        for (i, cx) in centers.iter().enumerate() {
+            for (j, cy) in centers.iter().enumerate() {
+                // Corners with finder patterns don't get one
+                if [(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    continue;
+                }
+
+                matrix.alignment(*cx, *cy);
+            }
+        }
+
+        // Reserved with some mask first, so data goes around them
+        matrix.format(0);
+        matrix.version(version);
+
+        let codewords = interleave(&data_codewords(data, version), version);
+        matrix.place(&codewords);
+
+        let mut best: Option<(usize, Vec<bool>)> = None;
+
+        for mask in 0..8 {
+            matrix.apply_mask(mask);
+            matrix.format(mask);
+
+            let penalty = matrix.penalty();
+            if best.as_ref().is_none_or(|(lowest, _)| penalty < *lowest) {
+                best = Some((penalty, matrix.modules.clone()));
+            }
+
+            // Masking twice takes it off again
+            matrix.apply_mask(mask);
+        }
+
+        Ok(Self {
+            version,
+            size,
+            modules: best.map(|(_, modules)| modules).unwrap_or_default(),
+        })
+    }
+
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Modules on each side, without the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// Draws the code as big as it fits in whole pixels per module, centered on black
+    ///
+    /// The code itself is dark on light with a [QUIET_ZONE], the way scanners expect it.
+    pub fn render(&self, (width, height): (u32, u32)) -> DynamicImage {
+        let mut image = RgbImage::new(width, height);
+        let modules = self.size as u32 + QUIET_ZONE * 2;
+        let scale = (width.min(height) / modules).max(1);
+        let left = (width as i64 - (modules * scale) as i64) / 2;
+        let top = (height as i64 - (modules * scale) as i64) / 2;
+
+        for my in 0..modules {
+            for mx in 0..modules {
+                let dark = self.is_dark(
+                    (mx as usize).wrapping_sub(QUIET_ZONE as usize),
+                    (my as usize).wrapping_sub(QUIET_ZONE as usize),
+                );
+                let color = if dark {
+                    Rgb([0, 0, 0])
+                } else {
+                    Rgb([255, 255, 255])
+                };
+
+                for py in 0..scale {
+                    for px in 0..scale {
+                        let x = left + (mx * scale + px) as i64;
+                        let y = top + (my * scale + py) as i64;
+
+                        if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                            image.put_pixel(x as u32, y as u32, color);
+                        }
+                    }
+                }
+            }
+        }
+
+        DynamicImage::ImageRgb8(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_correction_matches_the_spec_example() {
+        // "HELLO WORLD" as version 1-M, from the QR code tutorial everyone uses
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+
+        assert_eq!(
+            rs_remainder(&data, &rs_generator(10)),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+        // Format bits of level M with mask 0, and version bits of version 7
+        assert_eq!(bch(0, 10, 0x537) ^ 0x5412, 0b101010000010010);
+        assert_eq!(bch(7, 12, 0x1F25), 0b000111110010010100);
+    }
+
+    #[test]
+    fn codes_get_as_big_as_their_data() {
+        let code = QrCode::encode(b"https://example.com").unwrap();
+        assert_eq!((code.version(), code.size()), (2, 25));
+
+        // Finder patterns in three corners, the dark module next to the bottom left one
+        for (x, y) in [(0, 0), (24, 0), (0, 24), (3, 3), (21, 3), (3, 21), (8, 17)] {
+            assert!(code.is_dark(x, y), "{} {}", x, y);
+        }
+        // Inner ring of finder patterns and the separators around them are light
+        assert!(!code.is_dark(1, 1));
+        assert!(!code.is_dark(7, 7));
+
+        let wifi = QrCode::encode(&[b'x'; 200]).unwrap();
+        assert_eq!(wifi.version(), 10);
+        assert!(QrCode::encode(&[b'x'; 214]).is_err());
+
+        // 29 modules with the quiet zone, 4 pixels each and centered
+        let image = code.render((120, 120)).into_rgb8();
+        assert_eq!(image.get_pixel(2, 2), &Rgb([255, 255, 255]));
+        assert_eq!(image.get_pixel(10, 10), &Rgb([0, 0, 0]));
+        assert_eq!(image.get_pixel(1, 1), &Rgb([0, 0, 0]));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use mirajazz::state::DeviceStateUpdate;
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, device::send_update};
+
+/// How often held back twists are checked, they go out at most this late once there's room
+pub const RATE_TICK: Duration = Duration::from_millis(20);
+
+/// Budget of input events going out, twists over it are merged until there's room again
+///
+/// Presses always go out right away, they only use up room twists would have had.
+#[derive(Debug)]
+struct RateLimiter {
+    // Events that can go out right now, up to a second worth. Starts out full, the first
+    // refill brings it down to the rate, so the first twists after startup aren't held back.
+    tokens: f64,
+    last: Instant,
+    // Ticks held back by device id and encoder
+    pending: BTreeMap<(String, u8), i32>,
+}
+
+impl RateLimiter {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            last: now,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last = now;
+    }
+
+    /// Updates to send now for `update`, none if it's a twist held back for later
+    fn admit(
+        &mut self,
+        rate: u32,
+        id: &str,
+        update: DeviceStateUpdate,
+        now: Instant,
+    ) -> Vec<DeviceStateUpdate> {
+        if rate == 0 {
+            return vec![update];
+        }
+
+        self.refill(rate, now);
+
+        match update {
+            DeviceStateUpdate::EncoderTwist(encoder, ticks) => {
+                let key = (id.to_string(), encoder);
+
+                // Twists already waiting go first, so this one joins them
+                if let Some(pending) = self.pending.get_mut(&key) {
+                    *pending += ticks as i32;
+                    return vec![];
+                }
+
+                if self.tokens >= 1.0 {
+                    self.tokens -= 1.0;
+                    vec![update]
+                } else {
+                    log::debug!("Holding back twist of encoder {} of {}", encoder, id);
+                    self.pending.insert(key, ticks as i32);
+                    vec![]
+                }
+            }
+            DeviceStateUpdate::EncoderDown(encoder) | DeviceStateUpdate::EncoderUp(encoder) => {
+                self.tokens = (self.tokens - 1.0).max(0.0);
+
+                // Turning and then pressing an encoder reaches OpenDeck in that order
+                let mut updates = vec![];
+                while let Some(twist) = self.take_twist(&(id.to_string(), encoder)) {
+                    updates.push(twist);
+                }
+                updates.push(update);
+
+                updates
+            }
+            DeviceStateUpdate::ButtonDown(_) | DeviceStateUpdate::ButtonUp(_) => {
+                self.tokens = (self.tokens - 1.0).max(0.0);
+
+                vec![update]
+            }
+        }
+    }
+
+    /// Held back twists there's room for now, merged into one per encoder
+    fn due(&mut self, rate: u32, now: Instant) -> Vec<(String, DeviceStateUpdate)> {
+        self.refill(rate, now);
+
+        let mut due = vec![];
+        while (rate == 0 || self.tokens >= 1.0)
+            && let Some(key) = self.pending.keys().next().cloned()
+        {
+            if let Some(twist) = self.take_twist(&key) {
+                self.tokens = (self.tokens - 1.0).max(0.0);
+                due.push((key.0, twist));
+            }
+        }
+
+        due
+    }
+
+    /// Takes up to an [i8] worth of held back ticks of an encoder, leaving the rest waiting
+    fn take_twist(&mut self, key: &(String, u8)) -> Option<DeviceStateUpdate> {
+        let ticks = self.pending.remove(key)?;
+        let sent = ticks.clamp(i8::MIN as i32, i8::MAX as i32);
+
+        if ticks != sent {
+            self.pending.insert(key.clone(), ticks - sent);
+        }
+
+        Some(DeviceStateUpdate::EncoderTwist(key.1, sent as i8))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+static LIMITER: LazyLock<Mutex<RateLimiter>> =
+    LazyLock::new(|| Mutex::new(RateLimiter::new(Instant::now())));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Updates to send now for `update`, twists over `event_rate_limit` are held back and merged
+pub fn admit(id: &str, update: DeviceStateUpdate) -> Vec<DeviceStateUpdate> {
+    let rate = CONFIG.borrow().event_rate_limit;
+    let mut limiter = LIMITER.lock().unwrap();
+    let updates = limiter.admit(rate, id, update, Instant::now());
+
+    if !limiter.is_empty() {
+        CHANGED.notify_one();
+    }
+
+    updates
+}
+
+/// Sends held back twists once there's room for them
+pub async fn rate_limit_task(token: CancellationToken) {
+    loop {
+        let (due, waiting) = {
+            let rate = CONFIG.borrow().event_rate_limit;
+            let mut limiter = LIMITER.lock().unwrap();
+
+            (limiter.due(rate, Instant::now()), !limiter.is_empty())
+        };
+
+        for (id, update) in due {
+            if let Err(err) = send_update(id, update).await {
+                log::warn!("Failed to send held back twist: {}", err);
+            }
+        }
+
+        // Only ticks while twists are waiting
+        tokio::select! {
+            _ = async {
+                if waiting {
+                    tokio::time::sleep(RATE_TICK).await
+                } else {
+                    std::future::pending().await
+                }
+            } => {}
+            _ = CHANGED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn twists_over_the_rate_are_merged_and_presses_go_first() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(now);
+
+        // A second worth of room right from the start, then the rest waits
+        for _ in 0..10 {
+            assert_eq!(
+                limiter
+                    .admit(10, "a5-1", DeviceStateUpdate::EncoderTwist(1, 1), now)
+                    .len(),
+                1
+            );
+        }
+        for _ in 0..5 {
+            assert!(
+                limiter
+                    .admit(10, "a5-1", DeviceStateUpdate::EncoderTwist(1, -1), now)
+                    .is_empty()
+            );
+        }
+
+        // Keys don't wait for twists
+        assert!(matches!(
+            limiter.admit(10, "a5-1", DeviceStateUpdate::ButtonDown(3), now)[..],
+            [DeviceStateUpdate::ButtonDown(3)]
+        ));
+        assert!(limiter.due(10, now).is_empty());
+
+        assert!(matches!(
+            limiter.due(10, now + Duration::from_millis(100))[..],
+            [(_, DeviceStateUpdate::EncoderTwist(1, -5))]
+        ));
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn encoder_presses_bring_their_twists_along() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(now);
+
+        // The first twists after startup go right away, until they used up the room
+        for _ in 0..10 {
+            assert_eq!(
+                limiter
+                    .admit(10, "a5-1", DeviceStateUpdate::EncoderTwist(3, 1), now)
+                    .len(),
+                1
+            );
+        }
+
+        assert!(
+            limiter
+                .admit(10, "a5-1", DeviceStateUpdate::EncoderTwist(2, 100), now)
+                .is_empty()
+        );
+        assert!(
+            limiter
+                .admit(10, "a5-1", DeviceStateUpdate::EncoderTwist(2, 100), now)
+                .is_empty()
+        );
+
+        assert!(matches!(
+            limiter.admit(10, "a5-1", DeviceStateUpdate::EncoderDown(2), now)[..],
+            [
+                DeviceStateUpdate::EncoderTwist(2, 127),
+                DeviceStateUpdate::EncoderTwist(2, 73),
+                DeviceStateUpdate::EncoderDown(2),
+            ]
+        ));
+        assert!(limiter.is_empty());
+
+        // Without a limit nothing waits
+        assert_eq!(
+            limiter
+                .admit(0, "a5-1", DeviceStateUpdate::EncoderTwist(2, 1), now)
+                .len(),
+            1
+        );
+    }
+}
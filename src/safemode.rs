@@ -0,0 +1,175 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::{
+    error::{Akp05Error, Operation},
+    images::KeyImage,
+    text::{TextStyle, render_lines},
+};
+
+use crate::{
+    device,
+    session::{self, Event},
+};
+
+/// Failed uploads within [FAILURE_WINDOW] that turn safe mode on, as many more in safe mode give
+/// up on the device
+pub const SAFE_MODE_FAILURES: usize = 3;
+
+/// How far back failed uploads are counted
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Highest JPEG quality in safe mode, smaller images take fewer writes to upload
+pub const SAFE_MODE_QUALITY: u8 = 40;
+
+/// Size text-only images are rendered at, images are resized to the device format anyway
+pub const TEXT_ONLY_SIZE: (u32, u32) = (120, 120);
+
+/// What happens after an upload failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    /// Device keeps going as it is
+    Tolerate,
+    /// Failures piled up, safe mode is on now
+    SafeMode,
+    /// Safe mode didn't help either, the device is dropped
+    GiveUp,
+}
+
+/// Failed uploads of a single device
+#[derive(Debug, Default)]
+struct Uploads {
+    failures: VecDeque<Instant>,
+    safe: bool,
+}
+
+impl Uploads {
+    fn failed(&mut self, now: Instant) -> Outcome {
+        self.failures
+            .retain(|at| now.duration_since(*at) < FAILURE_WINDOW);
+        self.failures.push_back(now);
+
+        if self.failures.len() < SAFE_MODE_FAILURES {
+            return Outcome::Tolerate;
+        }
+
+        if self.safe {
+            return Outcome::GiveUp;
+        }
+
+        // Safe mode gets a clean slate to prove itself
+        self.safe = true;
+        self.failures.clear();
+
+        Outcome::SafeMode
+    }
+}
+
+// Device id to its failed uploads, kept while the device is connected
+static UPLOADS: LazyLock<Mutex<HashMap<String, Uploads>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Checks if uploads to the device kept failing, so it gets fewer and simpler images
+///
+/// Animations stop, JPEG quality drops to [SAFE_MODE_QUALITY] and images that still fail are
+/// tried again as text only.
+pub fn is_active(device: &str) -> bool {
+    UPLOADS
+        .lock()
+        .unwrap()
+        .get(device)
+        .is_some_and(|uploads| uploads.safe)
+}
+
+/// JPEG quality to upload images to the device with
+pub fn quality(device: &str, quality: u8) -> u8 {
+    match is_active(device) {
+        true => quality.min(SAFE_MODE_QUALITY),
+        false => quality,
+    }
+}
+
+/// Starts over for a newly connected device, e.g. after the cable was replaced
+pub fn forget(device: &str) {
+    UPLOADS.lock().unwrap().remove(device);
+}
+
+/// Checks if the device failed to take an image, as opposed to OpenDeck sending a bad one
+pub fn is_upload_failure(err: &Akp05Error) -> bool {
+    matches!(
+        err,
+        Akp05Error::Device {
+            operation: Operation::SetImage | Operation::ClearImage,
+            ..
+        }
+    ) && !err.is_anomaly()
+}
+
+/// Plain image with the key number, tried when the real image doesn't make it to the device
+pub fn text_only(position: u8) -> KeyImage {
+    let number = (position as u32 + 1).to_string();
+    let image = render_lines(TEXT_ONLY_SIZE, &["KEY", &number], &[], TextStyle::default());
+
+    KeyImage::Rendered(Arc::new(image))
+}
+
+/// Handles errors of the writer, returning true if it should continue
+///
+/// Failed uploads don't drop the device right away, a few of them turn safe mode on instead.
+/// The device is only given up on if uploads keep failing in safe mode too.
+pub async fn handle_error(err: Akp05Error) -> bool {
+    if !is_upload_failure(&err) {
+        return device::handle_error(err).await;
+    }
+
+    let id = err.device_id().to_string();
+    let outcome = UPLOADS
+        .lock()
+        .unwrap()
+        .entry(id.clone())
+        .or_default()
+        .failed(Instant::now());
+
+    match outcome {
+        Outcome::Tolerate => {
+            log::warn!("{}", err);
+            session::advance(&id, Event::Failed, Some(&err.to_string()));
+            true
+        }
+        Outcome::SafeMode => {
+            log::warn!("{}", err);
+            log::warn!("Uploads to {} keep failing, turning safe mode on", id);
+            session::advance(&id, Event::SafeMode, Some(&err.to_string()));
+            true
+        }
+        Outcome::GiveUp => device::handle_error(err).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_failures_turn_safe_mode_on_then_give_up() {
+        let now = Instant::now();
+        let mut uploads = Uploads::default();
+
+        assert_eq!(uploads.failed(now), Outcome::Tolerate);
+        assert_eq!(uploads.failed(now), Outcome::Tolerate);
+        assert_eq!(uploads.failed(now), Outcome::SafeMode);
+        assert!(uploads.safe);
+
+        // Old failures don't count anymore
+        let later = now + FAILURE_WINDOW;
+        assert_eq!(uploads.failed(later), Outcome::Tolerate);
+        assert_eq!(uploads.failed(later), Outcome::Tolerate);
+        let later = later + FAILURE_WINDOW;
+        assert_eq!(uploads.failed(later), Outcome::Tolerate);
+        assert_eq!(uploads.failed(later), Outcome::Tolerate);
+        assert_eq!(uploads.failed(later), Outcome::GiveUp);
+    }
+}
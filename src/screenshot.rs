@@ -0,0 +1,89 @@
+use std::{collections::BTreeMap, path::Path};
+
+use akp05::mappings::{COL_COUNT, KEY_COUNT, Kind};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage, imageops::FilterType};
+
+/// Size every key and strip zone is drawn at
+pub const CELL_SIZE: u32 = 120;
+
+/// Space between keys, and around them
+pub const GAP: u32 = 8;
+
+const BACKGROUND: Rgb<u8> = Rgb([24, 24, 24]);
+
+/// Draws images of every position into one picture laid out like the device
+///
+/// Positions are placed where the device shows them, so the keys are above the strip.
+pub fn montage(images: &BTreeMap<u8, DynamicImage>) -> RgbImage {
+    let rows = (KEY_COUNT + COL_COUNT).div_ceil(COL_COUNT) as u32;
+    let size = |cells: u32| cells * CELL_SIZE + (cells + 1) * GAP;
+
+    let mut montage = RgbImage::from_pixel(size(COL_COUNT as u32), size(rows), BACKGROUND);
+
+    for (position, image) in images {
+        if *position as usize >= KEY_COUNT + COL_COUNT {
+            continue;
+        }
+
+        let physical = Kind::Akp05E.map_button_index(*position as usize) as u32;
+        let (row, column) = (physical / COL_COUNT as u32, physical % COL_COUNT as u32);
+
+        let cell = image
+            .resize_exact(CELL_SIZE, CELL_SIZE, FilterType::Triangle)
+            .into_rgb8();
+
+        image::imageops::replace(
+            &mut montage,
+            &cell,
+            (GAP + column * (CELL_SIZE + GAP)) as i64,
+            (GAP + row * (CELL_SIZE + GAP)) as i64,
+        );
+    }
+
+    montage
+}
+
+/// Saves a montage of the images as PNG
+pub fn save(path: &Path, images: &BTreeMap<u8, DynamicImage>) -> Result<(), String> {
+    montage(images)
+        .save_with_format(path, ImageFormat::Png)
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn montage_follows_device_layout() {
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 0, 0])));
+        let montage = montage(&BTreeMap::from([(0, red.clone()), (10, red)]));
+
+        let size = |cells: u32| cells * CELL_SIZE + (cells + 1) * GAP;
+        assert_eq!(montage.dimensions(), (size(5), size(3)));
+
+        // First strip zone is in the bottom row, position 10 is the first key of the top row
+        let center = |row: u32| GAP + row * (CELL_SIZE + GAP) + CELL_SIZE / 2;
+        assert_eq!(*montage.get_pixel(center(0), center(2)), Rgb([255, 0, 0]));
+        assert_eq!(*montage.get_pixel(center(0), center(0)), Rgb([255, 0, 0]));
+        assert_eq!(*montage.get_pixel(center(0), center(1)), BACKGROUND);
+    }
+
+    #[test]
+    fn screenshots_are_pngs() {
+        let path =
+            std::env::temp_dir().join(format!("akp05-screenshot-{}.png", std::process::id()));
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 0, 0])));
+        let images = BTreeMap::from([(0, red)]);
+        save(&path, &images).unwrap();
+
+        let saved = image::ImageReader::open(&path)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap();
+        assert_eq!(saved.format(), Some(ImageFormat::Png));
+        assert_eq!(saved.decode().unwrap().into_rgb8(), montage(&images));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
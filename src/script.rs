@@ -0,0 +1,320 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use mirajazz::state::DeviceStateUpdate;
+use rhai::{AST, CallFnOptions, Dynamic, Engine, Map, Scope, format_map_as_json};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG,
+    hooks::{self, HookEvent},
+};
+
+/// Operations a hook can run before it's stopped, so a runaway loop can't stall every input
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Command lines a script sent during the hook that's running
+type Sent = Arc<Mutex<Vec<String>>>;
+
+/// Rhai script loaded from `hook_script`
+struct Script {
+    engine: Engine,
+    ast: AST,
+    /// `this` of every hook, kept between calls so the script can remember earlier inputs
+    state: Dynamic,
+    sent: Sent,
+}
+
+impl Script {
+    /// Compiles the script, only its hooks run, there's no top level run when it's loaded
+    fn compile(source: &str) -> Result<Self, String> {
+        let sent = Sent::default();
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| log::info!("Hook script: {}", text));
+        register_api(&mut engine, &sent);
+
+        let ast = engine.compile(source).map_err(|err| err.to_string())?;
+
+        Ok(Self {
+            engine,
+            ast,
+            state: Dynamic::from_map(Map::new()),
+            sent,
+        })
+    }
+
+    /// Calls the hook for this event if the script has one, returns the commands it sent
+    fn call(&mut self, device: &str, event: &HookEvent) -> Vec<String> {
+        let Some(hook) = hook_name(event) else {
+            return Vec::new();
+        };
+
+        if !self
+            .ast
+            .iter_functions()
+            .any(|function| function.name == hook && function.params.len() == 1)
+        {
+            return Vec::new();
+        }
+
+        // Scripts get the same fields the hook command reads from its stdin
+        let event = match self.engine.parse_json(event.line(device), true) {
+            Ok(event) => event,
+            Err(err) => {
+                log::warn!("Not passing an event to the hook script: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            hook,
+            (Dynamic::from_map(event),),
+        );
+
+        // Commands sent before an error still go out, like lines a failing hook command printed
+        let sent = std::mem::take(&mut *self.sent.lock().unwrap());
+
+        if let Err(err) = result {
+            log::warn!("Hook script {} failed: {}", hook, err);
+        }
+
+        sent
+    }
+}
+
+/// Name of the script function an event goes to, [None] for events without a hook
+fn hook_name(event: &HookEvent) -> Option<&'static str> {
+    match event {
+        HookEvent::Update(DeviceStateUpdate::ButtonDown(_) | DeviceStateUpdate::ButtonUp(_)) => {
+            Some("on_key")
+        }
+        HookEvent::Update(_) => Some("on_dial"),
+        HookEvent::Touch(..) => Some("on_touch"),
+        HookEvent::Slider(..) | HookEvent::Raw(..) | HookEvent::System(_) => None,
+    }
+}
+
+/// Functions scripts control the deck with, each sends one of the hook command lines
+fn register_api(engine: &mut Engine, sent: &Sent) {
+    let send = |sent: &Sent, command: &str, device: Option<&str>, fields: Map| {
+        let mut line = fields;
+        line.insert("command".into(), command.into());
+
+        if let Some(device) = device {
+            line.insert("device".into(), device.into());
+        }
+
+        sent.lock().unwrap().push(format_map_as_json(&line));
+    };
+
+    // Any command, as a map of the fields the hook command prints
+    let commands = sent.clone();
+    engine.register_fn("command", move |line: Map| {
+        commands.lock().unwrap().push(format_map_as_json(&line));
+    });
+
+    let commands = sent.clone();
+    engine.register_fn("set_brightness", move |brightness: i64| {
+        send(
+            &commands,
+            "set_brightness",
+            None,
+            brightness_fields(brightness),
+        );
+    });
+
+    let commands = sent.clone();
+    engine.register_fn("set_brightness", move |device: &str, brightness: i64| {
+        send(
+            &commands,
+            "set_brightness",
+            Some(device),
+            brightness_fields(brightness),
+        );
+    });
+
+    let commands = sent.clone();
+    engine.register_fn("set_image", move |position: i64, image: Dynamic| {
+        send(&commands, "set_image", None, image_fields(position, image));
+    });
+
+    let commands = sent.clone();
+    engine.register_fn(
+        "set_image",
+        move |device: &str, position: i64, image: Dynamic| {
+            send(
+                &commands,
+                "set_image",
+                Some(device),
+                image_fields(position, image),
+            );
+        },
+    );
+
+    let commands = sent.clone();
+    engine.register_fn("switch_profile", move |device: &str, profile: &str| {
+        let mut fields = Map::new();
+        fields.insert("profile".into(), profile.into());
+
+        send(&commands, "switch_profile", Some(device), fields);
+    });
+}
+
+fn brightness_fields(brightness: i64) -> Map {
+    let mut fields = Map::new();
+    fields.insert("brightness".into(), brightness.into());
+
+    fields
+}
+
+/// Fields of `set_image`, a data URL or `()` to clear the position
+fn image_fields(position: i64, image: Dynamic) -> Map {
+    let mut fields = Map::new();
+    fields.insert("position".into(), position.into());
+    fields.insert("image".into(), image);
+
+    fields
+}
+
+async fn load(path: &Path) -> Result<Script, String> {
+    let source = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Script::compile(&source)
+}
+
+/// Runs the hooks of the configured script for every event, until the setting changes
+pub async fn script_task(token: CancellationToken) {
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        let path = config.borrow_and_update().hook_script.clone();
+
+        let script = match &path {
+            None => None,
+            Some(path) => match load(path).await {
+                Ok(script) => {
+                    log::info!("Loaded hook script {}", path.display());
+                    Some(script)
+                }
+                Err(err) => {
+                    log::error!("Failed to load hook script {}: {}", path.display(), err);
+                    None
+                }
+            },
+        };
+
+        if let Some(mut script) = script {
+            let mut events = hooks::subscribe();
+
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok((id, event)) => {
+                            for line in script.call(&id, &event) {
+                                if let Err(err) = hooks::run_command(&line).await {
+                                    log::warn!("Ignoring hook script command {}: {}", line, err);
+                                }
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("Hook script is too slow, dropped {} events", missed);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                    _ = config.changed() => {
+                        if config.borrow().hook_script != path {
+                            break;
+                        }
+                    }
+                    _ = token.cancelled() => return,
+                }
+            }
+
+            continue;
+        }
+
+        // Load again once the setting changes
+        tokio::select! {
+            _ = config.changed() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_send_commands() {
+        let mut script = Script::compile(
+            r#"
+            fn on_dial(event) {
+                if event.ticks == () {
+                    return;
+                }
+
+                // Two turns of the same dial within a second switch the layer
+                let now = timestamp();
+
+                if this.last_dial == event.dial && now - this.last_turn < 1.0 {
+                    switch_profile(event.device, "Layer B");
+                }
+
+                this.last_dial = event.dial;
+                this.last_turn = now;
+            }
+
+            fn on_touch(event) {
+                set_brightness(event.device, event.spot);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let turn = HookEvent::Update(DeviceStateUpdate::EncoderTwist(2, 1));
+
+        assert!(script.call("a5-1", &turn).is_empty());
+        assert_eq!(
+            script.call("a5-1", &turn),
+            vec![r#"{"command":"switch_profile","device":"a5-1","profile":"Layer B"}"#]
+        );
+
+        assert_eq!(
+            script.call("a5-1", &HookEvent::Touch(1, 66, None)),
+            vec![r#"{"brightness":66,"command":"set_brightness","device":"a5-1"}"#]
+        );
+
+        // No on_key in the script
+        assert!(
+            script
+                .call("a5-1", &HookEvent::Update(DeviceStateUpdate::ButtonDown(1)))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn runaway_hooks_are_stopped() {
+        let mut script = Script::compile("fn on_key(event) { set_image(1, ()); loop {} }").unwrap();
+
+        let sent = script.call("a5-1", &HookEvent::Update(DeviceStateUpdate::ButtonUp(1)));
+
+        assert_eq!(
+            sent,
+            vec![r#"{"command":"set_image","image":null,"position":1}"#]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::status::{self, Status};
+
+/// Time without failures after which a degraded device counts as working again
+pub const RECOVERY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Where the session of a connected device is at
+///
+/// ```text
+/// Init ──Probed──▶ Probed ──Started──▶ Active ──Failed──▶ Degraded ◀─Failed─┐
+///                    ▲                 │ │ ▲               │ │ │ └──────────┘
+///                    │                 │ │ └──Recovered────┘ │ │
+///                    └──Resetting──────┴─┼──Resetting────────┘ │
+///                                        │                     │
+///                                        └────▶ SafeMode ◀─────┘
+/// ```
+///
+/// Active and degraded devices go to [State::SafeMode] with [Event::SafeMode] and stay there
+/// through failures and resets, until they connect again. Every state goes to [State::Closed]
+/// with [Event::Closed], nothing leaves it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Device was claimed and is being opened and set up
+    Init,
+    /// Device is set up and its capabilities are known, OpenDeck doesn't use it yet
+    Probed,
+    /// Device is registered with OpenDeck, its reader and writer are running
+    Active,
+    /// Something failed, but the device keeps working
+    Degraded,
+    /// Image uploads kept failing, the device gets fewer and simpler images
+    SafeMode,
+    /// Device is gone, failed for good or the plugin is done with it
+    Closed,
+}
+
+/// What happened to a device, moving its session on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Device was opened, set up and its capabilities are known
+    Probed,
+    /// Reader and writer of the device are running
+    Started,
+    /// Something failed without taking the device down
+    Failed,
+    /// Nothing failed for [RECOVERY_WINDOW]
+    Recovered,
+    /// Image uploads kept failing, safe mode is on
+    SafeMode,
+    /// Device is re-initialized, it's started again once that's done
+    Resetting,
+    /// Device was unplugged, failed for good or shut down
+    Closed,
+}
+
+/// Moves a session on, [None] if the event can't happen in that state
+///
+/// Failures while connecting don't change anything, connecting keeps going or fails for good.
+/// Reset devices go back to [State::Probed], they're set up again but not started yet, except in
+/// safe mode, which only ends when the device connects again.
+pub fn transition(state: State, event: Event) -> Option<State> {
+    match (state, event) {
+        (State::Closed, _) => None,
+        (_, Event::Closed) => Some(State::Closed),
+        (State::Init, Event::Probed) => Some(State::Probed),
+        (State::Probed, Event::Started) => Some(State::Active),
+        (State::Init | State::Probed, Event::Failed) => Some(state),
+        (State::Active | State::Degraded, Event::Failed) => Some(State::Degraded),
+        (State::Degraded, Event::Recovered) => Some(State::Active),
+        (State::Active | State::Degraded, Event::SafeMode) => Some(State::SafeMode),
+        (State::SafeMode, Event::Failed | Event::SafeMode | Event::Resetting | Event::Started) => {
+            Some(State::SafeMode)
+        }
+        (State::Active | State::Degraded, Event::Resetting) => Some(State::Probed),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    state: State,
+    // Was active before, so being probed again means it's reconnecting
+    started: bool,
+    failed_at: Option<Instant>,
+}
+
+impl Session {
+    fn status(&self) -> Status {
+        match self.state {
+            State::Init => Status::Connecting,
+            State::Probed if self.started => Status::Reconnecting,
+            State::Probed => Status::Connecting,
+            State::Active => Status::Connected,
+            State::Degraded => Status::Degraded,
+            State::SafeMode => Status::SafeMode,
+            State::Closed => Status::Removed,
+        }
+    }
+}
+
+// Device id to its session, only while the device task runs
+static SESSIONS: LazyLock<Mutex<HashMap<String, Session>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Starts the session of a claimed device, replacing the one of a previous connection
+pub fn begin(device: &str) {
+    let session = Session {
+        state: State::Init,
+        started: false,
+        failed_at: None,
+    };
+    let status = session.status();
+
+    SESSIONS.lock().unwrap().insert(device.to_string(), session);
+    status::report(device, status, None);
+}
+
+/// Moves the session of the device on and reports its status, `detail` says why
+///
+/// Events that can't happen in the current state, e.g. for a device that's closed already, are
+/// ignored and return [None].
+pub fn advance(device: &str, event: Event, detail: Option<&str>) -> Option<State> {
+    let (state, status) = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let Some(session) = sessions.get_mut(device) else {
+            log::debug!("No session for {}, ignoring {:?}", device, event);
+            return None;
+        };
+
+        let Some(state) = transition(session.state, event) else {
+            log::debug!(
+                "Ignoring {:?} for {} while it's {:?}",
+                event,
+                device,
+                session.state
+            );
+            return None;
+        };
+
+        log::debug!("Session of {}: {:?} -> {:?}", device, session.state, state);
+
+        session.state = state;
+        session.started |= state == State::Active;
+        if event == Event::Failed {
+            session.failed_at = Some(Instant::now());
+        }
+        let status = session.status();
+
+        if state == State::Closed {
+            sessions.remove(device);
+        }
+
+        (state, status)
+    };
+
+    // Statuses don't change while connecting, failures are still worth telling about
+    if event == Event::Failed
+        && matches!(state, State::Init | State::Probed)
+        && let Some(detail) = detail
+    {
+        status::report_error(device, detail);
+    }
+
+    status::report(device, status, detail);
+
+    Some(state)
+}
+
+/// Tells the session of the device that something just worked
+///
+/// Degraded devices go back to [State::Active] once nothing failed for [RECOVERY_WINDOW].
+pub fn succeeded(device: &str) {
+    let recovered = SESSIONS.lock().unwrap().get(device).is_some_and(|session| {
+        session.state == State::Degraded
+            && session
+                .failed_at
+                .is_some_and(|at| at.elapsed() >= RECOVERY_WINDOW)
+    });
+
+    if recovered {
+        advance(device, Event::Recovered, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_move_through_documented_transitions() {
+        let mut state = State::Init;
+
+        for (event, expected) in [
+            (Event::Failed, State::Init),
+            (Event::Probed, State::Probed),
+            (Event::Started, State::Active),
+            (Event::Failed, State::Degraded),
+            (Event::Failed, State::Degraded),
+            (Event::Recovered, State::Active),
+            (Event::Failed, State::Degraded),
+            (Event::Resetting, State::Probed),
+            (Event::Started, State::Active),
+            (Event::SafeMode, State::SafeMode),
+            (Event::Failed, State::SafeMode),
+            // Resetting doesn't end safe mode
+            (Event::Resetting, State::SafeMode),
+            (Event::Started, State::SafeMode),
+            (Event::Closed, State::Closed),
+        ] {
+            state = transition(state, event).unwrap();
+            assert_eq!(state, expected, "after {:?}", event);
+        }
+    }
+
+    #[test]
+    fn impossible_events_are_rejected() {
+        assert_eq!(transition(State::Init, Event::Started), None);
+        assert_eq!(transition(State::Init, Event::Resetting), None);
+        assert_eq!(transition(State::Active, Event::Probed), None);
+        assert_eq!(transition(State::Active, Event::Started), None);
+        assert_eq!(transition(State::Active, Event::Recovered), None);
+        assert_eq!(transition(State::SafeMode, Event::Recovered), None);
+        assert_eq!(transition(State::Closed, Event::Closed), None);
+        assert_eq!(transition(State::Closed, Event::Probed), None);
+
+        // Connecting can be given up on at any point
+        assert_eq!(
+            transition(State::Probed, Event::Closed),
+            Some(State::Closed)
+        );
+    }
+}
@@ -0,0 +1,571 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::{
+    images::KeyImage,
+    inputs::{Control, InputReport},
+    mappings::{COL_COUNT, CandidateDevice, ENCODER_COUNT, KEY_COUNT},
+    text::{TextStyle, ZONE_SIZE, render_banner, render_lines},
+};
+use mirajazz::state::DeviceStateUpdate;
+use serde_json::{Value, json};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, WRITERS, config,
+    orientation::Orientation,
+    sliders,
+    toast::{self, Toast},
+    writer::WriterCommand,
+};
+
+/// The wizard gives up after this long without input, it runs again the next time the plugin
+/// starts
+pub const SETUP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Controls that don't answer their prompt within this long are skipped and reported as failed
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How often running wizards are checked for timeouts
+const SETUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Control the wizard asks to be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    Key(u8),
+    DialPress(u8),
+    DialTurn(u8),
+    Zone(u8),
+}
+
+impl Check {
+    /// Every control of the deck, in the order they're asked for
+    fn all() -> Vec<Self> {
+        let keys = (0..KEY_COUNT as u8).map(Self::Key);
+        let dials =
+            (0..ENCODER_COUNT as u8).flat_map(|dial| [Self::DialPress(dial), Self::DialTurn(dial)]);
+        let zones = (0..ENCODER_COUNT as u8).map(Self::Zone);
+
+        keys.chain(dials).chain(zones).collect()
+    }
+
+    fn is_answered_by(self, update: &DeviceStateUpdate) -> bool {
+        match (self, *update) {
+            (Self::Key(key), DeviceStateUpdate::ButtonDown(pressed)) => key == pressed,
+            (Self::DialPress(dial), DeviceStateUpdate::EncoderDown(pressed)) => dial == pressed,
+            (Self::DialTurn(dial), DeviceStateUpdate::EncoderTwist(turned, _)) => dial == turned,
+            _ => false,
+        }
+    }
+
+    fn prompt(self) -> String {
+        match self {
+            Self::Key(key) => format!("PRESS KEY {}", key + 1),
+            Self::DialPress(dial) => format!("PRESS DIAL {}", dial + 1),
+            Self::DialTurn(dial) => format!("TURN DIAL {}", dial + 1),
+            Self::Zone(zone) => format!("TOUCH ZONE {}", zone + 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// New deck was found, a key starts the setup and a dial press skips it
+    Detect,
+    /// Keys show their numbers, dials flip them around until 1 is top left
+    Orientation,
+    /// Asking for every control in turn, the index is of the one asked for
+    Test(usize),
+}
+
+/// What the wizard does with an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Not for the wizard, goes to OpenDeck
+    Dispatch,
+    /// Swallowed without changing anything
+    Swallow,
+    /// Next prompt or a new orientation, drawn again
+    Redraw,
+    /// Every control was asked for, the orientation is saved
+    Save,
+    /// Skipped on the first screen, saved as it is so it isn't asked again
+    Skip,
+}
+
+/// What the wizard shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Screen {
+    /// Lines across the strip
+    Banner(Vec<String>),
+    /// Lines on keys, keys left out are blank
+    Keys(Vec<(u8, Vec<String>)>),
+}
+
+/// Setup of a single device
+///
+/// Every input is swallowed while it runs, and releases of controls pressed during it after.
+#[derive(Debug)]
+struct Wizard {
+    serial: String,
+    active: bool,
+    step: Step,
+    orientation: Orientation,
+    checks: Vec<Check>,
+    failed: Vec<Check>,
+    last_input: Instant,
+    asked_at: Instant,
+    suppressed: HashSet<Control>,
+}
+
+impl Wizard {
+    fn new(serial: &str, now: Instant) -> Self {
+        Self {
+            serial: serial.to_string(),
+            active: true,
+            step: Step::Detect,
+            orientation: Orientation::default(),
+            checks: Check::all(),
+            failed: vec![],
+            last_input: now,
+            asked_at: now,
+            suppressed: HashSet::new(),
+        }
+    }
+
+    /// Moves on to the next check, or finishes after the last one
+    fn next_check(&mut self, index: usize, now: Instant) -> Action {
+        self.asked_at = now;
+
+        if index + 1 < self.checks.len() {
+            self.step = Step::Test(index + 1);
+            return Action::Redraw;
+        }
+
+        self.active = false;
+        Action::Save
+    }
+
+    fn input(&mut self, update: &DeviceStateUpdate, now: Instant) -> Action {
+        let control = Control::of(update);
+
+        if let Some((control, false)) = control {
+            return if self.suppressed.remove(&control) || self.active {
+                Action::Swallow
+            } else {
+                Action::Dispatch
+            };
+        }
+
+        if !self.active {
+            return Action::Dispatch;
+        }
+
+        self.last_input = now;
+
+        if let Some((control, _)) = control {
+            self.suppressed.insert(control);
+        }
+
+        match (self.step, *update) {
+            (Step::Detect, DeviceStateUpdate::ButtonDown(_)) => {
+                self.step = Step::Orientation;
+                Action::Redraw
+            }
+            (Step::Detect, DeviceStateUpdate::EncoderDown(_)) => {
+                self.active = false;
+                Action::Skip
+            }
+            (Step::Orientation, DeviceStateUpdate::EncoderTwist(..)) => {
+                self.orientation = self.orientation.toggled();
+                Action::Redraw
+            }
+            (Step::Orientation, DeviceStateUpdate::ButtonDown(_))
+            | (Step::Orientation, DeviceStateUpdate::EncoderDown(_)) => {
+                self.step = Step::Test(0);
+                self.asked_at = now;
+                Action::Redraw
+            }
+            (Step::Test(index), update) if self.checks[index].is_answered_by(&update) => {
+                self.next_check(index, now)
+            }
+            _ => Action::Swallow,
+        }
+    }
+
+    /// Touch on a strip zone, only answers the prompt for that zone
+    fn touch(&mut self, zone: u8, now: Instant) -> Action {
+        if !self.active {
+            return Action::Dispatch;
+        }
+
+        self.last_input = now;
+
+        match self.step {
+            Step::Test(index) if self.checks[index] == Check::Zone(zone) => {
+                self.next_check(index, now)
+            }
+            _ => Action::Swallow,
+        }
+    }
+
+    /// Skips checks nobody answered, [None] once the whole wizard timed out
+    fn tick(&mut self, now: Instant) -> Option<Action> {
+        if now.duration_since(self.last_input) >= SETUP_TIMEOUT {
+            self.active = false;
+            return None;
+        }
+
+        match self.step {
+            Step::Test(index) if now.duration_since(self.asked_at) >= CHECK_TIMEOUT => {
+                log::warn!("No answer to {}, skipping it", self.checks[index].prompt());
+                self.failed.push(self.checks[index]);
+                Some(self.next_check(index, now))
+            }
+            _ => Some(Action::Swallow),
+        }
+    }
+
+    fn screen(&self) -> Screen {
+        match self.step {
+            Step::Detect => Screen::Banner(vec![
+                format!("NEW DECK {}", self.serial),
+                "KEY: SET UP  DIAL: SKIP".to_string(),
+            ]),
+            Step::Orientation => Screen::Keys(
+                (0..KEY_COUNT as u8)
+                    .map(|key| {
+                        let mut lines = vec![(key + 1).to_string()];
+
+                        // Only the first key explains, the rest just count
+                        if key == 0 {
+                            lines.extend(["TOP LEFT?".to_string(), "TURN: FLIP".to_string()]);
+                        }
+
+                        (key, lines)
+                    })
+                    .collect(),
+            ),
+            Step::Test(index) => {
+                let check = self.checks[index];
+                let progress = format!("{}/{}", index + 1, self.checks.len());
+
+                match check {
+                    Check::Key(key) => Screen::Keys(vec![(key, vec![check.prompt(), progress])]),
+                    _ => Screen::Banner(vec![check.prompt(), progress]),
+                }
+            }
+        }
+    }
+}
+
+// Device id to its wizard, kept after it's done for releases that are still coming
+static WIZARDS: LazyLock<Mutex<HashMap<String, Wizard>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Serial numbers the wizard ran for since the plugin started, so reconnects don't start it over
+static STARTED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+pub fn is_active(device: &str) -> bool {
+    WIZARDS
+        .lock()
+        .unwrap()
+        .get(device)
+        .is_some_and(|wizard| wizard.active)
+}
+
+/// Checks if the wizard is drawn on the position, so other images for it are held back
+pub fn draws_position(device: &str, position: u8) -> bool {
+    (position as usize) < KEY_COUNT && is_active(device)
+}
+
+/// Which way up the device is, the one being tried while the wizard runs
+pub fn orientation(device: &str) -> Orientation {
+    if let Some(wizard) = WIZARDS.lock().unwrap().get(device)
+        && wizard.active
+    {
+        return wizard.orientation;
+    }
+
+    CONFIG.borrow().orientation(device).unwrap_or_default()
+}
+
+/// Images of every key for a screen, from the first key to the last
+fn render_screen(screen: &Screen) -> Vec<(u8, Option<KeyImage>)> {
+    let mut images: Vec<(u8, Option<KeyImage>)> = (0..KEY_COUNT as u8)
+        .map(|position| (position, None))
+        .collect();
+
+    match screen {
+        Screen::Banner(lines) => {
+            let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+            for (zone, image) in render_banner(COL_COUNT as u32, &lines, &[3, 2])
+                .into_iter()
+                .enumerate()
+            {
+                images[zone].1 = Some(KeyImage::Rendered(Arc::new(image)));
+            }
+        }
+        Screen::Keys(keys) => {
+            for (key, lines) in keys {
+                let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+                let weights: Vec<u32> = (0..lines.len())
+                    .map(|line| if line == 0 { 3 } else { 1 })
+                    .collect();
+                let image = render_lines(ZONE_SIZE, &lines, &weights, TextStyle::default());
+
+                images[*key as usize].1 = Some(KeyImage::Rendered(Arc::new(image)));
+            }
+        }
+    }
+
+    images
+}
+
+async fn draw(device: &str) {
+    let screen = match WIZARDS.lock().unwrap().get(device) {
+        Some(wizard) if wizard.active => wizard.screen(),
+        _ => return,
+    };
+
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        writer.send(WriterCommand::SetImages(render_screen(&screen)));
+    }
+}
+
+/// Asks for what the keys showed before the wizard took them over
+async fn closed(device: &str) {
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        let images = (0..KEY_COUNT as u8).map(|position| (position, None));
+
+        writer.send(WriterCommand::SetImages(images.collect()));
+        writer.send(WriterCommand::Redraw);
+    }
+}
+
+/// Writes the orientation the device was set up with to the config file
+async fn save(device: &str, serial: &str, orientation: Orientation) {
+    let result = config::edit_file(|settings| {
+        let orientations = settings.entry("orientation").or_insert_with(|| json!({}));
+
+        if !orientations.is_object() {
+            *orientations = json!({});
+        }
+
+        if let Value::Object(orientations) = orientations {
+            orientations.insert(serial.to_string(), json!(orientation.name()));
+        }
+    })
+    .await;
+
+    match result {
+        Ok(path) => log::info!(
+            "Saved {} orientation of {} to {}",
+            orientation.name(),
+            device,
+            path.display()
+        ),
+        Err(err) => log::error!("Failed to save setup of {}: {}", device, err),
+    }
+
+    config::reload(None).await;
+}
+
+/// Acts on what the wizard decided, `update` is passed on if it isn't for the wizard
+async fn apply(device: &str, action: Action) -> bool {
+    match action {
+        Action::Dispatch => return true,
+        Action::Swallow => {}
+        Action::Redraw => draw(device).await,
+        Action::Save | Action::Skip => {
+            let finished = WIZARDS.lock().unwrap().get(device).map(|wizard| {
+                (
+                    wizard.serial.clone(),
+                    wizard.orientation,
+                    wizard.failed.clone(),
+                )
+            });
+
+            let Some((serial, orientation, failed)) = finished else {
+                return false;
+            };
+
+            closed(device).await;
+            save(device, &serial, orientation).await;
+
+            let text = if action == Action::Skip {
+                "SETUP SKIPPED".to_string()
+            } else if failed.is_empty() {
+                "SETUP DONE".to_string()
+            } else {
+                log::warn!("Controls of {} that didn't answer: {:?}", device, failed);
+                format!("SETUP DONE, {} FAILED", failed.len())
+            };
+
+            toast::show(device, Toast::text(text));
+        }
+    }
+
+    false
+}
+
+/// Starts the wizard on a device that was never set up, once per plugin run
+pub async fn start(candidate: &CandidateDevice) {
+    {
+        let config = CONFIG.borrow();
+
+        if !config.setup_wizard || config.orientation(&candidate.id).is_some() {
+            return;
+        }
+    }
+
+    if !STARTED.lock().unwrap().insert(candidate.serial.clone()) {
+        return;
+    }
+
+    log::info!(
+        "{} wasn't set up before, starting the setup wizard",
+        candidate.id
+    );
+
+    WIZARDS.lock().unwrap().insert(
+        candidate.id.clone(),
+        Wizard::new(&candidate.serial, Instant::now()),
+    );
+
+    draw(&candidate.id).await;
+}
+
+/// Checks if an update should reach OpenDeck, everything goes to the wizard while it runs
+pub async fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let action = match WIZARDS.lock().unwrap().get_mut(device) {
+        Some(wizard) => wizard.input(update, Instant::now()),
+        None => Action::Dispatch,
+    };
+
+    apply(device, action).await
+}
+
+/// Passes touches on the strip to the wizard, they answer prompts for zones
+pub async fn touch(device: &str, report: Option<InputReport>) {
+    let Some((zone, _)) = report.and_then(|report| sliders::touch_value(report.code)) else {
+        return;
+    };
+
+    let action = match WIZARDS.lock().unwrap().get_mut(device) {
+        Some(wizard) => wizard.touch(zone, Instant::now()),
+        None => return,
+    };
+
+    apply(device, action).await;
+}
+
+/// Skips prompts nobody answered and closes wizards nobody touched for a while
+pub async fn setup_task(token: CancellationToken) {
+    loop {
+        let now = Instant::now();
+        let mut actions = vec![];
+        let mut expired = vec![];
+
+        for (device, wizard) in WIZARDS.lock().unwrap().iter_mut() {
+            if !wizard.active {
+                continue;
+            }
+
+            match wizard.tick(now) {
+                Some(action) => actions.push((device.clone(), action)),
+                None => expired.push(device.clone()),
+            }
+        }
+
+        for (device, action) in actions {
+            apply(&device, action).await;
+        }
+
+        for device in expired {
+            log::info!("Gave up on the setup wizard of {}", device);
+            closed(&device).await;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(SETUP_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn wizard_goes_through_orientation_and_every_control() {
+        let now = Instant::now();
+        let mut wizard = Wizard::new("ABC", now);
+
+        assert_eq!(wizard.input(&EncoderTwist(0, 1), now), Action::Swallow);
+        assert_eq!(wizard.input(&ButtonDown(4), now), Action::Redraw);
+        assert_eq!(wizard.input(&ButtonUp(4), now), Action::Swallow);
+        assert_eq!(wizard.step, Step::Orientation);
+
+        assert_eq!(wizard.input(&EncoderTwist(2, -1), now), Action::Redraw);
+        assert_eq!(wizard.orientation, Orientation::Flipped);
+        assert_eq!(wizard.input(&ButtonDown(0), now), Action::Redraw);
+        assert_eq!(
+            wizard.screen(),
+            Screen::Keys(vec![(
+                0,
+                vec!["PRESS KEY 1".to_string(), "1/22".to_string()]
+            )])
+        );
+
+        // Only the control asked for moves on
+        assert_eq!(wizard.input(&ButtonDown(3), now), Action::Swallow);
+
+        let mut action = Action::Swallow;
+        for check in Check::all() {
+            action = match check {
+                Check::Key(key) => wizard.input(&ButtonDown(key), now),
+                Check::DialPress(dial) => wizard.input(&EncoderDown(dial), now),
+                Check::DialTurn(dial) => wizard.input(&EncoderTwist(dial, 1), now),
+                Check::Zone(zone) => wizard.touch(zone, now),
+            };
+        }
+
+        assert_eq!(action, Action::Save);
+        assert!(!wizard.active);
+        assert!(wizard.failed.is_empty());
+
+        // Releases of controls pressed during the setup don't reach OpenDeck
+        assert_eq!(wizard.input(&ButtonUp(9), now), Action::Swallow);
+        assert_eq!(wizard.input(&ButtonDown(9), now), Action::Dispatch);
+    }
+
+    #[test]
+    fn unanswered_prompts_are_skipped_and_idle_wizards_give_up() {
+        let now = Instant::now();
+        let mut wizard = Wizard::new("ABC", now);
+
+        wizard.input(&ButtonDown(0), now);
+        wizard.input(&EncoderDown(0), now);
+        assert_eq!(wizard.step, Step::Test(0));
+
+        let later = now + CHECK_TIMEOUT;
+        assert_eq!(wizard.tick(later), Some(Action::Redraw));
+        assert_eq!(wizard.failed, [Check::Key(0)]);
+        assert_eq!(wizard.step, Step::Test(1));
+
+        assert_eq!(wizard.tick(now + SETUP_TIMEOUT), None);
+        assert!(!wizard.active);
+
+        // Dial press on the first screen skips the setup
+        let mut wizard = Wizard::new("ABC", now);
+        assert_eq!(wizard.input(&EncoderDown(1), now), Action::Skip);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use futures_lite::StreamExt;
+use futures_util::SinkExt;
+use image::DynamicImage;
+use mirajazz::{
+    error::MirajazzError,
+    types::{ImageMirroring, ImageRotation},
+};
+use serde_json::{Value, json};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, mpsc},
+};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use crate::{
+    images::{ImageFormat, ImageMode, encode_base64, encode_image, spawn_encode},
+    mappings::{AJAZZ_VID, AKP05E_PID, ENCODER_COUNT, KEY_COUNT},
+    transport::{DeviceTransport, build_report},
+};
+
+/// Port the panel connects to unless told otherwise
+pub const DEFAULT_SIM_PORT: u16 = 9871;
+
+/// ID of the simulated device
+pub const SIMULATED_ID: &str = "a5-simulated";
+
+/// Most reports a single turn from the panel is split into
+pub const MAX_PANEL_TICKS: u64 = 10;
+
+/// Press codes of encoders 1-4
+const DIAL_PRESS_CODES: [u8; ENCODER_COUNT] = [0x37, 0x35, 0x33, 0x36];
+
+/// Left and right turn codes of encoders 1-4
+const DIAL_TURN_CODES: [(u8, u8); ENCODER_COUNT] =
+    [(0xA0, 0xA1), (0x50, 0x51), (0x90, 0x91), (0x70, 0x71)];
+
+/// Touch code of the leftmost step of the strip, the steps after it count up
+const FIRST_TOUCH_CODE: u8 = 0x40;
+
+/// Touch steps across the whole strip
+const TOUCH_STEPS: usize = 16;
+
+/// Turns a panel message into the raw reports a real AKP05E would send for it
+///
+/// Keys and dials are numbered the way the device does it, so reports go through the same
+/// decoding and mapping as real ones. Also used for inputs injected into the plugin.
+pub fn panel_reports(text: &str) -> Result<Vec<Vec<u8>>, String> {
+    let value: Value = serde_json::from_str(text).map_err(|err| format!("Bad JSON: {}", err))?;
+
+    let number = |field: &str, count: usize| {
+        value
+            .get(field)
+            .and_then(Value::as_u64)
+            .filter(|number| *number < count as u64)
+            .map(|number| number as usize)
+            .ok_or_else(|| format!("\"{}\" must be a number below {}", field, count))
+    };
+    let pressed = || {
+        value
+            .get("pressed")
+            .and_then(Value::as_bool)
+            .unwrap_or(true) as u8
+    };
+
+    match value.get("event").and_then(Value::as_str) {
+        Some("key") => Ok(vec![build_report(
+            number("key", KEY_COUNT)? as u8 + 1,
+            pressed(),
+        )]),
+        Some("dialPress") => Ok(vec![build_report(
+            DIAL_PRESS_CODES[number("dial", ENCODER_COUNT)?],
+            pressed(),
+        )]),
+        Some("dialRotate") => {
+            let (left, right) = DIAL_TURN_CODES[number("dial", ENCODER_COUNT)?];
+            let ticks = value
+                .get("ticks")
+                .and_then(Value::as_i64)
+                .ok_or("\"ticks\" is missing")?;
+            let code = if ticks < 0 { left } else { right };
+
+            Ok((0..ticks.unsigned_abs().min(MAX_PANEL_TICKS))
+                .map(|_| build_report(code, 1))
+                .collect())
+        }
+        Some("touch") => Ok(vec![build_report(
+            FIRST_TOUCH_CODE + number("step", TOUCH_STEPS)? as u8,
+            1,
+        )]),
+        // Any code, for trying out mappings of codes the device doesn't send yet
+        Some("raw") => Ok(vec![build_report(
+            number("code", 256)? as u8,
+            number("state", 256)? as u8,
+        )]),
+        Some(event) => Err(format!("Unknown event \"{}\"", event)),
+        None => Err("\"event\" is missing".to_string()),
+    }
+}
+
+/// Fake AKP05E shown in a browser panel, see `tools/simulator.html`
+///
+/// Images and other writes are sent to the panel, clicks and scrolls on the panel come back as
+/// input reports. The device is gone once the panel disconnects.
+pub struct SimTransport {
+    outgoing: mpsc::UnboundedSender<String>,
+    reports: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    uploaded: AtomicU64,
+}
+
+impl SimTransport {
+    /// Accepts a panel connecting on `stream`, the device can be used right away
+    pub async fn accept(stream: TcpStream) -> Result<Self, tungstenite::Error> {
+        let mut socket = tokio_tungstenite::accept_async(stream).await?;
+        let (outgoing, mut messages) = mpsc::unbounded_channel::<String>();
+        let (reports, received) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    message = messages.recv() => {
+                        let Some(message) = message else { break };
+
+                        if socket.send(Message::text(message)).await.is_err() {
+                            break;
+                        }
+                    }
+                    message = socket.next() => match message {
+                        Some(Ok(Message::Text(text))) => match panel_reports(&text) {
+                            Ok(panel) => panel.into_iter().for_each(|report| {
+                                let _ = reports.send(report);
+                            }),
+                            Err(err) => log::warn!("Bad message from simulator panel: {}", err),
+                        },
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    },
+                }
+            }
+
+            log::info!("Simulator panel disconnected");
+        });
+
+        Ok(Self {
+            outgoing,
+            reports: Mutex::new(received),
+            uploaded: AtomicU64::new(0),
+        })
+    }
+
+    fn send(&self, event: Value) -> Result<(), MirajazzError> {
+        self.outgoing
+            .send(event.to_string())
+            .map_err(|_| MirajazzError::DeviceNotFoundError)
+    }
+}
+
+impl DeviceTransport for SimTransport {
+    fn vid(&self) -> u16 {
+        AJAZZ_VID
+    }
+
+    fn pid(&self) -> u16 {
+        AKP05E_PID
+    }
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+        self.send(json!({ "event": "setBrightness", "brightness": percent }))
+    }
+
+    async fn set_button_image(
+        &self,
+        key: u8,
+        format: ImageFormat,
+        quality: u8,
+        image: DynamicImage,
+    ) -> Result<(), MirajazzError> {
+        // Panel shows keys the right way up, only the size and encoding are the device ones.
+        // Browsers can't show raw pixels, those go as BMP, which isn't compressed either
+        let mode = match format.mode {
+            ImageMode::Jpeg => ImageMode::Jpeg,
+            ImageMode::Bmp | ImageMode::Rgb565 | ImageMode::Rgb888 => ImageMode::Bmp,
+        };
+        let format = ImageFormat {
+            mode,
+            rotation: ImageRotation::Rot0,
+            mirror: ImageMirroring::None,
+            ..format
+        };
+        let mime = match format.mode {
+            ImageMode::Bmp => "bmp",
+            _ => "jpeg",
+        };
+        let data = spawn_encode(move || encode_image(format, quality, image)).await?;
+        self.uploaded
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        let image = format!("data:image/{};base64,{}", mime, encode_base64(&data));
+
+        self.send(json!({ "event": "setImage", "key": key, "image": image }))
+    }
+
+    async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+        self.send(json!({ "event": "clearImage", "key": key }))
+    }
+
+    async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+        self.send(json!({ "event": "clearAll" }))
+    }
+
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        self.send(json!({ "event": "flush" }))
+    }
+
+    async fn reset(&self) -> Result<(), MirajazzError> {
+        self.send(json!({ "event": "reset" }))
+    }
+
+    async fn shutdown(&self) -> Result<(), MirajazzError> {
+        self.send(json!({ "event": "shutdown" }))
+    }
+
+    // The panel only knows the basics
+    async fn send_command(&self, _command: &[u8]) -> Result<(), MirajazzError> {
+        Err(MirajazzError::UnsupportedOperation)
+    }
+
+    async fn read_report(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, MirajazzError> {
+        let mut reports = self.reports.lock().await;
+
+        let report = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, reports.recv()).await {
+                Ok(report) => report,
+                Err(_) => return Ok(None),
+            },
+            None => reports.recv().await,
+        };
+
+        report.map(Some).ok_or(MirajazzError::DeviceNotFoundError)
+    }
+
+    fn uploaded_bytes(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        inputs::{Input, ReportDecoder},
+        mappings::Kind,
+    };
+
+    fn decode(text: &str) -> Vec<Input> {
+        panel_reports(text)
+            .unwrap()
+            .iter()
+            .map(|report| {
+                ReportDecoder::for_kind(&Kind::Akp05E)
+                    .decode(report)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn panel_inputs_decode_like_real_ones() {
+        let mut keys = [false; KEY_COUNT];
+        keys[2] = true;
+        assert_eq!(
+            decode(r#"{ "event": "key", "key": 2, "pressed": true }"#),
+            vec![Input::ButtonStateChange(keys)]
+        );
+
+        let mut dials = [false; ENCODER_COUNT];
+        dials[3] = true;
+        assert_eq!(
+            decode(r#"{ "event": "dialPress", "dial": 3 }"#),
+            vec![Input::EncoderStateChange(dials)]
+        );
+
+        for dial in 0..ENCODER_COUNT {
+            let mut ticks = [0; ENCODER_COUNT];
+            ticks[dial] = -1;
+
+            let text = format!(
+                r#"{{ "event": "dialRotate", "dial": {}, "ticks": -2 }}"#,
+                dial
+            );
+            assert_eq!(decode(&text), vec![Input::EncoderTwist(ticks); 2]);
+        }
+
+        assert_eq!(
+            decode(r#"{ "event": "touch", "step": 15 }"#),
+            vec![Input::Touch {
+                code: 0x4F,
+                contact: None
+            }]
+        );
+    }
+
+    #[test]
+    fn bad_panel_inputs_are_rejected() {
+        assert_eq!(
+            panel_reports(r#"{ "event": "key", "key": 10 }"#),
+            Err("\"key\" must be a number below 10".to_string())
+        );
+        assert_eq!(
+            panel_reports(r#"{ "event": "dialRotate", "dial": 0 }"#),
+            Err("\"ticks\" is missing".to_string())
+        );
+        assert_eq!(
+            panel_reports(r#"{ "event": "swipe" }"#),
+            Err("Unknown event \"swipe\"".to_string())
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::broadcast,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, hooks};
+
+/// Where inputs are mirrored to as JSON lines, next to OpenDeck
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EventSink {
+    /// Standard output of the plugin, mixed in with its log
+    Stdout,
+    /// File appended to, created if it's missing
+    File(PathBuf),
+    /// Unix socket the plugin listens on, every connected client gets every line
+    Socket(PathBuf),
+}
+
+impl EventSink {
+    /// Parses `stdout`, `file:<path>` or `socket:<path>`
+    pub fn parse(value: &str) -> Option<Self> {
+        let path = |path: &str| Some(PathBuf::from(path)).filter(|_| !path.is_empty());
+
+        match value.split_once(':') {
+            None if value == "stdout" => Some(Self::Stdout),
+            Some(("file", file)) => path(file).map(Self::File),
+            Some(("socket", socket)) => path(socket).map(Self::Socket),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for EventSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => write!(f, "stdout"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::Socket(path) => write!(f, "socket:{}", path.display()),
+        }
+    }
+}
+
+/// Writes every event to `output` until it fails or the sink is stopped
+async fn write_events(
+    sink: &EventSink,
+    mut output: impl AsyncWrite + Unpin,
+    token: &CancellationToken,
+) -> std::io::Result<()> {
+    let mut events = hooks::subscribe();
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = token.cancelled() => return Ok(()),
+        };
+
+        match event {
+            Ok((id, event)) => {
+                output
+                    .write_all((event.line(&id) + "\n").as_bytes())
+                    .await?;
+                output.flush().await?;
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("Event sink {} is too slow, dropped {} events", sink, missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Applies commands a socket client sends, the same ones a hook script prints
+#[cfg(unix)]
+async fn read_commands(reader: impl tokio::io::AsyncRead + Unpin) -> std::io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Err(err) = hooks::run_command(&line).await {
+            log::warn!("Ignoring event sink command {}: {}", line, err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn serve_socket(
+    sink: &EventSink,
+    path: &Path,
+    token: &CancellationToken,
+) -> std::io::Result<()> {
+    // Left behind by a previous run, nothing can be listening on it anymore
+    let _ = std::fs::remove_file(path);
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+
+    let result = loop {
+        let stream = tokio::select! {
+            stream = listener.accept() => stream,
+            _ = token.cancelled() => break Ok(()),
+        };
+
+        let (stream, _) = match stream {
+            Ok(stream) => stream,
+            Err(err) => break Err(err),
+        };
+
+        log::info!("Client connected to event sink {}", sink);
+
+        let sink = sink.clone();
+        let token = token.clone();
+
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+
+            // Clients that are done sending commands still get events
+            let commands = async {
+                read_commands(reader).await?;
+                std::future::pending().await
+            };
+
+            // Clients going away is how they stop listening
+            let result = tokio::select! {
+                result = write_events(&sink, writer, &token) => result,
+                result = commands => result,
+            };
+
+            if let Err(err) = result {
+                log::info!("Client of event sink {} went away: {}", sink, err);
+            }
+        });
+    };
+
+    let _ = std::fs::remove_file(path);
+
+    result
+}
+
+#[cfg(not(unix))]
+async fn serve_socket(
+    _sink: &EventSink,
+    _path: &Path,
+    _token: &CancellationToken,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets aren't supported on this platform",
+    ))
+}
+
+/// Mirrors events to the sink until it's stopped
+async fn run_sink(sink: EventSink, token: CancellationToken) {
+    log::info!("Mirroring events to {}", sink);
+
+    let result = match &sink {
+        EventSink::Stdout => write_events(&sink, tokio::io::stdout(), &token).await,
+        EventSink::File(path) => {
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await;
+
+            match file {
+                Ok(file) => write_events(&sink, file, &token).await,
+                Err(err) => Err(err),
+            }
+        }
+        EventSink::Socket(path) => serve_socket(&sink, path, &token).await,
+    };
+
+    if let Err(err) = result {
+        log::error!("Event sink {} failed: {}", sink, err);
+    }
+}
+
+/// Keeps the configured event sinks running, restarting them when they change
+pub async fn sinks_task(token: CancellationToken) {
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        let sinks = config.borrow_and_update().event_sinks.clone();
+        let running = token.child_token();
+
+        for sink in &sinks {
+            tokio::spawn(run_sink(sink.clone(), running.clone()));
+        }
+
+        // Only stopped once they change, a failed sink stays off until then
+        let stop = loop {
+            tokio::select! {
+                _ = config.changed() => {
+                    if config.borrow_and_update().event_sinks != sinks {
+                        break false;
+                    }
+                }
+                _ = token.cancelled() => break true,
+            }
+        };
+
+        running.cancel();
+
+        if stop {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sinks_are_parsed() {
+        assert_eq!(EventSink::parse("stdout"), Some(EventSink::Stdout));
+        assert_eq!(
+            EventSink::parse("file:/tmp/events.jsonl"),
+            Some(EventSink::File("/tmp/events.jsonl".into()))
+        );
+        assert_eq!(
+            EventSink::parse("socket:/tmp/akp05.sock"),
+            Some(EventSink::Socket("/tmp/akp05.sock".into()))
+        );
+
+        // Windows paths keep their drive letter
+        assert_eq!(
+            EventSink::parse("file:C:\\events.jsonl"),
+            Some(EventSink::File("C:\\events.jsonl".into()))
+        );
+
+        assert_eq!(EventSink::parse("file:"), None);
+        assert_eq!(EventSink::parse("stderr"), None);
+        assert_eq!(
+            EventSink::parse(&EventSink::Socket("/tmp/a.sock".into()).to_string()),
+            Some(EventSink::Socket("/tmp/a.sock".into()))
+        );
+    }
+}
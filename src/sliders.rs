@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::{
+    inputs::InputReport,
+    mappings::ENCODER_COUNT,
+    text::{TextStyle, render_lines},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, WRITERS, hooks, mqtt, writer::KeyPainter};
+
+/// Input code of a touch at the left end of the strip, codes go up to the right end
+pub const FIRST_TOUCH_CODE: u8 = 0x40;
+
+/// Touch positions the strip reports, from left to right
+pub const TOUCH_STEPS: u8 = 16;
+
+/// Size sliders are rendered at, images are resized to the device format anyway
+pub const SLIDER_SIZE: (u32, u32) = (120, 120);
+
+/// How often sliders are drawn on devices that connected since, changed values are drawn at once
+pub const SLIDER_INTERVAL: Duration = Duration::from_secs(1);
+
+// Device id and strip zone to the value last set by touching it
+static VALUES: LazyLock<Mutex<HashMap<(String, u8), u8>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Strip zone and slider value (0-100) of a touch, [None] if the code isn't a touch
+///
+/// The strip only reports a few positions per zone, so values move in steps of a third.
+pub fn touch_value(code: u8) -> Option<(u8, u8)> {
+    let step = code
+        .checked_sub(FIRST_TOUCH_CODE)
+        .filter(|step| *step < TOUCH_STEPS)?;
+    let steps_per_zone = TOUCH_STEPS / ENCODER_COUNT as u8;
+
+    let zone = step / steps_per_zone;
+    let value = (step % steps_per_zone) as u32 * 100 / (steps_per_zone as u32 - 1);
+
+    Some((zone, value as u8))
+}
+
+fn slider_lines(value: u8) -> Vec<String> {
+    vec!["SLIDER".to_string(), format!("{}%", value)]
+}
+
+/// Sets the value of a slider zone touched by this report
+///
+/// Changed values go to the hook script and the MQTT broker. Touches with a contact size always go
+/// to the hook script, so pressing harder on the same spot is seen too.
+pub fn touch(device: &str, report: Option<InputReport>) {
+    let Some(report) = report else {
+        return;
+    };
+    let Some((zone, value)) = touch_value(report.code) else {
+        return;
+    };
+
+    if !CONFIG.borrow().sliders.contains(&zone) {
+        return;
+    }
+
+    let previous = VALUES
+        .lock()
+        .unwrap()
+        .insert((device.to_string(), zone), value);
+
+    if previous == Some(value) {
+        if report.contact.is_some() {
+            hooks::publish_slider(device, zone, value, report.contact);
+        }
+
+        return;
+    }
+
+    log::debug!("Slider {} of {} is at {}%", zone, device, value);
+
+    hooks::publish_slider(device, zone, value, report.contact);
+    mqtt::publish_slider(device, zone, value);
+    CHANGED.notify_one();
+}
+
+/// Draws a fill bar with the value on every slider zone of every connected device
+pub async fn sliders_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut config = CONFIG.subscribe();
+
+    loop {
+        let (zones, bar) = {
+            let config = config.borrow_and_update();
+            (config.sliders.clone(), config.progress_bar)
+        };
+        let devices: Vec<String> = WRITERS.read().await.keys().cloned().collect();
+
+        painter.retain(|_, position| zones.contains(&position));
+
+        for device in devices {
+            for zone in &zones {
+                let value = VALUES
+                    .lock()
+                    .unwrap()
+                    .get(&(device.clone(), *zone))
+                    .copied()
+                    .unwrap_or(0);
+
+                let lines = slider_lines(value);
+                let image_lines = lines.clone();
+
+                painter
+                    .paint_device(&device, *zone, lines, || {
+                        let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+                        let mut image =
+                            render_lines(SLIDER_SIZE, &lines, &[], TextStyle::default());
+
+                        bar.draw_bottom(&mut image, value as u32);
+
+                        image
+                    })
+                    .await;
+            }
+        }
+
+        tokio::select! {
+            _ = CHANGED.notified() => {}
+            _ = config.changed() => {}
+            _ = tokio::time::sleep(SLIDER_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touches_map_to_zones_and_values() {
+        assert_eq!(touch_value(0x40), Some((0, 0)));
+        assert_eq!(touch_value(0x41), Some((0, 33)));
+        assert_eq!(touch_value(0x43), Some((0, 100)));
+        assert_eq!(touch_value(0x46), Some((1, 66)));
+        assert_eq!(touch_value(0x4F), Some((3, 100)));
+        assert_eq!(touch_value(0x3F), None);
+        assert_eq!(touch_value(0x50), None);
+    }
+}
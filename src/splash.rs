@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::{
+    images::KeyImage,
+    mappings::{COL_COUNT, KEY_COUNT},
+    text::{TextStyle, render_lines},
+};
+use image::DynamicImage;
+use tokio::time::Instant;
+
+use crate::{CONFIG, WRITERS, writer::WriterCommand};
+
+/// Longest the splash stays up when OpenDeck doesn't send any image, e.g. for an empty profile
+pub const SPLASH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of a key or strip zone, the splash is cut into these and resized to the device format
+/// anyway
+pub const SPLASH_ZONE: (u32, u32) = (120, 120);
+
+/// Every position the splash covers, the strip zones come first
+const POSITIONS: u8 = (KEY_COUNT + COL_COUNT) as u8;
+
+/// How far connecting got, shown on the strip under the logo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Device was opened, OpenDeck doesn't know about it yet
+    Connecting,
+    /// Device is registered, waiting for OpenDeck to send the profile
+    LoadingProfile,
+}
+
+impl Stage {
+    fn text(self) -> &'static str {
+        match self {
+            Self::Connecting => "CONNECTING",
+            Self::LoadingProfile => "LOADING PROFILE",
+        }
+    }
+
+    fn percent(self) -> u32 {
+        match self {
+            Self::Connecting => 30,
+            Self::LoadingProfile => 70,
+        }
+    }
+}
+
+// Device id to when its splash went up
+static SHOWN: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Draws the logo, two rows of keys high
+fn render_logo() -> DynamicImage {
+    let (width, height) = SPLASH_ZONE;
+
+    render_lines(
+        (width * COL_COUNT as u32, height * 2),
+        &["OPENDECK", "AKP05"],
+        &[3, 2],
+        TextStyle::default(),
+    )
+}
+
+/// Draws the stage of connecting with a bar, across the strip
+fn render_stage(stage: Stage) -> DynamicImage {
+    let (width, height) = SPLASH_ZONE;
+    let mut image = render_lines(
+        (width * COL_COUNT as u32, height),
+        &[stage.text()],
+        &[],
+        TextStyle::default(),
+    );
+
+    CONFIG
+        .borrow()
+        .progress_bar
+        .draw_bottom(&mut image, stage.percent());
+
+    image
+}
+
+/// Images of the splash, the logo on the keys is only there if `logo` is set
+///
+/// Strip zones are positions 0 to 4, the middle row of keys 5 to 9 and the top row 10 to 14.
+pub fn splash_images(stage: Stage, logo: bool) -> Vec<(u8, Option<KeyImage>)> {
+    let (width, height) = SPLASH_ZONE;
+    let zone = |image: &DynamicImage, column: u32, row: u32| {
+        let image = image.crop_imm(column * width, row * height, width, height);
+
+        Some(KeyImage::Rendered(Arc::new(image)))
+    };
+
+    let strip = render_stage(stage);
+    let mut images: Vec<(u8, Option<KeyImage>)> = (0..COL_COUNT as u32)
+        .map(|column| (column as u8, zone(&strip, column, 0)))
+        .collect();
+
+    if logo {
+        let logo = render_logo();
+
+        for column in 0..COL_COUNT as u32 {
+            images.push(((COL_COUNT * 2) as u8 + column as u8, zone(&logo, column, 0)));
+            images.push((COL_COUNT as u8 + column as u8, zone(&logo, column, 1)));
+        }
+    }
+
+    images
+}
+
+/// Remembers a splash went up on the device, it comes down with the first image from OpenDeck
+/// or after [SPLASH_TIMEOUT]
+pub fn shown(device: &str) {
+    let started = Instant::now();
+    SHOWN.lock().unwrap().insert(device.to_string(), started);
+
+    let device = device.to_string();
+
+    tokio::spawn(async move {
+        tokio::time::sleep_until(started + SPLASH_TIMEOUT).await;
+
+        // Only if it's still the same splash
+        let expired = {
+            let mut shown = SHOWN.lock().unwrap();
+            let expired = shown.get(&device) == Some(&started);
+
+            if expired {
+                shown.remove(&device);
+            }
+
+            expired
+        };
+
+        if expired && let Some(writer) = WRITERS.read().await.get(&device) {
+            log::info!(
+                "OpenDeck sent nothing for {}, taking the splash down",
+                device
+            );
+            writer.send(WriterCommand::SetImages(cleared()));
+        }
+    });
+}
+
+/// Checks if the splash is up on the device
+pub fn draws_position(device: &str, position: u8) -> bool {
+    position < POSITIONS && SHOWN.lock().unwrap().contains_key(device)
+}
+
+/// Takes the splash down if it's up
+///
+/// Images sent after this replace the splash in one go, see [WriterHandle::begin_frame].
+///
+/// [WriterHandle::begin_frame]: crate::writer::WriterHandle::begin_frame
+pub async fn finish(device: &str) {
+    if SHOWN.lock().unwrap().remove(device).is_none() {
+        return;
+    }
+
+    log::info!(
+        "First image for {} arrived, swapping the splash out",
+        device
+    );
+
+    if let Some(writer) = WRITERS.read().await.get(device) {
+        // Positions the profile leaves empty are cleared along with it
+        writer.begin_frame();
+        writer.send(WriterCommand::SetImages(cleared()));
+    }
+}
+
+/// Clears every position the splash covers
+fn cleared() -> Vec<(u8, Option<KeyImage>)> {
+    (0..POSITIONS).map(|position| (position, None)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splash_covers_keys_and_strip() {
+        let images = splash_images(Stage::Connecting, true);
+        let mut positions: Vec<u8> = images.iter().map(|(position, _)| *position).collect();
+        positions.sort();
+
+        assert_eq!(positions, (0..POSITIONS).collect::<Vec<u8>>());
+        assert!(images.iter().all(|(_, image)| image.is_some()));
+
+        // Later stages only change the strip
+        let images = splash_images(Stage::LoadingProfile, false);
+        assert_eq!(images.len(), COL_COUNT);
+        assert!(
+            images
+                .iter()
+                .all(|(position, _)| (*position as usize) < COL_COUNT)
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use akp05::text::{TextStyle, render_lines};
+use image::DynamicImage;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, writer::KeyPainter};
+
+/// Size widgets are rendered at, images are resized to the device format anyway
+pub const WIDGET_SIZE: (u32, u32) = (120, 120);
+
+/// System stat shown on a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Widget {
+    /// CPU usage of every core together
+    Cpu,
+    /// Used memory
+    Ram,
+    /// Receive and transmit rates of every network interface except loopback
+    Net,
+}
+
+/// Widgets for every position they are shown on
+pub type Widgets = BTreeMap<u8, Widget>;
+
+impl Widget {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "cpu" => Some(Self::Cpu),
+            "ram" => Some(Self::Ram),
+            "net" => Some(Self::Net),
+            _ => None,
+        }
+    }
+}
+
+/// Busy and total CPU time from the first line of `/proc/stat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CpuTimes {
+    idle: u64,
+    total: u64,
+}
+
+fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let values: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|value| value.parse().ok())
+        .collect::<Option<_>>()?;
+
+    if values.len() < 5 {
+        return None;
+    }
+
+    // idle + iowait, the rest is user, nice, system, irq, softirq and steal
+    Some(CpuTimes {
+        idle: values[3] + values[4],
+        total: values.iter().sum(),
+    })
+}
+
+fn cpu_percent(previous: CpuTimes, current: CpuTimes) -> Option<u64> {
+    let total = current.total.checked_sub(previous.total)?;
+    let idle = current.idle.checked_sub(previous.idle)?;
+
+    (total > 0).then(|| (total - idle.min(total)) * 100 / total)
+}
+
+/// Used and total memory in kB from `/proc/meminfo`
+fn parse_meminfo(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| {
+                rest.trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+    };
+
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+
+    Some((total.saturating_sub(available), total))
+}
+
+/// Received and transmitted bytes of every interface except loopback from `/proc/net/dev`
+fn parse_net_dev(dev: &str) -> Option<(u64, u64)> {
+    let mut totals = None;
+
+    // Two header lines, then `name: rx_bytes <7 more rx fields> tx_bytes ...`
+    for line in dev.lines().skip(2) {
+        let Some((name, fields)) = line.split_once(':') else {
+            continue;
+        };
+
+        if name.trim() == "lo" {
+            continue;
+        }
+
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let (Some(rx), Some(tx)) = (fields.first(), fields.get(8)) else {
+            continue;
+        };
+
+        let (total_rx, total_tx) = totals.get_or_insert((0u64, 0u64));
+        *total_rx += rx.parse::<u64>().ok()?;
+        *total_tx += tx.parse::<u64>().ok()?;
+    }
+
+    totals
+}
+
+/// Formats byte count in at most 4 characters, e.g. `512B`, `34K` or `1.2M`
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+
+    let mut value = bytes.max(0.0);
+    let mut unit = 0;
+
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if value < 10.0 && unit > 0 {
+        format!("{:.1}{}", value, UNITS[unit])
+    } else {
+        format!("{:.0}{}", value, UNITS[unit])
+    }
+}
+
+/// Previous readings, rates and percentages need two of them
+#[derive(Debug, Default)]
+pub struct Sampler {
+    cpu: Option<CpuTimes>,
+    net: Option<(u64, u64, Instant)>,
+}
+
+fn read(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+impl Sampler {
+    /// CPU usage since the previous call in percent, [None] on the first one
+    pub fn cpu(&mut self) -> Option<u64> {
+        let current = read("/proc/stat").and_then(|stat| parse_cpu_times(&stat));
+        let percent = self.cpu.zip(current).and_then(|(a, b)| cpu_percent(a, b));
+
+        self.cpu = current;
+
+        percent
+    }
+
+    /// Used and total memory in kB
+    pub fn ram(&self) -> Option<(u64, u64)> {
+        read("/proc/meminfo").and_then(|meminfo| parse_meminfo(&meminfo))
+    }
+
+    /// Reads current values and returns lines to draw for the widget, `--` if not known yet
+    fn lines(&mut self, widget: Widget) -> Vec<String> {
+        let unknown = || "--".to_string();
+
+        match widget {
+            Widget::Cpu => {
+                let percent = self
+                    .cpu()
+                    .map_or_else(unknown, |percent| format!("{}%", percent));
+
+                vec!["CPU".to_string(), percent]
+            }
+            Widget::Ram => {
+                let memory = self.ram();
+
+                match memory {
+                    Some((used, total)) if total > 0 => vec![
+                        "RAM".to_string(),
+                        format!("{}%", used * 100 / total),
+                        format!(
+                            "{}/{}",
+                            format_bytes(used as f64 * 1024.0),
+                            format_bytes(total as f64 * 1024.0)
+                        ),
+                    ],
+                    _ => vec!["RAM".to_string(), unknown(), String::new()],
+                }
+            }
+            Widget::Net => {
+                let now = Instant::now();
+                let current = read("/proc/net/dev").and_then(|dev| parse_net_dev(&dev));
+
+                let rates = match (self.net, current) {
+                    (Some((rx, tx, at)), Some((new_rx, new_tx))) => {
+                        let seconds = now.duration_since(at).as_secs_f64().max(0.001);
+
+                        Some((
+                            new_rx.saturating_sub(rx) as f64 / seconds,
+                            new_tx.saturating_sub(tx) as f64 / seconds,
+                        ))
+                    }
+                    _ => None,
+                };
+
+                self.net = current.map(|(rx, tx)| (rx, tx, now));
+
+                match rates {
+                    Some((rx, tx)) => vec![
+                        "NET".to_string(),
+                        format!("RX {}", format_bytes(rx)),
+                        format!("TX {}", format_bytes(tx)),
+                    ],
+                    None => vec!["NET".to_string(), unknown(), String::new()],
+                }
+            }
+        }
+    }
+}
+
+/// Renders lines of a widget, the value is the most prominent one
+pub fn render_widget(lines: &[String], size: (u32, u32)) -> DynamicImage {
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+    render_lines(size, &lines, &[1, 2, 1], TextStyle::default())
+}
+
+/// Draws configured widgets on every connected device, refreshing them every `widget_refresh_ms`
+///
+/// Stats are read from `/proc`, so values are only available on Linux.
+pub async fn stats_task(token: CancellationToken) {
+    let mut sampler = Sampler::default();
+    let mut painter = KeyPainter::default();
+    let mut warned = false;
+
+    loop {
+        let interval = CONFIG.borrow().widget_refresh();
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let widgets = CONFIG.borrow().widgets.clone();
+
+        painter.retain(|_, position| widgets.contains_key(&position));
+
+        if widgets.is_empty() {
+            continue;
+        }
+
+        if !cfg!(target_os = "linux") && !warned {
+            log::warn!("System stats widgets are only supported on Linux");
+            warned = true;
+        }
+
+        // Same widget can be shown more than once, it has to be sampled only once per refresh
+        let mut sampled = HashMap::new();
+
+        for (position, widget) in widgets {
+            let lines = sampled
+                .entry(widget)
+                .or_insert_with(|| sampler.lines(widget))
+                .clone();
+            let image_lines = lines.clone();
+
+            painter
+                .paint(position, lines, || render_widget(&image_lines, WIDGET_SIZE))
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_usage_is_calculated_from_two_readings() {
+        let first = parse_cpu_times("cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 1 2 3 4 5\n").unwrap();
+        let second = parse_cpu_times("cpu  200 0 200 1000 100 0 0 0 0 0\n").unwrap();
+
+        assert_eq!(
+            first,
+            CpuTimes {
+                idle: 800,
+                total: 1000
+            }
+        );
+        assert_eq!(cpu_percent(first, second), Some(40));
+        assert_eq!(cpu_percent(second, second), None);
+    }
+
+    #[test]
+    fn memory_and_network_are_parsed() {
+        let meminfo = "MemTotal:       16000000 kB\nMemFree:         1000000 kB\nMemAvailable:    4000000 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some((12000000, 16000000)));
+
+        let dev = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  5000      50    0    0    0     0          0         0     5000      50    0    0    0     0       0          0
+  eth0:  1000      10    0    0    0     0          0         0      200       2    0    0    0     0       0          0
+ wlan0:    24       1    0    0    0     0          0         0       30       1    0    0    0     0       0          0
+";
+        assert_eq!(parse_net_dev(dev), Some((1024, 230)));
+    }
+
+    #[test]
+    fn bytes_are_formatted_short() {
+        assert_eq!(format_bytes(512.0), "512B");
+        assert_eq!(format_bytes(34.0 * 1024.0), "34K");
+        assert_eq!(format_bytes(1.25 * 1024.0 * 1024.0), "1.2M");
+        assert_eq!(format_bytes(16e9), "15G");
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use openaction::OUTBOUND_EVENT_MANAGER;
+use serde_json::json;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::toast::{self, Toast};
+
+/// Stage of a device's life in the plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Device was found, nothing was opened yet
+    Discovered,
+    /// Device is being opened and set up
+    Connecting,
+    /// Device is registered with OpenDeck and working
+    Connected,
+    /// Something failed, but the device keeps working
+    Degraded,
+    /// Image uploads keep failing, the device gets fewer and simpler images
+    SafeMode,
+    /// Device is re-initialized, it's connected again once that's done
+    Reconnecting,
+    /// Device was unplugged, failed or was left to another plugin
+    Removed,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Discovered => "discovered",
+            Self::Connecting => "connecting",
+            Self::Connected => "connected",
+            Self::Degraded => "degraded",
+            Self::SafeMode => "safe_mode",
+            Self::Reconnecting => "reconnecting",
+            Self::Removed => "removed",
+        }
+    }
+}
+
+// Sending half, and the receiving half until the status task takes it
+type Events = (
+    UnboundedSender<String>,
+    Mutex<Option<UnboundedReceiver<String>>>,
+);
+
+// Events wait here until the status task sends them, so reporting never waits for OpenDeck
+static EVENTS: LazyLock<Events> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    (sender, Mutex::new(Some(receiver)))
+});
+
+// Last status of every device, the same one isn't reported twice in a row
+static LAST: LazyLock<Mutex<HashMap<String, Status>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Line describing a status change, `detail` says why if there's more to tell
+pub fn status_line(device: &str, status: Status, detail: Option<&str>) -> String {
+    let mut event = json!({ "event": "deviceStatus", "device": device, "status": status.name() });
+
+    if let Some(detail) = detail {
+        event["detail"] = json!(detail);
+    }
+
+    event.to_string()
+}
+
+/// Line describing an error that didn't change the status of a device
+pub fn error_line(device: &str, detail: &str) -> String {
+    json!({ "event": "deviceError", "device": device, "detail": detail }).to_string()
+}
+
+/// Reports an error to OpenDeck's log, every time it happens unlike status changes
+pub fn report_error(device: &str, detail: &str) {
+    let _ = EVENTS.0.send(error_line(device, detail));
+}
+
+/// Reports a status change of a device to OpenDeck's log and the plugin log
+///
+/// Safe to call from anywhere, including OpenDeck event handlers.
+pub fn report(device: &str, status: Status, detail: Option<&str>) {
+    let previous = LAST.lock().unwrap().insert(device.to_string(), status);
+
+    if previous == Some(status) {
+        return;
+    }
+
+    match status {
+        Status::Degraded => toast::show(device, Toast::text("DEVICE ERROR")),
+        Status::Connected if previous == Some(Status::Reconnecting) => {
+            toast::show(device, Toast::text("RECONNECTED"))
+        }
+        _ => {}
+    }
+
+    let line = status_line(device, status, detail);
+
+    log::info!("Device status: {}", line);
+
+    let _ = EVENTS.0.send(line);
+}
+
+/// Sends reported status changes over the plugin connection
+pub async fn status_task(token: CancellationToken) {
+    let Some(mut events) = EVENTS.1.lock().unwrap().take() else {
+        return;
+    };
+
+    loop {
+        let line = tokio::select! {
+            line = events.recv() => match line {
+                Some(line) => line,
+                None => break,
+            },
+            _ = token.cancelled() => break,
+        };
+
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut()
+            && let Err(err) = outbound.log_message(line).await
+        {
+            log::warn!("Failed to send device status: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn status_lines_are_json() {
+        let line = status_line("a5-1", Status::Degraded, Some("Failed to set image"));
+        let event: Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(
+            event,
+            json!({
+                "event": "deviceStatus",
+                "device": "a5-1",
+                "status": "degraded",
+                "detail": "Failed to set image"
+            })
+        );
+
+        let event: Value =
+            serde_json::from_str(&status_line("a5-1", Status::Connected, None)).unwrap();
+        assert_eq!(event.get("detail"), None);
+
+        let event: Value = serde_json::from_str(&error_line("a5-1", "Bad data")).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "deviceError", "device": "a5-1", "detail": "Bad data" })
+        );
+    }
+}
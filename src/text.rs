@@ -0,0 +1,940 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        Arc, LazyLock, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use image::{DynamicImage, Rgb, RgbImage, RgbaImage, imageops};
+
+use crate::bidi::visual_order;
+
+/// Width of a glyph in font pixels
+pub const GLYPH_WIDTH: u32 = 5;
+
+/// Height of a glyph in font pixels
+pub const GLYPH_HEIGHT: u32 = 7;
+
+/// Size of a strip zone, banners are cut into these and resized to the device format anyway
+pub const ZONE_SIZE: (u32, u32) = (120, 120);
+
+// Classic 5x7 font for printable ASCII (0x20-0x7E), one byte per column, bit 0 is the top row
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+/// Colors used to draw text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextStyle {
+    pub color: Rgb<u8>,
+    pub background: Rgb<u8>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            color: Rgb([255, 255, 255]),
+            background: Rgb([0, 0, 0]),
+        }
+    }
+}
+
+/// Returns glyph of a character, unsupported characters are drawn as `?`
+fn glyph(ch: char) -> &'static [u8; 5] {
+    let index = match ch {
+        ' '..='~' => ch as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+
+    &FONT[index]
+}
+
+/// Glyph of a bitmap font, one bool per pixel, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BitmapGlyph {
+    width: u32,
+    pixels: Vec<bool>,
+}
+
+/// Bitmap font read from a BDF file, e.g. GNU Unifont, for characters the built-in font lacks
+///
+/// Every glyph is as tall as the font, so glyphs of the same font line up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BitmapFont {
+    height: u32,
+    glyphs: HashMap<char, BitmapGlyph>,
+}
+
+impl BitmapFont {
+    /// Parses a BDF font, glyphs wider than 64 pixels or without an encoding are skipped
+    pub fn parse_bdf(bdf: &str) -> Result<Self, String> {
+        let numbers = |line: &str| -> Vec<i64> {
+            line.split_whitespace()
+                .skip(1)
+                .filter_map(|n| n.parse().ok())
+                .collect()
+        };
+
+        let mut lines = bdf.lines().map(str::trim);
+
+        let bounds = lines
+            .by_ref()
+            .find(|line| line.starts_with("FONTBOUNDINGBOX"))
+            .map(numbers)
+            .filter(|bounds| bounds.len() == 4 && bounds[1] > 0)
+            .ok_or("Missing FONTBOUNDINGBOX")?;
+
+        let height = bounds[1] as u32;
+        // Rows above the baseline
+        let ascent = bounds[1] + bounds[3];
+
+        let mut font = Self {
+            height,
+            glyphs: HashMap::new(),
+        };
+
+        let (mut encoding, mut advance, mut bbx) = (None, None, None);
+
+        while let Some(line) = lines.next() {
+            if line.starts_with("STARTCHAR") {
+                (encoding, advance, bbx) = (None, None, None);
+            } else if line.starts_with("ENCODING") {
+                encoding = numbers(line)
+                    .first()
+                    .and_then(|code| char::from_u32(u32::try_from(*code).ok()?));
+            } else if line.starts_with("DWIDTH") {
+                advance = numbers(line).first().copied();
+            } else if line.starts_with("BBX") {
+                bbx = Some(numbers(line)).filter(|bbx| bbx.len() == 4);
+            } else if line == "BITMAP" {
+                let Some(bbx) = bbx.clone() else {
+                    continue;
+                };
+
+                let (width, rows, x_offset, y_offset) = (bbx[0], bbx[1], bbx[2], bbx[3]);
+                let glyph_width = advance.unwrap_or(width + x_offset).max(width + x_offset);
+                let top = ascent - (rows + y_offset);
+
+                let mut glyph = BitmapGlyph {
+                    width: glyph_width.clamp(0, 64) as u32,
+                    pixels: vec![false; (glyph_width.clamp(0, 64) * height as i64) as usize],
+                };
+
+                for row in 0..rows {
+                    let Some(bits) = lines
+                        .next()
+                        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                    else {
+                        break;
+                    };
+
+                    // Rows are padded to whole bytes, the first pixel is the highest bit
+                    let row_bits = ((width + 7) / 8 * 8) as u32;
+
+                    for column in 0..width.min(64) {
+                        let (x, y) = (column + x_offset, top + row);
+
+                        if bits >> (row_bits - 1 - column as u32) & 1 == 1
+                            && (0..glyph_width).contains(&x)
+                            && (0..height as i64).contains(&y)
+                        {
+                            glyph.pixels[(y * glyph_width + x) as usize] = true;
+                        }
+                    }
+                }
+
+                if let Some(ch) = encoding
+                    && width <= 64
+                    && glyph_width <= 64
+                {
+                    font.glyphs.insert(ch, glyph);
+                }
+            }
+        }
+
+        Ok(font)
+    }
+
+    /// Reads a BDF font file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bdf = std::fs::read(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+        Self::parse_bdf(&String::from_utf8_lossy(&bdf))
+            .map_err(|err| format!("Invalid font {}: {}", path.display(), err))
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Number of characters the font has
+    pub fn len(&self) -> usize {
+        self.glyphs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.glyphs.is_empty()
+    }
+
+    pub fn has(&self, ch: char) -> bool {
+        self.glyphs.contains_key(&ch)
+    }
+}
+
+/// Color emoji images by the characters of their sequence, e.g. one for 🎤 and one for 👨‍💻
+///
+/// Images are scaled to the height of the line they're on. Variation selectors are left out of
+/// sequences, so text with and without them shows the same emoji.
+#[derive(Debug, Clone, Default)]
+pub struct EmojiAtlas {
+    images: HashMap<Vec<char>, RgbaImage>,
+    // Characters of the longest sequence
+    longest: usize,
+}
+
+fn is_variation_selector(ch: char) -> bool {
+    matches!(ch, '\u{FE0E}' | '\u{FE0F}')
+}
+
+impl EmojiAtlas {
+    /// Adds an emoji for a sequence of characters
+    pub fn insert(&mut self, sequence: &str, image: RgbaImage) {
+        let chars: Vec<char> = sequence
+            .chars()
+            .filter(|ch| !is_variation_selector(*ch))
+            .collect();
+
+        self.longest = self.longest.max(chars.len());
+        self.images.insert(chars, image);
+    }
+
+    /// Reads every JPEG and BMP image in the directory, named by the hex codepoints of their
+    /// sequence joined with `-` like Twemoji does it, e.g. `1f3a4.bmp` or `1f468-200d-1f4bb.bmp`
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?;
+
+        let mut atlas = Self::default();
+
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            let extension = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.to_ascii_lowercase());
+
+            if !matches!(extension.as_deref(), Some("jpg" | "jpeg" | "bmp")) {
+                continue;
+            }
+
+            let sequence: Option<String> = path
+                .file_stem()
+                .and_then(|name| name.to_str())
+                .and_then(|name| {
+                    name.split('-')
+                        .map(|code| char::from_u32(u32::from_str_radix(code, 16).ok()?))
+                        .collect()
+                });
+
+            let Some(sequence) = sequence else {
+                continue;
+            };
+
+            match image::open(&path) {
+                Ok(emoji) => atlas.insert(&sequence, emoji.into_rgba8()),
+                Err(err) => log::warn!("Skipping emoji {}: {}", path.display(), err),
+            }
+        }
+
+        Ok(atlas)
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Longest sequence at the start of `chars` that has an emoji, and how many characters it is
+    fn find(&self, chars: &[char]) -> Option<(&RgbaImage, usize)> {
+        (1..=self.longest.min(chars.len()))
+            .rev()
+            .find_map(|len| Some((self.images.get(&chars[..len])?, len)))
+    }
+}
+
+/// Fonts and emoji tried for characters the built-in font lacks
+#[derive(Debug, Default)]
+struct Fallback {
+    fonts: Vec<BitmapFont>,
+    emoji: EmojiAtlas,
+}
+
+// Fonts are shared by everything drawing text, like system fonts are
+static FALLBACK: LazyLock<RwLock<Arc<Fallback>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Fallback::default())));
+
+static FALLBACK_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn update_fallback(update: impl FnOnce(&mut Fallback)) {
+    let mut fallback = FALLBACK.write().unwrap();
+    let mut updated = Fallback {
+        fonts: fallback.fonts.clone(),
+        emoji: fallback.emoji.clone(),
+    };
+
+    update(&mut updated);
+    *fallback = Arc::new(updated);
+    FALLBACK_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Sets fonts tried in order for characters the built-in font lacks, e.g. CJK or Cyrillic
+pub fn set_fallback_fonts(fonts: Vec<BitmapFont>) {
+    update_fallback(|fallback| fallback.fonts = fonts);
+}
+
+/// Sets color emoji drawn instead of characters, they take precedence over every font
+pub fn set_emoji(emoji: EmojiAtlas) {
+    update_fallback(|fallback| fallback.emoji = emoji);
+}
+
+/// Changes every time fallback fonts or emoji are set, text drawn before may look different now
+pub fn fallback_generation() -> u64 {
+    FALLBACK_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Glyph from the built-in font, a fallback one or the emoji atlas
+#[derive(Debug, Clone, Copy)]
+enum Glyph<'a> {
+    Builtin(&'static [u8; 5]),
+    Bitmap(&'a BitmapGlyph, u32),
+    /// Square as tall as the line
+    Emoji(&'a RgbaImage),
+}
+
+impl Glyph<'_> {
+    fn width(&self, line_height: u32) -> u32 {
+        match self {
+            Self::Builtin(_) => GLYPH_WIDTH,
+            Self::Bitmap(glyph, _) => glyph.width,
+            Self::Emoji(_) => line_height,
+        }
+    }
+
+    /// Height in font pixels, emoji follow the line instead
+    fn height(&self) -> Option<u32> {
+        match self {
+            Self::Builtin(_) => Some(GLYPH_HEIGHT),
+            Self::Bitmap(_, height) => Some(*height),
+            Self::Emoji(_) => None,
+        }
+    }
+
+    fn lit(&self, x: u32, y: u32) -> bool {
+        match self {
+            Self::Builtin(columns) => columns[x as usize] & (1 << y) != 0,
+            Self::Bitmap(glyph, _) => glyph.pixels[(y * glyph.width + x) as usize],
+            Self::Emoji(_) => false,
+        }
+    }
+}
+
+/// Glyphs of a line in the order they're drawn, and the height of the line
+///
+/// Lines only the built-in font can draw use it. Otherwise every character comes from the first
+/// fallback font having it, so a line doesn't mix font sizes unless it has to. Emoji come first.
+fn layout<'a>(text: &str, fallback: &'a Fallback) -> (Vec<Glyph<'a>>, u32) {
+    let chars: Vec<char> = visual_order(text)
+        .into_iter()
+        .filter(|ch| !is_variation_selector(*ch))
+        .collect();
+
+    // Emoji and the characters between them
+    let mut runs: Vec<Result<&RgbaImage, char>> = vec![];
+    let mut index = 0;
+
+    while index < chars.len() {
+        match fallback.emoji.find(&chars[index..]) {
+            Some((emoji, len)) => {
+                runs.push(Ok(emoji));
+                index += len;
+            }
+            None => {
+                runs.push(Err(chars[index]));
+                index += 1;
+            }
+        }
+    }
+
+    let builtin_only = fallback.fonts.is_empty()
+        || runs.iter().all(|run| match run {
+            Ok(_) => true,
+            Err(ch) => (' '..='~').contains(ch),
+        });
+
+    let glyphs: Vec<Glyph> = runs
+        .into_iter()
+        .map(|run| match run {
+            Ok(emoji) => Glyph::Emoji(emoji),
+            Err(ch) if builtin_only => Glyph::Builtin(glyph(ch)),
+            Err(ch) => fallback
+                .fonts
+                .iter()
+                .find_map(|font| Some(Glyph::Bitmap(font.glyphs.get(&ch)?, font.height)))
+                .unwrap_or(Glyph::Builtin(glyph(ch))),
+        })
+        .collect();
+
+    let height = glyphs
+        .iter()
+        .filter_map(Glyph::height)
+        .max()
+        .unwrap_or(GLYPH_HEIGHT);
+
+    (glyphs, height)
+}
+
+fn fallback() -> Arc<Fallback> {
+    FALLBACK.read().unwrap().clone()
+}
+
+/// Size of a single line of text in pixels
+pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let fallback = fallback();
+    let (glyphs, height) = layout(text, &fallback);
+
+    // No gap after the last character
+    let width = glyphs
+        .iter()
+        .map(|glyph| glyph.width(height) + 1)
+        .sum::<u32>()
+        .saturating_sub(1);
+
+    (width * scale, height * scale)
+}
+
+fn put_clipped(image: &mut RgbImage, x: i64, y: i64, color: Rgb<u8>) {
+    if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+        image.put_pixel(x as u32, y as u32, color);
+    }
+}
+
+/// Draws a single line of text with its top left corner at `x`, `y`, clipping at image edges
+///
+/// Right-to-left text is reordered and shaped first, glyphs of different heights share the
+/// bottom of the line. Emoji keep their own colors and are smoothly scaled, not in blocks.
+pub fn draw_text(image: &mut RgbImage, x: i64, y: i64, text: &str, scale: u32, color: Rgb<u8>) {
+    let fallback = fallback();
+    let (glyphs, height) = layout(text, &fallback);
+    let scale = scale as i64;
+    let mut left = x;
+
+    for glyph in glyphs {
+        let width = glyph.width(height);
+
+        if let Glyph::Emoji(emoji) = glyph {
+            let size = (height as i64 * scale) as u32;
+            let emoji = imageops::resize(emoji, size, size, imageops::FilterType::Triangle);
+
+            for (ex, ey, pixel) in emoji.enumerate_pixels() {
+                // Mostly transparent pixels are left out, there's nothing to blend with
+                if pixel.0[3] >= 128 {
+                    let [r, g, b, _] = pixel.0;
+                    put_clipped(image, left + ex as i64, y + ey as i64, Rgb([r, g, b]));
+                }
+            }
+        }
+
+        let glyph_height = glyph.height().unwrap_or(0);
+        let top = y + (height - glyph_height) as i64 * scale;
+
+        for column in 0..width {
+            for row in 0..glyph_height {
+                if !glyph.lit(column, row) {
+                    continue;
+                }
+
+                let px = left + column as i64 * scale;
+                let py = top + row as i64 * scale;
+
+                for dx in 0..scale {
+                    for dy in 0..scale {
+                        put_clipped(image, px + dx, py + dy, color);
+                    }
+                }
+            }
+        }
+
+        left += (width + 1) as i64 * scale;
+    }
+}
+
+/// Largest scale that fits every line into the area, at least 1
+pub fn fit_scale(lines: &[&str], width: u32, height: u32) -> u32 {
+    let sizes: Vec<(u32, u32)> = lines.iter().map(|line| text_size(line, 1)).collect();
+
+    let widest = sizes.iter().map(|size| size.0).max().unwrap_or(0).max(1);
+    let tallest = sizes
+        .iter()
+        .map(|size| size.1 + 1)
+        .sum::<u32>()
+        .saturating_sub(1)
+        .max(1);
+
+    (width / widest).min(height / tallest).max(1)
+}
+
+/// Characters a scrolling line shows at once at least, so it isn't drawn as tall as it could be
+pub const MARQUEE_CHARS: u32 = 8;
+
+/// Where lines go on an image, and how big they are
+struct LinesLayout {
+    margin: u32,
+    scales: Vec<u32>,
+    sizes: Vec<(u32, u32)>,
+}
+
+/// Scales lines to fill their share of the height, lines too wide for the image are shrunk
+/// unless `scroll` is set, then they stay readable and reach past the image
+fn layout_lines(size: (u32, u32), lines: &[&str], weights: &[u32], scroll: bool) -> LinesLayout {
+    let (width, height) = size;
+
+    // Leave a small margin, displays cut off edges a bit
+    let margin = width.min(height) / 12;
+    let (inner_width, inner_height) = (width - margin * 2, height - margin * 2);
+
+    let total_weight: u32 = (0..lines.len())
+        .map(|i| weights.get(i).copied().unwrap_or(1))
+        .sum();
+
+    let scales: Vec<u32> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let share = inner_height * weights.get(i).copied().unwrap_or(1) / total_weight.max(1);
+            let fit = fit_scale(&[line], inner_width, share);
+
+            if !scroll {
+                return fit;
+            }
+
+            let tall = share / text_size(line, 1).1.max(1);
+            let readable = tall.min(inner_width / (MARQUEE_CHARS * (GLYPH_WIDTH + 1)));
+
+            fit.max(readable).max(1)
+        })
+        .collect();
+
+    let sizes = lines
+        .iter()
+        .zip(&scales)
+        .map(|(line, scale)| text_size(line, *scale))
+        .collect();
+
+    LinesLayout {
+        margin,
+        scales,
+        sizes,
+    }
+}
+
+fn render(
+    size: (u32, u32),
+    lines: &[&str],
+    layout: LinesLayout,
+    offsets: &[u32],
+    style: TextStyle,
+) -> DynamicImage {
+    let (width, height) = size;
+    let mut image = RgbImage::from_pixel(width, height, style.background);
+    let LinesLayout {
+        margin,
+        scales,
+        sizes,
+    } = layout;
+
+    // Same gap below every line but the last, one font pixel
+    let content_height: u32 = sizes
+        .iter()
+        .zip(&scales)
+        .map(|(size, scale)| size.1 + scale)
+        .sum::<u32>()
+        - scales.last().copied().unwrap_or(0);
+    let mut y = (height as i64 - content_height as i64) / 2;
+
+    for (index, ((line, scale), (line_width, line_height))) in
+        lines.iter().zip(scales).zip(sizes).enumerate()
+    {
+        let overflow = line_width.saturating_sub(width - margin * 2);
+
+        let x = if overflow > 0 {
+            let offset = offsets.get(index).copied().unwrap_or(0).min(overflow);
+            margin as i64 - offset as i64
+        } else {
+            (width as i64 - line_width as i64) / 2
+        };
+
+        draw_text(&mut image, x, y, line, scale, style.color);
+
+        y += (line_height + scale) as i64;
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Renders lines of text centered on an image, each line scaled separately to fill the width
+///
+/// `weights` tell how much of the height every line gets, e.g. `[2, 1]` makes the first line
+/// twice as tall as the second one.
+pub fn render_lines(
+    size: (u32, u32),
+    lines: &[&str],
+    weights: &[u32],
+    style: TextStyle,
+) -> DynamicImage {
+    render(
+        size,
+        lines,
+        layout_lines(size, lines, weights, false),
+        &[],
+        style,
+    )
+}
+
+/// Renders lines of text across strip zones, returns an image of every zone from left to right
+///
+/// The text is drawn as one banner and then cut up, so it runs across zone borders.
+pub fn render_banner(zones: u32, lines: &[&str], weights: &[u32]) -> Vec<DynamicImage> {
+    let (width, height) = ZONE_SIZE;
+    let banner = render_lines(
+        (width * zones, height),
+        lines,
+        weights,
+        TextStyle::default(),
+    );
+
+    (0..zones)
+        .map(|zone| banner.crop_imm(zone * width, 0, width, height))
+        .collect()
+}
+
+/// How many pixels every line reaches past the image with [render_marquee], 0 if it fits
+pub fn marquee_overflow(size: (u32, u32), lines: &[&str], weights: &[u32]) -> Vec<u32> {
+    let layout = layout_lines(size, lines, weights, true);
+    let inner_width = size.0 - layout.margin * 2;
+
+    layout
+        .sizes
+        .iter()
+        .map(|(width, _)| width.saturating_sub(inner_width))
+        .collect()
+}
+
+/// Same as [render_lines], but lines too long to read when shrunk keep a readable size and are
+/// shifted left by their entry of `offsets`, up to their [marquee_overflow]
+pub fn render_marquee(
+    size: (u32, u32),
+    lines: &[&str],
+    weights: &[u32],
+    offsets: &[u32],
+    style: TextStyle,
+) -> DynamicImage {
+    render(
+        size,
+        lines,
+        layout_lines(size, lines, weights, true),
+        offsets,
+        style,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn text_is_measured_without_trailing_gap() {
+        assert_eq!(text_size("", 1), (0, 7));
+        assert_eq!(text_size("A", 1), (5, 7));
+        assert_eq!(text_size("12:30", 3), (29 * 3, 21));
+    }
+
+    #[test]
+    fn lines_are_centered_and_fit() {
+        let image = render_lines((120, 120), &["20:08"], &[1], TextStyle::default()).into_rgb8();
+
+        let lit: Vec<(u32, u32)> = image
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel.0 != [0, 0, 0])
+            .map(|(x, y, _)| (x, y))
+            .collect();
+
+        let left = lit.iter().map(|(x, _)| *x).min().unwrap();
+        let right = lit.iter().map(|(x, _)| *x).max().unwrap();
+
+        // Digits fill their whole cell, so margins on both sides are the same, give or take a
+        // pixel of rounding
+        assert!(left >= 10 && right <= 110);
+        assert!((left as i64 - (119 - right) as i64).abs() <= 1);
+    }
+
+    #[test]
+    fn banners_run_across_zones() {
+        let zones = render_banner(3, &["ABCDEFGHIJKL"], &[1]);
+        assert_eq!(zones.len(), 3);
+
+        // Every zone got a part of the line, none is blank
+        for zone in zones {
+            assert_eq!((zone.width(), zone.height()), ZONE_SIZE);
+            assert!(zone.into_rgb8().pixels().any(|pixel| pixel.0 != [0, 0, 0]));
+        }
+    }
+
+    #[test]
+    fn long_lines_scroll_at_a_readable_size() {
+        let line = ["ABCDEFGHIJKLMNOP"];
+        let lit_columns = |offset| {
+            let image = render_marquee((120, 40), &line, &[], &[offset], TextStyle::default());
+            let columns: Vec<u32> = image
+                .into_rgb8()
+                .enumerate_pixels()
+                .filter(|(_, _, pixel)| pixel.0 != [0, 0, 0])
+                .map(|(x, _, _)| x)
+                .collect();
+
+            (
+                *columns.iter().min().unwrap(),
+                *columns.iter().max().unwrap(),
+            )
+        };
+
+        // At scale 2 instead of 1, so 76 pixels don't fit between the margins
+        assert_eq!(marquee_overflow((120, 40), &line, &[]), [76]);
+        assert_eq!(marquee_overflow((120, 40), &["SHORT"], &[]), [0]);
+
+        // Starts at the left margin and stops once the end reaches the right one
+        assert_eq!(lit_columns(0).0, 3);
+        assert_eq!(lit_columns(76).1, 116);
+        assert_eq!(lit_columns(500), lit_columns(76));
+    }
+
+    // Font 6 pixels tall with 2 below the baseline, and a 4x3 block for é sitting on the baseline
+    const BDF: &str = "STARTFONT 2.1
+FONTBOUNDINGBOX 8 6 0 -2
+CHARS 2
+STARTCHAR eacute
+ENCODING 233
+DWIDTH 6 0
+BBX 4 3 1 0
+BITMAP
+F0
+90
+F0
+ENDCHAR
+STARTCHAR broken
+BBX 4 1 0 0
+BITMAP
+F0
+ENDCHAR
+ENDFONT
+";
+
+    #[test]
+    fn bdf_glyphs_are_placed_on_the_baseline() {
+        let font = BitmapFont::parse_bdf(BDF).unwrap();
+        assert_eq!(font.height(), 6);
+        // Glyphs without an encoding are skipped
+        assert_eq!(font.len(), 1);
+
+        let glyph = Glyph::Bitmap(&font.glyphs[&'é'], font.height);
+        let rows: Vec<String> = (0..6)
+            .map(|y| {
+                (0..glyph.width(6))
+                    .map(|x| if glyph.lit(x, y) { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(
+            rows,
+            ["......", ".####.", ".#..#.", ".####.", "......", "......"]
+        );
+        assert!(BitmapFont::parse_bdf("STARTFONT 2.1").is_err());
+    }
+
+    #[test]
+    fn fallback_fonts_draw_what_the_built_in_one_lacks() {
+        assert_eq!(text_size("é", 1), (5, 7));
+
+        set_fallback_fonts(vec![BitmapFont::parse_bdf(BDF).unwrap()]);
+        let generation = fallback_generation();
+
+        // Lines the built-in font can draw don't change
+        assert_eq!(text_size("A", 1), (5, 7));
+        // Characters missing from the fallback font come from the built-in one, which is taller
+        assert_eq!(text_size("éA", 2), ((6 + 1 + 5) * 2, 14));
+
+        set_fallback_fonts(vec![]);
+        assert!(fallback_generation() > generation);
+    }
+
+    #[test]
+    fn emoji_are_drawn_in_their_colors() {
+        let mut atlas = EmojiAtlas::default();
+        atlas.insert("🎤", RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        atlas.insert(
+            "👨\u{200D}💻",
+            RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255])),
+        );
+        set_emoji(atlas);
+
+        // Emoji are squares as tall as the line, variation selectors don't change anything
+        assert_eq!(text_size("🎤\u{FE0F} A", 1), (7 + 1 + 5 + 1 + 5, 7));
+        assert_eq!(text_size("👨\u{200D}💻", 1), (7, 7));
+
+        let mut image = RgbImage::new(40, 20);
+        draw_text(&mut image, 0, 0, "🎤A", 2, Rgb([255, 255, 255]));
+
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(image.get_pixel(13, 13).0, [255, 0, 0]);
+        assert_eq!(image.get_pixel(14, 0).0, [0, 0, 0]);
+        assert!(
+            image
+                .enumerate_pixels()
+                .any(|(x, _, pixel)| x >= 16 && pixel.0 == [255, 255, 255])
+        );
+
+        set_emoji(EmojiAtlas::default());
+    }
+
+    #[test]
+    fn emoji_are_loaded_by_codepoints() {
+        let dir = std::env::temp_dir().join(format!("akp05-emoji-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let emoji = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        emoji.save(dir.join("1f3a4.bmp")).unwrap();
+        emoji.save(dir.join("1F468-200D-1F4BB.BMP")).unwrap();
+        emoji.save(dir.join("mic.bmp")).unwrap();
+        std::fs::write(dir.join("LICENSE.txt"), "not an emoji").unwrap();
+
+        let atlas = EmojiAtlas::load(&dir).unwrap();
+        assert_eq!(atlas.len(), 2);
+        assert!(atlas.find(&['🎤', 'A']).is_some_and(|(_, len)| len == 1));
+        assert!(
+            atlas
+                .find(&['👨', '\u{200D}', '💻'])
+                .is_some_and(|(_, len)| len == 3)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(EmojiAtlas::load(&dir).is_err());
+    }
+
+    #[test]
+    fn unsupported_characters_are_drawn_as_question_marks() {
+        assert_eq!(glyph('é'), glyph('?'));
+        assert_eq!(glyph('\n'), glyph('?'));
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::images::{ImageFormat, encode_image};
+use image::{DynamicImage, ImageError};
+
+/// Bytes of encoded tiles kept in memory unless set otherwise, a few hundred key images
+pub const DEFAULT_CAPACITY: usize = 32 * 1024 * 1024;
+
+/// FNV-1a, unlike the std hasher it stays the same between builds, so keys of tiles on disk do too
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Hash of the pixels of an image, the same image always gets the same one
+pub fn content_hash(image: &DynamicImage) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, &image.width().to_le_bytes());
+    let hash = fnv1a(hash, &image.height().to_le_bytes());
+    let hash = fnv1a(hash, format!("{:?}", image.color()).as_bytes());
+
+    fnv1a(hash, image.as_bytes())
+}
+
+/// Key of an encoded tile, made of the image content and everything encoding it depends on
+pub fn tile_key(content: u64, format: ImageFormat, quality: u8) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, &content.to_le_bytes());
+    let hash = fnv1a(hash, format!("{:?}", format).as_bytes());
+
+    fnv1a(hash, &[quality])
+}
+
+/// Tiles in memory, the least recently used ones go first once they take more than `capacity`
+#[derive(Debug)]
+struct Tiles {
+    // Tile and when it was last used
+    tiles: HashMap<u64, (Arc<Vec<u8>>, u64)>,
+    // Keys by when they were last used
+    used: BTreeMap<u64, u64>,
+    clock: u64,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl Tiles {
+    fn new(capacity: usize) -> Self {
+        Self {
+            tiles: HashMap::new(),
+            used: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: u64) -> Option<Arc<Vec<u8>>> {
+        let (tile, used) = self.tiles.get_mut(&key)?;
+
+        self.clock += 1;
+        self.used.remove(used);
+        self.used.insert(self.clock, key);
+        *used = self.clock;
+
+        Some(tile.clone())
+    }
+
+    fn insert(&mut self, key: u64, tile: Arc<Vec<u8>>) {
+        self.clock += 1;
+        self.bytes += tile.len();
+        self.used.insert(self.clock, key);
+
+        if let Some((old, used)) = self.tiles.insert(key, (tile, self.clock)) {
+            self.bytes -= old.len();
+            self.used.remove(&used);
+        }
+
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.capacity {
+            let Some((_, key)) = self.used.pop_first() else {
+                break;
+            };
+
+            if let Some((tile, _)) = self.tiles.remove(&key) {
+                self.bytes -= tile.len();
+            }
+        }
+    }
+}
+
+/// Images encoded the way the device expects them, so the same image is only encoded once
+///
+/// Tiles are kept in memory up to a size. With a directory, tiles saved with
+/// [TileCache::persist] are kept on disk too, so they survive restarts and are read back instead
+/// of encoded again once they're dropped from memory. Everything else, e.g. frames of
+/// animations, only stays in memory.
+#[derive(Debug)]
+pub struct TileCache {
+    dir: Option<PathBuf>,
+    tiles: Mutex<Tiles>,
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl TileCache {
+    /// Cache keeping tiles in `dir` too, it's created when the first tile is written
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            tiles: Mutex::new(Tiles::new(DEFAULT_CAPACITY)),
+        }
+    }
+
+    /// Limits bytes of tiles kept in memory, dropping the least recently used ones over it
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut tiles = self.tiles.lock().unwrap();
+
+        tiles.capacity = capacity;
+        tiles.evict();
+    }
+
+    /// Number of tiles in memory
+    pub fn len(&self) -> usize {
+        self.tiles.lock().unwrap().tiles.len()
+    }
+
+    /// Bytes of tiles in memory
+    pub fn bytes(&self) -> usize {
+        self.tiles.lock().unwrap().bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn tile_path(dir: &Path, key: u64, format: ImageFormat) -> PathBuf {
+        dir.join(format!("{:016x}.{}", key, format.mode.extension()))
+    }
+
+    /// Encoded image, from memory or disk if it was encoded before
+    pub fn encode(
+        &self,
+        format: ImageFormat,
+        quality: u8,
+        image: &DynamicImage,
+    ) -> Result<Arc<Vec<u8>>, ImageError> {
+        self.tile(format, quality, image, false)
+    }
+
+    /// Same as [TileCache::encode], but also saves the tile to disk if it's not there yet
+    pub fn persist(
+        &self,
+        format: ImageFormat,
+        quality: u8,
+        image: &DynamicImage,
+    ) -> Result<Arc<Vec<u8>>, ImageError> {
+        self.tile(format, quality, image, true)
+    }
+
+    fn tile(
+        &self,
+        format: ImageFormat,
+        quality: u8,
+        image: &DynamicImage,
+        save: bool,
+    ) -> Result<Arc<Vec<u8>>, ImageError> {
+        let key = tile_key(content_hash(image), format, quality);
+
+        if let Some(tile) = self.tiles.lock().unwrap().get(key) {
+            if save {
+                self.save(key, format, &tile);
+            }
+
+            return Ok(tile);
+        }
+
+        let path = self
+            .dir
+            .as_deref()
+            .map(|dir| Self::tile_path(dir, key, format));
+
+        let tile = match path.as_deref().and_then(|path| std::fs::read(path).ok()) {
+            Some(tile) => tile,
+            None => {
+                let tile = encode_image(format, quality, image.clone())?;
+
+                if save {
+                    self.save(key, format, &tile);
+                }
+
+                tile
+            }
+        };
+
+        let tile = Arc::new(tile);
+        self.tiles.lock().unwrap().insert(key, tile.clone());
+
+        Ok(tile)
+    }
+
+    fn save(&self, key: u64, format: ImageFormat, tile: &[u8]) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+
+        let path = Self::tile_path(dir, key, format);
+
+        // Only a slower start next time if this fails
+        if !path.exists()
+            && let Err(err) = Self::write_tile(&path, tile)
+        {
+            log::warn!("Failed to save tile to {}: {}", path.display(), err);
+        }
+    }
+
+    fn write_tile(path: &Path, tile: &[u8]) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Written next to it first, so a crash never leaves half a tile behind
+        let partial = path.with_extension("part");
+        std::fs::write(&partial, tile)?;
+        std::fs::rename(partial, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+    use mirajazz::types::{ImageMirroring, ImageRotation};
+
+    use super::*;
+    use crate::images::ImageMode;
+
+    const FORMAT: ImageFormat = ImageFormat {
+        mode: ImageMode::Jpeg,
+        size: (60, 60),
+        rotation: ImageRotation::Rot0,
+        mirror: ImageMirroring::None,
+    };
+
+    fn filled(value: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([value, 0, 0])))
+    }
+
+    #[test]
+    fn tiles_are_encoded_once() {
+        let dir = std::env::temp_dir().join(format!("akp05-tiles-{}", std::process::id()));
+        let cache = TileCache::new(Some(dir.clone()));
+
+        let first = cache.persist(FORMAT, 90, &filled(200)).unwrap();
+        let again = cache.encode(FORMAT, 90, &filled(200)).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!(cache.len(), 1);
+
+        cache.persist(FORMAT, 70, &filled(200)).unwrap();
+        cache.encode(FORMAT, 90, &filled(100)).unwrap();
+        assert_eq!(cache.len(), 3);
+
+        // A new cache reads what the first one saved, tiles that weren't persisted stay in memory
+        let restarted = TileCache::new(Some(dir.clone()));
+        assert_eq!(restarted.encode(FORMAT, 90, &filled(200)).unwrap(), first);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_tiles_are_dropped() {
+        let cache = TileCache::new(None);
+
+        let first = cache.encode(FORMAT, 90, &filled(1)).unwrap();
+        cache.encode(FORMAT, 90, &filled(2)).unwrap();
+        cache.encode(FORMAT, 90, &filled(3)).unwrap();
+        assert_eq!(cache.len(), 3);
+
+        // The first one was used last, so the second one goes first
+        cache.encode(FORMAT, 90, &filled(1)).unwrap();
+        cache.set_capacity(cache.bytes() - 1);
+        assert_eq!(cache.len(), 2);
+
+        let again = cache.encode(FORMAT, 90, &filled(1)).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+}
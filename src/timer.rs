@@ -0,0 +1,300 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::text::{TextStyle, render_lines};
+use openaction::SettingsValue;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, writer::KeyPainter};
+
+/// How often running timers are redrawn, frames are only sent when the text changes
+pub const TIMER_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Size timers are rendered at, images are resized to the device format anyway
+pub const TIMER_SIZE: (u32, u32) = (120, 120);
+
+/// Minutes a new timer starts with
+pub const DEFAULT_MINUTES: u32 = 5;
+
+/// Longest countdown that can be set with the dial
+pub const MAX_MINUTES: u32 = 999;
+
+// Timer actions by their OpenDeck context
+static TIMERS: LazyLock<Mutex<HashMap<String, Timer>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Countdown, or a stopwatch if set to 0 minutes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timer {
+    pub device: String,
+    /// Position the timer is drawn on, the key itself or the strip zone of the encoder
+    pub position: u8,
+    pub minutes: u32,
+    // Time counted before the current run
+    elapsed: Duration,
+    started: Option<Instant>,
+}
+
+impl Timer {
+    pub fn new(device: String, position: u8, minutes: u32) -> Self {
+        Self {
+            device,
+            position,
+            minutes: minutes.min(MAX_MINUTES),
+            elapsed: Duration::ZERO,
+            started: None,
+        }
+    }
+
+    fn elapsed(&self, now: Instant) -> Duration {
+        self.elapsed
+            + self
+                .started
+                .map_or(Duration::ZERO, |started| now.duration_since(started))
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::from_secs(self.minutes as u64 * 60)
+    }
+
+    pub fn is_finished(&self, now: Instant) -> bool {
+        self.minutes > 0 && self.elapsed(now) >= self.duration()
+    }
+
+    /// Changes the countdown by `ticks` minutes, only while the timer isn't running
+    ///
+    /// Setting the timer also drops whatever was counted before.
+    pub fn rotate(&mut self, ticks: i16) {
+        if self.started.is_some() {
+            return;
+        }
+
+        self.minutes = (self.minutes as i64 + ticks as i64).clamp(0, MAX_MINUTES as i64) as u32;
+        self.elapsed = Duration::ZERO;
+    }
+
+    /// Starts or pauses the timer, a finished countdown is set up again
+    pub fn toggle(&mut self, now: Instant) {
+        if self.is_finished(now) {
+            self.elapsed = Duration::ZERO;
+            self.started = None;
+        } else if let Some(started) = self.started.take() {
+            self.elapsed += now.duration_since(started);
+        } else {
+            self.started = Some(now);
+        }
+    }
+
+    /// Percent of the countdown that's over, [None] for stopwatches
+    pub fn progress(&self, now: Instant) -> Option<u32> {
+        if self.minutes == 0 {
+            return None;
+        }
+
+        let percent = self.elapsed(now).as_millis() * 100 / self.duration().as_millis();
+
+        Some(percent.min(100) as u32)
+    }
+
+    /// Time and state to draw, e.g. `04:59` and `RUN`
+    pub fn lines(&self, now: Instant) -> Vec<String> {
+        let elapsed = self.elapsed(now);
+
+        let shown = if self.minutes == 0 {
+            elapsed
+        } else {
+            // Round up, so the countdown shows 00:00 only once it's actually over
+            self.duration().saturating_sub(elapsed) + Duration::from_nanos(999_999_999)
+        };
+
+        let state = if self.is_finished(now) {
+            "DONE"
+        } else if self.started.is_some() {
+            "RUN"
+        } else if elapsed.is_zero() {
+            "SET"
+        } else {
+            "PAUSE"
+        };
+
+        vec![format_duration(shown), state.to_string()]
+    }
+}
+
+/// Formats duration as `MM:SS`, or `H:MM:SS` from an hour up
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{:02}:{:02}", minutes, seconds)
+    }
+}
+
+/// Minutes stored in action settings
+pub fn settings_minutes(settings: &SettingsValue) -> u32 {
+    settings
+        .get("minutes")
+        .and_then(|minutes| minutes.as_u64())
+        .map_or(DEFAULT_MINUTES, |minutes| {
+            minutes.min(MAX_MINUTES as u64) as u32
+        })
+}
+
+/// Action settings with the timer's minutes, so they survive restarts
+pub fn to_settings(minutes: u32) -> SettingsValue {
+    json!({ "minutes": minutes })
+}
+
+pub fn add_timer(context: &str, timer: Timer) {
+    TIMERS.lock().unwrap().insert(context.to_string(), timer);
+}
+
+pub fn remove_timer(context: &str) {
+    TIMERS.lock().unwrap().remove(context);
+}
+
+/// Applies `change` to a timer, returning its minutes if it exists
+pub fn update_timer(context: &str, change: impl FnOnce(&mut Timer)) -> Option<u32> {
+    let mut timers = TIMERS.lock().unwrap();
+    let timer = timers.get_mut(context)?;
+
+    change(timer);
+
+    Some(timer.minutes)
+}
+
+/// Checks if a timer is drawn on the position, so OpenDeck images for it are ignored
+pub fn draws_position(device: &str, position: u8) -> bool {
+    TIMERS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|timer| timer.device == device && timer.position == position)
+}
+
+/// Draws every timer on its device
+pub async fn timer_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(TIMER_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let now = Instant::now();
+        let frames: Vec<(String, u8, Vec<String>, Option<u32>)> = TIMERS
+            .lock()
+            .unwrap()
+            .values()
+            .map(|timer| {
+                (
+                    timer.device.clone(),
+                    timer.position,
+                    timer.lines(now),
+                    timer.progress(now),
+                )
+            })
+            .collect();
+
+        painter.retain(|device, position| {
+            frames
+                .iter()
+                .any(|(id, other, ..)| id == device && *other == position)
+        });
+
+        let bar = CONFIG.borrow().progress_bar;
+
+        for (device, position, lines, progress) in frames {
+            let image_lines = lines.clone();
+
+            // Bar of a long countdown can move without the time changing
+            let mut frame = lines;
+            frame.extend(progress.map(|percent| format!("{}%", percent)));
+
+            painter
+                .paint_device(&device, position, frame, || {
+                    let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+                    let mut image = render_lines(TIMER_SIZE, &lines, &[2, 1], TextStyle::default());
+
+                    if let Some(percent) = progress {
+                        bar.draw_bottom(&mut image, percent);
+                    }
+
+                    image
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdown_runs_pauses_and_finishes() {
+        let start = Instant::now();
+        let mut timer = Timer::new("a5-test".to_string(), 0, 1);
+
+        assert_eq!(timer.lines(start), ["01:00", "SET"]);
+        assert_eq!(timer.progress(start), Some(0));
+
+        timer.toggle(start);
+        assert_eq!(
+            timer.lines(start + Duration::from_millis(1500)),
+            ["00:59", "RUN"]
+        );
+
+        // Running timers can't be set, so a stray turn doesn't lose progress
+        timer.rotate(5);
+        timer.toggle(start + Duration::from_secs(20));
+        assert_eq!(
+            timer.lines(start + Duration::from_secs(90)),
+            ["00:40", "PAUSE"]
+        );
+        assert_eq!(timer.progress(start + Duration::from_secs(90)), Some(33));
+
+        timer.toggle(start + Duration::from_secs(100));
+        let end = start + Duration::from_secs(140);
+        assert!(timer.is_finished(end));
+        assert_eq!(timer.lines(end), ["00:00", "DONE"]);
+        assert_eq!(timer.progress(end), Some(100));
+
+        // Pressing a finished timer sets it up again
+        timer.toggle(end);
+        assert_eq!(timer.lines(end), ["01:00", "SET"]);
+    }
+
+    #[test]
+    fn rotating_sets_minutes() {
+        let now = Instant::now();
+        let mut timer = Timer::new("a5-test".to_string(), 0, 5);
+
+        timer.rotate(-10);
+        assert_eq!(timer.minutes, 0);
+
+        // 0 minutes makes it a stopwatch
+        timer.toggle(now);
+        assert_eq!(
+            timer.lines(now + Duration::from_secs(3725)),
+            ["1:02:05", "RUN"]
+        );
+        assert!(!timer.is_finished(now + Duration::from_secs(3725)));
+        assert_eq!(timer.progress(now), None);
+    }
+
+    #[test]
+    fn minutes_are_read_from_settings() {
+        assert_eq!(settings_minutes(&json!({ "minutes": 12 })), 12);
+        assert_eq!(settings_minutes(&json!({ "minutes": 5000 })), MAX_MINUTES);
+        assert_eq!(settings_minutes(&json!(null)), DEFAULT_MINUTES);
+    }
+}
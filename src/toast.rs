@@ -0,0 +1,268 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::{images::KeyImage, mappings::COL_COUNT, text::render_banner};
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{WRITERS, lock, menu, writer::WriterCommand};
+
+/// How long a toast stays on the strip
+pub const TOAST_DURATION: Duration = Duration::from_secs(3);
+
+/// Toasts waiting for the strip of a device, the oldest ones are dropped past this
+pub const MAX_QUEUED_TOASTS: usize = 5;
+
+/// How often toasts waiting for a locked device check if it's unlocked
+const LOCKED_RETRY: Duration = Duration::from_secs(1);
+
+/// Short message drawn across the strip for a few seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Toast {
+    pub text: String,
+    /// Drawn on the first strip zone, the text gets the rest
+    pub icon: Option<KeyImage>,
+}
+
+impl Toast {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            icon: None,
+        }
+    }
+}
+
+/// What the strip of a device changes to
+#[derive(Debug, PartialEq)]
+enum Change {
+    /// Toast to draw and how many more are waiting after it
+    Show(Toast, usize),
+    /// Last toast is over, the strip shows what it did before
+    Restore,
+}
+
+/// Toasts of a single device, shown one after another
+#[derive(Debug, Default)]
+struct ToastQueue {
+    // When the toast on the strip is over, [None] if there's none
+    shown_until: Option<Instant>,
+    waiting: VecDeque<Toast>,
+}
+
+impl ToastQueue {
+    fn push(&mut self, toast: Toast) {
+        if self.waiting.len() == MAX_QUEUED_TOASTS {
+            self.waiting.pop_front();
+        }
+
+        self.waiting.push_back(toast);
+    }
+
+    /// Moves on to the next toast once the shown one is over, toasts for locked devices wait
+    fn advance(&mut self, now: Instant, locked: bool) -> Option<Change> {
+        if self.shown_until.is_some_and(|until| now < until) {
+            return None;
+        }
+
+        if !locked && let Some(toast) = self.waiting.pop_front() {
+            self.shown_until = Some(now + TOAST_DURATION);
+
+            return Some(Change::Show(toast, self.waiting.len()));
+        }
+
+        self.shown_until.take().map(|_| Change::Restore)
+    }
+}
+
+// Device id to its toasts
+static TOASTS: LazyLock<Mutex<HashMap<String, ToastQueue>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Queues a toast for the strip of a device
+pub fn show(device: &str, toast: Toast) {
+    log::info!("Toast for {}: {}", device, toast.text);
+
+    TOASTS
+        .lock()
+        .unwrap()
+        .entry(device.to_string())
+        .or_default()
+        .push(toast);
+    CHANGED.notify_one();
+}
+
+/// Queues a toast for the strip of every connected device
+pub async fn show_all(toast: Toast) {
+    for device in WRITERS.read().await.keys() {
+        show(device, toast.clone());
+    }
+}
+
+/// Checks if a toast is drawn on the position, so other images for it are held back
+pub fn draws_position(device: &str, position: u8) -> bool {
+    (position as usize) < COL_COUNT
+        && TOASTS
+            .lock()
+            .unwrap()
+            .get(device)
+            .is_some_and(|queue| queue.shown_until.is_some())
+}
+
+/// Images of the strip zones, from left to right
+fn render_toast(toast: &Toast, waiting: usize) -> Vec<KeyImage> {
+    let mut zones = vec![];
+
+    if let Some(icon) = &toast.icon {
+        zones.push(icon.clone());
+    }
+
+    let text_zones = (COL_COUNT - zones.len()) as u32;
+
+    let more = format!("+{} MORE", waiting);
+    let mut lines = vec![toast.text.as_str()];
+    if waiting > 0 {
+        lines.push(&more);
+    }
+
+    zones.extend(
+        render_banner(text_zones, &lines, &[3, 1])
+            .into_iter()
+            .map(|image| KeyImage::Rendered(Arc::new(image))),
+    );
+
+    zones
+}
+
+/// Draws toasts on the strip one after another, then asks for the previous content again
+pub async fn toast_task(token: CancellationToken) {
+    loop {
+        let now = Instant::now();
+        let mut changes = vec![];
+        let mut wake: Option<Instant> = None;
+
+        TOASTS.lock().unwrap().retain(|device, queue| {
+            // Open menus keep the strip like the padlock does
+            let locked = lock::is_locked(device) || menu::is_open(device);
+
+            if let Some(change) = queue.advance(now, locked) {
+                changes.push((device.clone(), change, locked));
+            }
+
+            let at = match queue.shown_until {
+                Some(until) => Some(until),
+                None if !queue.waiting.is_empty() => Some(now + LOCKED_RETRY),
+                None => None,
+            };
+
+            if let Some(at) = at {
+                wake = Some(wake.map_or(at, |wake| wake.min(at)));
+            }
+
+            at.is_some()
+        });
+
+        for (device, change, locked) in changes {
+            let Some(writer) = WRITERS.read().await.get(&device).cloned() else {
+                continue;
+            };
+
+            match change {
+                Change::Show(toast, waiting) => {
+                    let images = render_toast(&toast, waiting)
+                        .into_iter()
+                        .enumerate()
+                        .map(|(position, image)| (position as u8, Some(image)))
+                        .collect();
+
+                    writer.send(WriterCommand::SetImages(images));
+                }
+                // Padlock took over the strip in the meantime, unlocking redraws it anyway
+                Change::Restore if locked => {}
+                Change::Restore => {
+                    let images = (0..COL_COUNT as u8).map(|position| (position, None));
+
+                    writer.send(WriterCommand::SetImages(images.collect()));
+                    writer.send(WriterCommand::Redraw);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = async {
+                match wake {
+                    Some(wake) => tokio::time::sleep_until(wake).await,
+                    None => std::future::pending().await,
+                }
+            } => {}
+            _ = CHANGED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toasts_take_turns() {
+        let now = Instant::now();
+        let mut queue = ToastQueue::default();
+
+        queue.push(Toast::text("ONE"));
+        queue.push(Toast::text("TWO"));
+
+        // Nothing shows up on a locked device
+        assert_eq!(queue.advance(now, true), None);
+
+        assert_eq!(
+            queue.advance(now, false),
+            Some(Change::Show(Toast::text("ONE"), 1))
+        );
+        assert_eq!(queue.advance(now + Duration::from_secs(1), false), None);
+        assert_eq!(
+            queue.advance(now + TOAST_DURATION, false),
+            Some(Change::Show(Toast::text("TWO"), 0))
+        );
+        assert_eq!(
+            queue.advance(now + TOAST_DURATION * 2, false),
+            Some(Change::Restore)
+        );
+        assert_eq!(queue.advance(now + TOAST_DURATION * 3, false), None);
+    }
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let mut queue = ToastQueue::default();
+
+        for number in 0..MAX_QUEUED_TOASTS + 2 {
+            queue.push(Toast::text(number.to_string()));
+        }
+
+        assert_eq!(queue.waiting.len(), MAX_QUEUED_TOASTS);
+        assert_eq!(queue.waiting[0].text, "2");
+    }
+
+    #[test]
+    fn toasts_cover_the_strip() {
+        assert_eq!(render_toast(&Toast::text("HELLO"), 2).len(), COL_COUNT);
+
+        let icon = KeyImage::DataUrl("data:image/jpeg;base64,".to_string());
+        let zones = render_toast(
+            &Toast {
+                text: "CALL".to_string(),
+                icon: Some(icon.clone()),
+            },
+            0,
+        );
+
+        assert_eq!(zones.len(), COL_COUNT);
+        assert_eq!(zones[0], icon);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::{
+    images::KeyImage,
+    text::{TextStyle, render_lines},
+};
+use image::Rgb;
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{WRITERS, safemode::TEXT_ONLY_SIZE, writer::WriterCommand};
+
+/// Length of a slot of the timer wheel, transient images revert at most this late
+pub const WHEEL_TICK: Duration = Duration::from_millis(50);
+
+/// Slots of the timer wheel, timers further out than a whole turn wait for the next one
+const WHEEL_SLOTS: u64 = 64;
+
+/// Longest a transient image can be asked to show for
+pub const MAX_TRANSIENT_SECONDS: f64 = 3600.0;
+
+/// How long error flashes show
+pub const ERROR_FLASH: Duration = Duration::from_secs(2);
+
+/// Hashed timer wheel, timers go into the slot of the tick they're due at and stay there for as
+/// many turns as it takes
+#[derive(Debug)]
+struct TimerWheel<T> {
+    slots: Vec<Vec<(u64, T)>>,
+    // Every tick up to this one was handled
+    tick: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    fn new(tick: u64) -> Self {
+        Self {
+            slots: (0..WHEEL_SLOTS).map(|_| vec![]).collect(),
+            tick,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a timer due at `tick`, ones due already go off on the next advance
+    fn insert(&mut self, tick: u64, value: T) {
+        let tick = tick.max(self.tick + 1);
+
+        self.slots[(tick % WHEEL_SLOTS) as usize].push((tick, value));
+        self.len += 1;
+    }
+
+    /// Takes every timer due up to `tick` out
+    fn advance(&mut self, tick: u64) -> Vec<T> {
+        let mut due = vec![];
+
+        // A whole turn visits every slot, however long the wheel wasn't advanced
+        for current in self.tick + 1..=tick.min(self.tick + WHEEL_SLOTS) {
+            let slot = &mut self.slots[(current % WHEEL_SLOTS) as usize];
+            let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(slot)
+                .into_iter()
+                .partition(|(at, _)| *at <= tick);
+
+            *slot = waiting;
+            due.extend(ready.into_iter().map(|(_, value)| value));
+        }
+
+        self.tick = self.tick.max(tick);
+        self.len -= due.len();
+
+        due
+    }
+}
+
+struct Transients {
+    // Device id, position and generation of the image to revert
+    wheel: TimerWheel<(String, u8, u64)>,
+    // Device id and position to the generation of the image shown there, newer images replace
+    // older ones along with their timers
+    shown: HashMap<(String, u8), u64>,
+    generation: u64,
+    // Images to send, [None] reverts to the key image
+    pending: Vec<(String, u8, Option<KeyImage>)>,
+}
+
+// Ticks are counted from the first time anything is shown
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+static TRANSIENTS: LazyLock<Mutex<Transients>> = LazyLock::new(|| {
+    Mutex::new(Transients {
+        wheel: TimerWheel::new(0),
+        shown: HashMap::new(),
+        generation: 0,
+        pending: vec![],
+    })
+});
+
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Tick `at` falls into, rounded up so images never revert early
+fn tick_at(at: Instant) -> u64 {
+    let elapsed = at.saturating_duration_since(*EPOCH).as_millis();
+
+    elapsed.div_ceil(WHEEL_TICK.as_millis()) as u64
+}
+
+/// Shows an image on a key for `duration`, then the key image it had, whatever that is by then
+///
+/// Showing another one on the same key replaces the image and its timer.
+pub fn show(device: &str, position: u8, image: KeyImage, duration: Duration) {
+    let due = tick_at(Instant::now() + duration);
+    let mut transients = TRANSIENTS.lock().unwrap();
+
+    transients.generation += 1;
+    let generation = transients.generation;
+
+    log::debug!(
+        "Showing transient image on key {} of {} for {:?}",
+        position,
+        device,
+        duration
+    );
+
+    transients
+        .shown
+        .insert((device.to_string(), position), generation);
+    transients
+        .wheel
+        .insert(due, (device.to_string(), position, generation));
+    transients
+        .pending
+        .push((device.to_string(), position, Some(image)));
+    CHANGED.notify_one();
+}
+
+/// Puts the key image back right away, if a transient image is showing
+pub fn revert(device: &str, position: u8) {
+    let mut transients = TRANSIENTS.lock().unwrap();
+
+    // Timer goes off for nothing, its generation isn't shown anymore
+    if transients
+        .shown
+        .remove(&(device.to_string(), position))
+        .is_some()
+    {
+        transients
+            .pending
+            .push((device.to_string(), position, None));
+        CHANGED.notify_one();
+    }
+}
+
+/// Red key with a few words on it, for actions that didn't work
+pub fn flash_error(device: &str, position: u8, text: &str) {
+    let style = TextStyle {
+        color: Rgb([255, 255, 255]),
+        background: Rgb([200, 0, 0]),
+    };
+    let image = render_lines(TEXT_ONLY_SIZE, &[text], &[], style);
+
+    show(
+        device,
+        position,
+        KeyImage::Rendered(Arc::new(image)),
+        ERROR_FLASH,
+    );
+}
+
+/// Sends transient images and reverts them once their time is up
+pub async fn transient_task(token: CancellationToken) {
+    loop {
+        let (changes, ticking) = {
+            let mut transients = TRANSIENTS.lock().unwrap();
+            let due = transients.wheel.advance(tick_at(Instant::now()));
+
+            for (device, position, generation) in due {
+                let key = (device, position);
+
+                if transients.shown.get(&key) == Some(&generation) {
+                    transients.shown.remove(&key);
+                    transients.pending.push((key.0, key.1, None));
+                }
+            }
+
+            (
+                std::mem::take(&mut transients.pending),
+                !transients.wheel.is_empty(),
+            )
+        };
+
+        for (device, position, image) in changes {
+            if let Some(writer) = WRITERS.read().await.get(&device) {
+                writer.send(WriterCommand::Transient { position, image });
+            }
+        }
+
+        // Wheel only turns while there's something on it
+        tokio::select! {
+            _ = async {
+                if ticking {
+                    tokio::time::sleep(WHEEL_TICK).await
+                } else {
+                    std::future::pending().await
+                }
+            } => {}
+            _ = CHANGED.notified() => {}
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timers_go_off_once_their_tick_comes() {
+        let mut wheel = TimerWheel::new(10);
+
+        wheel.insert(12, "soon");
+        // More than a turn away, shares a slot with the one above
+        wheel.insert(12 + WHEEL_SLOTS, "later");
+        // Already due
+        wheel.insert(3, "late");
+
+        assert_eq!(wheel.advance(11), vec!["late"]);
+        assert_eq!(wheel.advance(12), vec!["soon"]);
+        assert!(wheel.advance(12 + WHEEL_SLOTS - 1).is_empty());
+        assert!(!wheel.is_empty());
+
+        // Skipping several turns at once still finds it
+        assert_eq!(wheel.advance(1000), vec!["later"]);
+        assert!(wheel.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::images::{KeyImage, decode_data_url};
+use image::{DynamicImage, Rgb, RgbImage, imageops::FilterType};
+use tokio_util::sync::CancellationToken;
+
+use crate::{WRITERS, duty, writer::WriterCommand};
+
+/// Time between frames, about 15 per second
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(66);
+
+/// Frames of a transition, the last one is the new page itself
+pub const FRAME_COUNT: u32 = 4;
+
+/// Size frames are rendered at, images are resized to the device format anyway
+pub const FRAME_SIZE: (u32, u32) = (120, 120);
+
+// Page shown last on every device, switching again cancels it so the older transition stops and
+// its images still waiting in the writer are dropped
+static GENERATIONS: LazyLock<Mutex<HashMap<String, CancellationToken>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Starts a new page generation on the device, cancelling the one before
+fn next_generation(device: &str) -> CancellationToken {
+    let page = CancellationToken::new();
+
+    if let Some(previous) = GENERATIONS
+        .lock()
+        .unwrap()
+        .insert(device.to_string(), page.clone())
+    {
+        previous.cancel();
+    }
+
+    page
+}
+
+/// How switching pages is animated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transition {
+    /// New page replaces the old one at once
+    #[default]
+    None,
+    /// Old key images fade into new ones
+    Fade,
+    /// New key images slide in over old ones
+    Slide,
+}
+
+impl Transition {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Self::None),
+            "fade" => Some(Self::Fade),
+            "slide" => Some(Self::Slide),
+            _ => None,
+        }
+    }
+}
+
+/// Key image to animate, black if there's none or it can't be decoded
+fn frame_image(image: &Option<KeyImage>) -> RgbImage {
+    let image = match image {
+        Some(KeyImage::DataUrl(url)) => decode_data_url(url).ok(),
+        Some(KeyImage::Rendered(image)) => Some((**image).clone()),
+        None => None,
+    };
+
+    match image {
+        Some(image) => image
+            .resize_exact(FRAME_SIZE.0, FRAME_SIZE.1, FilterType::Triangle)
+            .into_rgb8(),
+        None => RgbImage::new(FRAME_SIZE.0, FRAME_SIZE.1),
+    }
+}
+
+/// Mixes `from` into `to`, `progress` goes from 0 (only `from`) to 1 (only `to`)
+pub fn fade(from: &RgbImage, to: &RgbImage, progress: f32) -> RgbImage {
+    RgbImage::from_fn(to.width(), to.height(), |x, y| {
+        let (a, b) = (from.get_pixel(x, y).0, to.get_pixel(x, y).0);
+
+        Rgb(std::array::from_fn(|channel| {
+            (a[channel] as f32 + (b[channel] as f32 - a[channel] as f32) * progress).round() as u8
+        }))
+    })
+}
+
+/// Pushes `from` out with `to`, coming from the right if `forward` and from the left otherwise
+pub fn slide(from: &RgbImage, to: &RgbImage, progress: f32, forward: bool) -> RgbImage {
+    let width = to.width();
+    let offset = (width as f32 * progress).round() as u32;
+
+    RgbImage::from_fn(width, to.height(), |x, y| {
+        // Both images side by side, the view moves over them
+        if forward {
+            match x + offset {
+                x if x < width => *from.get_pixel(x, y),
+                x => *to.get_pixel(x - width, y),
+            }
+        } else {
+            match x + width - offset {
+                x if x < width => *to.get_pixel(x, y),
+                x => *from.get_pixel(x - width, y),
+            }
+        }
+    })
+}
+
+/// Animates switching the keys of a device from `from` to `to`, which is sent last as it is
+///
+/// Both lists hold the same positions. Frames are rendered on a blocking thread, a transition
+/// started later on the same device stops this one and drops its frames not written yet.
+pub async fn play(
+    device: String,
+    transition: Transition,
+    forward: bool,
+    from: Vec<(u8, Option<KeyImage>)>,
+    to: Vec<(u8, Option<KeyImage>)>,
+) {
+    let page = next_generation(&device);
+
+    if transition != Transition::None {
+        let keys: Vec<(u8, Option<KeyImage>, Option<KeyImage>)> = from
+            .into_iter()
+            .zip(&to)
+            .map(|((position, old), (_, new))| (position, old, new.clone()))
+            .collect();
+
+        let frames = tokio::task::spawn_blocking(move || {
+            let keys: Vec<(u8, RgbImage, RgbImage)> = keys
+                .iter()
+                .map(|(position, old, new)| (*position, frame_image(old), frame_image(new)))
+                .collect();
+
+            (1..FRAME_COUNT)
+                .map(|frame| {
+                    let progress = frame as f32 / FRAME_COUNT as f32;
+
+                    keys.iter()
+                        .map(|(position, old, new)| {
+                            let image = match transition {
+                                Transition::Slide => slide(old, new, progress, forward),
+                                _ => fade(old, new, progress),
+                            };
+
+                            let image = Arc::new(DynamicImage::ImageRgb8(image));
+
+                            (*position, Some(KeyImage::Rendered(image)))
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+
+        for frame in frames {
+            if page.is_cancelled() {
+                return;
+            }
+
+            if let Some(writer) = WRITERS.read().await.get(&device) {
+                writer.send(WriterCommand::SetPage {
+                    images: frame,
+                    page: page.clone(),
+                });
+            }
+
+            tokio::time::sleep(duty::frame_interval(Some(&device), FRAME_INTERVAL)).await;
+        }
+    }
+
+    if !page.is_cancelled()
+        && let Some(writer) = WRITERS.read().await.get(&device)
+    {
+        writer.send(WriterCommand::SetPage { images: to, page });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(value: u8) -> RgbImage {
+        RgbImage::from_pixel(4, 2, Rgb([value; 3]))
+    }
+
+    #[test]
+    fn fade_mixes_images() {
+        let frame = fade(&filled(0), &filled(200), 0.25);
+
+        assert_eq!(frame.get_pixel(3, 1).0, [50; 3]);
+        assert_eq!(fade(&filled(0), &filled(200), 1.0), filled(200));
+    }
+
+    #[test]
+    fn slide_moves_in_from_the_side() {
+        let (old, new) = (filled(0), filled(200));
+
+        let forward = slide(&old, &new, 0.25, true);
+        assert_eq!(forward.get_pixel(2, 0).0, [0; 3]);
+        assert_eq!(forward.get_pixel(3, 0).0, [200; 3]);
+
+        let back = slide(&old, &new, 0.25, false);
+        assert_eq!(back.get_pixel(0, 0).0, [200; 3]);
+        assert_eq!(back.get_pixel(1, 0).0, [0; 3]);
+
+        assert_eq!(slide(&old, &new, 1.0, true), new);
+        assert_eq!(slide(&old, &new, 0.0, false), old);
+    }
+}
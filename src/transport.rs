@@ -0,0 +1,667 @@
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use image::{
+    DynamicImage, ImageError,
+    error::{EncodingError, ImageFormatHint},
+};
+use mirajazz::{
+    device::Device, error::MirajazzError, state::DeviceStateReader, types::DeviceInput,
+};
+
+use crate::{
+    discovery::find_vendor_interface,
+    images::{ImageFormat, encode_image, spawn_encode},
+    mappings::CandidateDevice,
+    tiles::TileCache,
+};
+
+/// Length of a single input report read from the device
+pub const REPORT_LENGTH: usize = 512;
+
+/// Builds a report the way the device sends it: ACK prefix, input code at 9, state at 10
+pub fn build_report(input: u8, state: u8) -> Vec<u8> {
+    let mut report = vec![0u8; REPORT_LENGTH];
+    report[..3].copy_from_slice(&[65, 67, 75]);
+    report[9] = input;
+    report[10] = state;
+
+    report
+}
+
+/// Abstracts HID calls made to the device, so device handling can run without hardware
+pub trait DeviceTransport: Send + Sync {
+    /// Vendor ID of the device
+    fn vid(&self) -> u16;
+
+    /// Product ID of the device
+    fn pid(&self) -> u16;
+
+    /// Sets brightness of the device, value range is 0 - 100
+    fn set_brightness(&self, percent: u8)
+    -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Queues button image, must be flushed to appear on the device
+    fn set_button_image(
+        &self,
+        key: u8,
+        format: ImageFormat,
+        quality: u8,
+        image: DynamicImage,
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Sets button image to blank, must be flushed to appear on the device
+    fn clear_button_image(&self, key: u8)
+    -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Sets every button image to blank, must be flushed to appear on the device
+    fn clear_all_button_images(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Sends queued images to the device
+    fn flush(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Re-initializes the device with the protocol's `DIS` command, then blanks every display and
+    /// sets brightness to 100
+    fn reset(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Blanks the displays and puts device to sleep
+    fn shutdown(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Sends a raw command, the part after the `CRT` prefix, for commands not every firmware has
+    fn send_command(
+        &self,
+        command: &[u8],
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Reads a single raw input report, returns [None] if timeout was reached first
+    fn read_report(
+        &self,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, MirajazzError>> + Send;
+
+    /// Bytes of image data sent to the device so far
+    fn uploaded_bytes(&self) -> u64;
+
+    /// Whether the vendor command interface of the device was opened
+    fn has_vendor_interface(&self) -> bool {
+        false
+    }
+
+    /// Sends a raw command through the vendor command interface, the part after the `CRT` prefix
+    ///
+    /// Fails with [MirajazzError::UnsupportedOperation] if the device has no such interface.
+    fn send_vendor_command(
+        &self,
+        _command: &[u8],
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        async { Err(MirajazzError::UnsupportedOperation) }
+    }
+}
+
+/// How image data is paced on its way to the device
+///
+/// Reports have a fixed size, so a chunk is how much image data goes out back to back before
+/// pausing. Slow USB 2.0 hubs can drop reports sent too quickly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadPacing {
+    /// Bytes of image data sent before each pause, 0 never pauses
+    pub chunk: usize,
+    /// Pause between chunks
+    pub delay: Duration,
+}
+
+impl Default for UploadPacing {
+    /// Windows queues overlapped writes, a short pause now and then lets slow hubs catch up.
+    /// Writes wait for the transfer elsewhere, so they don't need one.
+    fn default() -> Self {
+        if cfg!(target_os = "windows") {
+            Self {
+                chunk: 16 * 1024,
+                delay: Duration::from_millis(1),
+            }
+        } else {
+            Self {
+                chunk: 0,
+                delay: Duration::ZERO,
+            }
+        }
+    }
+}
+
+impl UploadPacing {
+    /// Checks if sending has to pause after `sent` bytes of data since the last pause
+    pub fn pauses_after(&self, sent: usize) -> bool {
+        self.chunk > 0 && !self.delay.is_zero() && sent >= self.chunk
+    }
+}
+
+/// Length of image data as the upload header has it, there's only room for 16 bits
+fn image_length(data: &[u8]) -> Result<u16, MirajazzError> {
+    u16::try_from(data.len()).map_err(|_| {
+        let reason = format!(
+            "Image is {} bytes, at most {} fit in an upload",
+            data.len(),
+            u16::MAX
+        );
+
+        ImageError::Encoding(EncodingError::new(ImageFormatHint::Unknown, reason)).into()
+    })
+}
+
+/// Reports uploading an image to a key, the header and the data split into `packet_size` pieces
+///
+/// Same as mirajazz sends them, every report starts with the report ID 0 and is padded. Fails if
+/// the data is too long for the header.
+fn image_reports(key: u8, data: &[u8], packet_size: usize) -> Result<Vec<Vec<u8>>, MirajazzError> {
+    let report = |bytes: &[u8]| {
+        let mut report = Vec::with_capacity(packet_size + 1);
+        report.push(0x00);
+        report.extend_from_slice(bytes);
+        report.resize(packet_size + 1, 0);
+
+        report
+    };
+
+    let length = image_length(data)?;
+    let header = [
+        0x43,
+        0x52,
+        0x54,
+        0x00,
+        0x00,
+        0x42,
+        0x41,
+        0x54,
+        0x00,
+        0x00,
+        (length >> 8) as u8,
+        length as u8,
+        key + 1,
+    ];
+
+    Ok(std::iter::once(report(&header))
+        .chain(data.chunks(packet_size).map(report))
+        .collect())
+}
+
+/// Runs commands made of several writes one at a time, so others can't land in between
+///
+/// An image upload is split into many reports, a brightness change sent halfway through it
+/// corrupts the frame. Tokio's mutex is fair, commands go out in the order they were started.
+#[derive(Debug, Default)]
+pub struct Transactions(tokio::sync::Mutex<()>);
+
+impl Transactions {
+    /// Runs `transaction` once every one started before it is done
+    pub async fn run<T>(&self, transaction: impl Future<Output = T>) -> T {
+        let _turn = self.0.lock().await;
+
+        transaction.await
+    }
+}
+
+/// Transport backed by a real device connected through mirajazz
+pub struct HidTransport {
+    device: Device,
+    reader: Arc<DeviceStateReader>,
+    tiles: Option<Arc<TileCache>>,
+    uploaded: AtomicU64,
+    transactions: Transactions,
+    // Images waiting for the flush, mirajazz's own queue can't be paced
+    queued: tokio::sync::Mutex<Vec<(u8, Arc<Vec<u8>>)>>,
+    packet_size: usize,
+    pacing: UploadPacing,
+    // Secondary interface for configuration commands, only some models have it
+    vendor: Option<Device>,
+}
+
+impl HidTransport {
+    /// Opens the device, input reports can be read right away
+    pub async fn connect(candidate: &CandidateDevice) -> Result<Self, MirajazzError> {
+        let device = Device::connect(
+            &candidate.dev,
+            candidate.kind.protocol_version(),
+            candidate.kind.key_count(),
+            candidate.kind.encoder_count(),
+        )
+        .await?;
+
+        // Reports are decoded on our side, so the reader is only used for raw reads
+        let reader = device.get_reader(|_, _| Ok(DeviceInput::NoData));
+
+        Ok(Self {
+            device,
+            reader,
+            tiles: None,
+            uploaded: AtomicU64::new(0),
+            transactions: Transactions::default(),
+            queued: tokio::sync::Mutex::new(vec![]),
+            packet_size: match candidate.kind.protocol_version() {
+                0 | 1 => 512,
+                _ => 1024,
+            },
+            pacing: UploadPacing::default(),
+            vendor: None,
+        })
+    }
+
+    /// Takes encoded images from `tiles`, images shown before aren't encoded again
+    pub fn with_tiles(mut self, tiles: Arc<TileCache>) -> Self {
+        self.tiles = Some(tiles);
+        self
+    }
+
+    /// Paces image uploads other than the default for the platform
+    pub fn with_pacing(mut self, pacing: UploadPacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Also opens the vendor command interface of the device, if it has one
+    ///
+    /// The device works without it, so failing to open it is only logged.
+    pub async fn with_vendor_interface(mut self, candidate: &CandidateDevice) -> Self {
+        let Some(dev) = find_vendor_interface(candidate).await else {
+            log::debug!("{} has no vendor interface", candidate.id);
+            return self;
+        };
+
+        let vendor = Device::connect(
+            &dev,
+            candidate.kind.protocol_version(),
+            candidate.kind.key_count(),
+            candidate.kind.encoder_count(),
+        )
+        .await;
+
+        match vendor {
+            Ok(vendor) => {
+                log::info!("Opened vendor interface of {}", candidate.id);
+                self.vendor = Some(vendor);
+            }
+            Err(err) => log::warn!(
+                "Failed to open vendor interface of {}: {}",
+                candidate.id,
+                err
+            ),
+        }
+
+        self
+    }
+}
+
+impl DeviceTransport for HidTransport {
+    fn vid(&self) -> u16 {
+        self.device.vid
+    }
+
+    fn pid(&self) -> u16 {
+        self.device.pid
+    }
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+        self.transactions
+            .run(self.device.set_brightness(percent))
+            .await
+    }
+
+    async fn set_button_image(
+        &self,
+        key: u8,
+        format: ImageFormat,
+        quality: u8,
+        image: DynamicImage,
+    ) -> Result<(), MirajazzError> {
+        // Encoded on our side, mirajazz always uses the same JPEG quality
+        let tiles = self.tiles.clone();
+        let data = spawn_encode(move || match tiles {
+            Some(tiles) => tiles.encode(format, quality, &image),
+            None => encode_image(format, quality, image).map(Arc::new),
+        })
+        .await?;
+
+        // Checked before queueing, a queued image that can't be sent would fail every flush
+        image_length(&data)?;
+
+        // Only queued, the flush sends it in pieces
+        self.uploaded
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.queued.lock().await.push((key, data));
+
+        Ok(())
+    }
+
+    async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+        self.transactions
+            .run(self.device.clear_button_image(key))
+            .await
+    }
+
+    async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+        self.transactions
+            .run(self.device.clear_all_button_images())
+            .await
+    }
+
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        self.transactions
+            .run(async {
+                let mut queued = self.queued.lock().await;
+
+                if queued.is_empty() {
+                    return Ok(());
+                }
+
+                let mut sent = 0;
+
+                for (key, data) in queued.iter() {
+                    for report in image_reports(*key, data, self.packet_size)? {
+                        self.device.write_data(&report).await?;
+                        sent += report.len() - 1;
+
+                        if self.pacing.pauses_after(sent) {
+                            tokio::time::sleep(self.pacing.delay).await;
+                            sent = 0;
+                        }
+                    }
+                }
+
+                // Images only show up once the device is told they're all there
+                let mut stop = vec![0x00, 0x43, 0x52, 0x54, 0x00, 0x00, 0x53, 0x54, 0x50];
+                self.device.write_extended_data(&mut stop).await?;
+
+                // Kept on failure, like mirajazz does, so the next flush sends them again
+                queued.clear();
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn reset(&self) -> Result<(), MirajazzError> {
+        // mirajazz only sends DIS the first time, its reset would just blank the displays
+        self.send_command(b"DIS").await?;
+
+        self.transactions.run(self.device.reset()).await
+    }
+
+    async fn shutdown(&self) -> Result<(), MirajazzError> {
+        self.transactions.run(self.device.shutdown()).await
+    }
+
+    async fn send_command(&self, command: &[u8]) -> Result<(), MirajazzError> {
+        let mut buf = vec![0x00, 0x43, 0x52, 0x54, 0x00, 0x00];
+        buf.extend_from_slice(command);
+
+        self.transactions
+            .run(self.device.write_extended_data(&mut buf))
+            .await
+    }
+
+    async fn read_report(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, MirajazzError> {
+        match timeout {
+            Some(timeout) => {
+                self.reader
+                    .raw_read_data_with_timeout(REPORT_LENGTH, timeout)
+                    .await
+            }
+            None => Ok(Some(self.reader.raw_read_data(REPORT_LENGTH).await?)),
+        }
+    }
+
+    fn uploaded_bytes(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    fn has_vendor_interface(&self) -> bool {
+        self.vendor.is_some()
+    }
+
+    async fn send_vendor_command(&self, command: &[u8]) -> Result<(), MirajazzError> {
+        let Some(vendor) = &self.vendor else {
+            return Err(MirajazzError::UnsupportedOperation);
+        };
+
+        let mut buf = vec![0x00, 0x43, 0x52, 0x54, 0x00, 0x00];
+        buf.extend_from_slice(command);
+
+        vendor.write_extended_data(&mut buf).await
+    }
+}
+
+/// Fake device for testing code that drives the deck without hardware, needs the `mock` feature
+#[cfg(any(test, feature = "mock"))]
+pub mod mock {
+    use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+    use image::{DynamicImage, GenericImageView};
+    use mirajazz::error::MirajazzError;
+
+    use super::{DeviceTransport, build_report};
+    use crate::images::ImageFormat;
+    use crate::mappings::{AJAZZ_VID, AKP05E_PID};
+
+    /// Write that was made to the mock device
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum MockWrite {
+        Brightness(u8),
+        Image { key: u8, size: (u32, u32) },
+        Clear(u8),
+        ClearAll,
+        Flush,
+        Reset,
+        Shutdown,
+        Command(Vec<u8>),
+        VendorCommand(Vec<u8>),
+    }
+
+    /// Transport that records writes and replays scripted input reports
+    #[derive(Default)]
+    pub struct MockTransport {
+        writes: Mutex<Vec<MockWrite>>,
+        reports: Mutex<VecDeque<Vec<u8>>>,
+        uploaded: Mutex<u64>,
+        vendor: bool,
+        screenless: Vec<u8>,
+    }
+
+    impl MockTransport {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Builds a report the way the device sends it, see [build_report]
+        pub fn report(input: u8, state: u8) -> Vec<u8> {
+            build_report(input, state)
+        }
+
+        /// Queues a raw report to be returned by the next read
+        pub fn inject(&self, report: Vec<u8>) {
+            self.reports.lock().unwrap().push_back(report);
+        }
+
+        /// Gives the mock device a vendor command interface
+        pub fn with_vendor_interface(mut self) -> Self {
+            self.vendor = true;
+            self
+        }
+
+        /// Makes images and clears of a key fail, like on a device without a display there
+        pub fn without_screen(mut self, key: u8) -> Self {
+            self.screenless.push(key);
+            self
+        }
+
+        /// Returns and forgets writes recorded so far
+        pub fn take_writes(&self) -> Vec<MockWrite> {
+            std::mem::take(&mut self.writes.lock().unwrap())
+        }
+
+        fn record(&self, write: MockWrite) -> Result<(), MirajazzError> {
+            self.writes.lock().unwrap().push(write);
+
+            Ok(())
+        }
+    }
+
+    impl DeviceTransport for MockTransport {
+        fn vid(&self) -> u16 {
+            AJAZZ_VID
+        }
+
+        fn pid(&self) -> u16 {
+            AKP05E_PID
+        }
+
+        async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+            self.record(MockWrite::Brightness(percent))
+        }
+
+        async fn set_button_image(
+            &self,
+            key: u8,
+            _format: ImageFormat,
+            _quality: u8,
+            image: DynamicImage,
+        ) -> Result<(), MirajazzError> {
+            if self.screenless.contains(&key) {
+                return Err(MirajazzError::NoScreen);
+            }
+
+            // Nothing is encoded here, raw pixels stand in for the data
+            *self.uploaded.lock().unwrap() += image.as_bytes().len() as u64;
+
+            self.record(MockWrite::Image {
+                key,
+                size: image.dimensions(),
+            })
+        }
+
+        async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+            if self.screenless.contains(&key) {
+                return Err(MirajazzError::NoScreen);
+            }
+
+            self.record(MockWrite::Clear(key))
+        }
+
+        async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+            self.record(MockWrite::ClearAll)
+        }
+
+        async fn flush(&self) -> Result<(), MirajazzError> {
+            self.record(MockWrite::Flush)
+        }
+
+        async fn reset(&self) -> Result<(), MirajazzError> {
+            self.record(MockWrite::Reset)
+        }
+
+        async fn shutdown(&self) -> Result<(), MirajazzError> {
+            self.record(MockWrite::Shutdown)
+        }
+
+        async fn send_command(&self, command: &[u8]) -> Result<(), MirajazzError> {
+            self.record(MockWrite::Command(command.to_vec()))
+        }
+
+        async fn read_report(
+            &self,
+            _timeout: Option<Duration>,
+        ) -> Result<Option<Vec<u8>>, MirajazzError> {
+            Ok(self.reports.lock().unwrap().pop_front())
+        }
+
+        fn uploaded_bytes(&self) -> u64 {
+            *self.uploaded.lock().unwrap()
+        }
+
+        fn has_vendor_interface(&self) -> bool {
+            self.vendor
+        }
+
+        async fn send_vendor_command(&self, command: &[u8]) -> Result<(), MirajazzError> {
+            if !self.vendor {
+                return Err(MirajazzError::UnsupportedOperation);
+            }
+
+            self.record(MockWrite::VendorCommand(command.to_vec()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn images_are_split_into_padded_reports() {
+        let data = vec![7; 1500];
+        let reports = image_reports(4, &data, 1024).unwrap();
+
+        assert_eq!(reports.len(), 3);
+        assert!(reports.iter().all(|report| report.len() == 1025));
+        assert_eq!(
+            &reports[0][..7],
+            &[0x00, 0x43, 0x52, 0x54, 0x00, 0x00, 0x42]
+        );
+        assert_eq!(&reports[0][11..14], &[0x05, 0xDC, 5]);
+        assert_eq!(reports[2][476], 7);
+        assert_eq!(reports[2][477], 0);
+
+        let pacing = UploadPacing {
+            chunk: 2048,
+            delay: Duration::from_millis(1),
+        };
+        assert!(!pacing.pauses_after(1024));
+        assert!(pacing.pauses_after(2048));
+        assert!(!UploadPacing { chunk: 0, ..pacing }.pauses_after(4096));
+    }
+
+    #[test]
+    fn images_too_long_for_the_header_are_rejected() {
+        let data = vec![7; u16::MAX as usize];
+        assert_eq!(
+            &image_reports(4, &data, 1024).unwrap()[0][11..13],
+            &[0xFF, 0xFF]
+        );
+
+        let data = vec![7; u16::MAX as usize + 1];
+        assert!(matches!(
+            image_reports(4, &data, 1024),
+            Err(MirajazzError::ImageError(ImageError::Encoding(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn transactions_dont_interleave() {
+        let transactions = Transactions::default();
+        let writes = Mutex::new(vec![]);
+        let write = |report: &'static str| writes.lock().unwrap().push(report);
+
+        let upload = transactions.run(async {
+            write("image 1/2");
+            tokio::task::yield_now().await;
+            write("image 2/2");
+        });
+        let brightness = transactions.run(async { write("brightness") });
+
+        tokio::join!(upload, brightness);
+
+        assert_eq!(
+            *writes.lock().unwrap(),
+            vec!["image 1/2", "image 2/2", "brightness"]
+        );
+    }
+}
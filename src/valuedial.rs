@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use akp05::text::{TextStyle, render_lines};
+use image::Rgb;
+use openaction::SettingsValue;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::writer::KeyPainter;
+
+/// How often value dials are redrawn, frames are only sent when the text changes
+pub const VALUE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Size value dials are rendered at, images are resized to the device format anyway
+pub const VALUE_SIZE: (u32, u32) = (120, 120);
+
+/// Values that aren't confirmed within this long are dropped, the dial shows the set one again
+pub const VALUE_EDIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Digits after the point are taken from the step, but never more than these
+const MAX_DECIMALS: usize = 3;
+
+// Value dial actions by their OpenDeck context
+static DIALS: LazyLock<Mutex<HashMap<String, ValueDial>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Number set by turning an encoder and confirmed by pressing it, kept in action settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueDial {
+    pub device: String,
+    /// Strip zone of the encoder, the value is drawn there
+    pub position: u8,
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    /// Drawn under the value, e.g. what it's for
+    pub label: String,
+    // Value being picked and when the dial was last turned, [None] while not editing
+    editing: Option<(f64, Instant)>,
+}
+
+impl ValueDial {
+    /// Dial from action settings, missing or invalid ones fall back to 0-100 in steps of 1
+    pub fn from_settings(device: String, position: u8, settings: &SettingsValue) -> Self {
+        let number = |key: &str| settings.get(key).and_then(|value| value.as_f64());
+
+        let min = number("min").unwrap_or(0.0);
+        let max = number("max")
+            .filter(|max| *max > min)
+            .unwrap_or(min + 100.0);
+        let step = number("step").filter(|step| *step > 0.0).unwrap_or(1.0);
+
+        Self {
+            device,
+            position,
+            value: number("value").unwrap_or(min).clamp(min, max),
+            min,
+            max,
+            step,
+            label: settings
+                .get("label")
+                .and_then(|label| label.as_str())
+                .unwrap_or("VALUE")
+                .to_string(),
+            editing: None,
+        }
+    }
+
+    /// Action settings with the confirmed value, so it survives restarts
+    pub fn to_settings(&self) -> SettingsValue {
+        json!({
+            "value": self.value,
+            "min": self.min,
+            "max": self.max,
+            "step": self.step,
+            "label": self.label,
+        })
+    }
+
+    /// Moves the value being picked by `ticks` steps, starting from the set one
+    pub fn rotate(&mut self, ticks: i16, now: Instant) {
+        let current = self.editing.map_or(self.value, |(value, _)| value);
+        let value = (current + ticks as f64 * self.step).clamp(self.min, self.max);
+
+        // Steps are counted from the minimum, so the value doesn't drift with float errors
+        let steps = ((value - self.min) / self.step).round();
+        let value = (self.min + steps * self.step).clamp(self.min, self.max);
+
+        self.editing = Some((value, now));
+    }
+
+    /// Sets the value being picked, returning false if nothing was picked
+    pub fn confirm(&mut self) -> bool {
+        let Some((value, _)) = self.editing.take() else {
+            return false;
+        };
+
+        self.value = value;
+        true
+    }
+
+    /// Drops the value being picked once it wasn't turned for [VALUE_EDIT_TIMEOUT]
+    pub fn expire(&mut self, now: Instant) {
+        if self
+            .editing
+            .is_some_and(|(_, turned)| now.duration_since(turned) >= VALUE_EDIT_TIMEOUT)
+        {
+            self.editing = None;
+        }
+    }
+
+    pub fn is_editing(&self) -> bool {
+        self.editing.is_some()
+    }
+
+    /// Value and label to draw, or the value being picked and a hint to press
+    pub fn lines(&self) -> Vec<String> {
+        match self.editing {
+            Some((value, _)) => vec![self.format(value), "PRESS TO SET".to_string()],
+            None => vec![self.format(self.value), self.label.clone()],
+        }
+    }
+
+    /// Value with as many decimals as the step has
+    fn format(&self, value: f64) -> String {
+        let decimals = self
+            .step
+            .to_string()
+            .split_once('.')
+            .map_or(0, |(_, fraction)| fraction.len().min(MAX_DECIMALS));
+
+        format!("{:.*}", decimals, value)
+    }
+}
+
+pub fn add_dial(context: &str, dial: ValueDial) {
+    DIALS.lock().unwrap().insert(context.to_string(), dial);
+}
+
+pub fn remove_dial(context: &str) {
+    DIALS.lock().unwrap().remove(context);
+}
+
+/// Takes settings changed elsewhere, a value being picked is dropped
+pub fn update_settings(context: &str, settings: &SettingsValue) {
+    let mut dials = DIALS.lock().unwrap();
+
+    if let Some(dial) = dials.get_mut(context) {
+        *dial = ValueDial::from_settings(dial.device.clone(), dial.position, settings);
+    }
+}
+
+/// Applies `change` to a dial, returning what it returns if the dial exists
+pub fn update_dial<T>(context: &str, change: impl FnOnce(&mut ValueDial) -> T) -> Option<T> {
+    DIALS.lock().unwrap().get_mut(context).map(change)
+}
+
+/// Checks if a value dial is drawn on the position, so OpenDeck images for it are ignored
+pub fn draws_position(device: &str, position: u8) -> bool {
+    DIALS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|dial| dial.device == device && dial.position == position)
+}
+
+/// Draws every value dial on its device, values being picked are highlighted
+pub async fn value_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(VALUE_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let now = Instant::now();
+        let frames: Vec<(String, u8, Vec<String>, bool)> = DIALS
+            .lock()
+            .unwrap()
+            .values_mut()
+            .map(|dial| {
+                dial.expire(now);
+
+                (
+                    dial.device.clone(),
+                    dial.position,
+                    dial.lines(),
+                    dial.is_editing(),
+                )
+            })
+            .collect();
+
+        painter.retain(|device, position| {
+            frames
+                .iter()
+                .any(|(id, other, ..)| id == device && *other == position)
+        });
+
+        for (device, position, lines, editing) in frames {
+            let image_lines = lines.clone();
+
+            painter
+                .paint_device(&device, position, lines, || {
+                    let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+                    let style = if editing {
+                        TextStyle {
+                            color: Rgb([0, 0, 0]),
+                            background: Rgb([255, 200, 0]),
+                        }
+                    } else {
+                        TextStyle::default()
+                    };
+
+                    render_lines(VALUE_SIZE, &lines, &[2, 1], style)
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turned_values_are_only_set_once_confirmed() {
+        let now = Instant::now();
+        let settings = json!({ "value": 0.5, "min": 0, "max": 1, "step": 0.1, "label": "GAIN" });
+        let mut dial = ValueDial::from_settings("a5-test".to_string(), 2, &settings);
+
+        assert_eq!(dial.lines(), ["0.5", "GAIN"]);
+
+        dial.rotate(3, now);
+        assert_eq!(dial.lines(), ["0.8", "PRESS TO SET"]);
+        assert_eq!(dial.value, 0.5);
+
+        // Turning past the end stops there
+        dial.rotate(10, now);
+        assert!(dial.confirm());
+        assert_eq!(dial.value, 1.0);
+        assert_eq!(dial.to_settings()["value"], json!(1.0));
+        assert!(!dial.confirm());
+
+        // Left alone, the dial goes back to the set value
+        dial.rotate(-4, now);
+        dial.expire(now + VALUE_EDIT_TIMEOUT);
+        assert_eq!(dial.lines(), ["1.0", "GAIN"]);
+        assert!(!dial.confirm());
+    }
+
+    #[test]
+    fn settings_fall_back_to_defaults() {
+        let dial = ValueDial::from_settings("a5-test".to_string(), 0, &json!({ "max": -5 }));
+
+        assert_eq!((dial.min, dial.max, dial.step), (0.0, 100.0, 1.0));
+        assert_eq!(dial.lines(), ["0", "VALUE"]);
+    }
+}
@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+use akp05::text::{TextStyle, render_lines};
+use mirajazz::state::DeviceStateUpdate;
+use tokio_util::sync::CancellationToken;
+
+use crate::{CONFIG, WRITERS, mixer::target_volume, writer::KeyPainter};
+
+/// Encoder bound to the system volume, the leftmost one
+pub const VOLUME_DIAL: u8 = 0;
+
+/// How often the system volume is checked
+pub const VOLUME_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Size the level is rendered at, images are resized to the device format anyway
+pub const VOLUME_SIZE: (u32, u32) = (120, 120);
+
+/// Volume of the default output device in percent and whether it's muted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Level {
+    pub volume: u32,
+    pub muted: bool,
+}
+
+#[cfg(windows)]
+mod endpoint {
+    use windows::Win32::{
+        Media::Audio::{
+            Endpoints::IAudioEndpointVolume, IMMDeviceEnumerator, MMDeviceEnumerator, eConsole,
+            eRender,
+        },
+        System::Com::{
+            CLSCTX_ALL, COINIT_MULTITHREADED, CoCreateInstance, CoInitializeEx, CoUninitialize,
+        },
+    };
+
+    use super::Level;
+
+    /// Runs `f` on the endpoint volume of the default output device, blocks the thread
+    fn with_endpoint<T>(
+        f: impl FnOnce(&IAudioEndpointVolume) -> windows::core::Result<T>,
+    ) -> Result<T, String> {
+        let initialized = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.is_ok();
+
+        let result = unsafe {
+            CoCreateInstance::<_, IMMDeviceEnumerator>(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .and_then(|enumerator| enumerator.GetDefaultAudioEndpoint(eRender, eConsole))
+                .and_then(|device| device.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None))
+                .and_then(|volume| f(&volume))
+        };
+
+        // Interfaces are released above, before COM goes away
+        if initialized {
+            unsafe { CoUninitialize() };
+        }
+
+        result.map_err(|err| err.to_string())
+    }
+
+    pub fn level() -> Result<Level, String> {
+        with_endpoint(|endpoint| unsafe {
+            Ok(Level {
+                volume: (endpoint.GetMasterVolumeLevelScalar()? * 100.0).round() as u32,
+                muted: endpoint.GetMute()?.as_bool(),
+            })
+        })
+    }
+
+    pub fn set_volume(volume: u32) -> Result<(), String> {
+        with_endpoint(|endpoint| unsafe {
+            endpoint.SetMasterVolumeLevelScalar(volume.min(100) as f32 / 100.0, std::ptr::null())
+        })
+    }
+
+    pub fn toggle_mute() -> Result<(), String> {
+        with_endpoint(|endpoint| unsafe {
+            let muted = endpoint.GetMute()?.as_bool();
+
+            endpoint.SetMute(!muted, std::ptr::null())
+        })
+    }
+}
+
+#[cfg(not(windows))]
+mod endpoint {
+    use super::Level;
+
+    const UNSUPPORTED: &str = "System volume is only supported on Windows";
+
+    pub fn level() -> Result<Level, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn set_volume(_volume: u32) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn toggle_mute() -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+}
+
+/// Changes the system volume or mute, COM calls block so they run on their own thread
+fn apply(update: DeviceStateUpdate) {
+    tokio::task::spawn_blocking(move || {
+        let result = match update {
+            DeviceStateUpdate::EncoderTwist(_, ticks) => endpoint::level()
+                .and_then(|level| endpoint::set_volume(target_volume(level.volume, ticks))),
+            DeviceStateUpdate::EncoderDown(_) => endpoint::toggle_mute(),
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            log::warn!("Failed to change system volume: {}", err);
+        }
+    });
+}
+
+/// Checks if an update should reach OpenDeck, the volume dial changes the system volume instead
+pub fn filter(device: &str, update: &DeviceStateUpdate) -> bool {
+    let is_dial = match *update {
+        DeviceStateUpdate::EncoderDown(encoder)
+        | DeviceStateUpdate::EncoderUp(encoder)
+        | DeviceStateUpdate::EncoderTwist(encoder, _) => encoder == VOLUME_DIAL,
+        _ => false,
+    };
+
+    if !is_dial || !CONFIG.borrow().system_volume_on(device) {
+        return true;
+    }
+
+    apply(*update);
+
+    false
+}
+
+/// Checks if the level is drawn on the position, so OpenDeck images for it are ignored
+pub fn draws_position(device: &str, position: u8) -> bool {
+    position == VOLUME_DIAL && CONFIG.borrow().system_volume_on(device)
+}
+
+fn volume_lines(level: Option<Level>) -> Vec<String> {
+    let status = match level {
+        None => "-".to_string(),
+        Some(level) if level.muted => "MUTE".to_string(),
+        Some(level) => format!("{}%", level.volume),
+    };
+
+    vec!["VOLUME".to_string(), status]
+}
+
+/// Draws the system volume above the volume dial of devices in `system_volume_devices`
+pub async fn volume_task(token: CancellationToken) {
+    let mut painter = KeyPainter::default();
+    let mut warned = false;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(VOLUME_INTERVAL) => {}
+            _ = token.cancelled() => break,
+        }
+
+        let mut devices: Vec<String> = WRITERS.read().await.keys().cloned().collect();
+        devices.retain(|id| CONFIG.borrow().system_volume_on(id));
+
+        painter.retain(|id, _| devices.iter().any(|device| device == id));
+
+        if devices.is_empty() {
+            continue;
+        }
+
+        let level = match tokio::task::spawn_blocking(endpoint::level).await {
+            Ok(Ok(level)) => Some(level),
+            Ok(Err(err)) => {
+                if !warned {
+                    log::warn!("Failed to read system volume: {}", err);
+                    warned = true;
+                }
+
+                None
+            }
+            Err(_) => None,
+        };
+
+        let lines = volume_lines(level);
+        let image_lines = lines.clone();
+        let bar = CONFIG.borrow().progress_bar;
+        let shown = level
+            .filter(|level| !level.muted)
+            .map_or(0, |level| level.volume);
+
+        for device in devices {
+            painter
+                .paint_device(&device, VOLUME_DIAL, lines.clone(), || {
+                    let lines: Vec<&str> = image_lines.iter().map(String::as_str).collect();
+                    let mut image = render_lines(VOLUME_SIZE, &lines, &[], TextStyle::default());
+
+                    bar.draw_bottom(&mut image, shown);
+
+                    image
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_is_shown() {
+        assert_eq!(
+            volume_lines(Some(Level {
+                volume: 35,
+                muted: false
+            })),
+            ["VOLUME", "35%"]
+        );
+        assert_eq!(
+            volume_lines(Some(Level {
+                volume: 35,
+                muted: true
+            })),
+            ["VOLUME", "MUTE"]
+        );
+        assert_eq!(volume_lines(None), ["VOLUME", "-"]);
+    }
+}
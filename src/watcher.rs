@@ -1,6 +1,16 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use akp05::{
+    discovery::{device_info_to_candidate, device_path, get_candidates, serial_to_id},
+    mappings::{CandidateDevice, QUERIES},
+};
 use futures_lite::StreamExt;
 use mirajazz::{
-    device::{DeviceWatcher, list_devices},
+    device::DeviceWatcher,
     error::MirajazzError,
     types::{DeviceLifecycleEvent, HidDeviceInfo},
 };
@@ -8,69 +18,138 @@ use openaction::OUTBOUND_EVENT_MANAGER;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    DEVICES, TOKENS, TRACKER,
+    CONFIG, DEVICES, TOKENS, TRACKER, WRITERS,
     device::device_task,
-    mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES},
+    status::{self, Status},
 };
 
-fn serial_to_id(serial: &String) -> String {
-    format!("{}-{}", DEVICE_NAMESPACE, serial)
+/// Time old device tasks get to let go of their devices before they are opened again
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// IDs of spawned devices by device path, the port can't be read anymore once they are unplugged
+static IDS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Gives the candidate the kind of ID picked in settings
+fn with_configured_id(candidate: CandidateDevice) -> CandidateDevice {
+    if CONFIG.borrow().usb_port_ids {
+        candidate.with_port_id()
+    } else {
+        candidate
+    }
 }
 
-fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
-    let id = serial_to_id(&dev.serial_number.clone()?);
-    let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
+/// ID of a device that is gone
+fn disconnected_id(info: &HidDeviceInfo) -> Option<String> {
+    if let Some(id) = IDS.lock().unwrap().remove(&device_path(info)) {
+        return Some(id);
+    }
 
-    Some(CandidateDevice { id, dev, kind })
+    info.serial_number.as_deref().map(serial_to_id)
 }
 
-/// Returns devices that matches known pid/vid pairs
-async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
-    log::info!("Looking for candidate devices");
-    log::info!("Using {} device queries", QUERIES.len());
+async fn spawn_device(candidate: CandidateDevice) {
+    status::report(&candidate.id, Status::Discovered, None);
+
+    // Devices can be left to other plugins in settings
+    if !CONFIG.borrow().device_filter().allows(&candidate) {
+        log::info!(
+            "Device {} is excluded in settings, leaving it alone",
+            candidate.id
+        );
+        status::report(&candidate.id, Status::Removed, Some("Excluded in settings"));
+        return;
+    }
 
-    let mut candidates: Vec<CandidateDevice> = Vec::new();
+    IDS.lock()
+        .unwrap()
+        .insert(device_path(&candidate.dev), candidate.id.clone());
 
-    let devices = list_devices(&QUERIES).await?;
-    log::info!("Found {} raw devices from queries", devices.len());
+    let token = CancellationToken::new();
 
-    for dev in devices {
-        log::info!("Processing device: VID={:04x}, PID={:04x}, Serial={:?}", 
-                  dev.vendor_id, dev.product_id, dev.serial_number);
-        
-        if let Some(candidate) = device_info_to_candidate(dev.clone()) {
-            log::info!("Successfully created candidate for device");
-            candidates.push(candidate);
-        } else {
-            log::warn!("Failed to create candidate for device VID={:04x}, PID={:04x}", 
-                      dev.vendor_id, dev.product_id);
-            continue;
+    TOKENS
+        .write()
+        .await
+        .insert(candidate.id.clone(), token.clone());
+
+    log::debug!("Spawning task for new device: {:?}", candidate);
+    TRACKER.lock().await.spawn(device_task(candidate, token));
+}
+
+/// Stops the device task and tells OpenDeck the device is gone
+async fn remove_device(id: &str) {
+    if let Some(token) = TOKENS.write().await.remove(id) {
+        log::info!("Sending cancel request for {}", id);
+        token.cancel();
+    }
+
+    DEVICES.write().await.remove(id);
+    WRITERS.write().await.remove(id);
+
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        outbound.deregister_device(id.to_string()).await.ok();
+    }
+}
+
+/// Looks the device up among connected ones, e.g. to check it wasn't unplugged
+///
+/// The same device can come back on another path after it was enumerated again, the new one is
+/// remembered so unplugging it is still noticed.
+pub async fn find_candidate(id: &str) -> Option<CandidateDevice> {
+    let candidates = match get_candidates().await {
+        Ok(candidates) => candidates,
+        Err(err) => {
+            log::warn!("Failed to list devices: {}", err);
+            return None;
+        }
+    };
+
+    let candidate = candidates
+        .into_iter()
+        .map(with_configured_id)
+        .find(|candidate| candidate.id == id)?;
+
+    IDS.lock()
+        .unwrap()
+        .insert(device_path(&candidate.dev), candidate.id.clone());
+
+    Some(candidate)
+}
+
+/// Drops every open device and opens connected ones again, for handles that died without a word
+pub async fn reconnect_all() -> Result<(), MirajazzError> {
+    let ids: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+    for id in &ids {
+        log::info!("Reconnecting device {}", id);
+        status::report(id, Status::Reconnecting, None);
+        remove_device(id).await;
+    }
+
+    // Device tasks hold their claims until they finish shutting down
+    tokio::time::sleep(RECONNECT_DELAY).await;
+
+    for candidate in get_candidates().await?.into_iter().map(with_configured_id) {
+        if !DEVICES.read().await.contains_key(&candidate.id) {
+            spawn_device(candidate).await;
         }
     }
 
-    log::info!("Final candidates count: {}", candidates.len());
-    Ok(candidates)
+    Ok(())
 }
 
+/// Opens connected devices, then follows them being plugged in and out
+///
+/// Nothing here polls: the HID backend is told about devices by the system, through IOKit
+/// matching callbacks on macOS, udev/netlink events on Linux and device notifications on Windows.
 pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError> {
-    let tracker = TRACKER.lock().await.clone();
-
     // Scans for connected devices that (possibly) we can use
     let candidates = get_candidates().await?;
 
     log::info!("Looking for connected devices");
 
-    for candidate in candidates {
+    for candidate in candidates.into_iter().map(with_configured_id) {
         log::info!("New candidate {:#?}", candidate);
-
-        let token = CancellationToken::new();
-
-        TOKENS
-            .write()
-            .await
-            .insert(candidate.id.clone(), token.clone());
-
-        tracker.spawn(device_task(candidate, token));
+        spawn_device(candidate).await;
     }
 
     let mut watcher = DeviceWatcher::new();
@@ -89,39 +168,27 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
 
             match ev {
                 DeviceLifecycleEvent::Connected(info) => {
-                    if let Some(candidate) = device_info_to_candidate(info) {
+                    if let Some(candidate) = device_info_to_candidate(info).map(with_configured_id)
+                    {
                         // Don't add existing device again
                         if DEVICES.read().await.contains_key(&candidate.id) {
                             continue;
                         }
 
-                        let token = CancellationToken::new();
-
-                        TOKENS
-                            .write()
-                            .await
-                            .insert(candidate.id.clone(), token.clone());
-
-                        log::debug!("Spawning task for new device: {:?}", candidate);
-                        tracker.spawn(device_task(candidate, token));
+                        spawn_device(candidate).await;
                         log::debug!("Spawned");
                     }
                 }
                 DeviceLifecycleEvent::Disconnected(info) => {
-                    let id = serial_to_id(&info.serial_number.unwrap());
+                    let Some(id) = disconnected_id(&info) else {
+                        log::warn!("Disconnected device has no serial number, ignoring");
+                        continue;
+                    };
 
-                    if let Some(token) = TOKENS.write().await.remove(&id) {
-                        log::info!("Sending cancel request for {}", id);
-                        token.cancel();
-                    }
-
-                    DEVICES.write().await.remove(&id);
-
-                    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                        outbound.deregister_device(id.clone()).await.ok();
-                    }
+                    remove_device(&id).await;
 
                     log::info!("Disconnected device {}", id);
+                    status::report(&id, Status::Removed, Some("Device was unplugged"));
                 }
             }
         } else {
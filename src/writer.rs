@@ -0,0 +1,1926 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use image::DynamicImage;
+use tokio::sync::{
+    Notify,
+    mpsc::{self, Receiver, Sender, error::TrySendError},
+};
+use tokio_util::sync::CancellationToken;
+
+use akp05::{
+    deck::{handle_set_image, write_image},
+    error::{Akp05Error, ErrorContext, Operation},
+    images::{ColorCorrection, KeyImage, decode_data_url},
+    text::fallback_generation,
+    transport::DeviceTransport,
+};
+
+use crate::{
+    CONFIG, WRITERS,
+    badge::Badge,
+    blink::BlinkImage,
+    device::{request_redraw, reset_device},
+    dnd, duty, lock, menu,
+    press::PressEffect,
+    safemode::{self, handle_error},
+    screenshot, session, setup, splash, toast,
+};
+
+/// How many commands can wait for the device before updates start being merged
+pub const WRITER_QUEUE_SIZE: usize = 32;
+
+/// How long after a key is pressed or released images for it count as press feedback
+pub const FEEDBACK_WINDOW: Duration = Duration::from_secs(1);
+
+/// How long an open frame waits for more images before they are sent together
+pub const FRAME_SETTLE: Duration = Duration::from_millis(100);
+
+/// Longest a frame stays open, images collected so far are sent once it's over
+pub const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Command with the order it was sent in, shared by the queue and the priority lane
+type Queued = (u64, WriterCommand);
+
+/// Command for the device writer
+#[derive(Debug, Clone)]
+pub enum WriterCommand {
+    /// Sets (or clears, if image is [None]) image of a button, or clears every button if
+    /// position is [None]
+    SetImage {
+        position: Option<u8>,
+        image: Option<KeyImage>,
+    },
+    /// Sets or clears images of several buttons at once, the device is flushed only after the
+    /// last one so they change together
+    SetImages(Vec<(u8, Option<KeyImage>)>),
+    /// Same as [WriterCommand::SetImages] for a frame of a page switch, dropped once `page` is
+    /// cancelled because the page was switched again
+    SetPage {
+        images: Vec<(u8, Option<KeyImage>)>,
+        page: CancellationToken,
+    },
+    /// Sets brightness of the device
+    SetBrightness(u8),
+    /// Keeps brightness at most at this level while do-not-disturb is on, [None] restores the
+    /// one that was set
+    Dim(Option<u8>),
+    /// Re-initializes the device, the writer draws every image again afterwards
+    Reset,
+    /// Asks OpenDeck to send every image again, e.g. after image settings changed
+    Redraw,
+    /// Key was pressed or released, its image shows `press_effect` while it's held
+    Pressed { position: u8, pressed: bool },
+    /// Shows the other image of a blinking key, [None] shows the key image again
+    Blink {
+        position: u8,
+        image: Option<BlinkImage>,
+    },
+    /// Saves what the device shows as a PNG, once images queued before are written
+    Screenshot(PathBuf),
+    /// Draws a badge over the image of a key, or removes it if badge is [None]
+    ///
+    /// The badge stays while the key image changes, only the key it's on is written again.
+    Badge { position: u8, badge: Option<Badge> },
+    /// Shows an image over the key image for a while, [None] shows the key image again
+    Transient {
+        position: u8,
+        image: Option<KeyImage>,
+    },
+}
+
+/// Everything a device shows, kept when it disconnects so it can be drawn again right away
+#[derive(Debug, Clone, Default)]
+pub struct Framebuffer {
+    pub brightness: Option<u8>,
+    pub images: BTreeMap<u8, KeyImage>,
+    pub badges: BTreeMap<u8, Badge>,
+}
+
+// Device id to what it showed when its writer stopped
+static FRAMEBUFFERS: LazyLock<Mutex<HashMap<String, Framebuffer>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Checks if the device was connected before, so it has something to show right away
+pub fn has_framebuffer(id: &str) -> bool {
+    FRAMEBUFFERS.lock().unwrap().contains_key(id)
+}
+
+/// What the device showed before it disconnected, empty if it wasn't connected before
+pub fn take_framebuffer(id: &str) -> Framebuffer {
+    FRAMEBUFFERS.lock().unwrap().remove(id).unwrap_or_default()
+}
+
+/// Updates that didn't fit into the queue
+///
+/// Only the latest state is kept: newer image for the same button replaces the older one,
+/// clearing every button drops pending images, and only the last brightness value survives.
+/// Reset only redraws what the device showed before it, so images merged around it are kept
+/// and written after it.
+/// Once something is in here, everything goes in here until the writer drains it, so entries
+/// are always newer than anything still sitting in the queue.
+#[derive(Debug, Default)]
+struct Overflow {
+    reset: bool,
+    redraw: bool,
+    clear_all: bool,
+    images: BTreeMap<u8, (u64, Option<KeyImage>)>,
+    // Sequence of the latest merged command
+    sequence: u64,
+    brightness: Option<u8>,
+    dim: Option<Option<u8>>,
+    screenshot: Option<PathBuf>,
+    badges: BTreeMap<u8, Option<Badge>>,
+}
+
+impl Overflow {
+    fn is_empty(&self) -> bool {
+        !self.reset
+            && !self.redraw
+            && !self.clear_all
+            && self.images.is_empty()
+            && self.brightness.is_none()
+            && self.dim.is_none()
+            && self.screenshot.is_none()
+            && self.badges.is_empty()
+    }
+
+    fn merge(&mut self, (sequence, command): Queued) {
+        self.sequence = sequence;
+
+        match command {
+            WriterCommand::SetImage {
+                position: Some(position),
+                image,
+            } => {
+                self.images.insert(position, (sequence, image));
+            }
+            WriterCommand::SetImage { position: None, .. } => {
+                self.clear_all = true;
+                self.images.clear();
+            }
+            WriterCommand::SetPage { page, .. } if page.is_cancelled() => {}
+            // Batch is split up, still newer than anything in the queue
+            WriterCommand::SetImages(images) | WriterCommand::SetPage { images, .. } => {
+                self.images.extend(
+                    images
+                        .into_iter()
+                        .map(|(position, image)| (position, (sequence, image))),
+                )
+            }
+            WriterCommand::SetBrightness(brightness) => self.brightness = Some(brightness),
+            WriterCommand::Dim(level) => self.dim = Some(level),
+            WriterCommand::Reset => self.reset = true,
+            WriterCommand::Redraw => self.redraw = true,
+            WriterCommand::Screenshot(path) => self.screenshot = Some(path),
+            WriterCommand::Badge { position, badge } => {
+                self.badges.insert(position, badge);
+            }
+            // Always goes through the priority lane
+            WriterCommand::Pressed { .. }
+            | WriterCommand::Blink { .. }
+            | WriterCommand::Transient { .. } => {}
+        }
+    }
+
+    /// Checks if queued command is going to be overwritten by merged updates anyway
+    fn supersedes(&self, command: &WriterCommand) -> bool {
+        match command {
+            WriterCommand::SetImage {
+                position: Some(position),
+                ..
+            } => self.clear_all || self.images.contains_key(position),
+            WriterCommand::SetImage { position: None, .. } => self.clear_all,
+            WriterCommand::SetPage { page, .. } if page.is_cancelled() => true,
+            WriterCommand::SetImages(images) | WriterCommand::SetPage { images, .. } => {
+                self.clear_all
+                    || images
+                        .iter()
+                        .all(|(position, _)| self.images.contains_key(position))
+            }
+            WriterCommand::SetBrightness(_) => self.brightness.is_some(),
+            WriterCommand::Dim(_) => self.dim.is_some(),
+            WriterCommand::Reset => self.reset,
+            WriterCommand::Redraw => self.redraw,
+            WriterCommand::Badge { position, .. } => self.badges.contains_key(position),
+            WriterCommand::Pressed { .. }
+            | WriterCommand::Blink { .. }
+            | WriterCommand::Transient { .. }
+            | WriterCommand::Screenshot(_) => false,
+        }
+    }
+
+    fn drain(&mut self) -> Vec<Queued> {
+        let mut commands = vec![];
+        let sequence = self.sequence;
+
+        if std::mem::take(&mut self.reset) {
+            commands.push((sequence, WriterCommand::Reset));
+        }
+
+        if std::mem::take(&mut self.clear_all) {
+            commands.push((
+                sequence,
+                WriterCommand::SetImage {
+                    position: None,
+                    image: None,
+                },
+            ));
+        }
+
+        for (position, (sequence, image)) in std::mem::take(&mut self.images) {
+            commands.push((
+                sequence,
+                WriterCommand::SetImage {
+                    position: Some(position),
+                    image,
+                },
+            ));
+        }
+
+        for (position, badge) in std::mem::take(&mut self.badges) {
+            commands.push((sequence, WriterCommand::Badge { position, badge }));
+        }
+
+        if let Some(brightness) = self.brightness.take() {
+            commands.push((sequence, WriterCommand::SetBrightness(brightness)));
+        }
+
+        if let Some(level) = self.dim.take() {
+            commands.push((sequence, WriterCommand::Dim(level)));
+        }
+
+        if std::mem::take(&mut self.redraw) {
+            commands.push((sequence, WriterCommand::Redraw));
+        }
+
+        if let Some(path) = self.screenshot.take() {
+            commands.push((sequence, WriterCommand::Screenshot(path)));
+        }
+
+        commands
+    }
+}
+
+/// Presses and images of keys that were just pressed, written before anything in the queue
+#[derive(Debug, Default)]
+struct PriorityLane {
+    // Sequence of the last command sent, on either lane
+    sequence: u64,
+    commands: VecDeque<Queued>,
+    // When keys were last pressed or released
+    pressed: HashMap<u8, Instant>,
+}
+
+/// Images collected between [WriterHandle::begin_frame] and [WriterHandle::commit]
+#[derive(Debug)]
+struct Frame {
+    // Also tells frames apart, so a late timeout doesn't commit the next one
+    started: Instant,
+    last_image: Instant,
+    clear_all: bool,
+    images: BTreeMap<u8, Option<KeyImage>>,
+}
+
+impl Frame {
+    /// When the frame commits by itself if nobody does it before
+    fn deadline(&self) -> Instant {
+        (self.last_image + FRAME_SETTLE).min(self.started + FRAME_TIMEOUT)
+    }
+
+    /// Takes the image command into the frame, or gives it back if it isn't one
+    fn collect(&mut self, command: WriterCommand) -> Option<WriterCommand> {
+        match command {
+            WriterCommand::SetImage {
+                position: Some(position),
+                image,
+            } => {
+                self.images.insert(position, image);
+            }
+            WriterCommand::SetImage {
+                position: None,
+                image: None,
+            } => {
+                self.clear_all = true;
+                self.images.clear();
+            }
+            WriterCommand::SetImages(images) => self.images.extend(images),
+            command => return Some(command),
+        }
+
+        self.last_image = Instant::now();
+        None
+    }
+
+    fn into_commands(self) -> Vec<WriterCommand> {
+        let mut commands = vec![];
+
+        if self.clear_all {
+            commands.push(WriterCommand::SetImage {
+                position: None,
+                image: None,
+            });
+        }
+
+        if !self.images.is_empty() {
+            commands.push(WriterCommand::SetImages(self.images.into_iter().collect()));
+        }
+
+        commands
+    }
+}
+
+/// Sending half of the device writer, never blocks the caller
+#[derive(Clone)]
+pub struct WriterHandle {
+    sender: Sender<Queued>,
+    overflow: Arc<Mutex<Overflow>>,
+    lane: Arc<Mutex<PriorityLane>>,
+    notify: Arc<Notify>,
+    frame: Arc<Mutex<Option<Frame>>>,
+}
+
+impl WriterHandle {
+    /// Queues command for the device, merging it with other pending updates if the queue is full
+    ///
+    /// Images of keys pressed or released within [FEEDBACK_WINDOW] skip the queue, so press
+    /// feedback shows up even while a whole page is being uploaded.
+    pub fn send(&self, command: WriterCommand) {
+        let sequence = {
+            let mut lane = self.lane.lock().unwrap();
+            lane.sequence += 1;
+            let sequence = lane.sequence;
+
+            let feedback = match &command {
+                WriterCommand::SetImage {
+                    position: Some(position),
+                    ..
+                } => lane
+                    .pressed
+                    .get(position)
+                    .is_some_and(|at| at.elapsed() < FEEDBACK_WINDOW),
+                WriterCommand::Pressed { .. }
+                | WriterCommand::Blink { .. }
+                | WriterCommand::Transient { .. } => true,
+                _ => false,
+            };
+
+            if feedback {
+                lane.commands.push_back((sequence, command));
+                self.notify.notify_one();
+
+                return;
+            }
+
+            sequence
+        };
+
+        let command = match self.frame.lock().unwrap().as_mut() {
+            Some(frame) => match frame.collect(command) {
+                Some(command) => command,
+                None => return,
+            },
+            None => command,
+        };
+
+        let command = (sequence, command);
+        let mut overflow = self.overflow.lock().unwrap();
+
+        if !overflow.is_empty() {
+            overflow.merge(command);
+            self.notify.notify_one();
+
+            return;
+        }
+
+        match self.sender.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => {
+                log::warn!("Device writer queue is full, merging updates");
+
+                overflow.merge(command);
+                self.notify.notify_one();
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::debug!("Device writer is gone, dropping command");
+            }
+        }
+    }
+
+    /// Starts collecting images, so they are sent as one batch and change together on
+    /// [WriterHandle::commit]
+    ///
+    /// Meant for bursts like a profile switch, where OpenDeck sends every image separately.
+    /// The frame commits by itself once no image came for [FRAME_SETTLE], or after
+    /// [FRAME_TIMEOUT] at the latest. Press feedback and other commands aren't held back.
+    pub fn begin_frame(&self) {
+        let started = {
+            let mut frame = self.frame.lock().unwrap();
+            if frame.is_some() {
+                return;
+            }
+
+            let now = Instant::now();
+
+            *frame = Some(Frame {
+                started: now,
+                last_image: now,
+                clear_all: false,
+                images: BTreeMap::new(),
+            });
+
+            now
+        };
+
+        let handle = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let deadline = match handle.frame.lock().unwrap().as_ref() {
+                    Some(frame) if frame.started == started => frame.deadline(),
+                    _ => return,
+                };
+
+                if deadline <= Instant::now() {
+                    break;
+                }
+
+                tokio::time::sleep_until(deadline.into()).await;
+            }
+
+            handle.commit();
+        });
+    }
+
+    /// Sends images collected since [WriterHandle::begin_frame] in one go
+    pub fn commit(&self) {
+        let Some(frame) = self.frame.lock().unwrap().take() else {
+            return;
+        };
+
+        log::debug!("Committing frame of {} images", frame.images.len());
+
+        for command in frame.into_commands() {
+            self.send(command);
+        }
+    }
+
+    /// Marks a key as just pressed or released, images sent for it soon after are feedback
+    pub fn key_changed(&self, position: u8) {
+        self.lane
+            .lock()
+            .unwrap()
+            .pressed
+            .insert(position, Instant::now());
+    }
+}
+
+/// Draws plugin rendered content on every connected device, skipping frames that didn't change
+///
+/// Frames are described by their text lines, so nothing is rendered or sent over USB unless
+/// the text changes, a device connects or the position is painted again after being dropped.
+#[derive(Debug, Default)]
+pub struct KeyPainter {
+    // Device id and position to lines last drawn there
+    drawn: HashMap<(String, u8), Vec<String>>,
+    // Fallback fonts the lines were drawn with
+    fonts: u64,
+}
+
+impl KeyPainter {
+    /// Sends image from `render` to devices which don't show `lines` at the position yet
+    pub async fn paint(
+        &mut self,
+        position: u8,
+        lines: Vec<String>,
+        render: impl Fn() -> DynamicImage,
+    ) {
+        self.paint_where(|_| true, position, lines, render).await
+    }
+
+    /// Same as [KeyPainter::paint], but only for a single device
+    pub async fn paint_device(
+        &mut self,
+        device: &str,
+        position: u8,
+        lines: Vec<String>,
+        render: impl Fn() -> DynamicImage,
+    ) {
+        self.paint_where(|id| id == device, position, lines, render)
+            .await
+    }
+
+    /// Same as [KeyPainter::paint], but only for devices `filter` picks
+    pub async fn paint_where(
+        &mut self,
+        filter: impl Fn(&str) -> bool,
+        position: u8,
+        lines: Vec<String>,
+        render: impl Fn() -> DynamicImage,
+    ) {
+        let writers = WRITERS.read().await;
+        self.drawn.retain(|(id, _), _| writers.contains_key(id));
+
+        // Same lines can look different with other fonts
+        if self.fonts != fallback_generation() {
+            self.fonts = fallback_generation();
+            self.drawn.clear();
+        }
+
+        let mut image = None;
+
+        for (id, writer) in writers.iter().filter(|(id, _)| filter(id)) {
+            let key = (id.clone(), position);
+
+            // Splash, padlock, menu and toasts go over everything, forget the frame so it's drawn
+            // again after
+            if splash::draws_position(id, position)
+                || lock::draws_position(id, position)
+                || menu::draws_position(id, position)
+                || toast::draws_position(id, position)
+            {
+                self.drawn.remove(&key);
+                continue;
+            }
+
+            if self.drawn.get(&key) == Some(&lines) {
+                continue;
+            }
+
+            let image = image.get_or_insert_with(|| Arc::new(render()));
+
+            writer.send(WriterCommand::SetImage {
+                position: Some(position),
+                image: Some(KeyImage::Rendered(image.clone())),
+            });
+
+            self.drawn.insert(key, lines.clone());
+        }
+    }
+
+    /// Forgets positions that aren't painted anymore, so they are drawn again if they come back
+    pub fn retain(&mut self, keep: impl Fn(&str, u8) -> bool) {
+        self.drawn.retain(|(id, position), _| keep(id, *position));
+    }
+}
+
+/// Receiving half of the device writer
+pub struct WriterQueue {
+    receiver: Receiver<Queued>,
+    overflow: Arc<Mutex<Overflow>>,
+    lane: Arc<Mutex<PriorityLane>>,
+    notify: Arc<Notify>,
+}
+
+impl WriterQueue {
+    /// Waits for next batch of commands, returns [None] once every handle is dropped
+    ///
+    /// Batch is empty if only press feedback is waiting, see [Self::take_feedback].
+    async fn next(&mut self) -> Option<Vec<Queued>> {
+        loop {
+            if !self.lane.lock().unwrap().commands.is_empty() {
+                return Some(vec![]);
+            }
+
+            {
+                let mut overflow = self.overflow.lock().unwrap();
+
+                match self.receiver.try_recv() {
+                    Ok(command) if overflow.supersedes(&command.1) => continue,
+                    Ok(command) => {
+                        drop(overflow);
+                        return Some(self.coalesce(command));
+                    }
+                    // Queue is empty, so merged updates are the newest ones left
+                    Err(_) if !overflow.is_empty() => return Some(overflow.drain()),
+                    Err(_) => {}
+                }
+            }
+
+            tokio::select! {
+                command = self.receiver.recv() => {
+                    let command = command?;
+
+                    if !self.overflow.lock().unwrap().supersedes(&command.1) {
+                        return Some(self.coalesce(command));
+                    }
+                }
+                _ = self.notify.notified() => {}
+            }
+        }
+    }
+
+    /// Takes commands of the same kind queued right after `first` along, keeping only the last
+    ///
+    /// Only for settings where the latest value is all that matters, e.g. brightness turned with
+    /// a dial sends one command per tick and only the last one needs to reach the device.
+    fn coalesce(&mut self, first: Queued) -> Vec<Queued> {
+        let mut commands = vec![first];
+
+        loop {
+            let last = &commands[commands.len() - 1].1;
+
+            if !matches!(
+                last,
+                WriterCommand::SetBrightness(_) | WriterCommand::Dim(_) | WriterCommand::Redraw
+            ) {
+                break;
+            }
+
+            let Ok(next) = self.receiver.try_recv() else {
+                break;
+            };
+
+            if self.overflow.lock().unwrap().supersedes(&next.1) {
+                continue;
+            }
+
+            if std::mem::discriminant(last) == std::mem::discriminant(&next.1) {
+                log::debug!("Coalescing {:?} into {:?}", last, next.1);
+                commands.pop();
+                commands.push(next);
+            } else {
+                commands.push(next);
+                break;
+            }
+        }
+
+        commands
+    }
+
+    /// Oldest press feedback waiting
+    fn take_feedback(&self) -> Option<Queued> {
+        self.lane.lock().unwrap().commands.pop_front()
+    }
+}
+
+/// Creates a bounded writer queue for a device
+///
+/// Every device gets its own queue and writer task, so a device busy with animations only fills
+/// and merges its own queue, while writes to other devices go out at the same time. The upload
+/// limit is shared the same way, see [duty::uploaded].
+pub fn writer_channel() -> (WriterHandle, WriterQueue) {
+    let (sender, receiver) = mpsc::channel(WRITER_QUEUE_SIZE);
+    let overflow = Arc::new(Mutex::new(Overflow::default()));
+    let lane = Arc::new(Mutex::new(PriorityLane::default()));
+    let notify = Arc::new(Notify::new());
+
+    (
+        WriterHandle {
+            sender,
+            overflow: overflow.clone(),
+            lane: lane.clone(),
+            notify: notify.clone(),
+            frame: Arc::new(Mutex::new(None)),
+        },
+        WriterQueue {
+            receiver,
+            overflow,
+            lane,
+            notify,
+        },
+    )
+}
+
+/// Draws images rendered by the plugin again, OpenDeck only knows about its own ones
+async fn restore_rendered(
+    id: &str,
+    device: &impl DeviceTransport,
+    rendered: &BTreeMap<u8, Arc<DynamicImage>>,
+) -> Result<(), Akp05Error> {
+    let (quality, correction) = {
+        let config = CONFIG.borrow();
+        (config.jpeg_quality, config.color_correction(id))
+    };
+    let quality = safemode::quality(id, quality);
+
+    for (position, image) in rendered {
+        let image = KeyImage::Rendered(Arc::new(correction.apply(image)));
+
+        handle_set_image(id, device, Some(*position), Some(image), quality).await?;
+    }
+
+    Ok(())
+}
+
+/// Everything drawn over a key image, applied in one go
+#[derive(Debug, Clone, Default)]
+struct Overlays {
+    // Blinking keys without another image are shown dimmed
+    dimmed: bool,
+    badge: Option<Badge>,
+    effect: PressEffect,
+    correction: ColorCorrection,
+}
+
+impl Overlays {
+    fn is_empty(&self) -> bool {
+        !self.dimmed
+            && self.badge.is_none()
+            && self.effect == PressEffect::None
+            && self.correction.is_identity()
+    }
+
+    fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let mut image = Cow::Borrowed(image);
+
+        if self.dimmed {
+            image = Cow::Owned(PressEffect::Darken.apply(&image));
+        }
+
+        if let Some(badge) = &self.badge {
+            image = Cow::Owned(badge.apply(&image));
+        }
+
+        if self.effect != PressEffect::None {
+            image = Cow::Owned(self.effect.apply(&image));
+        }
+
+        if !self.correction.is_identity() {
+            image = Cow::Owned(self.correction.apply(&image));
+        }
+
+        image.into_owned()
+    }
+}
+
+/// Image `apply` drew from the decoded one, images that can't be decoded are left as they are
+/// since writing them fails anyway
+fn map_decoded(
+    image: Option<KeyImage>,
+    apply: impl FnOnce(&DynamicImage) -> DynamicImage,
+) -> Option<KeyImage> {
+    let applied = match &image {
+        Some(KeyImage::DataUrl(url)) => decode_data_url(url).ok().map(|decoded| apply(&decoded)),
+        Some(KeyImage::Rendered(rendered)) => Some(apply(rendered)),
+        None => None,
+    };
+
+    match applied {
+        Some(applied) => Some(KeyImage::Rendered(Arc::new(applied))),
+        None => image,
+    }
+}
+
+/// Brightness to show, `dim` caps the one that was set
+pub fn effective_brightness(brightness: u8, dim: Option<u8>) -> u8 {
+    dim.map_or(brightness, |level| level.min(brightness))
+}
+
+/// What the writer keeps track of between commands
+struct Writer<'a, D> {
+    id: &'a str,
+    device: &'a D,
+    // Remembered so it can be restored after a reset or do-not-disturb
+    brightness: u8,
+    dim: Option<u8>,
+    rendered: BTreeMap<u8, Arc<DynamicImage>>,
+    // Every image on the device, keys held with the press effect shown and blinking keys
+    // showing their other image
+    shown: BTreeMap<u8, KeyImage>,
+    held: HashSet<u8>,
+    blinking: HashMap<u8, BlinkImage>,
+    // Images shown over key images until they're reverted
+    transient: HashMap<u8, KeyImage>,
+    badges: BTreeMap<u8, Badge>,
+    // Sequence of the feedback image last written to a position, queued images older than
+    // that would overwrite it
+    feedback: HashMap<u8, u64>,
+    // Bytes the device had taken when last counted towards the upload limit
+    uploaded: u64,
+}
+
+impl<D: DeviceTransport> Writer<'_, D> {
+    /// JPEG quality to write images with, lower in safe mode
+    fn quality(&self) -> u8 {
+        safemode::quality(self.id, CONFIG.borrow().jpeg_quality)
+    }
+
+    /// Writes an image without flushing, in safe mode images that fail are tried again as text
+    ///
+    /// Decks mounted the other way round get it upside down on the key across from `position`.
+    async fn write(&mut self, position: u8, image: Option<KeyImage>) -> Result<(), Akp05Error> {
+        let quality = self.quality();
+        let orientation = setup::orientation(self.id);
+        let mounted = orientation.position(position);
+
+        let result = write_image(
+            self.id,
+            self.device,
+            mounted,
+            orientation.image(image.clone()),
+            quality,
+        )
+        .await;
+
+        let result = match result {
+            Err(err)
+                if image.is_some()
+                    && safemode::is_active(self.id)
+                    && safemode::is_upload_failure(&err) =>
+            {
+                log::warn!("{}, trying again with text only", err);
+
+                let image = orientation.image(Some(safemode::text_only(position)));
+                write_image(self.id, self.device, mounted, image, quality).await
+            }
+            result => result,
+        };
+
+        let uploaded = self.device.uploaded_bytes();
+        duty::uploaded(self.id, uploaded.saturating_sub(self.uploaded)).await;
+        self.uploaded = uploaded;
+
+        result
+    }
+
+    /// Writes an image and flushes it, so it shows up right away
+    async fn set_image(&mut self, position: u8, image: Option<KeyImage>) -> Result<(), Akp05Error> {
+        let operation = match image {
+            Some(_) => Operation::SetImage,
+            None => Operation::ClearImage,
+        };
+
+        self.write(position, image).await?;
+        self.device.flush().await.context(self.id, operation)
+    }
+
+    fn track(&mut self, position: u8, image: &Option<KeyImage>) {
+        match image {
+            Some(KeyImage::Rendered(image)) => self.rendered.insert(position, image.clone()),
+            _ => self.rendered.remove(&position),
+        };
+
+        match image {
+            Some(image) => self.shown.insert(position, image.clone()),
+            None => self.shown.remove(&position),
+        };
+    }
+
+    /// Device was cleared, only rendered images are going to be there again
+    fn forget_shown(&mut self) {
+        self.feedback.clear();
+        self.shown
+            .retain(|position, _| self.rendered.contains_key(position));
+    }
+
+    /// Image to write to a position, its transient image while there is one, its other image
+    /// while it blinks, with its badge and its pressed variant while the key is held, color
+    /// corrected for the device
+    async fn displayed(&self, position: u8, image: Option<KeyImage>) -> Option<KeyImage> {
+        let image = match self.transient.get(&position) {
+            Some(transient) => Some(transient.clone()),
+            None => image,
+        };
+
+        let mut overlays = {
+            let config = CONFIG.borrow();
+
+            Overlays {
+                badge: self.badges.get(&position).cloned(),
+                correction: config.color_correction(self.id),
+                ..Overlays::default()
+            }
+        };
+
+        if self.held.contains(&position) {
+            overlays.effect = CONFIG.borrow().press_effect;
+        }
+
+        let image = match self.blinking.get(&position) {
+            Some(BlinkImage::Image(other)) => Some(other.clone()),
+            Some(BlinkImage::Dimmed) => {
+                overlays.dimmed = true;
+                image
+            }
+            None => image,
+        };
+
+        if image.is_none() || overlays.is_empty() {
+            return image;
+        }
+
+        // Decoding and drawing is slow, other devices' writers shouldn't wait for it
+        tokio::task::spawn_blocking(move || map_decoded(image, |decoded| overlays.apply(decoded)))
+            .await
+            .unwrap_or_else(|err| {
+                log::error!(
+                    "Failed to draw over key {} of {}: {}",
+                    position,
+                    self.id,
+                    err
+                );
+                None
+            })
+    }
+
+    /// Shows the pressed variant of a key image while it's held, and the image itself after
+    async fn press(&mut self, position: u8, pressed: bool) -> Result<(), Akp05Error> {
+        if pressed {
+            if CONFIG.borrow().press_effect == PressEffect::None || !self.held.insert(position) {
+                return Ok(());
+            }
+        } else if !self.held.remove(&position) {
+            return Ok(());
+        }
+
+        // Keys without an image have nothing to show pressed
+        if !self.shown.contains_key(&position) && !self.transient.contains_key(&position) {
+            return Ok(());
+        }
+
+        let image = self
+            .displayed(position, self.shown.get(&position).cloned())
+            .await;
+
+        self.set_image(position, image).await
+    }
+
+    /// Switches a blinking key between its image and the other one
+    async fn blink(&mut self, position: u8, image: Option<BlinkImage>) -> Result<(), Akp05Error> {
+        match image {
+            Some(image) => self.blinking.insert(position, image),
+            None => self.blinking.remove(&position),
+        };
+
+        let image = self
+            .displayed(position, self.shown.get(&position).cloned())
+            .await;
+
+        self.set_image(position, image).await
+    }
+
+    /// Shows a transient image over a key image, or the key image again
+    async fn transient(&mut self, position: u8, image: Option<KeyImage>) -> Result<(), Akp05Error> {
+        match image {
+            Some(image) => {
+                self.transient.insert(position, image);
+            }
+            None if self.transient.remove(&position).is_none() => return Ok(()),
+            None => {}
+        }
+
+        let image = self
+            .displayed(position, self.shown.get(&position).cloned())
+            .await;
+
+        self.set_image(position, image).await
+    }
+
+    /// Draws a badge over a key image, or removes it, leaving the key image as it is
+    async fn badge(&mut self, position: u8, badge: Option<Badge>) -> Result<(), Akp05Error> {
+        let changed = match badge {
+            Some(badge) => self.badges.insert(position, badge.clone()) != Some(badge),
+            None => self.badges.remove(&position).is_some(),
+        };
+
+        // Keys without an image get their badge once they have one
+        if !changed || !self.shown.contains_key(&position) {
+            return Ok(());
+        }
+
+        let image = self
+            .displayed(position, self.shown.get(&position).cloned())
+            .await;
+
+        self.set_image(position, image).await
+    }
+
+    /// Draws every image the device should show again, e.g. after a reset or reconnect
+    async fn restore(&mut self) -> Result<(), Akp05Error> {
+        if self.shown.is_empty() && self.transient.is_empty() {
+            return Ok(());
+        }
+
+        let positions: BTreeSet<u8> = self
+            .shown
+            .keys()
+            .chain(self.transient.keys())
+            .copied()
+            .collect();
+
+        for position in positions {
+            let image = self
+                .displayed(position, self.shown.get(&position).cloned())
+                .await;
+            self.write(position, image).await?;
+        }
+
+        self.device
+            .flush()
+            .await
+            .context(self.id, Operation::SetImage)
+    }
+
+    /// Saves every image the way it's shown, failures are only logged
+    async fn screenshot(&self, path: &PathBuf) {
+        let mut images = BTreeMap::new();
+
+        for (position, image) in &self.shown {
+            let decoded = match self.displayed(*position, Some(image.clone())).await {
+                Some(KeyImage::DataUrl(url)) => decode_data_url(&url).ok(),
+                Some(KeyImage::Rendered(image)) => Some(Arc::unwrap_or_clone(image)),
+                None => None,
+            };
+
+            if let Some(decoded) = decoded {
+                images.insert(*position, decoded);
+            }
+        }
+
+        let target = path.clone();
+        let saved = tokio::task::spawn_blocking(move || screenshot::save(&target, &images))
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|saved| saved);
+
+        match saved {
+            Ok(()) => log::info!("Saved screenshot of {} to {:?}", self.id, path),
+            Err(err) => log::error!("Failed to save screenshot of {}: {}", self.id, err),
+        }
+    }
+
+    fn is_stale(&self, position: u8, sequence: u64) -> bool {
+        self.feedback
+            .get(&position)
+            .is_some_and(|written| *written > sequence)
+    }
+
+    /// Writes every press feedback image waiting in the priority lane
+    async fn write_feedback(&mut self, queue: &WriterQueue) -> Result<(), Akp05Error> {
+        while let Some((sequence, command)) = queue.take_feedback() {
+            log::debug!("Writing feedback {:?}", command);
+
+            match command {
+                WriterCommand::SetImage {
+                    position: Some(position),
+                    image,
+                } => {
+                    self.feedback.insert(position, sequence);
+                    self.track(position, &image);
+
+                    let image = self.displayed(position, image).await;
+                    self.set_image(position, image).await?;
+                }
+                WriterCommand::Pressed { position, pressed } => {
+                    self.press(position, pressed).await?
+                }
+                WriterCommand::Blink { position, image } => self.blink(position, image).await?,
+                WriterCommand::Transient { position, image } => {
+                    self.transient(position, image).await?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a batch of images, stopping early if `page` gets cancelled meanwhile
+    async fn set_images(
+        &mut self,
+        sequence: u64,
+        images: Vec<(u8, Option<KeyImage>)>,
+        page: Option<&CancellationToken>,
+        queue: &WriterQueue,
+    ) -> Result<(), Akp05Error> {
+        let (id, device) = (self.id, self.device);
+
+        for (position, image) in images {
+            // Feedback doesn't wait for the rest of a long batch, even if that shows
+            // the part written so far
+            self.write_feedback(queue).await?;
+
+            // The newer page is on its way, the images written so far are flushed anyway
+            if page.is_some_and(|page| page.is_cancelled()) {
+                break;
+            }
+
+            if self.is_stale(position, sequence) {
+                continue;
+            }
+
+            self.track(position, &image);
+
+            let image = self.displayed(position, image).await;
+            self.write(position, image).await?;
+        }
+
+        device.flush().await.context(id, Operation::SetImage)
+    }
+
+    async fn apply(
+        &mut self,
+        sequence: u64,
+        command: WriterCommand,
+        queue: &WriterQueue,
+    ) -> Result<(), Akp05Error> {
+        let (id, device) = (self.id, self.device);
+
+        log::debug!("Writing {:?}", command);
+
+        match command {
+            WriterCommand::SetImage {
+                position: Some(position),
+                ..
+            } if self.is_stale(position, sequence) => Ok(()),
+            WriterCommand::SetImage {
+                position: Some(position),
+                image,
+            } => {
+                self.track(position, &image);
+
+                let image = self.displayed(position, image).await;
+                self.set_image(position, image).await
+            }
+            WriterCommand::SetImage {
+                position: None,
+                image,
+            } => {
+                self.forget_shown();
+
+                handle_set_image(id, device, None, image, self.quality()).await?;
+
+                restore_rendered(id, device, &self.rendered).await
+            }
+            WriterCommand::SetImages(images) => {
+                self.set_images(sequence, images, None, queue).await
+            }
+            WriterCommand::SetPage { page, .. } if page.is_cancelled() => {
+                log::debug!("Dropping images of a page that was switched away from");
+                Ok(())
+            }
+            WriterCommand::SetPage { images, page } => {
+                self.set_images(sequence, images, Some(&page), queue).await
+            }
+            WriterCommand::SetBrightness(value) => {
+                self.brightness = value;
+
+                device
+                    .set_brightness(effective_brightness(self.brightness, self.dim))
+                    .await
+                    .context(id, Operation::SetBrightness)
+            }
+            WriterCommand::Dim(level) => {
+                self.dim = level;
+
+                device
+                    .set_brightness(effective_brightness(self.brightness, self.dim))
+                    .await
+                    .context(id, Operation::SetBrightness)
+            }
+            WriterCommand::Reset => {
+                // Only the device forgets its images, held keys don't show up pressed anymore
+                self.feedback.clear();
+                self.held.clear();
+
+                reset_device(id, device, effective_brightness(self.brightness, self.dim)).await?;
+
+                self.restore().await
+            }
+            WriterCommand::Redraw => request_redraw(id).await,
+            WriterCommand::Pressed { position, pressed } => self.press(position, pressed).await,
+            WriterCommand::Blink { position, image } => self.blink(position, image).await,
+            WriterCommand::Screenshot(path) => {
+                self.screenshot(&path).await;
+                Ok(())
+            }
+            WriterCommand::Badge { position, badge } => self.badge(position, badge).await,
+            WriterCommand::Transient { position, image } => self.transient(position, image).await,
+        }
+    }
+}
+
+// Whatever way the writer stops, the next one for the device picks up from here
+impl<D> Drop for Writer<'_, D> {
+    fn drop(&mut self) {
+        FRAMEBUFFERS.lock().unwrap().insert(
+            self.id.to_string(),
+            Framebuffer {
+                brightness: Some(self.brightness),
+                images: std::mem::take(&mut self.shown),
+                badges: std::mem::take(&mut self.badges),
+            },
+        );
+    }
+}
+
+/// Applies queued commands to the device until the queue is closed or device fails
+///
+/// `framebuffer` is drawn first, so a reconnected device shows what it did before without
+/// waiting for OpenDeck.
+pub async fn writer_task(
+    id: &str,
+    device: &impl DeviceTransport,
+    mut queue: WriterQueue,
+    framebuffer: Framebuffer,
+) {
+    let rendered = framebuffer
+        .images
+        .iter()
+        .filter_map(|(position, image)| match image {
+            KeyImage::Rendered(image) => Some((*position, image.clone())),
+            KeyImage::DataUrl(_) => None,
+        })
+        .collect();
+
+    let mut writer = Writer {
+        id,
+        device,
+        brightness: framebuffer.brightness.unwrap_or(CONFIG.borrow().brightness),
+        dim: dnd::dim_level(),
+        rendered,
+        shown: framebuffer.images,
+        held: HashSet::new(),
+        blinking: HashMap::new(),
+        transient: HashMap::new(),
+        badges: framebuffer.badges,
+        feedback: HashMap::new(),
+        uploaded: device.uploaded_bytes(),
+    };
+
+    if framebuffer.brightness.is_some() {
+        log::info!("Restoring previous state of {}", id);
+
+        let restored = async {
+            device
+                .set_brightness(effective_brightness(writer.brightness, writer.dim))
+                .await
+                .context(id, Operation::SetBrightness)?;
+
+            writer.restore().await
+        }
+        .await;
+
+        if let Err(err) = restored
+            && !handle_error(err).await
+        {
+            return;
+        }
+    }
+
+    while let Some(commands) = queue.next().await {
+        // Press feedback goes ahead of everything that was queued before it
+        if let Err(err) = writer.write_feedback(&queue).await
+            && !handle_error(err).await
+        {
+            return;
+        }
+
+        for (sequence, command) in commands {
+            match writer.apply(sequence, command, &queue).await {
+                Ok(()) => session::succeeded(id),
+                Err(err) => {
+                    if !handle_error(err).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::badge::{BadgeKind, Corner};
+    use akp05::transport::mock::{MockTransport, MockWrite};
+
+    // Positions 5-9 map to the same physical buttons, which keeps assertions readable
+    fn clear(position: u8) -> WriterCommand {
+        WriterCommand::SetImage {
+            position: Some(position),
+            image: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_merges_updates() {
+        let (handle, queue) = writer_channel();
+
+        for _ in 0..WRITER_QUEUE_SIZE {
+            handle.send(WriterCommand::SetBrightness(10));
+        }
+
+        // These don't fit, so only the latest of each kind survives
+        handle.send(clear(5));
+        handle.send(WriterCommand::SetBrightness(20));
+        handle.send(clear(6));
+        handle.send(WriterCommand::SetBrightness(30));
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        // Queued brightness changes are superseded by the merged one
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Clear(5),
+                MockWrite::Flush,
+                MockWrite::Clear(6),
+                MockWrite::Flush,
+                MockWrite::Brightness(30),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn clear_all_drops_pending_images() {
+        let (handle, queue) = writer_channel();
+
+        for position in 0..WRITER_QUEUE_SIZE as u8 {
+            handle.send(clear(5 + position % 5));
+        }
+
+        handle.send(clear(8));
+        handle.send(WriterCommand::SetImage {
+            position: None,
+            image: None,
+        });
+        handle.send(clear(9));
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::ClearAll,
+                MockWrite::Flush,
+                MockWrite::Clear(9),
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_restores_brightness_and_keeps_pending_images() {
+        let (handle, queue) = writer_channel();
+
+        for _ in 0..WRITER_QUEUE_SIZE {
+            handle.send(WriterCommand::SetBrightness(80));
+        }
+
+        // Reset only draws what the device showed, so images merged before it still follow
+        handle.send(clear(6));
+        handle.send(WriterCommand::Reset);
+        handle.send(clear(7));
+        drop(handle);
+
+        // Key 6 already shows an image
+        let framebuffer = Framebuffer {
+            images: BTreeMap::from([(
+                6,
+                KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(4, 4))),
+            )]),
+            ..Framebuffer::default()
+        };
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, framebuffer).await;
+
+        let drawn = MockWrite::Image {
+            key: 6,
+            size: (4, 4),
+        };
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(80),
+                MockWrite::Reset,
+                MockWrite::Brightness(80),
+                MockWrite::Flush,
+                drawn,
+                MockWrite::Flush,
+                MockWrite::Clear(6),
+                MockWrite::Flush,
+                MockWrite::Clear(7),
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rendered_images_are_restored() {
+        let (handle, queue) = writer_channel();
+        let image = Arc::new(DynamicImage::new_rgb8(4, 4));
+
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: Some(KeyImage::Rendered(image)),
+        });
+        handle.send(WriterCommand::Reset);
+        handle.send(WriterCommand::SetImage {
+            position: None,
+            image: None,
+        });
+        // OpenDeck taking the button back stops restoring it
+        handle.send(clear(5));
+        handle.send(WriterCommand::Reset);
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        let drawn = MockWrite::Image {
+            key: 5,
+            size: (4, 4),
+        };
+        let brightness = MockWrite::Brightness(CONFIG.borrow().brightness);
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                drawn.clone(),
+                MockWrite::Flush,
+                MockWrite::Reset,
+                brightness.clone(),
+                MockWrite::Flush,
+                drawn.clone(),
+                MockWrite::Flush,
+                MockWrite::ClearAll,
+                MockWrite::Flush,
+                drawn,
+                MockWrite::Flush,
+                MockWrite::Clear(5),
+                MockWrite::Flush,
+                MockWrite::Reset,
+                brightness,
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnected_devices_show_what_they_did_before() {
+        let id = "a5-framebuffer-test";
+        let (handle, queue) = writer_channel();
+
+        handle.send(WriterCommand::SetBrightness(40));
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: Some(KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(4, 4)))),
+        });
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task(id, &device, queue, take_framebuffer(id)).await;
+        device.take_writes();
+
+        let (handle, queue) = writer_channel();
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task(id, &device, queue, take_framebuffer(id)).await;
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(40),
+                MockWrite::Image {
+                    key: 5,
+                    size: (4, 4),
+                },
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn consecutive_brightness_changes_are_coalesced() {
+        let (handle, queue) = writer_channel();
+
+        for brightness in [10, 20, 30] {
+            handle.send(WriterCommand::SetBrightness(brightness));
+        }
+        handle.send(clear(5));
+        handle.send(WriterCommand::SetBrightness(40));
+        handle.send(WriterCommand::SetBrightness(50));
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        // Order with other commands stays the same
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(30),
+                MockWrite::Clear(5),
+                MockWrite::Flush,
+                MockWrite::Brightness(50),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn images_of_old_pages_are_dropped() {
+        let (handle, queue) = writer_channel();
+
+        let old = CancellationToken::new();
+        handle.send(WriterCommand::SetPage {
+            images: vec![(5, None), (6, None)],
+            page: old.clone(),
+        });
+        handle.send(WriterCommand::SetPage {
+            images: vec![(7, None)],
+            page: CancellationToken::new(),
+        });
+        drop(handle);
+
+        // Switched again before the writer got to it
+        old.cancel();
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        assert_eq!(
+            device.take_writes(),
+            vec![MockWrite::Clear(7), MockWrite::Flush]
+        );
+    }
+
+    #[tokio::test]
+    async fn image_batches_are_sent_together() {
+        let (handle, queue) = writer_channel();
+        let image = Arc::new(DynamicImage::new_rgb8(4, 4));
+
+        handle.send(WriterCommand::SetImages(vec![
+            (5, Some(KeyImage::Rendered(image))),
+            (6, None),
+        ]));
+        handle.send(WriterCommand::Reset);
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        let drawn = MockWrite::Image {
+            key: 5,
+            size: (4, 4),
+        };
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                drawn.clone(),
+                MockWrite::Clear(6),
+                MockWrite::Flush,
+                MockWrite::Reset,
+                MockWrite::Brightness(CONFIG.borrow().brightness),
+                MockWrite::Flush,
+                drawn,
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_send_images_together() {
+        let (handle, queue) = writer_channel();
+        let image = Some(KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(4, 4))));
+
+        handle.begin_frame();
+        handle.send(clear(5));
+        handle.send(WriterCommand::SetImage {
+            position: Some(6),
+            image: image.clone(),
+        });
+        handle.send(WriterCommand::SetBrightness(40));
+        // Newer image for the same key replaces the collected one
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image,
+        });
+        handle.commit();
+
+        // Frame that isn't committed by hand goes out once images stop coming
+        handle.begin_frame();
+        handle.send(clear(7));
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        let drawn = |key| MockWrite::Image { key, size: (4, 4) };
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(40),
+                drawn(5),
+                drawn(6),
+                MockWrite::Flush,
+                MockWrite::Clear(7),
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_devices_dont_hold_back_others() {
+        let (busy, busy_queue) = writer_channel();
+        let (idle, idle_queue) = writer_channel();
+
+        for _ in 0..WRITER_QUEUE_SIZE * 2 {
+            busy.send(clear(5));
+        }
+        idle.send(clear(6));
+        drop((busy, idle));
+
+        let (busy_device, idle_device) = (MockTransport::new(), MockTransport::new());
+
+        tokio::join!(
+            writer_task("a5-busy", &busy_device, busy_queue, Framebuffer::default()),
+            writer_task("a5-idle", &idle_device, idle_queue, Framebuffer::default()),
+        );
+
+        assert_eq!(
+            idle_device.take_writes(),
+            vec![MockWrite::Clear(6), MockWrite::Flush]
+        );
+    }
+
+    #[tokio::test]
+    async fn feedback_jumps_ahead_of_queued_images() {
+        let (handle, queue) = writer_channel();
+        let image = |size| {
+            Some(KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(
+                size, size,
+            ))))
+        };
+
+        handle.send(WriterCommand::SetImages(vec![(6, image(4)), (5, image(4))]));
+        handle.key_changed(5);
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: image(2),
+        });
+        // Key 6 wasn't pressed, so its image waits for its turn
+        handle.send(WriterCommand::SetImage {
+            position: Some(6),
+            image: image(2),
+        });
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        // Older image of key 5 from the batch would hide the feedback, so it's skipped
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Image {
+                    key: 5,
+                    size: (2, 2)
+                },
+                MockWrite::Flush,
+                MockWrite::Image {
+                    key: 6,
+                    size: (4, 4)
+                },
+                MockWrite::Flush,
+                MockWrite::Image {
+                    key: 6,
+                    size: (2, 2)
+                },
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn blinking_keys_show_the_other_image_until_stopped() {
+        let (handle, queue) = writer_channel();
+        let image_of = |size| KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(size, size)));
+
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: Some(image_of(4)),
+        });
+        handle.send(WriterCommand::Blink {
+            position: 5,
+            image: Some(BlinkImage::Image(image_of(2))),
+        });
+        handle.send(WriterCommand::Blink {
+            position: 5,
+            image: None,
+        });
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        let image = |size| MockWrite::Image {
+            key: 5,
+            size: (size, size),
+        };
+
+        // Blinks go through the priority lane, ahead of the key image that was still queued
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                image(2),
+                MockWrite::Flush,
+                MockWrite::Clear(5),
+                MockWrite::Flush,
+                image(4),
+                MockWrite::Flush,
+            ]
+        );
+
+        // Key images that change while it blinks don't stop the blinking
+        let (handle, queue) = writer_channel();
+        handle.send(WriterCommand::Blink {
+            position: 5,
+            image: Some(BlinkImage::Image(image_of(2))),
+        });
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: Some(image_of(4)),
+        });
+        drop(handle);
+
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        assert_eq!(
+            device.take_writes(),
+            vec![image(2), MockWrite::Flush, image(2), MockWrite::Flush]
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_images_revert_to_the_latest_key_image() {
+        let (handle, queue) = writer_channel();
+        let image_of = |size| KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(size, size)));
+
+        handle.send(WriterCommand::Transient {
+            position: 5,
+            image: Some(image_of(2)),
+        });
+        // Key image changing meanwhile is kept for later
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: Some(image_of(4)),
+        });
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-transient-test", &device, queue, Framebuffer::default()).await;
+
+        let image = |size| MockWrite::Image {
+            key: 5,
+            size: (size, size),
+        };
+        assert_eq!(
+            device.take_writes(),
+            vec![image(2), MockWrite::Flush, image(2), MockWrite::Flush]
+        );
+
+        // Transient images aren't kept for reconnects, the key image is
+        let (handle, queue) = writer_channel();
+        handle.send(WriterCommand::Transient {
+            position: 5,
+            image: Some(image_of(2)),
+        });
+        handle.send(WriterCommand::Transient {
+            position: 5,
+            image: None,
+        });
+        drop(handle);
+
+        let framebuffer = take_framebuffer("a5-transient-test");
+        writer_task("a5-transient-test", &device, queue, framebuffer).await;
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(CONFIG.borrow().brightness),
+                image(4),
+                MockWrite::Flush,
+                image(2),
+                MockWrite::Flush,
+                image(4),
+                MockWrite::Flush,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn badges_only_rewrite_their_key() {
+        let (handle, queue) = writer_channel();
+        let badge = |count| {
+            Some(Badge {
+                kind: BadgeKind::Count(count),
+                corner: Corner::TopRight,
+            })
+        };
+
+        handle.send(WriterCommand::SetImage {
+            position: Some(5),
+            image: Some(KeyImage::Rendered(Arc::new(DynamicImage::new_rgb8(4, 4)))),
+        });
+        handle.send(WriterCommand::Badge {
+            position: 5,
+            badge: badge(3),
+        });
+        // Same badge again and a key without an image have nothing to write
+        handle.send(WriterCommand::Badge {
+            position: 5,
+            badge: badge(3),
+        });
+        handle.send(WriterCommand::Badge {
+            position: 6,
+            badge: badge(1),
+        });
+        handle.send(WriterCommand::Badge {
+            position: 5,
+            badge: None,
+        });
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-badge-test", &device, queue, Framebuffer::default()).await;
+
+        let image = MockWrite::Image {
+            key: 5,
+            size: (4, 4),
+        };
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                image.clone(),
+                MockWrite::Flush,
+                image.clone(),
+                MockWrite::Flush,
+                image,
+                MockWrite::Flush,
+            ]
+        );
+
+        // Badges of keys are still there after a reconnect
+        let framebuffer = take_framebuffer("a5-badge-test");
+        assert_eq!(
+            framebuffer.badges.into_iter().collect::<Vec<_>>(),
+            vec![(6, badge(1).unwrap())]
+        );
+    }
+
+    #[test]
+    fn overlays_are_drawn_in_one_go() {
+        let image = Some(KeyImage::Rendered(Arc::new(DynamicImage::ImageRgba8(
+            image::RgbaImage::from_pixel(2, 2, image::Rgba([200, 200, 200, 255])),
+        ))));
+        let overlays = Overlays {
+            dimmed: true,
+            effect: PressEffect::Invert,
+            ..Overlays::default()
+        };
+
+        // Dimmed first, then inverted
+        let Some(KeyImage::Rendered(drawn)) = map_decoded(image, |image| overlays.apply(image))
+        else {
+            panic!("Expected a rendered image");
+        };
+        assert_eq!(drawn.to_rgba8().get_pixel(0, 0).0, [155, 155, 155, 255]);
+
+        // Images that can't be decoded fail once they're written
+        let broken = Some(KeyImage::DataUrl("data:,".to_string()));
+        assert_eq!(
+            map_decoded(broken.clone(), |image| overlays.apply(image)),
+            broken
+        );
+    }
+
+    #[tokio::test]
+    async fn dimming_caps_brightness_until_restored() {
+        let (handle, queue) = writer_channel();
+
+        handle.send(WriterCommand::SetBrightness(80));
+        handle.send(WriterCommand::Dim(Some(10)));
+        handle.send(WriterCommand::SetBrightness(60));
+        handle.send(WriterCommand::Dim(None));
+        drop(handle);
+
+        let device = MockTransport::new();
+        writer_task("a5-test", &device, queue, Framebuffer::default()).await;
+
+        assert_eq!(
+            device.take_writes(),
+            vec![
+                MockWrite::Brightness(80),
+                MockWrite::Brightness(10),
+                MockWrite::Brightness(10),
+                MockWrite::Brightness(60),
+            ]
+        );
+    }
+}